@@ -0,0 +1,91 @@
+//! Timings of the parse, lookup and group stages on synthetic CIF files of 100k and 1M lines.
+//!
+//! Run with `cargo bench`. Each stage is run a few times and the fastest and mean times are
+//! printed, with the 1M line scale given fewer runs. The peak heap use of grouping, counted by
+//! the allocator below, is printed after its times.
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rail_hourly_departures::hour_grouping::GroupingContext;
+use rail_hourly_departures::{Date, Day, assemble_journeys, create_lookup, group, log, parse};
+
+/// The system allocator, keeping count of the bytes allocated and their peak
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn main() {
+    log::set_level(log::Level::Quiet);
+    for (lines, runs) in [(100_000, 10), (1_000_000, 3)] {
+        let cif = support::synthetic_cif(lines);
+        println!("{lines} lines:");
+
+        bench("records::parse", runs, || parse(cif.text.clone()));
+        let records = parse(cif.text.clone());
+        bench("stops::create_lookup", runs, || {
+            create_lookup(&records, &cif.three_alpha_codes)
+        });
+        let lookup = create_lookup(&records, &cif.three_alpha_codes);
+        let journeys = assemble_journeys(records);
+        let context = GroupingContext::for_week(Date(260113));
+        bench("hour_grouping::group", runs, || {
+            group(&journeys, &lookup, &Day::Tuesday, &context)
+        });
+        peak_heap("hour_grouping::group", || {
+            group(&journeys, &lookup, &Day::Tuesday, &context)
+        });
+    }
+}
+
+/// Print the most heap `stage` had allocated at once, beyond what was allocated before it,
+/// including its result
+fn peak_heap<T>(name: &str, stage: impl FnOnce() -> T) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let result = stage();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    drop(black_box(result));
+    println!(
+        "  {name:<24} peak heap {:>8.1} MiB",
+        peak as f64 / (1 << 20) as f64
+    );
+}
+
+fn bench<T>(name: &str, runs: usize, mut stage: impl FnMut() -> T) {
+    let times: Vec<Duration> = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(stage());
+            start.elapsed()
+        })
+        .collect();
+    let fastest = times.iter().min().expect("at least one run");
+    let mean = times.iter().sum::<Duration>() / runs as u32;
+    println!("  {name:<24} fastest {fastest:>10.2?}  mean {mean:>10.2?}");
+}
@@ -2,10 +2,9 @@
 
 set -e
 
-mkdir -p data
-
 time cargo run --release -- \
 	--input-file-dir=input \
     --operating-day=tuesday \
 	--operating-week=260112 \
-	--output-directory=data
\ No newline at end of file
+	--output-directory=data \
+	--force
\ No newline at end of file
@@ -0,0 +1,232 @@
+//! Criteria pass counts by area. Three alpha codes carry no area, so a station's area is the
+//! STANOX area of its TIPLOC: the first two digits of the STANOX, which number the TOPS areas.
+
+#[cfg(feature = "fs")]
+use anyhow::Result;
+#[cfg(feature = "fs")]
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use super::criteria::CriteriaResults;
+use super::records::{Record, ThreeAlphaCode, Tiploc};
+#[cfg(feature = "fs")]
+use super::utils;
+
+/// The area of stations whose STANOX is blank or not numeric
+pub const UNKNOWN_AREA: &str = "unknown";
+
+/// A TOML area names file, such as `[areas]` then `87 = "London North Western"`
+#[cfg(feature = "fs")]
+#[derive(Deserialize)]
+struct AreaNamesToml {
+    areas: HashMap<String, String>,
+}
+
+/// Read the names of the STANOX areas from a TOML `[areas]` table
+#[cfg(feature = "fs")]
+pub fn read_area_names(path: &Path) -> Result<HashMap<String, String>> {
+    let area_names: AreaNamesToml = utils::read_toml_file(path)?;
+    Ok(area_names.areas)
+}
+
+/// The STANOX area of every station in `lookup`, taken from the TIPLOC carrying its three
+/// alpha code
+pub fn create_station_areas(
+    records: &[Record],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+) -> HashMap<ThreeAlphaCode, String> {
+    let mut station_areas: HashMap<ThreeAlphaCode, String> = HashMap::new();
+    for record in records.iter() {
+        if let Record::Stop(stop) = record
+            && stop.three_alpha_code.is_some()
+            && let Some(three_alpha_code) = lookup.get(&stop.tiploc)
+            && let Some(area) = stop
+                .stanox
+                .get(0..2)
+                .filter(|area| area.bytes().all(|byte| byte.is_ascii_digit()))
+        {
+            station_areas
+                .entry(three_alpha_code.clone())
+                .or_insert_with(|| area.to_string());
+        }
+    }
+    station_areas
+}
+
+/// The area of a station in `station_areas`, or [`UNKNOWN_AREA`] without one
+pub fn area_of<'a>(
+    three_alpha_code: &ThreeAlphaCode,
+    station_areas: &'a HashMap<ThreeAlphaCode, String>,
+) -> &'a str {
+    station_areas
+        .get(three_alpha_code)
+        .map_or(UNKNOWN_AREA, String::as_str)
+}
+
+/// An area as listed in the index.json of --split-by-area
+#[derive(Debug, PartialEq, Serialize)]
+pub struct AreaIndexEntry {
+    pub area: String,
+    pub name: Option<String>,
+    pub stations: usize,
+}
+
+/// The areas of the stations, each station counted once, in area order
+pub fn area_index<'a>(
+    three_alpha_codes: impl IntoIterator<Item = &'a ThreeAlphaCode>,
+    station_areas: &HashMap<ThreeAlphaCode, String>,
+    area_names: &HashMap<String, String>,
+) -> Vec<AreaIndexEntry> {
+    let three_alpha_codes: HashSet<&ThreeAlphaCode> = three_alpha_codes.into_iter().collect();
+    let mut by_area: BTreeMap<&str, usize> = BTreeMap::new();
+    for three_alpha_code in three_alpha_codes {
+        *by_area
+            .entry(area_of(three_alpha_code, station_areas))
+            .or_default() += 1;
+    }
+    by_area
+        .into_iter()
+        .map(|(area, stations)| AreaIndexEntry {
+            area: area.to_string(),
+            name: area_names.get(area).cloned(),
+            stations,
+        })
+        .collect()
+}
+
+/// How many of an area's stations pass each criterion
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct AreaCriteria {
+    pub area: String,
+    pub name: Option<String>,
+    pub stations: usize,
+    pub all_7_7: usize,
+    pub all_6_10: usize,
+    pub avg_7_7: usize,
+    pub avg_6_10: usize,
+}
+
+/// Count the stations passing each criterion in each area, in area order. Stations without an
+/// area are counted under [`UNKNOWN_AREA`].
+pub fn criteria_by_area(
+    criteria_results: &HashMap<ThreeAlphaCode, CriteriaResults>,
+    station_areas: &HashMap<ThreeAlphaCode, String>,
+    area_names: &HashMap<String, String>,
+) -> Vec<AreaCriteria> {
+    let mut by_area: BTreeMap<&str, AreaCriteria> = BTreeMap::new();
+    for (three_alpha_code, results) in criteria_results {
+        let area = area_of(three_alpha_code, station_areas);
+        let counts = by_area.entry(area).or_insert_with(|| AreaCriteria {
+            area: area.to_string(),
+            name: area_names.get(area).cloned(),
+            ..AreaCriteria::default()
+        });
+        counts.stations += 1;
+        counts.all_7_7 += results.all_7_7 as usize;
+        counts.all_6_10 += results.all_6_10 as usize;
+        counts.avg_7_7 += results.avg_7_7 as usize;
+        counts.avg_6_10 += results.avg_6_10 as usize;
+    }
+    by_area.into_values().collect()
+}
+
+/// The area counts as a Markdown table
+pub fn format_table(areas: &[AreaCriteria]) -> String {
+    let mut table = String::from(
+        "| Area | Name | Stations | all_7_7 | all_6_10 | avg_7_7 | avg_6_10 |\n\
+         |---|---|---:|---:|---:|---:|---:|\n",
+    );
+    for area in areas {
+        table.push_str(&format!(
+            "| {} | {} | {} | {} | {} | {} | {} |\n",
+            area.area,
+            area.name.as_deref().unwrap_or(""),
+            area.stations,
+            area.all_7_7,
+            area.all_6_10,
+            area.avg_7_7,
+            area.avg_6_10
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hour_grouping::HourlyDepartures;
+    use crate::test_cif;
+    use crate::{CriteriaThresholds, create_lookup, evaluate_criteria, parse};
+
+    #[test]
+    fn test_criteria_by_area() {
+        let cif = test_cif::cif(test_cif::STATIONS);
+        let records = parse(cif);
+        let codes = ["KGX", "SVG", "CBG", "XXX"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let station_areas = create_station_areas(&records, &lookup);
+        assert_eq!(station_areas[&codes[0]], "87");
+        assert_eq!(station_areas[&codes[2]], "51");
+
+        let departures: HashMap<ThreeAlphaCode, HourlyDepartures> = codes
+            .iter()
+            .zip([6, 1, 6, 6])
+            .map(|(three_alpha_code, count)| {
+                let hourly_departures = HourlyDepartures {
+                    three_alpha_code: three_alpha_code.clone(),
+                    hour_counts: [count; 24],
+                    hour_counts_journey_starts: [0; 24],
+                    hour_counts_arrivals: [0; 24],
+                    hour_counts_journey_ends: [0; 24],
+                    next_stop_three_alpha_code: vec![Vec::new(); 24],
+                    ..HourlyDepartures::default()
+                }
+                .with_totals();
+                (three_alpha_code.clone(), hourly_departures)
+            })
+            .collect();
+        let area_names = HashMap::from([("87".to_string(), "London North".to_string())]);
+        let areas = criteria_by_area(
+            &evaluate_criteria(&departures, &CriteriaThresholds::default()),
+            &station_areas,
+            &area_names,
+        );
+
+        assert_eq!(
+            areas
+                .iter()
+                .map(|area| area.area.as_str())
+                .collect::<Vec<_>>(),
+            ["51", "87", UNKNOWN_AREA]
+        );
+        assert_eq!(areas[1].name.as_deref(), Some("London North"));
+        assert_eq!((areas[1].stations, areas[1].all_7_7), (2, 1));
+        assert_eq!((areas[2].stations, areas[2].all_7_7), (1, 1));
+        assert!(format_table(&areas).contains("| 87 | London North | 2 | 1 |"));
+
+        let index = area_index(codes.iter().chain(&codes[..2]), &station_areas, &area_names);
+        assert_eq!(
+            index,
+            [
+                AreaIndexEntry {
+                    area: "51".to_string(),
+                    name: None,
+                    stations: 1,
+                },
+                AreaIndexEntry {
+                    area: "87".to_string(),
+                    name: Some("London North".to_string()),
+                    stations: 2,
+                },
+                AreaIndexEntry {
+                    area: UNKNOWN_AREA.to_string(),
+                    name: None,
+                    stations: 1,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::Result;
+use std::path::Path;
+
+use super::records::{self, ParseOptions, ParseSummary, Record};
+use super::storage::{OutputDirectory, OutputSink};
+use super::utils;
+use super::{info, warn};
+
+/// Parse the raw CIF text, reusing the records cached in `cache_dir` by an earlier run over
+/// identical input. The cache file is keyed by a hash of the input text and the crate version,
+/// so a new timetable or a new release never picks up stale records. The parse summary is cached
+/// with the records, so reports from cached runs match fresh ones. A miss is parsed as
+/// `options` say, which must read every record type.
+pub fn parse_with_cache(
+    raw_cif_text: String,
+    cache_dir: &Path,
+    options: ParseOptions,
+) -> Result<(Vec<Record>, ParseSummary)> {
+    // Hashed one after the other, rather than joined, so the text is never copied
+    let mut hasher = utils::Fnv1a::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update(raw_cif_text.as_bytes());
+    let key = hasher.finish();
+    let file_name = format!("records_{key:016x}");
+    let path = cache_dir.join(format!("{file_name}.json"));
+
+    if path.exists() {
+        info!("Loading cached records from {}", path.display());
+        match utils::read_json_file(&path) {
+            Ok(parsed) => return Ok(parsed),
+            Err(e) => {
+                // Written by a build with a different record layout, so parse again
+                warn!("Ignoring the unreadable cache file {}: {e}", path.display());
+                fs_err::remove_file(&path)?;
+            }
+        }
+    }
+
+    let parsed = records::parse_with_options(raw_cif_text, options);
+    OutputDirectory::new(cache_dir.to_path_buf(), false).write_json(&file_name, &parsed)?;
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hour_grouping::{GroupingContext, group};
+    use crate::records::{Date, Day, ThreeAlphaCode};
+    use crate::stops::create_lookup;
+    use crate::test_cif;
+
+    fn cif() -> String {
+        test_cif::with_stations([
+            "BSNC123452601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LISTEVNGE 0820 0821      08200821         T",
+            "LTCAMBDGE 0900 0900",
+        ])
+    }
+
+    fn departures_json(records: Vec<Record>) -> serde_json::Value {
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let context = GroupingContext::for_week(Date(260113));
+        let journeys = records::assemble_journeys(records);
+        let departures = group(&journeys, &lookup, &Day::Tuesday, &context);
+        serde_json::to_value(departures).unwrap()
+    }
+
+    #[test]
+    fn test_cached_run_matches_fresh_parse() {
+        let cache_dir = std::env::temp_dir().join(format!("rhd_cache_{}", std::process::id()));
+
+        let (fresh_records, fresh_summary) = records::parse_with_summary(cif());
+        let (first_records, first_summary) =
+            parse_with_cache(cif(), &cache_dir, ParseOptions::default()).unwrap();
+        let (cached_records, cached_summary) =
+            parse_with_cache(cif(), &cache_dir, ParseOptions::default()).unwrap();
+        fs_err::remove_dir_all(&cache_dir).unwrap();
+
+        let fresh = departures_json(fresh_records);
+        assert_eq!(fresh, departures_json(first_records));
+        assert_eq!(fresh, departures_json(cached_records));
+        assert_eq!(fresh_summary, first_summary);
+        assert_eq!(fresh_summary, cached_summary);
+    }
+}
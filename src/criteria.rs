@@ -1,43 +1,221 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     ops::Range,
 };
 
-use super::hour_grouping::HourlyDepartures;
+use super::hour_grouping::{HourWindow, HourlyDepartures};
 use super::records::ThreeAlphaCode;
 
+/// How busy an hour must be to count towards the criteria. An hour passes with at least
+/// `min_departures_per_hour` departures, or with at least `min_journey_starts_per_hour`
+/// journeys starting from the station. The arrival criteria do the same with arrivals and
+/// journeys ending at the station, and are left out without `min_arrivals_per_hour`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CriteriaThresholds {
+    pub min_departures_per_hour: u32,
+    /// Also the threshold for journey ends
+    pub min_journey_starts_per_hour: u32,
+    #[serde(default)]
+    pub min_arrivals_per_hour: Option<u32>,
+    /// Departures each of the [`NIGHT_SERVICE_HOURS`] needs for the night service criterion
+    pub night_service_min_departures_per_hour: u32,
+    /// The early start criterion needs a departure from 01:00 up to this hour
+    pub early_start_before_hour: u32,
+    /// Evaluate the criteria over [`HourlyDepartures::weighted_hour_counts`], each hour rounded
+    /// down to whole departures, rather than over the departures themselves
+    pub weighted_counts: bool,
+}
+
+pub const DEFAULT_MIN_DEPARTURES_PER_HOUR: u32 = 4;
+pub const DEFAULT_MIN_JOURNEY_STARTS_PER_HOUR: u32 = 2;
+pub const DEFAULT_MIN_ARRIVALS_PER_HOUR: u32 = 4;
+pub const DEFAULT_NIGHT_SERVICE_MIN_DEPARTURES_PER_HOUR: u32 = 1;
+pub const DEFAULT_EARLY_START_BEFORE_HOUR: u32 = 6;
+
+/// The hours of the night service criterion, 22:00 to 00:59. Departures in the hour after
+/// midnight are those of the evening's late trains, so they don't count as an early start.
+pub const NIGHT_SERVICE_HOURS: [usize; 3] = [22, 23, 0];
+
+impl Default for CriteriaThresholds {
+    fn default() -> Self {
+        CriteriaThresholds {
+            min_departures_per_hour: DEFAULT_MIN_DEPARTURES_PER_HOUR,
+            min_journey_starts_per_hour: DEFAULT_MIN_JOURNEY_STARTS_PER_HOUR,
+            min_arrivals_per_hour: Some(DEFAULT_MIN_ARRIVALS_PER_HOUR),
+            night_service_min_departures_per_hour: DEFAULT_NIGHT_SERVICE_MIN_DEPARTURES_PER_HOUR,
+            early_start_before_hour: DEFAULT_EARLY_START_BEFORE_HOUR,
+            weighted_counts: false,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct CriteriaResults {
     pub three_alpha_code: ThreeAlphaCode,
+    #[serde(with = "super::hour_grouping::hour_array")]
     pub hour_counts: [u32; 24],
+    #[serde(with = "super::hour_grouping::hour_array")]
     pub hour_counts_journey_starts: [u32; 24],
     pub all_7_7: bool,
     pub all_6_10: bool,
     pub avg_7_7: bool,
     pub avg_6_10: bool,
+    /// The departures all_7_7 is short of, summed over its failing hours, and the hour short
+    /// of the most. See [`shortfall`].
+    pub all_7_7_shortfall: u32,
+    pub all_7_7_worst_hour: Option<usize>,
+    pub all_6_10_shortfall: u32,
+    pub all_6_10_worst_hour: Option<usize>,
+    /// Departures in every one of the [`NIGHT_SERVICE_HOURS`]
+    pub night_service: bool,
+    /// A departure before [`CriteriaThresholds::early_start_before_hour`]
+    pub early_start: bool,
+    /// The arrival side of all_7_7, for destinations, unless the arrival criteria are off
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub all_7_7_arrivals: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_7_7_arrivals: Option<bool>,
     pub flagged_for_review: bool,
     pub next_stop_three_alpha_code: Option<Vec<Vec<ThreeAlphaCode>>>,
 }
 
+/// The criteria needing an hour `window` leaves out, so which can't be evaluated over
+/// departures counted only within it
+pub fn criteria_outside_window(
+    window: &HourWindow,
+    thresholds: &CriteriaThresholds,
+) -> Vec<&'static str> {
+    let before_hour = (thresholds.early_start_before_hour as usize).clamp(1, 24);
+    [
+        ("all_7_7", window.covers(7..19)),
+        ("all_6_10", window.covers(6..22)),
+        ("avg_7_7", window.covers(7..19)),
+        ("avg_6_10", window.covers(6..22)),
+        ("night_service", window.covers(NIGHT_SERVICE_HOURS)),
+        ("early_start", window.covers(1..before_hour)),
+    ]
+    .into_iter()
+    .filter(|(_, covered)| !covered)
+    .map(|(criterion, _)| criterion)
+    .collect()
+}
+
+/// Check every station's hourly departures against the frequency criteria
+///
+/// ```
+/// use std::collections::HashMap;
+/// use rail_hourly_departures::{
+///     CriteriaThresholds, HourlyDepartures, ThreeAlphaCode, evaluate_criteria,
+/// };
+///
+/// let code = ThreeAlphaCode("KGX".to_string());
+/// let departures = HashMap::from([(
+///     code.clone(),
+///     HourlyDepartures {
+///         three_alpha_code: code.clone(),
+///         hour_counts: [6; 24],
+///         hour_counts_journey_starts: [0; 24],
+///         hour_counts_arrivals: [6; 24],
+///         hour_counts_journey_ends: [0; 24],
+///         next_stop_three_alpha_code: vec![Vec::new(); 24],
+///         ..HourlyDepartures::default()
+///     }
+///     .with_totals(),
+/// )]);
+///
+/// let results = evaluate_criteria(&departures, &CriteriaThresholds::default());
+/// assert!(results[&code].all_7_7);
+/// assert_eq!(results[&code].all_7_7_arrivals, Some(true));
+/// ```
 pub fn evaluate_criteria(
     departures: &HashMap<ThreeAlphaCode, HourlyDepartures>,
+    thresholds: &CriteriaThresholds,
 ) -> HashMap<ThreeAlphaCode, CriteriaResults> {
     let mut results: HashMap<ThreeAlphaCode, CriteriaResults> = HashMap::new();
 
     for (three_alpha_code, hourly_departure) in departures.iter() {
+        let weighted;
+        let hourly_departure = match thresholds.weighted_counts {
+            true => {
+                weighted = hourly_departure.as_weighted();
+                &weighted
+            }
+            false => hourly_departure,
+        };
         let mut flagged_for_review = false;
         let mut criteria_result = CriteriaResults {
             three_alpha_code: hourly_departure.three_alpha_code.clone(),
             hour_counts: hourly_departure.hour_counts,
             hour_counts_journey_starts: hourly_departure.hour_counts_journey_starts,
-            all_7_7: all_meet_criteria(7..19, hourly_departure, &mut flagged_for_review),
-            all_6_10: all_meet_criteria(6..22, hourly_departure, &mut flagged_for_review),
-            avg_7_7: avg_meet_criteria(7..19, hourly_departure, &mut flagged_for_review),
-            avg_6_10: avg_meet_criteria(6..22, hourly_departure, &mut flagged_for_review),
+            all_7_7: all_meet_criteria(
+                7..19,
+                hourly_departure,
+                thresholds,
+                &mut flagged_for_review,
+            ),
+            all_6_10: all_meet_criteria(
+                6..22,
+                hourly_departure,
+                thresholds,
+                &mut flagged_for_review,
+            ),
+            avg_7_7: avg_meet_criteria(
+                7..19,
+                hourly_departure,
+                thresholds,
+                &mut flagged_for_review,
+            ),
+            avg_6_10: avg_meet_criteria(
+                6..22,
+                hourly_departure,
+                thresholds,
+                &mut flagged_for_review,
+            ),
+            all_7_7_shortfall: 0,
+            all_7_7_worst_hour: None,
+            all_6_10_shortfall: 0,
+            all_6_10_worst_hour: None,
+            night_service: night_service(&hourly_departure.hour_counts, thresholds),
+            early_start: early_start(&hourly_departure.hour_counts, thresholds),
+            all_7_7_arrivals: thresholds
+                .min_arrivals_per_hour
+                .map(|min_arrivals_per_hour| {
+                    all_hours_meet(
+                        7..19,
+                        &hourly_departure.hour_counts_arrivals,
+                        &hourly_departure.hour_counts_journey_ends,
+                        min_arrivals_per_hour,
+                        thresholds.min_journey_starts_per_hour,
+                    )
+                }),
+            avg_7_7_arrivals: thresholds
+                .min_arrivals_per_hour
+                .map(|min_arrivals_per_hour| {
+                    average_meets(
+                        7..19,
+                        &hourly_departure.hour_counts_arrivals,
+                        &hourly_departure.hour_counts_journey_ends,
+                        min_arrivals_per_hour,
+                        thresholds.min_journey_starts_per_hour,
+                    )
+                }),
             flagged_for_review: false,
             next_stop_three_alpha_code: None,
         };
+        if !criteria_result.all_7_7 {
+            (
+                criteria_result.all_7_7_shortfall,
+                criteria_result.all_7_7_worst_hour,
+            ) = shortfall(7..19, hourly_departure, thresholds);
+        }
+        if !criteria_result.all_6_10 {
+            (
+                criteria_result.all_6_10_shortfall,
+                criteria_result.all_6_10_worst_hour,
+            ) = shortfall(6..22, hourly_departure, thresholds);
+        }
         criteria_result.flagged_for_review = flagged_for_review;
         if flagged_for_review {
             criteria_result.next_stop_three_alpha_code =
@@ -52,18 +230,17 @@ pub fn evaluate_criteria(
 fn all_meet_criteria(
     range: Range<usize>,
     departures: &HourlyDepartures,
+    thresholds: &CriteriaThresholds,
     flagged_for_review: &mut bool,
 ) -> bool {
-    // Each station which has more than four departures per hour (or more than 2 at the start of
-    // their route) for every hour within the range
-    let mut criteria_met = true;
-
-    // First check all hours have 4+ departures
-    for hour in range.clone() {
-        if departures.hour_counts[hour] < 4 && departures.hour_counts_journey_starts[hour] < 2 {
-            criteria_met = false;
-        }
-    }
+    // First check all hours have enough departures
+    let mut criteria_met = all_hours_meet(
+        range.clone(),
+        &departures.hour_counts,
+        &departures.hour_counts_journey_starts,
+        thresholds.min_departures_per_hour,
+        thresholds.min_journey_starts_per_hour,
+    );
 
     // If not met, check if all hours have 2+ departures to the same next stop
     if !criteria_met {
@@ -72,6 +249,100 @@ fn all_meet_criteria(
     criteria_met
 }
 
+/// Whether every hour within the range has at least `min_count` calls, or at least
+/// `min_journeys` journeys starting (or ending) there
+fn all_hours_meet(
+    range: Range<usize>,
+    counts: &[u32; 24],
+    journeys: &[u32; 24],
+    min_count: u32,
+    min_journeys: u32,
+) -> bool {
+    range
+        .into_iter()
+        .all(|hour| counts[hour] >= min_count || journeys[hour] >= min_journeys)
+}
+
+/// Whether the hours within the range average at least `min_count` calls, or at least
+/// `min_journeys` journeys starting (or ending) there. A journey is worth
+/// min_count / min_journeys calls, so both sides are scaled by min_journeys to stay in whole
+/// numbers.
+fn average_meets(
+    range: Range<usize>,
+    counts: &[u32; 24],
+    journeys: &[u32; 24],
+    min_count: u32,
+    min_journeys: u32,
+) -> bool {
+    let total: u64 = range
+        .clone()
+        .map(|hour| {
+            u64::max(
+                counts[hour] as u64 * min_journeys as u64,
+                journeys[hour] as u64 * min_count as u64,
+            )
+        })
+        .sum();
+    total >= range.len() as u64 * min_count as u64 * min_journeys as u64
+}
+
+/// The departures needed to pass an all hours criterion over the range, summed over the hours
+/// short of it, and the hour short of the most (the earliest of several). An hour short of
+/// both thresholds needs the fewer of the departures or the journey starts it lacks, as each
+/// journey added starting there counts towards both.
+pub fn shortfall(
+    range: Range<usize>,
+    departures: &HourlyDepartures,
+    thresholds: &CriteriaThresholds,
+) -> (u32, Option<usize>) {
+    let mut total = 0;
+    let mut worst: Option<(usize, u32)> = None;
+    for hour in range {
+        let needed = u32::min(
+            thresholds
+                .min_departures_per_hour
+                .saturating_sub(departures.hour_counts[hour]),
+            thresholds
+                .min_journey_starts_per_hour
+                .saturating_sub(departures.hour_counts_journey_starts[hour]),
+        );
+        total += needed;
+        if needed > 0 && worst.is_none_or(|(_, most)| needed > most) {
+            worst = Some((hour, needed));
+        }
+    }
+    (total, worst.map(|(hour, _)| hour))
+}
+
+/// The stations failing a criterion, fewest departures short first, as picked out by
+/// `shortfall` from [`CriteriaResults`]
+pub fn closest_to_passing(
+    criteria_results: &HashMap<ThreeAlphaCode, CriteriaResults>,
+    shortfall: fn(&CriteriaResults) -> u32,
+) -> Vec<&CriteriaResults> {
+    let mut failing: Vec<&CriteriaResults> = criteria_results
+        .values()
+        .filter(|results| shortfall(results) > 0)
+        .collect();
+    failing.sort_by(|a, b| {
+        shortfall(a)
+            .cmp(&shortfall(b))
+            .then_with(|| a.three_alpha_code.cmp(&b.three_alpha_code))
+    });
+    failing
+}
+
+fn night_service(hour_counts: &[u32; 24], thresholds: &CriteriaThresholds) -> bool {
+    NIGHT_SERVICE_HOURS
+        .iter()
+        .all(|&hour| hour_counts[hour] >= thresholds.night_service_min_departures_per_hour)
+}
+
+fn early_start(hour_counts: &[u32; 24], thresholds: &CriteriaThresholds) -> bool {
+    let before_hour = (thresholds.early_start_before_hour as usize).clamp(1, 24);
+    hour_counts[1..before_hour].iter().any(|&count| count > 0)
+}
+
 fn all_hours_have_2_same_next_stop(
     range: Range<usize>,
     departures: &HourlyDepartures,
@@ -135,20 +406,21 @@ fn all_hours_have_2_same_next_stop(
 fn avg_meet_criteria(
     range: Range<usize>,
     departures: &HourlyDepartures,
+    thresholds: &CriteriaThresholds,
     flagged_for_review: &mut bool,
 ) -> bool {
-    // Each station which has an average of 4 departures or more (i.e. an average of four per hour) or
-    // an average of 2+ at the start of their route across the hours in the range
-    let total: u32 = range
-        .clone()
-        .map(|hour| {
-            u32::max(
-                departures.hour_counts[hour],
-                departures.hour_counts_journey_starts[hour] * 2,
-            )
-        })
-        .sum();
-    let mut criteria_met = total >= (range.len() * 4) as u32;
+    // Each station which averages at least the minimum departures per hour, or at least the
+    // minimum journey starts, across the hours in the range. Most stations that pass do so on
+    // their departures alone, which the precomputed total shows without going hour by hour.
+    let mut criteria_met = departures.total_within(&range) as u64
+        >= range.len() as u64 * thresholds.min_departures_per_hour as u64
+        || average_meets(
+            range.clone(),
+            &departures.hour_counts,
+            &departures.hour_counts_journey_starts,
+            thresholds.min_departures_per_hour,
+            thresholds.min_journey_starts_per_hour,
+        );
 
     // If not met, check if average of 2+ departures to the same next stop
     if !criteria_met {
@@ -208,9 +480,18 @@ mod tests {
             hour_counts_journey_starts: [
                 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             ],
+            hour_counts_arrivals: [0; 24],
+            hour_counts_journey_ends: [0; 24],
             next_stop_three_alpha_code: vec![Vec::new(); 24],
-        };
-        assert!(avg_meet_criteria(7..19, &departures, &mut false));
+            ..HourlyDepartures::default()
+        }
+        .with_totals();
+        assert!(avg_meet_criteria(
+            7..19,
+            &departures,
+            &CriteriaThresholds::default(),
+            &mut false
+        ));
     }
 
     #[test]
@@ -223,9 +504,18 @@ mod tests {
             hour_counts_journey_starts: [
                 0, 0, 0, 0, 0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
             ],
+            hour_counts_arrivals: [0; 24],
+            hour_counts_journey_ends: [0; 24],
             next_stop_three_alpha_code: vec![Vec::new(); 24],
-        };
-        assert!(avg_meet_criteria(6..22, &departures, &mut false));
+            ..HourlyDepartures::default()
+        }
+        .with_totals();
+        assert!(avg_meet_criteria(
+            6..22,
+            &departures,
+            &CriteriaThresholds::default(),
+            &mut false
+        ));
     }
 
     #[test]
@@ -238,9 +528,18 @@ mod tests {
             hour_counts_journey_starts: [
                 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             ],
+            hour_counts_arrivals: [0; 24],
+            hour_counts_journey_ends: [0; 24],
             next_stop_three_alpha_code: vec![Vec::new(); 24],
-        };
-        assert!(!all_meet_criteria(7..19, &departures, &mut false));
+            ..HourlyDepartures::default()
+        }
+        .with_totals();
+        assert!(!all_meet_criteria(
+            7..19,
+            &departures,
+            &CriteriaThresholds::default(),
+            &mut false
+        ));
     }
 
     #[test]
@@ -253,8 +552,212 @@ mod tests {
             hour_counts_journey_starts: [
                 0, 0, 0, 0, 0, 2, 2, 2, 2, 2, 2, 2, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
             ],
+            hour_counts_arrivals: [0; 24],
+            hour_counts_journey_ends: [0; 24],
             next_stop_three_alpha_code: vec![Vec::new(); 24],
+            ..HourlyDepartures::default()
+        }
+        .with_totals();
+        assert!(!all_meet_criteria(
+            7..19,
+            &departures,
+            &CriteriaThresholds::default(),
+            &mut false
+        ));
+    }
+
+    type Criterion = fn(Range<usize>, &HourlyDepartures, &CriteriaThresholds, &mut bool) -> bool;
+
+    const CRITERIA: [Criterion; 2] = [all_meet_criteria, avg_meet_criteria];
+
+    fn constant_departures(count: u32, starts: u32) -> HourlyDepartures {
+        HourlyDepartures {
+            three_alpha_code: ThreeAlphaCode("TST".to_string()),
+            hour_counts: [count; 24],
+            hour_counts_journey_starts: [starts; 24],
+            hour_counts_arrivals: [0; 24],
+            hour_counts_journey_ends: [0; 24],
+            next_stop_three_alpha_code: vec![Vec::new(); 24],
+            ..HourlyDepartures::default()
+        }
+        .with_totals()
+    }
+
+    #[test]
+    fn test_min_departures_are_at_least() {
+        for min_departures_per_hour in [2, 4, 6] {
+            let thresholds = CriteriaThresholds {
+                min_departures_per_hour,
+                ..CriteriaThresholds::default()
+            };
+            let at = constant_departures(min_departures_per_hour, 0);
+            let below = constant_departures(min_departures_per_hour - 1, 0);
+            for meets in CRITERIA {
+                assert!(meets(7..19, &at, &thresholds, &mut false));
+                assert!(!meets(7..19, &below, &thresholds, &mut false));
+            }
+        }
+    }
+
+    #[test]
+    fn test_min_journey_starts_are_at_least() {
+        for min_journey_starts_per_hour in [1, 2, 3] {
+            let thresholds = CriteriaThresholds {
+                min_journey_starts_per_hour,
+                ..CriteriaThresholds::default()
+            };
+            let at = constant_departures(min_journey_starts_per_hour, min_journey_starts_per_hour);
+            let below = constant_departures(
+                min_journey_starts_per_hour - 1,
+                min_journey_starts_per_hour - 1,
+            );
+            for meets in CRITERIA {
+                assert!(meets(7..19, &at, &thresholds, &mut false));
+                assert!(!meets(7..19, &below, &thresholds, &mut false));
+            }
+        }
+    }
+
+    #[test]
+    fn test_avg_threshold_is_met_on_average() {
+        // Alternating 5 and 7 departures averages exactly 6, alternating 5 and 6 falls short
+        let thresholds = CriteriaThresholds {
+            min_departures_per_hour: 6,
+            ..CriteriaThresholds::default()
+        };
+        let mut departures = constant_departures(5, 0);
+        for hour in (0..24).step_by(2) {
+            departures.hour_counts[hour] = 7;
+        }
+        departures.update_totals();
+        assert!(avg_meet_criteria(
+            7..19,
+            &departures,
+            &thresholds,
+            &mut false
+        ));
+        assert!(!all_meet_criteria(
+            7..19,
+            &departures,
+            &thresholds,
+            &mut false
+        ));
+        for hour in (0..24).step_by(2) {
+            departures.hour_counts[hour] = 6;
+        }
+        departures.update_totals();
+        assert!(!avg_meet_criteria(
+            7..19,
+            &departures,
+            &thresholds,
+            &mut false
+        ));
+    }
+
+    #[test]
+    fn test_arrival_criteria() {
+        // Four arrivals every hour but one, which has two journeys ending instead
+        let mut departures = constant_departures(0, 0);
+        departures.hour_counts_arrivals = [4; 24];
+        departures.hour_counts_arrivals[12] = 1;
+        departures.hour_counts_journey_ends[12] = 2;
+        let code = departures.three_alpha_code.clone();
+        let departures = HashMap::from([(code.clone(), departures)]);
+
+        let results = &evaluate_criteria(&departures, &CriteriaThresholds::default())[&code];
+        assert!(!results.all_7_7);
+        assert_eq!(results.all_7_7_arrivals, Some(true));
+        assert_eq!(results.avg_7_7_arrivals, Some(true));
+
+        let thresholds = CriteriaThresholds {
+            min_journey_starts_per_hour: 3,
+            ..CriteriaThresholds::default()
+        };
+        let results = &evaluate_criteria(&departures, &thresholds)[&code];
+        assert_eq!(results.all_7_7_arrivals, Some(false));
+        assert_eq!(results.avg_7_7_arrivals, Some(false));
+
+        let thresholds = CriteriaThresholds {
+            min_arrivals_per_hour: None,
+            ..CriteriaThresholds::default()
+        };
+        let results = &evaluate_criteria(&departures, &thresholds)[&code];
+        assert_eq!(results.all_7_7_arrivals, None);
+        assert!(
+            !serde_json::to_value(results)
+                .unwrap()
+                .as_object()
+                .unwrap()
+                .contains_key("all_7_7_arrivals")
+        );
+    }
+
+    #[test]
+    fn test_night_service() {
+        // A single 22:00 departure counts in its hour
+        let mut departures = constant_departures(0, 0);
+        for hour in NIGHT_SERVICE_HOURS {
+            departures.hour_counts[hour] = 1;
+        }
+        let thresholds = CriteriaThresholds::default();
+        assert!(night_service(&departures.hour_counts, &thresholds));
+        departures.hour_counts[23] = 0;
+        assert!(!night_service(&departures.hour_counts, &thresholds));
+        departures.hour_counts[23] = 1;
+        let thresholds = CriteriaThresholds {
+            night_service_min_departures_per_hour: 2,
+            ..thresholds
+        };
+        assert!(!night_service(&departures.hour_counts, &thresholds));
+    }
+
+    #[test]
+    fn test_early_start() {
+        let thresholds = CriteriaThresholds::default();
+        let mut hour_counts = [0; 24];
+        // First train exactly at 06:00
+        hour_counts[6] = 1;
+        assert!(!early_start(&hour_counts, &thresholds));
+        // Only the last of the night before
+        hour_counts[0] = 1;
+        assert!(!early_start(&hour_counts, &thresholds));
+        hour_counts[5] = 1;
+        assert!(early_start(&hour_counts, &thresholds));
+        let thresholds = CriteriaThresholds {
+            early_start_before_hour: 5,
+            ..thresholds
         };
-        assert!(!all_meet_criteria(7..19, &departures, &mut false));
+        assert!(!early_start(&hour_counts, &thresholds));
+    }
+
+    #[test]
+    fn test_shortfall_takes_the_cheaper_threshold() {
+        let mut departures = constant_departures(4, 0);
+        // Three departures short, or one journey start
+        departures.hour_counts[8] = 1;
+        departures.hour_counts_journey_starts[8] = 1;
+        // Two short, with no journey starts to make up
+        departures.hour_counts[12] = 2;
+        // Short before 07:00 only
+        departures.hour_counts[6] = 0;
+        departures.update_totals();
+        let thresholds = CriteriaThresholds::default();
+        assert_eq!(shortfall(7..19, &departures, &thresholds), (3, Some(12)));
+        assert_eq!(shortfall(6..22, &departures, &thresholds), (5, Some(6)));
+
+        let code = departures.three_alpha_code.clone();
+        let passing = constant_departures(4, 0);
+        let other = ThreeAlphaCode("OTH".to_string());
+        let results = evaluate_criteria(
+            &HashMap::from([(code.clone(), departures), (other, passing)]),
+            &thresholds,
+        );
+        assert_eq!(results[&code].all_7_7_shortfall, 3);
+        assert_eq!(results[&code].all_7_7_worst_hour, Some(12));
+        let closest = closest_to_passing(&results, |results| results.all_7_7_shortfall);
+        assert_eq!(closest.len(), 1);
+        assert_eq!(closest[0].three_alpha_code, code);
+        let oth = &results[&ThreeAlphaCode("OTH".to_string())];
+        assert_eq!((oth.all_7_7_shortfall, oth.all_7_7_worst_hour), (0, None));
     }
 }
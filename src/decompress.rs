@@ -0,0 +1,565 @@
+//! Reading gzip and zip compressed CIF files, and writing the uncompressed zips of the GTFS
+//! export.
+//!
+//! The timetable drops arrive as `.CIF.gz` or as a `.zip` of several CIFs. Both use DEFLATE
+//! (RFC 1951), decoded here in the style of zlib's puff.c: a canonical Huffman decoder that
+//! favours simplicity over speed.
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+
+use super::info;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+/// How an input file is compressed, from its first bytes
+#[derive(Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zip,
+}
+
+impl Compression {
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if bytes.starts_with(&ZIP_MAGIC) {
+            Compression::Zip
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// The character encoding of a CIF file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// UTF-8, falling back to Latin-1 when the file is not valid UTF-8
+    Auto,
+    Utf8,
+    /// ISO-8859-1, which also covers the accented letters of Windows-1252
+    Latin1,
+}
+
+impl Encoding {
+    pub fn decode(self, bytes: Vec<u8>) -> Result<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes).context("The CIF file is not valid UTF-8"),
+            Encoding::Latin1 => Ok(latin1_to_string(&bytes)),
+            Encoding::Auto => String::from_utf8(bytes).or_else(|e| {
+                info!("The CIF file is not valid UTF-8, reading it as Latin-1");
+                Ok(latin1_to_string(e.as_bytes()))
+            }),
+        }
+    }
+}
+
+/// Every Latin-1 byte is the Unicode code point of the same value
+fn latin1_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| *byte as char).collect()
+}
+
+/// The text of a CIF file, decompressing gzip files and joining every .cif entry of a zip
+/// archive in archive order
+pub fn decode_cif_bytes(bytes: Vec<u8>, encoding: Encoding) -> Result<String> {
+    let decompressed = match Compression::detect(&bytes) {
+        Compression::None => bytes,
+        Compression::Gzip => gunzip(&bytes)?,
+        Compression::Zip => {
+            let mut joined = Vec::new();
+            for (name, contents) in unzip(&bytes)? {
+                if !name.to_ascii_lowercase().ends_with(".cif") {
+                    continue;
+                }
+                info!("Reading {name} from the zip archive");
+                joined.extend(contents);
+                if joined.last().is_some_and(|byte| *byte != b'\n') {
+                    joined.push(b'\n');
+                }
+            }
+            joined
+        }
+    };
+    encoding.decode(decompressed)
+}
+
+/// Decompress every member of a gzip file (RFC 1952)
+pub fn gunzip(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        let member = &bytes[position..];
+        ensure!(
+            member.len() >= 18 && member.starts_with(&GZIP_MAGIC) && member[2] == 8,
+            "Not a gzip deflate stream at byte {position}"
+        );
+        let flags = member[3];
+        let mut header_len = 10;
+        if flags & 0x04 != 0 {
+            ensure!(member.len() >= header_len + 2, "Truncated gzip header");
+            let extra_len = u16::from_le_bytes([member[10], member[11]]) as usize;
+            header_len += 2 + extra_len;
+        }
+        for flag in [0x08, 0x10] {
+            // Zero terminated file name and comment
+            if flags & flag != 0 {
+                let end = member
+                    .get(header_len..)
+                    .ok_or_else(|| anyhow!("Truncated gzip header"))?
+                    .iter()
+                    .position(|byte| *byte == 0)
+                    .ok_or_else(|| anyhow!("Truncated gzip header"))?;
+                header_len += end + 1;
+            }
+        }
+        if flags & 0x02 != 0 {
+            header_len += 2;
+        }
+        ensure!(header_len < member.len(), "Truncated gzip header");
+
+        let start = output.len();
+        let deflate_len = inflate(&member[header_len..], &mut output)?;
+        let trailer = member
+            .get(header_len + deflate_len..header_len + deflate_len + 8)
+            .ok_or_else(|| anyhow!("Truncated gzip trailer"))?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        ensure!(
+            crc32(&output[start..]) == crc && (output.len() - start) as u32 == size,
+            "gzip checksum mismatch, the file is corrupt"
+        );
+        position += header_len + deflate_len + 8;
+    }
+    Ok(output)
+}
+
+/// The name and contents of every file in a zip archive, in central directory order
+pub fn unzip(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let u16_at = |offset: usize| -> Result<usize> {
+        let slice = bytes
+            .get(offset..offset + 2)
+            .ok_or_else(|| anyhow!("Truncated zip archive"))?;
+        Ok(u16::from_le_bytes([slice[0], slice[1]]) as usize)
+    };
+    let u32_at = |offset: usize| -> Result<usize> {
+        let slice = bytes
+            .get(offset..offset + 4)
+            .ok_or_else(|| anyhow!("Truncated zip archive"))?;
+        Ok(u32::from_le_bytes([slice[0], slice[1], slice[2], slice[3]]) as usize)
+    };
+
+    // The end of central directory record is the last 22 bytes, before any archive comment
+    let end_of_directory = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|offset| bytes[*offset..].starts_with(&[b'P', b'K', 0x05, 0x06]))
+        .ok_or_else(|| anyhow!("No zip central directory found"))?;
+    let entry_count = u16_at(end_of_directory + 10)?;
+    let mut offset = u32_at(end_of_directory + 16)?;
+
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        ensure!(
+            u32_at(offset)? == 0x02014b50,
+            "Corrupt zip central directory"
+        );
+        let method = u16_at(offset + 10)?;
+        let compressed_size = u32_at(offset + 20)?;
+        let name_len = u16_at(offset + 28)?;
+        let entry_header_len = 46 + name_len + u16_at(offset + 30)? + u16_at(offset + 32)?;
+        let local_header = u32_at(offset + 42)?;
+        let name = String::from_utf8_lossy(
+            bytes
+                .get(offset + 46..offset + 46 + name_len)
+                .ok_or_else(|| anyhow!("Truncated zip archive"))?,
+        )
+        .to_string();
+
+        let data_start =
+            local_header + 30 + u16_at(local_header + 26)? + u16_at(local_header + 28)?;
+        let data = bytes
+            .get(data_start..data_start + compressed_size)
+            .ok_or_else(|| anyhow!("Truncated zip entry {name}"))?;
+        let contents = match method {
+            0 => data.to_vec(),
+            8 => {
+                let mut contents = Vec::new();
+                inflate(data, &mut contents)
+                    .with_context(|| format!("Corrupt zip entry {name}"))?;
+                contents
+            }
+            _ => bail!("Zip entry {name} uses unsupported compression method {method}"),
+        };
+        entries.push((name, contents));
+        offset += entry_header_len;
+    }
+    Ok(entries)
+}
+
+/// A zip archive of `entries`, stored without compression. There is no zip64 support, so the
+/// archive must stay under 4 GiB.
+pub fn zip_stored(entries: &[(String, Vec<u8>)]) -> Result<Vec<u8>> {
+    // 1980-01-01 00:00, the earliest date a zip can hold
+    const DOS_DATE: u16 = 0x21;
+    let mut archive: Vec<u8> = Vec::new();
+    let mut directory: Vec<u8> = Vec::new();
+
+    for (name, contents) in entries {
+        let offset = u32::try_from(archive.len())?;
+        let size = u32::try_from(contents.len())
+            .with_context(|| format!("Zip entry {name} is too large"))?;
+        let name_len = u16::try_from(name.len())?;
+        let crc = crc32(contents);
+        // Version needed, flags, method, time, date, CRC and sizes are shared with the
+        // central directory entry
+        let common = [
+            &20u16.to_le_bytes()[..],
+            &0u16.to_le_bytes(),
+            &0u16.to_le_bytes(),
+            &0u16.to_le_bytes(),
+            &DOS_DATE.to_le_bytes(),
+            &crc.to_le_bytes(),
+            &size.to_le_bytes(),
+            &size.to_le_bytes(),
+            &name_len.to_le_bytes(),
+            &0u16.to_le_bytes(),
+        ]
+        .concat();
+
+        archive.extend(0x04034b50u32.to_le_bytes());
+        archive.extend(&common);
+        archive.extend(name.as_bytes());
+        archive.extend(contents);
+
+        directory.extend(0x02014b50u32.to_le_bytes());
+        directory.extend(20u16.to_le_bytes());
+        directory.extend(&common);
+        // Comment length, disk number, internal and external attributes
+        directory.extend([0u8; 10]);
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+
+    let entry_count = u16::try_from(entries.len())?;
+    let directory_offset = u32::try_from(archive.len())?;
+    let directory_len = u32::try_from(directory.len())?;
+    archive.extend(directory);
+    archive.extend(0x06054b50u32.to_le_bytes());
+    archive.extend([0u8; 4]);
+    archive.extend(entry_count.to_le_bytes());
+    archive.extend(entry_count.to_le_bytes());
+    archive.extend(directory_len.to_le_bytes());
+    archive.extend(directory_offset.to_le_bytes());
+    archive.extend(0u16.to_le_bytes());
+    Ok(archive)
+}
+
+/// CRC-32 as used by gzip and zip
+fn crc32(bytes: &[u8]) -> u32 {
+    let table: [u32; 256] = std::array::from_fn(|index| {
+        (0..8).fold(index as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    });
+    !bytes.iter().fold(!0, |crc, byte| {
+        (crc >> 8) ^ table[((crc ^ *byte as u32) & 0xff) as usize]
+    })
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        while self.bit_count < count {
+            let byte = *self
+                .bytes
+                .get(self.position)
+                .ok_or_else(|| anyhow!("Unexpected end of deflate stream"))?;
+            self.position += 1;
+            self.bit_buffer |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buffer & ((1u64 << count) - 1) as u32;
+        self.bit_buffer >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Drop the rest of the current byte, for stored blocks
+    fn align_to_byte(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        bail!("Invalid Huffman code in deflate stream")
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in for dynamic blocks
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompress a raw DEFLATE stream onto `output`, returning how many input bytes it used
+fn inflate(bytes: &[u8], output: &mut Vec<u8>) -> Result<usize> {
+    let mut reader = BitReader {
+        bytes,
+        position: 0,
+        bit_buffer: 0,
+        bit_count: 0,
+    };
+    let block_start = output.len();
+    loop {
+        let is_last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align_to_byte();
+                let header = bytes
+                    .get(reader.position..reader.position + 4)
+                    .ok_or_else(|| anyhow!("Unexpected end of deflate stream"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                ensure!(
+                    len == !u16::from_le_bytes([header[2], header[3]]),
+                    "Corrupt stored block in deflate stream"
+                );
+                let start = reader.position + 4;
+                let stored = bytes
+                    .get(start..start + len as usize)
+                    .ok_or_else(|| anyhow!("Unexpected end of deflate stream"))?;
+                output.extend_from_slice(stored);
+                reader.position = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(&mut reader, output, block_start, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, output, block_start, &literals, &distances)?;
+            }
+            _ => bail!("Invalid block type in deflate stream"),
+        }
+        if is_last {
+            return Ok(reader.position);
+        }
+    }
+}
+
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+
+    let mut code_length_lengths = [0u8; 19];
+    for index in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_length_lengths[*index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_length_lengths);
+
+    let mut lengths: Vec<u8> = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| anyhow!("Repeated length with no previous length"))?;
+                (previous, 3 + reader.bits(2)?)
+            }
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        ensure!(
+            lengths.len() + repeat as usize <= literal_count + distance_count,
+            "Too many code lengths in deflate stream"
+        );
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    let (literal_lengths, distance_lengths) = lengths.split_at(literal_count);
+    Ok((
+        Huffman::new(literal_lengths),
+        Huffman::new(distance_lengths),
+    ))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    stream_start: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                ensure!(index < 29, "Invalid length code in deflate stream");
+                let length =
+                    LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index])? as usize;
+                let index = distances.decode(reader)? as usize;
+                ensure!(index < 30, "Invalid distance code in deflate stream");
+                let distance =
+                    DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index])? as usize;
+                ensure!(
+                    distance <= output.len() - stream_start,
+                    "Distance too far back in deflate stream"
+                );
+                // Byte by byte, as the copy may overlap the bytes it is producing
+                let from = output.len() - distance;
+                for offset in 0..length {
+                    output.push(output[from + offset]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIF: &str = include_str!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.CIF");
+
+    #[test]
+    fn test_gzip_fixture_round_trips() {
+        let gzipped = include_bytes!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.CIF.gz");
+        assert_eq!(Compression::detect(gzipped), Compression::Gzip);
+        assert_eq!(
+            decode_cif_bytes(gzipped.to_vec(), Encoding::Utf8).unwrap(),
+            CIF
+        );
+    }
+
+    #[test]
+    fn test_zip_fixture_joins_cif_entries_in_order() {
+        let zipped = include_bytes!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.zip");
+        assert_eq!(Compression::detect(zipped), Compression::Zip);
+        let names: Vec<String> = unzip(zipped).unwrap().into_iter().map(|e| e.0).collect();
+        assert_eq!(names, ["part1.CIF", "README.txt", "part2.CIF"]);
+        assert_eq!(
+            decode_cif_bytes(zipped.to_vec(), Encoding::Utf8).unwrap(),
+            CIF
+        );
+    }
+
+    #[test]
+    fn test_stored_zip_round_trips_through_unzip() {
+        let entries = vec![
+            ("stops.txt".to_string(), b"stop_id\nKGX\n".to_vec()),
+            ("empty.txt".to_string(), Vec::new()),
+        ];
+        let zipped = zip_stored(&entries).unwrap();
+        assert_eq!(Compression::detect(&zipped), Compression::Zip);
+        assert_eq!(unzip(&zipped).unwrap(), entries);
+    }
+
+    #[test]
+    fn test_plain_text_is_left_alone() {
+        assert_eq!(
+            decode_cif_bytes(CIF.as_bytes().to_vec(), Encoding::Utf8).unwrap(),
+            CIF
+        );
+    }
+
+    #[test]
+    fn test_latin1_bytes_decode_to_accented_letters() {
+        let bytes = b"TIPONTYPR00121000 PONTYPRIDD GAR\xe9".to_vec();
+        assert!(Encoding::Utf8.decode(bytes.clone()).is_err());
+        let decoded = Encoding::Auto.decode(bytes.clone()).unwrap();
+        assert!(decoded.ends_with("GARé"));
+        assert_eq!(Encoding::Latin1.decode(bytes).unwrap(), decoded);
+        assert_eq!(Encoding::Auto.decode("é".as_bytes().to_vec()).unwrap(), "é");
+    }
+
+    #[test]
+    fn test_corrupt_gzip_is_an_error() {
+        let mut gzipped =
+            include_bytes!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.CIF.gz").to_vec();
+        let last = gzipped.len() - 9;
+        gzipped[last] ^= 0xff;
+        assert!(gunzip(&gzipped).is_err());
+    }
+
+    #[test]
+    fn test_truncated_gzip_header_is_an_error() {
+        // FEXTRA and FNAME set, with an extra field longer than the whole member
+        let mut header = vec![0x1f, 0x8b, 8, 0x04 | 0x08, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff];
+        header.extend(b"name");
+        header.resize(20, b'x');
+        let error = gunzip(&header).unwrap_err();
+        assert_eq!(error.to_string(), "Truncated gzip header");
+    }
+}
@@ -0,0 +1,35 @@
+//! The built in input of --demo: the miniature CIF file and station list of the integration
+//! tests, so the demo is exercised whenever they run.
+
+use anyhow::Result;
+use std::path::PathBuf;
+
+use super::records::Date;
+use super::{Args, cif_file_path, info};
+
+const DEMO_CIF: &str = include_str!("../tests/fixtures/mini/CIF_ALL_FULL_DAILY_toc-full.CIF");
+const DEMO_STATION_CODES: &str = include_str!("../tests/fixtures/mini/station_codes.toml");
+
+/// The operating week counted by --demo when neither --operating-week nor --date is given
+pub const DEMO_OPERATING_WEEK: Date = Date(260113);
+
+/// Write the demo input to a directory of its own under the temporary directory and point
+/// `args` at it, returning the directory
+pub fn use_demo_input(args: &mut Args) -> Result<PathBuf> {
+    let input_file_dir = std::env::temp_dir().join(format!(
+        "rail-hourly-departures-demo-{}",
+        std::process::id()
+    ));
+    fs_err::create_dir_all(&input_file_dir)?;
+    fs_err::write(cif_file_path(&input_file_dir), DEMO_CIF)?;
+    let station_codes_file = input_file_dir.join("station_codes.toml");
+    fs_err::write(&station_codes_file, DEMO_STATION_CODES)?;
+    info!("Running the demo over {}", input_file_dir.display());
+
+    args.input_file_dir = input_file_dir.clone();
+    args.station_codes_files = vec![station_codes_file];
+    if args.date.is_none() && args.operating_week.is_none() {
+        args.operating_week = Some(DEMO_OPERATING_WEEK);
+    }
+    Ok(input_file_dir)
+}
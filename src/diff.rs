@@ -0,0 +1,407 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use super::hour_grouping::HourlyDepartures;
+use super::operators::OperatorOrigins;
+use super::records::ThreeAlphaCode;
+
+/// How far, in percent, a station's daily total may move from the baseline's before
+/// [`anomalies`] lists it
+pub const DEFAULT_MAX_CHANGE_PERCENT: f64 = 25.0;
+
+/// The hourly counts of a departures or criteria results file, ignoring every other field
+#[derive(Debug, Deserialize)]
+pub struct DepartureCounts {
+    #[serde(alias = "hourCounts", with = "super::hour_grouping::hour_array")]
+    pub hour_counts: [u32; 24],
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StationDiff {
+    pub three_alpha_code: ThreeAlphaCode,
+    pub change: Change,
+    pub old_total: u32,
+    pub new_total: u32,
+    pub total_delta: i64,
+    pub hour_deltas: [i64; 24],
+}
+
+/// Compare the departures of two runs station by station, largest absolute change first.
+/// Changed stations are dropped when neither the daily total nor any single hour moved by at
+/// least `threshold` departures.
+pub fn diff_departures(
+    old: &HashMap<ThreeAlphaCode, DepartureCounts>,
+    new: &HashMap<ThreeAlphaCode, DepartureCounts>,
+    threshold: u32,
+) -> Vec<StationDiff> {
+    let codes: BTreeSet<&ThreeAlphaCode> = old.keys().chain(new.keys()).collect();
+
+    let mut diffs: Vec<StationDiff> = Vec::new();
+    for code in codes {
+        let old_counts = old.get(code).map(|counts| counts.hour_counts);
+        let new_counts = new.get(code).map(|counts| counts.hour_counts);
+        let change = match (old_counts, new_counts) {
+            (None, Some(_)) => Change::Added,
+            (Some(_), None) => Change::Removed,
+            _ => Change::Changed,
+        };
+        let old_counts = old_counts.unwrap_or([0; 24]);
+        let new_counts = new_counts.unwrap_or([0; 24]);
+
+        let hour_deltas: [i64; 24] =
+            std::array::from_fn(|hour| new_counts[hour] as i64 - old_counts[hour] as i64);
+        let old_total: u32 = old_counts.iter().sum();
+        let new_total: u32 = new_counts.iter().sum();
+        let total_delta = new_total as i64 - old_total as i64;
+
+        let largest_change = hour_deltas
+            .iter()
+            .chain([&total_delta])
+            .map(|delta| delta.unsigned_abs())
+            .max()
+            .unwrap_or_default();
+        if change == Change::Changed && (largest_change == 0 || largest_change < threshold as u64) {
+            continue;
+        }
+
+        diffs.push(StationDiff {
+            three_alpha_code: code.clone(),
+            change,
+            old_total,
+            new_total,
+            total_delta,
+            hour_deltas,
+        });
+    }
+
+    // Stable sort, so ties stay in three alpha code order
+    diffs.sort_by_key(|diff| std::cmp::Reverse(diff.total_delta.unsigned_abs()));
+    diffs
+}
+
+/// A station listed in anomalies.json, as [`anomalies`] picks them out
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Anomaly {
+    pub three_alpha_code: ThreeAlphaCode,
+    pub change: Change,
+    pub baseline_total: u32,
+    pub total: u32,
+    /// The change from the baseline's total, None for a station added or removed, and also
+    /// for one with no departures in the baseline
+    pub percent_change: Option<f64>,
+}
+
+/// The stations of `departures` whose daily total moved by more than `max_change_percent`
+/// from the `baseline` run's, and those only one of the two has, largest change first. A QA
+/// gate for the data, as a regression in the timetable or the config shows as stations
+/// appearing, disappearing or changing implausibly.
+pub fn anomalies(
+    baseline: &HashMap<ThreeAlphaCode, DepartureCounts>,
+    departures: &HashMap<ThreeAlphaCode, HourlyDepartures>,
+    max_change_percent: f64,
+) -> Vec<Anomaly> {
+    let counts: HashMap<ThreeAlphaCode, DepartureCounts> = departures
+        .iter()
+        .map(|(three_alpha_code, hourly_departures)| {
+            let hour_counts = hourly_departures.hour_counts;
+            (three_alpha_code.clone(), DepartureCounts { hour_counts })
+        })
+        .collect();
+    diff_departures(baseline, &counts, 1)
+        .into_iter()
+        .filter_map(|diff| {
+            let percent_change = (diff.change == Change::Changed && diff.old_total > 0)
+                .then(|| diff.total_delta as f64 / diff.old_total as f64 * 100.0);
+            let implausible = match diff.change {
+                Change::Added | Change::Removed => true,
+                Change::Changed => percent_change
+                    .is_none_or(|percent_change| percent_change.abs() > max_change_percent),
+            };
+            implausible.then_some(Anomaly {
+                three_alpha_code: diff.three_alpha_code,
+                change: diff.change,
+                baseline_total: diff.old_total,
+                total: diff.new_total,
+                percent_change,
+            })
+        })
+        .collect()
+}
+
+/// The operator_origins.json of a run: each day's operators, keyed by day then operator code
+pub type OperatorOriginsByDay = BTreeMap<String, BTreeMap<String, OperatorOrigins>>;
+
+/// An operator of one day whose journeys changed between two runs
+#[derive(Debug, PartialEq, Serialize)]
+pub struct OperatorDiff {
+    pub day: String,
+    pub operator: String,
+    pub change: Change,
+    pub old_journeys: usize,
+    pub new_journeys: usize,
+    pub journeys_delta: i64,
+    /// The stations the operator's journeys start from in the new run but not the old
+    pub origins_added: Vec<ThreeAlphaCode>,
+    pub origins_removed: Vec<ThreeAlphaCode>,
+}
+
+/// Compare the operators of two runs' operator_origins.json day by day, with each day's
+/// largest absolute change first. Changed operators are dropped when their journeys moved by
+/// fewer than `threshold` and they start from the same stations.
+pub fn diff_operator_origins(
+    old: &OperatorOriginsByDay,
+    new: &OperatorOriginsByDay,
+    threshold: u32,
+) -> Vec<OperatorDiff> {
+    let no_operators = BTreeMap::new();
+    let days: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let mut diffs: Vec<OperatorDiff> = Vec::new();
+    for day in days {
+        let old_operators = old.get(day).unwrap_or(&no_operators);
+        let new_operators = new.get(day).unwrap_or(&no_operators);
+        let operators: BTreeSet<&String> =
+            old_operators.keys().chain(new_operators.keys()).collect();
+        let mut day_diffs: Vec<OperatorDiff> = Vec::new();
+        for operator in operators {
+            let old_origins = old_operators.get(operator);
+            let new_origins = new_operators.get(operator);
+            let change = match (old_origins, new_origins) {
+                (None, Some(_)) => Change::Added,
+                (Some(_), None) => Change::Removed,
+                _ => Change::Changed,
+            };
+            let (old_origins, new_origins) = (
+                old_origins.cloned().unwrap_or_default(),
+                new_origins.cloned().unwrap_or_default(),
+            );
+            let only_in = |origins: &OperatorOrigins, other: &OperatorOrigins| {
+                origins
+                    .origins
+                    .keys()
+                    .filter(|origin| !other.origins.contains_key(origin))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let origins_added = only_in(&new_origins, &old_origins);
+            let origins_removed = only_in(&old_origins, &new_origins);
+            let journeys_delta = new_origins.journeys as i64 - old_origins.journeys as i64;
+            if change == Change::Changed
+                && journeys_delta.unsigned_abs() < (threshold as u64).max(1)
+                && origins_added.is_empty()
+                && origins_removed.is_empty()
+            {
+                continue;
+            }
+            day_diffs.push(OperatorDiff {
+                day: day.clone(),
+                operator: operator.clone(),
+                change,
+                old_journeys: old_origins.journeys,
+                new_journeys: new_origins.journeys,
+                journeys_delta,
+                origins_added,
+                origins_removed,
+            });
+        }
+        // Stable sort, so ties stay in operator code order
+        day_diffs.sort_by_key(|diff| std::cmp::Reverse(diff.journeys_delta.unsigned_abs()));
+        diffs.extend(day_diffs);
+    }
+    diffs
+}
+
+/// A plain text table of the operator diffs for printing
+pub fn format_operator_table(diffs: &[OperatorDiff]) -> String {
+    let mut table = format!(
+        "{:<10}{:<10}{:<9}{:>8}{:>8}{:>8}{:>9}{:>9}\n",
+        "Day", "Operator", "Change", "Old", "New", "Delta", "+Origins", "-Origins"
+    );
+    for diff in diffs {
+        table.push_str(&format!(
+            "{:<10}{:<10}{:<9}{:>8}{:>8}{:>+8}{:>9}{:>9}\n",
+            diff.day,
+            diff.operator,
+            format!("{:?}", diff.change),
+            diff.old_journeys,
+            diff.new_journeys,
+            diff.journeys_delta,
+            diff.origins_added.len(),
+            diff.origins_removed.len()
+        ));
+    }
+    table
+}
+
+/// A plain text table of the diffs for printing
+pub fn format_table(diffs: &[StationDiff]) -> String {
+    let mut table = format!(
+        "{:<6}{:<9}{:>8}{:>8}{:>8}\n",
+        "Code", "Change", "Old", "New", "Delta"
+    );
+    for diff in diffs {
+        table.push_str(&format!(
+            "{:<6}{:<9}{:>8}{:>8}{:>+8}\n",
+            diff.three_alpha_code.0,
+            format!("{:?}", diff.change),
+            diff.old_total,
+            diff.new_total,
+            diff.total_delta
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(pairs: &[(&str, usize, u32)]) -> HashMap<ThreeAlphaCode, DepartureCounts> {
+        let mut map: HashMap<ThreeAlphaCode, DepartureCounts> = HashMap::new();
+        for (code, hour, count) in pairs {
+            map.entry(ThreeAlphaCode(code.to_string()))
+                .or_insert(DepartureCounts {
+                    hour_counts: [0; 24],
+                })
+                .hour_counts[*hour] = *count;
+        }
+        map
+    }
+
+    #[test]
+    fn test_diff_departures() {
+        let old = counts(&[("AAA", 8, 4), ("BBB", 8, 4), ("CCC", 8, 4), ("DDD", 9, 1)]);
+        let new = counts(&[("AAA", 8, 4), ("BBB", 8, 1), ("CCC", 8, 5), ("EEE", 9, 2)]);
+
+        let diffs = diff_departures(&old, &new, 2);
+        let summary: Vec<(&str, &Change, i64)> = diffs
+            .iter()
+            .map(|diff| {
+                (
+                    diff.three_alpha_code.0.as_str(),
+                    &diff.change,
+                    diff.total_delta,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("BBB", &Change::Changed, -3),
+                ("EEE", &Change::Added, 2),
+                ("DDD", &Change::Removed, -1),
+            ]
+        );
+        assert_eq!(diffs[0].hour_deltas[8], -3);
+    }
+
+    #[test]
+    fn test_diff_departures_threshold_one_keeps_any_change() {
+        let old = counts(&[("AAA", 8, 4)]);
+        let new = counts(&[("AAA", 8, 5)]);
+        assert_eq!(diff_departures(&old, &new, 1).len(), 1);
+        assert!(diff_departures(&old, &old, 0).is_empty());
+    }
+
+    #[test]
+    fn test_anomalies() {
+        let baseline = counts(&[("AAA", 8, 10), ("BBB", 8, 10), ("CCC", 8, 4), ("DDD", 9, 0)]);
+        let departures: HashMap<ThreeAlphaCode, HourlyDepartures> =
+            [("AAA", 12), ("BBB", 6), ("DDD", 3), ("EEE", 1)]
+                .into_iter()
+                .map(|(code, count)| {
+                    let three_alpha_code = ThreeAlphaCode(code.to_string());
+                    let mut hourly_departures = HourlyDepartures {
+                        three_alpha_code: three_alpha_code.clone(),
+                        ..HourlyDepartures::default()
+                    };
+                    hourly_departures.hour_counts[8] = count;
+                    (three_alpha_code, hourly_departures.with_totals())
+                })
+                .collect();
+
+        let summary = |anomalies: Vec<Anomaly>| -> Vec<(String, Change, Option<f64>)> {
+            anomalies
+                .into_iter()
+                .map(|anomaly| {
+                    (
+                        anomaly.three_alpha_code.0,
+                        anomaly.change,
+                        anomaly.percent_change,
+                    )
+                })
+                .collect()
+        };
+        // AAA is up 20%, within the threshold, while BBB is down 40%. DDD had no departures
+        // in the baseline, so any is too many.
+        assert_eq!(
+            summary(anomalies(
+                &baseline,
+                &departures,
+                DEFAULT_MAX_CHANGE_PERCENT
+            )),
+            [
+                ("BBB".to_string(), Change::Changed, Some(-40.0)),
+                ("CCC".to_string(), Change::Removed, None),
+                ("DDD".to_string(), Change::Changed, None),
+                ("EEE".to_string(), Change::Added, None),
+            ]
+        );
+        assert_eq!(anomalies(&baseline, &departures, 10.0).len(), 5);
+        assert_eq!(anomalies(&baseline, &departures, 50.0).len(), 3);
+    }
+
+    #[test]
+    fn test_diff_operator_origins() {
+        let origins = |journeys: usize, codes: &[(&str, usize)]| OperatorOrigins {
+            journeys,
+            origins: codes
+                .iter()
+                .map(|(code, count)| (ThreeAlphaCode(code.to_string()), *count))
+                .collect(),
+        };
+        let day = |operators: Vec<(&str, OperatorOrigins)>| {
+            let operators = operators
+                .into_iter()
+                .map(|(operator, origins)| (operator.to_string(), origins))
+                .collect();
+            OperatorOriginsByDay::from([("Tuesday".to_string(), operators)])
+        };
+        let old = day(vec![
+            ("GR", origins(10, &[("KGX", 10)])),
+            ("GN", origins(20, &[("KGX", 20)])),
+            ("LE", origins(5, &[("LST", 5)])),
+            ("XC", origins(8, &[("BHM", 8)])),
+        ]);
+        let new = day(vec![
+            ("GR", origins(11, &[("KGX", 11)])),
+            ("GN", origins(12, &[("KGX", 12)])),
+            ("LE", origins(5, &[("LST", 4), ("SRA", 1)])),
+            ("TL", origins(3, &[("BDM", 3)])),
+        ]);
+
+        let diffs = diff_operator_origins(&old, &new, 2);
+        let summary: Vec<(&str, &Change, i64)> = diffs
+            .iter()
+            .map(|diff| (diff.operator.as_str(), &diff.change, diff.journeys_delta))
+            .collect();
+        // GR moved by fewer than the threshold and starts from the same stations
+        assert_eq!(
+            summary,
+            [
+                ("GN", &Change::Changed, -8),
+                ("XC", &Change::Removed, -8),
+                ("TL", &Change::Added, 3),
+                ("LE", &Change::Changed, 0),
+            ]
+        );
+        assert_eq!(diffs[3].origins_added, [ThreeAlphaCode("SRA".to_string())]);
+        assert!(diffs[3].origins_removed.is_empty());
+    }
+}
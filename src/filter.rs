@@ -0,0 +1,317 @@
+#[cfg(feature = "fs")]
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::str::FromStr;
+
+use super::DayOutputs;
+use super::records::ThreeAlphaCode;
+use super::stops::StationName;
+
+/// A WGS84 area, as --bbox minlon,minlat,maxlon,maxlat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        (self.min_latitude..=self.max_latitude).contains(&latitude)
+            && (self.min_longitude..=self.max_longitude).contains(&longitude)
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| format!("Invalid bounding box {s:?}: {e}"))?;
+        let [min_longitude, min_latitude, max_longitude, max_latitude] = values[..] else {
+            return Err(format!(
+                "Invalid bounding box {s:?}: expected minlon,minlat,maxlon,maxlat"
+            ));
+        };
+        if min_longitude > max_longitude || min_latitude > max_latitude {
+            return Err(format!(
+                "Invalid bounding box {s:?}: the minimums must not exceed the maximums"
+            ));
+        }
+        Ok(BoundingBox {
+            min_longitude,
+            min_latitude,
+            max_longitude,
+            max_latitude,
+        })
+    }
+}
+
+impl fmt::Display for BoundingBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.min_longitude, self.min_latitude, self.max_longitude, self.max_latitude
+        )
+    }
+}
+
+/// The stations kept in the outputs of a run. Journeys are still counted in full, so a
+/// journey from outside the area counts at the stations inside it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StationFilter {
+    /// Keep stations whose three alpha code starts with one of these
+    pub code_prefixes: Vec<String>,
+    /// Keep stations located inside this box, dropping those without a location
+    pub bounding_box: Option<BoundingBox>,
+    /// Keep only these stations
+    pub include: Option<HashSet<ThreeAlphaCode>>,
+    /// Drop these stations
+    pub exclude: Option<HashSet<ThreeAlphaCode>>,
+}
+
+/// How many stations each of the include and exclude lists dropped, for the run report
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct StationListCounts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped_by_include_list: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped_by_exclude_list: Option<usize>,
+}
+
+/// Read a list of three alpha codes, one per line. Blank lines and anything after a # are
+/// ignored.
+#[cfg(feature = "fs")]
+pub fn read_station_list(path: &Path) -> Result<HashSet<ThreeAlphaCode>> {
+    let text = fs_err::read_to_string(path)
+        .with_context(|| format!("Failed to read station list {}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|code| !code.is_empty())
+        .map(|code| ThreeAlphaCode(code.to_string()))
+        .collect())
+}
+
+impl StationFilter {
+    pub fn is_empty(&self) -> bool {
+        self.code_prefixes.is_empty()
+            && self.bounding_box.is_none()
+            && self.include.is_none()
+            && self.exclude.is_none()
+    }
+
+    pub fn keeps(
+        &self,
+        three_alpha_code: &ThreeAlphaCode,
+        station_names: &HashMap<ThreeAlphaCode, StationName>,
+    ) -> bool {
+        let prefix_matches = self.code_prefixes.is_empty()
+            || self
+                .code_prefixes
+                .iter()
+                .any(|prefix| three_alpha_code.0.starts_with(prefix.as_str()));
+        let located_inside = self.bounding_box.is_none_or(|bounding_box| {
+            station_names
+                .get(three_alpha_code)
+                .and_then(StationName::latitude_longitude)
+                .is_some_and(|(latitude, longitude)| bounding_box.contains(latitude, longitude))
+        });
+        let listed = self
+            .include
+            .as_ref()
+            .is_none_or(|include| include.contains(three_alpha_code))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.contains(three_alpha_code));
+        prefix_matches && located_inside && listed
+    }
+
+    /// Drop the stations the filter doesn't keep from the departures, criteria results and
+    /// names. The names are filtered last, as the bounding box needs their locations.
+    pub fn apply(
+        &self,
+        days: &mut [DayOutputs],
+        station_names: &mut HashMap<ThreeAlphaCode, StationName>,
+    ) -> StationListCounts {
+        let all_codes: HashSet<&ThreeAlphaCode> = days
+            .iter()
+            .flat_map(|day_outputs| day_outputs.departures.keys())
+            .chain(station_names.keys())
+            .collect();
+        let counts = StationListCounts {
+            dropped_by_include_list: self.include.as_ref().map(|include| {
+                all_codes
+                    .iter()
+                    .filter(|three_alpha_code| !include.contains(three_alpha_code))
+                    .count()
+            }),
+            dropped_by_exclude_list: self.exclude.as_ref().map(|exclude| {
+                all_codes
+                    .iter()
+                    .filter(|three_alpha_code| exclude.contains(three_alpha_code))
+                    .count()
+            }),
+        };
+        if self.is_empty() {
+            return counts;
+        }
+        for day_outputs in days.iter_mut() {
+            day_outputs
+                .departures
+                .retain(|three_alpha_code, _| self.keeps(three_alpha_code, station_names));
+            day_outputs
+                .criteria_results
+                .retain(|three_alpha_code, _| self.keeps(three_alpha_code, station_names));
+        }
+        let kept: HashSet<ThreeAlphaCode> = station_names
+            .keys()
+            .filter(|three_alpha_code| self.keeps(three_alpha_code, station_names))
+            .cloned()
+            .collect();
+        station_names.retain(|three_alpha_code, _| kept.contains(three_alpha_code));
+        counts
+    }
+}
+
+/// How the filter was set, for the run manifest
+impl fmt::Display for StationFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if !self.code_prefixes.is_empty() {
+            parts.push(format!("code prefix {}", self.code_prefixes.join(",")));
+        }
+        if let Some(bounding_box) = &self.bounding_box {
+            parts.push(format!("bbox {bounding_box}"));
+        }
+        if let Some(include) = &self.include {
+            parts.push(format!("include list of {}", include.len()));
+        }
+        if let Some(exclude) = &self.exclude {
+            parts.push(format!("exclude list of {}", exclude.len()));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stops::NameSource;
+
+    fn station_names() -> HashMap<ThreeAlphaCode, StationName> {
+        [
+            ("KGX", Some((530_400, 183_300))),
+            ("CBG", Some((546_200, 257_300))),
+            ("KNX", None),
+        ]
+        .into_iter()
+        .map(|(code, location)| {
+            (
+                ThreeAlphaCode(code.to_string()),
+                StationName {
+                    name: code.to_string(),
+                    easting: location.map(|(easting, _)| easting),
+                    northing: location.map(|(_, northing)| northing),
+                    source: NameSource::Naptan,
+                    locality: None,
+                    nlc_description: None,
+                },
+            )
+        })
+        .collect()
+    }
+
+    fn kept(filter: &StationFilter) -> Vec<&'static str> {
+        let station_names = station_names();
+        ["CBG", "KGX", "KNX"]
+            .into_iter()
+            .filter(|code| filter.keeps(&ThreeAlphaCode(code.to_string()), &station_names))
+            .collect()
+    }
+
+    #[test]
+    fn test_code_prefixes() {
+        let filter = StationFilter {
+            code_prefixes: vec!["K".to_string(), "CB".to_string()],
+            bounding_box: None,
+            ..StationFilter::default()
+        };
+        assert_eq!(kept(&filter), ["CBG", "KGX", "KNX"]);
+        let filter = StationFilter {
+            code_prefixes: vec!["KG".to_string()],
+            bounding_box: None,
+            ..StationFilter::default()
+        };
+        assert_eq!(kept(&filter), ["KGX"]);
+        assert_eq!(filter.to_string(), "code prefix KG");
+    }
+
+    #[test]
+    fn test_bounding_box_drops_stations_without_a_location() {
+        // Central London only
+        let filter = StationFilter {
+            code_prefixes: Vec::new(),
+            bounding_box: Some("-0.3,51.4,0.1,51.6".parse().unwrap()),
+            ..StationFilter::default()
+        };
+        assert_eq!(kept(&filter), ["KGX"]);
+        assert_eq!(filter.to_string(), "bbox -0.3,51.4,0.1,51.6");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_station_lists() {
+        let dir = std::env::temp_dir().join(format!("rhd_station_lists_{}", std::process::id()));
+        fs_err::create_dir_all(&dir).unwrap();
+        let path = dir.join("exclude_stops.txt");
+        fs_err::write(
+            &path,
+            "# Heritage platforms\nKNX\n\n  CBG # leaks in from the CIF\n",
+        )
+        .unwrap();
+        let exclude = read_station_list(&path).unwrap();
+        fs_err::remove_dir_all(&dir).unwrap();
+        assert_eq!(exclude.len(), 2);
+
+        let filter = StationFilter {
+            exclude: Some(exclude),
+            ..StationFilter::default()
+        };
+        assert_eq!(kept(&filter), ["KGX"]);
+        let filter = StationFilter {
+            include: Some(HashSet::from([ThreeAlphaCode("KNX".to_string())])),
+            ..filter
+        };
+        assert!(kept(&filter).is_empty());
+
+        let mut days = [];
+        let mut station_names = station_names();
+        assert_eq!(
+            filter.apply(&mut days, &mut station_names),
+            StationListCounts {
+                dropped_by_include_list: Some(2),
+                dropped_by_exclude_list: Some(2),
+            }
+        );
+        assert!(station_names.is_empty());
+    }
+
+    #[test]
+    fn test_parse_bounding_box() {
+        assert!("-0.3,51.4,0.1".parse::<BoundingBox>().is_err());
+        assert!("0.1,51.4,-0.3,51.6".parse::<BoundingBox>().is_err());
+        assert!("a,51.4,0.1,51.6".parse::<BoundingBox>().is_err());
+    }
+}
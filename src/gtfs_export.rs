@@ -0,0 +1,481 @@
+//! Export of the assembled journeys as a GTFS feed.
+//!
+//! Journeys are written as they are counted: passenger journeys that are not deleted, calling
+//! only at the GB stations of the lookup where passengers can board or alight. Stops are keyed
+//! by three alpha code and routes by operator, origin and destination, as rail has no route
+//! numbers.
+
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use super::decompress;
+use super::records::{
+    ActivityFlag, Date, Day, Journey, SecondsPastMidnight, ThreeAlphaCode, Tiploc, TrainCategory,
+};
+use super::stops::StationName;
+use super::storage::OutputSink;
+use super::utils::join_csv_line;
+use super::warn;
+
+pub const GTFS_FILE_NAME: &str = "gtfs.zip";
+
+/// The agency of journeys without a BX record
+const UNKNOWN_AGENCY: &str = "ZZ";
+const AGENCY_URL: &str = "https://www.nationalrail.co.uk";
+const AGENCY_TIMEZONE: &str = "Europe/London";
+/// The GTFS route_type of rail services
+const RAIL_ROUTE_TYPE: &str = "2";
+
+/// A stop of an exported trip, with its times counted on from the start of the service day
+struct GtfsStopTime<'a> {
+    three_alpha_code: &'a ThreeAlphaCode,
+    arrival: usize,
+    departure: usize,
+    activity_flag: &'a ActivityFlag,
+}
+
+/// The files of a GTFS feed, each as a file name and its CSV text
+pub fn build_feed(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+    operator_names: &HashMap<String, String>,
+) -> Vec<(String, String)> {
+    let mut agencies: BTreeSet<&str> = BTreeSet::new();
+    let mut stops: BTreeSet<&ThreeAlphaCode> = BTreeSet::new();
+    let mut routes: BTreeMap<String, (&str, &ThreeAlphaCode, &ThreeAlphaCode)> = BTreeMap::new();
+    let mut services: BTreeMap<(Vec<bool>, usize, usize), String> = BTreeMap::new();
+    let mut trip_ids: HashMap<String, usize> = HashMap::new();
+    let mut trips = vec![join_csv_line(&["route_id", "service_id", "trip_id"])];
+    let mut stop_times = vec![join_csv_line(&[
+        "trip_id",
+        "arrival_time",
+        "departure_time",
+        "stop_id",
+        "stop_sequence",
+        "pickup_type",
+        "drop_off_type",
+    ])];
+
+    for journey in journeys {
+        let header = &journey.header;
+        if !header.status.is_operating() || header.category != TrainCategory::Passenger {
+            continue;
+        }
+        let trip_stops = gtfs_stop_times(journey, lookup);
+        if trip_stops.len() < 2 {
+            continue;
+        }
+        let (origin, destination) = (&trip_stops[0], &trip_stops[trip_stops.len() - 1]);
+
+        let agency = journey.atoc_code.as_deref().unwrap_or(UNKNOWN_AGENCY);
+        agencies.insert(agency);
+        let route_id = format!(
+            "{agency}_{}_{}",
+            origin.three_alpha_code.0, destination.three_alpha_code.0
+        );
+        routes.insert(
+            route_id.clone(),
+            (
+                agency,
+                origin.three_alpha_code,
+                destination.three_alpha_code,
+            ),
+        );
+
+        let days: Vec<bool> = Day::all()
+            .iter()
+            .map(|day| header.operating_days.contains(day))
+            .collect();
+        let service_count = services.len();
+        let service_id = services
+            .entry((days, header.date_runs_from.0, header.date_runs_to.0))
+            .or_insert_with(|| format!("S{}", service_count + 1))
+            .clone();
+
+        // A UID is shared by schedules of the same train over different dates
+        let base_trip_id = format!("{}_{}", header.uid, header.date_runs_from);
+        let duplicates = trip_ids.entry(base_trip_id.clone()).or_insert(0);
+        *duplicates += 1;
+        let trip_id = match *duplicates {
+            1 => base_trip_id,
+            n => format!("{base_trip_id}_{n}"),
+        };
+        trips.push(join_csv_line(&[&route_id, &service_id, &trip_id]));
+
+        for (index, stop) in trip_stops.iter().enumerate() {
+            stops.insert(stop.three_alpha_code);
+            let (pickup_type, drop_off_type) = match stop.activity_flag {
+                ActivityFlag::PickUpOnly => ("0", "1"),
+                ActivityFlag::SetDownOnly => ("1", "0"),
+                ActivityFlag::Both | ActivityFlag::Neither => ("0", "0"),
+            };
+            stop_times.push(join_csv_line(&[
+                trip_id.as_str(),
+                &gtfs_time(stop.arrival),
+                &gtfs_time(stop.departure),
+                &stop.three_alpha_code.0,
+                &(index + 1).to_string(),
+                pickup_type,
+                drop_off_type,
+            ]));
+        }
+    }
+
+    let name = |code: &ThreeAlphaCode| {
+        station_names
+            .get(code)
+            .map_or_else(|| code.0.clone(), |station_name| station_name.name.clone())
+    };
+
+    let mut agency_lines = vec![join_csv_line(&[
+        "agency_id",
+        "agency_name",
+        "agency_url",
+        "agency_timezone",
+    ])];
+    for agency in agencies {
+        agency_lines.push(join_csv_line(&[
+            agency,
+            operator_names.get(agency).map_or(agency, String::as_str),
+            AGENCY_URL,
+            AGENCY_TIMEZONE,
+        ]));
+    }
+
+    let mut stop_lines = vec![join_csv_line(&[
+        "stop_id",
+        "stop_name",
+        "stop_lat",
+        "stop_lon",
+    ])];
+    let unlocated = stops
+        .iter()
+        .filter(|code| {
+            station_names
+                .get(**code)
+                .and_then(StationName::latitude_longitude)
+                .is_none()
+        })
+        .count();
+    if unlocated > 0 {
+        warn!("{unlocated} GTFS stops have no location, give --naptan-csv to locate them");
+    }
+    for code in stops {
+        let (latitude, longitude) = station_names
+            .get(code)
+            .and_then(StationName::latitude_longitude)
+            .map_or((String::new(), String::new()), |(latitude, longitude)| {
+                (format!("{latitude:.6}"), format!("{longitude:.6}"))
+            });
+        stop_lines.push(join_csv_line(&[
+            &code.0,
+            &name(code),
+            &latitude,
+            &longitude,
+        ]));
+    }
+
+    let mut route_lines = vec![join_csv_line(&[
+        "route_id",
+        "agency_id",
+        "route_short_name",
+        "route_long_name",
+        "route_type",
+    ])];
+    for (route_id, (agency, origin, destination)) in routes {
+        route_lines.push(join_csv_line(&[
+            route_id.as_str(),
+            agency,
+            "",
+            &format!("{} to {}", name(origin), name(destination)),
+            RAIL_ROUTE_TYPE,
+        ]));
+    }
+
+    let mut calendar_lines = vec![join_csv_line(&[
+        "service_id",
+        "monday",
+        "tuesday",
+        "wednesday",
+        "thursday",
+        "friday",
+        "saturday",
+        "sunday",
+        "start_date",
+        "end_date",
+    ])];
+    for ((days, date_runs_from, date_runs_to), service_id) in services {
+        let mut fields = vec![service_id];
+        fields.extend(
+            days.iter()
+                .map(|runs| if *runs { "1" } else { "0" }.to_string()),
+        );
+        fields.push(gtfs_date(&Date(date_runs_from)));
+        fields.push(gtfs_date(&Date(date_runs_to)));
+        calendar_lines.push(join_csv_line(&fields));
+    }
+
+    [
+        ("agency.txt", agency_lines),
+        ("stops.txt", stop_lines),
+        ("routes.txt", route_lines),
+        ("trips.txt", trips),
+        ("stop_times.txt", stop_times),
+        ("calendar.txt", calendar_lines),
+    ]
+    .into_iter()
+    .map(|(file_name, lines)| (file_name.to_string(), lines.join("\n") + "\n"))
+    .collect()
+}
+
+/// Write the feed to `sink` as gtfs.zip
+pub fn write_zip(feed: &[(String, String)], sink: &mut impl OutputSink) -> Result<()> {
+    let entries: Vec<(String, Vec<u8>)> = feed
+        .iter()
+        .map(|(file_name, text)| (file_name.clone(), text.as_bytes().to_vec()))
+        .collect();
+    sink.write_output(GTFS_FILE_NAME, &decompress::zip_stored(&entries)?)
+}
+
+/// The stops of `journey` at stations in the lookup, with times that keep counting past
+/// 24:00 when the journey runs over midnight. Stops with no times can't be placed, so are
+/// left out.
+fn gtfs_stop_times<'a>(
+    journey: &'a Journey,
+    lookup: &'a HashMap<Tiploc, ThreeAlphaCode>,
+) -> Vec<GtfsStopTime<'a>> {
+    let mut day_offset = 0;
+    let mut previous = 0;
+    let mut continue_day = |time: SecondsPastMidnight| {
+        if time.0 + day_offset < previous {
+            day_offset += 24 * 3600;
+        }
+        previous = time.0 + day_offset;
+        previous
+    };
+
+    journey
+        .stops
+        .iter()
+        .filter_map(|stop| {
+            let first_time = stop.arrival_time.or(stop.departure_time)?;
+            let last_time = stop.departure_time.unwrap_or(first_time);
+            // Every time moves the day on, including those of stops that aren't exported
+            let arrival = continue_day(first_time);
+            let departure = continue_day(last_time);
+            if stop.activity_flag == ActivityFlag::Neither {
+                return None;
+            }
+            Some(GtfsStopTime {
+                three_alpha_code: lookup.get(&stop.tiploc)?,
+                arrival,
+                departure,
+                activity_flag: &stop.activity_flag,
+            })
+        })
+        .collect()
+}
+
+/// HH:MM:SS, with hours of 24 and over for the next day
+fn gtfs_time(seconds: usize) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// YYYYMMDD from a CIF YYMMDD date
+fn gtfs_date(date: &Date) -> String {
+    format!("20{:06}", date.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{assemble_journeys, parse};
+    use crate::stops::{NamePolicy, NameSource, create_lookup, create_station_name_lookup};
+    use crate::test_cif;
+    use crate::utils::split_csv_line;
+
+    /// A file's header row and its rows of fields keyed by column name
+    type Table = (Vec<String>, Vec<HashMap<String, String>>);
+
+    fn tables(feed: &[(String, String)]) -> HashMap<String, Table> {
+        feed.iter()
+            .map(|(file_name, text)| {
+                let mut lines = text.lines();
+                let columns = split_csv_line(lines.next().unwrap());
+                let rows = lines
+                    .map(|line| columns.iter().cloned().zip(split_csv_line(line)).collect())
+                    .collect();
+                (file_name.clone(), (columns, rows))
+            })
+            .collect()
+    }
+
+    /// The checks of a basic GTFS validator: the required files and columns are present, every
+    /// reference between files resolves, stops are located and stop times never go backwards
+    fn validate(feed: &[(String, String)]) -> Vec<String> {
+        let tables = tables(feed);
+        let mut errors = Vec::new();
+        let required = [
+            (
+                "agency.txt",
+                &["agency_id", "agency_name", "agency_url", "agency_timezone"][..],
+            ),
+            (
+                "stops.txt",
+                &["stop_id", "stop_name", "stop_lat", "stop_lon"],
+            ),
+            ("routes.txt", &["route_id", "agency_id", "route_type"]),
+            ("trips.txt", &["route_id", "service_id", "trip_id"]),
+            (
+                "stop_times.txt",
+                &[
+                    "trip_id",
+                    "arrival_time",
+                    "departure_time",
+                    "stop_id",
+                    "stop_sequence",
+                ],
+            ),
+            (
+                "calendar.txt",
+                &["service_id", "monday", "sunday", "start_date", "end_date"],
+            ),
+        ];
+        for (file_name, columns) in required {
+            let Some((header, _)) = tables.get(file_name) else {
+                errors.push(format!("{file_name} is missing"));
+                continue;
+            };
+            for column in columns {
+                if !header.iter().any(|name| name == column) {
+                    errors.push(format!("{file_name} has no {column} column"));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return errors;
+        }
+
+        let ids = |file_name: &str, column: &str| -> BTreeSet<String> {
+            tables[file_name]
+                .1
+                .iter()
+                .map(|row| row[column].clone())
+                .collect()
+        };
+        let references = [
+            ("routes.txt", "agency_id", "agency.txt"),
+            ("trips.txt", "route_id", "routes.txt"),
+            ("trips.txt", "service_id", "calendar.txt"),
+            ("stop_times.txt", "trip_id", "trips.txt"),
+            ("stop_times.txt", "stop_id", "stops.txt"),
+        ];
+        for (file_name, column, target) in references {
+            let target_ids = ids(target, column);
+            for id in ids(file_name, column) {
+                if !target_ids.contains(&id) {
+                    errors.push(format!("{file_name} {column} {id} is not in {target}"));
+                }
+            }
+        }
+        for stop in tables["stops.txt"].1.iter() {
+            if stop["stop_lat"].parse::<f64>().is_err() || stop["stop_lon"].parse::<f64>().is_err()
+            {
+                errors.push(format!("stop {} has no location", stop["stop_id"]));
+            }
+        }
+        let seconds = |time: &str| -> usize {
+            let parts: Vec<usize> = time.split(':').map(|part| part.parse().unwrap()).collect();
+            parts[0] * 3600 + parts[1] * 60 + parts[2]
+        };
+        let mut previous: HashMap<&str, usize> = HashMap::new();
+        for stop_time in tables["stop_times.txt"].1.iter() {
+            let (arrival, departure) = (
+                seconds(&stop_time["arrival_time"]),
+                seconds(&stop_time["departure_time"]),
+            );
+            let trip_previous = previous.entry(&stop_time["trip_id"]).or_insert(0);
+            if arrival < *trip_previous || departure < arrival {
+                errors.push(format!("trip {} goes back in time", stop_time["trip_id"]));
+            }
+            *trip_previous = departure;
+        }
+        errors
+    }
+
+    #[test]
+    fn test_fixture_feed_passes_validation() {
+        let records =
+            parse(include_str!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.CIF").to_string());
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let (mut station_names, _) =
+            create_station_name_lookup(&records, &lookup, None, NamePolicy::default());
+        for (index, station_name) in station_names.values_mut().enumerate() {
+            station_name.easting = Some(530_000 + index as u32 * 1000);
+            station_name.northing = Some(183_000);
+        }
+
+        let feed = build_feed(
+            &assemble_journeys(records),
+            &lookup,
+            &station_names,
+            &HashMap::new(),
+        );
+        assert_eq!(validate(&feed), Vec::<String>::new());
+        let tables = tables(&feed);
+        assert_eq!(tables["agency.txt"].1.len(), 1);
+        assert!(tables["trips.txt"].1.len() > 1);
+        assert_eq!(tables["calendar.txt"].1[0]["start_date"], "20260101");
+    }
+
+    #[test]
+    fn test_times_past_midnight_count_on_from_24_00() {
+        let cif = test_cif::with_stations([
+            "BSNC123452601012612311111100 POO",
+            "BX         GRY",
+            "LOKNGX    2345 2345",
+            "LTCAMBDGE 0030H0030",
+        ]);
+        let records = parse(cif);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let station_names = HashMap::from([(
+            codes[0].clone(),
+            StationName {
+                name: "London Kings Cross".to_string(),
+                easting: Some(530_400),
+                northing: Some(183_300),
+                source: NameSource::Naptan,
+                locality: None,
+                nlc_description: None,
+            },
+        )]);
+
+        let operator_names = HashMap::from([("GR".to_string(), "LNER".to_string())]);
+        let feed = build_feed(
+            &assemble_journeys(records),
+            &lookup,
+            &station_names,
+            &operator_names,
+        );
+        let tables = tables(&feed);
+        assert_eq!(tables["agency.txt"].1[0]["agency_name"], "LNER");
+        let stop_times = &tables["stop_times.txt"].1;
+        assert_eq!(stop_times[0]["departure_time"], "23:45:00");
+        assert_eq!(stop_times[1]["arrival_time"], "24:30:30");
+        assert_eq!(stop_times[1]["pickup_type"], "1");
+        assert_eq!(tables["routes.txt"].1[0]["route_id"], "GR_KGX_CBG");
+        assert_eq!(
+            tables["routes.txt"].1[0]["route_long_name"],
+            "London Kings Cross to CBG"
+        );
+        assert_eq!(validate(&feed), ["stop CBG has no location".to_string()]);
+    }
+}
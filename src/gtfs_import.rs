@@ -0,0 +1,371 @@
+//! Reading a GTFS feed into the same records a CIF file is parsed into.
+//!
+//! Each GTFS stop becomes a TIPLOC insert record whose TIPLOC is the stop_id, and each trip a
+//! journey header followed by its stops, so the lookup, grouping and criteria stages run
+//! unchanged. A stop's three alpha code is its stop_id, as written by the GTFS export, unless a
+//! stop mapping file gives another. Services are only read from calendar.txt; the exceptions
+//! of calendar_dates.txt are not applied.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[cfg(feature = "fs")]
+use super::decompress;
+use super::records::{
+    ActivityFlag, Date, Day, JourneyHeader, JourneyRecordStop, OperatingDays, ParseSummary, Record,
+    SecondsPastMidnight, Status, Stop, ThreeAlphaCode, Tiploc, TrainCategory,
+};
+#[cfg(feature = "fs")]
+use super::utils;
+use super::utils::split_csv_line;
+use super::{info, warn};
+
+pub const GTFS_ZIP_FILE_NAME: &str = "gtfs.zip";
+
+/// The files read from a feed, in the order they are read
+const GTFS_FILES: [&str; 4] = ["stops.txt", "calendar.txt", "trips.txt", "stop_times.txt"];
+
+/// The feed files in `input_file_dir`, or failing that its gtfs.zip
+pub fn gtfs_input_paths(input_file_dir: &Path) -> Vec<PathBuf> {
+    let files: Vec<PathBuf> = GTFS_FILES
+        .iter()
+        .map(|file_name| input_file_dir.join(file_name))
+        .collect();
+    if files.iter().any(|path| path.exists()) {
+        files
+    } else {
+        vec![input_file_dir.join(GTFS_ZIP_FILE_NAME)]
+    }
+}
+
+/// Read the GTFS feed in `input_file_dir` into records, with `stop_mapping` giving the three
+/// alpha code of any stop_id that isn't one itself
+#[cfg(feature = "fs")]
+pub fn read_gtfs(
+    input_file_dir: &Path,
+    stop_mapping: Option<&Path>,
+) -> Result<(Vec<Record>, ParseSummary)> {
+    parse_gtfs(
+        &read_feed_files(input_file_dir)?,
+        &read_stop_mapping(stop_mapping)?,
+    )
+}
+
+/// Parse the feed files `texts`, keyed by file name, into records, with `stop_mapping` giving
+/// the three alpha code of any stop_id that isn't one itself
+pub fn parse_gtfs(
+    texts: &HashMap<String, String>,
+    stop_mapping: &HashMap<String, ThreeAlphaCode>,
+) -> Result<(Vec<Record>, ParseSummary)> {
+    let (records, summary) = gtfs_to_records(texts, stop_mapping)?;
+    info!("Records len: {:?}", records.len());
+    info!("{}", summary.format_table().trim_end());
+    Ok((records, summary))
+}
+
+/// The three alpha codes of the stop mapping file at `path`, keyed by stop_id, or none without
+/// one
+#[cfg(feature = "fs")]
+pub fn read_stop_mapping(path: Option<&Path>) -> Result<HashMap<String, ThreeAlphaCode>> {
+    match path {
+        Some(path) => utils::read_json_file(path)
+            .with_context(|| format!("Failed to read the stop mapping {}", path.display())),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// The text of each feed file in `input_file_dir`, keyed by file name
+#[cfg(feature = "fs")]
+pub fn read_feed_files(input_file_dir: &Path) -> Result<HashMap<String, String>> {
+    let paths = gtfs_input_paths(input_file_dir);
+    let mut texts = HashMap::new();
+    if let [zip_path] = paths.as_slice() {
+        info!("Reading GTFS feed {}", zip_path.display());
+        let bytes = fs_err::read(zip_path)
+            .with_context(|| format!("No GTFS feed found in {}", input_file_dir.display()))?;
+        for (name, contents) in decompress::unzip(&bytes)? {
+            let file_name = name.rsplit('/').next().unwrap_or(&name).to_string();
+            texts.insert(file_name, String::from_utf8_lossy(&contents).into_owned());
+        }
+    } else {
+        info!("Reading GTFS feed from {}", input_file_dir.display());
+        for path in paths {
+            if path.exists() {
+                let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+                texts.insert(file_name, fs_err::read_to_string(&path)?);
+            }
+        }
+    }
+    Ok(texts)
+}
+
+/// The rows of a feed file as maps from column name to field
+fn table(texts: &HashMap<String, String>, file_name: &str) -> Result<Vec<HashMap<String, String>>> {
+    let text = texts
+        .get(file_name)
+        .ok_or_else(|| anyhow!("The GTFS feed has no {file_name}"))?;
+    let mut lines = text.trim_start_matches('\u{feff}').lines();
+    let columns: Vec<String> = split_csv_line(lines.next().unwrap_or_default())
+        .into_iter()
+        .map(|column| column.trim().to_string())
+        .collect();
+    Ok(lines
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| columns.iter().cloned().zip(split_csv_line(line)).collect())
+        .collect())
+}
+
+/// The field of `row` in `column`, which every row must have
+fn field<'a>(row: &'a HashMap<String, String>, file_name: &str, column: &str) -> Result<&'a str> {
+    row.get(column)
+        .map(|value| value.trim())
+        .ok_or_else(|| anyhow!("{file_name} has no {column} column"))
+}
+
+fn gtfs_to_records(
+    texts: &HashMap<String, String>,
+    stop_mapping: &HashMap<String, ThreeAlphaCode>,
+) -> Result<(Vec<Record>, ParseSummary)> {
+    let mut records: Vec<Record> = Vec::new();
+    let mut summary = ParseSummary::default();
+
+    for row in table(texts, "stops.txt")? {
+        let stop_id = field(&row, "stops.txt", "stop_id")?;
+        let three_alpha_code = stop_mapping
+            .get(stop_id)
+            .cloned()
+            .or_else(|| ThreeAlphaCode::from_str(stop_id).ok());
+        records.push(Record::Stop(Stop {
+            tiploc: Tiploc(stop_id.to_string()),
+            nlc: String::new(),
+            tps_description: field(&row, "stops.txt", "stop_name")?.to_string(),
+            // Stops sharing a three alpha code are matched to it through their shared STANOX
+            stanox: three_alpha_code
+                .as_ref()
+                .map_or_else(|| stop_id.to_string(), |code| code.0.clone()),
+            three_alpha_code,
+            po_mcp_code: None,
+            nlc_description: None,
+        }));
+        summary.ti += 1;
+    }
+
+    let mut services: HashMap<String, (OperatingDays, Date, Date)> = HashMap::new();
+    for row in table(texts, "calendar.txt")? {
+        let mut days = Vec::new();
+        for day in Day::all() {
+            if field(&row, "calendar.txt", &day.to_string().to_lowercase())? == "1" {
+                days.push(day);
+            }
+        }
+        services.insert(
+            field(&row, "calendar.txt", "service_id")?.to_string(),
+            (
+                days.into_iter().collect::<OperatingDays>(),
+                gtfs_date(field(&row, "calendar.txt", "start_date")?)?,
+                gtfs_date(field(&row, "calendar.txt", "end_date")?)?,
+            ),
+        );
+    }
+
+    let mut trip_stops: HashMap<String, Vec<(usize, JourneyRecordStop)>> = HashMap::new();
+    for row in table(texts, "stop_times.txt")? {
+        let stop_sequence: usize = field(&row, "stop_times.txt", "stop_sequence")?
+            .parse()
+            .context("stop_times.txt has a stop_sequence that is not a number")?;
+        let stop = JourneyRecordStop {
+            tiploc: Tiploc(field(&row, "stop_times.txt", "stop_id")?.to_string()),
+            activity_flag: activity_flag(
+                row.get("pickup_type").map(|value| value.trim()),
+                row.get("drop_off_type").map(|value| value.trim()),
+            ),
+            arrival_time: gtfs_time(field(&row, "stop_times.txt", "arrival_time")?)?,
+            departure_time: gtfs_time(field(&row, "stop_times.txt", "departure_time")?)?,
+            is_first_stop: false,
+        };
+        trip_stops
+            .entry(field(&row, "stop_times.txt", "trip_id")?.to_string())
+            .or_default()
+            .push((stop_sequence, stop));
+    }
+
+    let mut trips_without_service = 0;
+    for row in table(texts, "trips.txt")? {
+        let trip_id = field(&row, "trips.txt", "trip_id")?;
+        let Some((operating_days, date_runs_from, date_runs_to)) =
+            services.get(field(&row, "trips.txt", "service_id")?)
+        else {
+            trips_without_service += 1;
+            continue;
+        };
+        let mut stops = trip_stops.remove(trip_id).unwrap_or_default();
+        stops.sort_by_key(|(stop_sequence, _)| *stop_sequence);
+
+        records.push(Record::JourneyHeader(JourneyHeader {
+            status: Status::New,
+            uid: trip_id.to_string(),
+            date_runs_from: date_runs_from.clone(),
+            date_runs_to: date_runs_to.clone(),
+            operating_days: *operating_days,
+            train_status: 'P',
+            category: TrainCategory::Passenger,
+        }));
+        summary.bs += 1;
+        let stop_count = stops.len();
+        for (index, (_, mut stop)) in stops.into_iter().enumerate() {
+            // The origin and destination only have the time that matters to them, as in CIF
+            if index == 0 {
+                stop.is_first_stop = true;
+                stop.arrival_time = None;
+                summary.lo += 1;
+            } else if index == stop_count - 1 {
+                stop.departure_time = None;
+                summary.lt += 1;
+            } else {
+                summary.li += 1;
+            }
+            records.push(Record::JourneyRecordStop(stop));
+        }
+    }
+    if trips_without_service > 0 {
+        warn!(
+            "{trips_without_service} GTFS trips have no service in calendar.txt and were \
+             skipped"
+        );
+    }
+    Ok((records, summary))
+}
+
+/// The CIF activity of a stop from its GTFS pickup and drop off types, where anything other
+/// than 1 (none) allows passengers on or off
+fn activity_flag(pickup_type: Option<&str>, drop_off_type: Option<&str>) -> ActivityFlag {
+    match (pickup_type == Some("1"), drop_off_type == Some("1")) {
+        (false, false) => ActivityFlag::Both,
+        (false, true) => ActivityFlag::PickUpOnly,
+        (true, false) => ActivityFlag::SetDownOnly,
+        (true, true) => ActivityFlag::Neither,
+    }
+}
+
+/// A GTFS H:MM:SS time, wrapped back into the day when it is 24:00 or later, or None when blank
+fn gtfs_time(s: &str) -> Result<Option<SecondsPastMidnight>> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let parts: Vec<usize> = s
+        .split(':')
+        .map(|part| part.parse::<usize>())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("{s:?} is not a GTFS time"))?;
+    let [hours, minutes, seconds] = parts.as_slice() else {
+        bail!("{s:?} is not a GTFS time");
+    };
+    Ok(Some(SecondsPastMidnight(
+        (hours * 3600 + minutes * 60 + seconds) % (24 * 3600),
+    )))
+}
+
+/// A GTFS YYYYMMDD date as a CIF YYMMDD date
+fn gtfs_date(s: &str) -> Result<Date> {
+    if s.len() != 8 || !s.bytes().all(|byte| byte.is_ascii_digit()) {
+        bail!("{s:?} is not a GTFS YYYYMMDD date");
+    }
+    Ok(Date(s[2..].parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "fs")]
+    use crate::hour_grouping::{GroupingContext, group};
+    use crate::records::assemble_journeys;
+    #[cfg(feature = "fs")]
+    use crate::records::parse;
+    use crate::stops::create_lookup;
+
+    #[cfg(feature = "fs")]
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_gtfs_fixture_counts_the_same_departures_as_the_cif() {
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let context = GroupingContext::for_week(Date(260113));
+        let count = |records: Vec<Record>| {
+            let lookup = create_lookup(&records, &codes);
+            let departures = group(
+                &assemble_journeys(records),
+                &lookup,
+                &Day::Tuesday,
+                &context,
+            );
+            let mut counts: Vec<(String, [u32; 24], [u32; 24])> = departures
+                .into_values()
+                .map(|departures| {
+                    (
+                        departures.three_alpha_code.0,
+                        departures.hour_counts,
+                        departures.hour_counts_journey_starts,
+                    )
+                })
+                .collect();
+            counts.sort();
+            counts
+        };
+
+        let cif =
+            fs_err::read_to_string(format!("{FIXTURES}/CIF_ALL_FULL_DAILY_toc-full.CIF")).unwrap();
+        let (gtfs, summary) = read_gtfs(&Path::new(FIXTURES).join("gtfs"), None).unwrap();
+        assert_eq!(summary.ti, 3);
+        let cif_counts = count(parse(cif));
+        assert!(!cif_counts.is_empty());
+        assert_eq!(count(gtfs), cif_counts);
+    }
+
+    #[test]
+    fn test_stop_mapping_and_pickup_types() {
+        let texts: HashMap<String, String> = [
+            (
+                "stops.txt",
+                "stop_id,stop_name\n1001,Kings Cross\n1002,Cambridge\n",
+            ),
+            (
+                "calendar.txt",
+                "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,\
+                 start_date,end_date\nWK,1,1,1,1,1,0,0,20260101,20261231\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,service_id,trip_id\nR,WK,T1\nR,SUN,T2\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence,pickup_type,\
+                 drop_off_type\nT1,24:40:00,24:40:00,1002,2,1,0\nT1,,23:50:00,1001,1,0,1\n",
+            ),
+        ]
+        .map(|(file_name, text)| (file_name.to_string(), text.to_string()))
+        .into_iter()
+        .collect();
+        let mapping = HashMap::from([("1001".to_string(), ThreeAlphaCode("KGX".to_string()))]);
+
+        let (records, summary) = gtfs_to_records(&texts, &mapping).unwrap();
+        assert_eq!((summary.bs, summary.lo, summary.lt), (1, 1, 1));
+        let lookup = create_lookup(&records, &[ThreeAlphaCode("KGX".to_string())]);
+        assert_eq!(lookup.len(), 1);
+
+        let journeys = assemble_journeys(records);
+        let stops = &journeys[0].stops;
+        assert_eq!(stops[0].tiploc.0, "1001");
+        assert_eq!(stops[0].activity_flag, ActivityFlag::PickUpOnly);
+        assert_eq!(
+            stops[0].departure_time,
+            Some(SecondsPastMidnight(23 * 3600 + 50 * 60))
+        );
+        assert_eq!(stops[1].activity_flag, ActivityFlag::SetDownOnly);
+        assert_eq!(stops[1].arrival_time, Some(SecondsPastMidnight(40 * 60)));
+        assert_eq!(journeys[0].header.operating_days.len(), 5);
+    }
+}
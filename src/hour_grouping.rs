@@ -1,166 +1,1948 @@
-use indicatif::ProgressIterator;
-use serde::Serialize;
-use std::collections::HashMap;
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
 
+use super::TimeFormat;
+use super::modes::{ModeAliases, UNKNOWN_MODE};
 use super::records::{
-    ActivityFlag, Date, Day, JourneyHeader, Record, SecondsPastMidnight, ThreeAlphaCode, Tiploc,
-    TrainCategory,
+    ActivityFlag, CalendarDate, Date, Day, DaySelector, Journey, SecondsPastMidnight, Status,
+    ThreeAlphaCode, Tiploc, TrainCategory,
 };
-use super::utils::progress_bar_for_count;
+use super::utils::{self, progress_bar_for_count};
+use super::weights::DepartureWeights;
 
 #[derive(Clone, Debug)]
 pub struct TripStop {
     pub three_alpha_code: ThreeAlphaCode,
     pub activity_flag: ActivityFlag,
     pub departure_time: Option<SecondsPastMidnight>,
+    pub arrival_time: Option<SecondsPastMidnight>,
     pub is_first_stop: bool,
+    pub is_last_stop: bool,
 }
 
-#[derive(Debug, Serialize)]
+impl TripStop {
+    /// Whether passengers can board or alight, rather than the train only passing through or
+    /// stopping for operational reasons
+    pub fn calls(&self) -> bool {
+        self.activity_flag != ActivityFlag::Neither
+    }
+}
+
+/// Departures from a single station, bucketed by the hour they leave in, with the arrivals
+/// bucketed by the hour they arrive in
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "HourlyDeparturesFile")]
 pub struct HourlyDepartures {
     pub three_alpha_code: ThreeAlphaCode,
+    #[serde(with = "hour_array")]
     pub hour_counts: [u32; 24],
+    #[serde(with = "hour_array")]
     pub hour_counts_journey_starts: [u32; 24],
+    #[serde(with = "hour_array")]
+    pub hour_counts_arrivals: [u32; 24],
+    #[serde(with = "hour_array")]
+    pub hour_counts_journey_ends: [u32; 24],
+    /// The departures over the whole day, and within the hours of the 7 to 7 and 6 to 10
+    /// criteria. Nothing keeps them in step with `hour_counts`, so any edit to the counts must
+    /// be followed by [`HourlyDepartures::update_totals`], or the criteria read stale totals.
+    pub total: u32,
+    pub total_7_19: u32,
+    pub total_6_22: u32,
+    /// The departures summed by their weights from --weights, all 0 and left out of the
+    /// files without it. See [`DepartureWeights`].
+    #[serde(skip_serializing_if = "unweighted", with = "hour_array")]
+    pub weighted_hour_counts: [f32; 24],
     pub next_stop_three_alpha_code: Vec<Vec<ThreeAlphaCode>>,
+    /// Every departure time counted, in journey order, only with
+    /// [`GroupingContext::collect_departure_times`] and otherwise empty. Left out of the files,
+    /// as it would make the departures files several times larger.
+    #[serde(skip_serializing)]
+    pub departure_times: Vec<SecondsPastMidnight>,
+}
+
+/// [`HourlyDepartures`] as read from a departures file. The totals are worked out again from
+/// the hourly counts, so they can't disagree, and files of schema version 7 and earlier don't
+/// have them.
+#[derive(Deserialize)]
+struct HourlyDeparturesFile {
+    // The aliases read files written with --camel-case
+    #[serde(alias = "threeAlphaCode")]
+    three_alpha_code: ThreeAlphaCode,
+    #[serde(alias = "hourCounts", with = "hour_array")]
+    hour_counts: [u32; 24],
+    #[serde(alias = "hourCountsJourneyStarts", with = "hour_array")]
+    hour_counts_journey_starts: [u32; 24],
+    // Files of schema version 3 and earlier have no arrivals
+    #[serde(default, alias = "hourCountsArrivals", with = "hour_array")]
+    hour_counts_arrivals: [u32; 24],
+    #[serde(default, alias = "hourCountsJourneyEnds", with = "hour_array")]
+    hour_counts_journey_ends: [u32; 24],
+    #[serde(default, alias = "weightedHourCounts", with = "hour_array")]
+    weighted_hour_counts: [f32; 24],
+    #[serde(alias = "nextStopThreeAlphaCode")]
+    next_stop_three_alpha_code: Vec<Vec<ThreeAlphaCode>>,
+    #[serde(default)]
+    departure_times: Vec<SecondsPastMidnight>,
+}
+
+impl From<HourlyDeparturesFile> for HourlyDepartures {
+    fn from(file: HourlyDeparturesFile) -> Self {
+        HourlyDepartures {
+            three_alpha_code: file.three_alpha_code,
+            hour_counts: file.hour_counts,
+            hour_counts_journey_starts: file.hour_counts_journey_starts,
+            hour_counts_arrivals: file.hour_counts_arrivals,
+            hour_counts_journey_ends: file.hour_counts_journey_ends,
+            weighted_hour_counts: file.weighted_hour_counts,
+            next_stop_three_alpha_code: file.next_stop_three_alpha_code,
+            departure_times: file.departure_times,
+            ..HourlyDepartures::default()
+        }
+        .with_totals()
+    }
 }
 
+fn unweighted(weighted_hour_counts: &[f32; 24]) -> bool {
+    weighted_hour_counts.iter().all(|&count| count == 0.0)
+}
+
+impl Default for HourlyDepartures {
+    fn default() -> Self {
+        HourlyDepartures {
+            three_alpha_code: ThreeAlphaCode("".to_string()), // A bit hacky
+            hour_counts: [0; 24],
+            hour_counts_journey_starts: [0; 24],
+            hour_counts_arrivals: [0; 24],
+            hour_counts_journey_ends: [0; 24],
+            total: 0,
+            total_7_19: 0,
+            total_6_22: 0,
+            weighted_hour_counts: [0.0; 24],
+            next_stop_three_alpha_code: vec![Vec::new(); 24],
+            departure_times: Vec::new(),
+        }
+    }
+}
+
+/// A station's departure times as HH:MM or as seconds past midnight
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum DepartureTimes {
+    Clock(Vec<SecondsPastMidnight>),
+    Seconds(Vec<usize>),
+}
+
+impl HourlyDepartures {
+    /// Sum `hour_counts` into the totals again, after changing them. Grouping, merging and
+    /// reading a file do this already.
+    pub fn update_totals(&mut self) {
+        self.total = self.hour_counts.iter().sum();
+        self.total_7_19 = self.hour_counts[7..19].iter().sum();
+        self.total_6_22 = self.hour_counts[6..22].iter().sum();
+    }
+
+    /// The departures within `hours`, from the totals for the whole day and the criteria's
+    /// windows, or summed for any other hours
+    pub fn total_within(&self, hours: &Range<usize>) -> u32 {
+        match (hours.start, hours.end) {
+            (0, 24) => self.total,
+            (7, 19) => self.total_7_19,
+            (6, 22) => self.total_6_22,
+            _ => self.hour_counts[hours.clone()].iter().sum(),
+        }
+    }
+
+    /// These departures with the weighted counts in place of `hour_counts`, each hour rounded
+    /// down to whole departures, for criteria over the weighted counts
+    pub fn as_weighted(&self) -> HourlyDepartures {
+        HourlyDepartures {
+            three_alpha_code: self.three_alpha_code.clone(),
+            hour_counts: self.weighted_hour_counts.map(|count| count.floor() as u32),
+            hour_counts_journey_starts: self.hour_counts_journey_starts,
+            hour_counts_arrivals: self.hour_counts_arrivals,
+            hour_counts_journey_ends: self.hour_counts_journey_ends,
+            weighted_hour_counts: self.weighted_hour_counts,
+            next_stop_three_alpha_code: self.next_stop_three_alpha_code.clone(),
+            ..HourlyDepartures::default()
+        }
+        .with_totals()
+    }
+
+    /// These departures with their totals summed, see [`HourlyDepartures::update_totals`]
+    pub fn with_totals(mut self) -> Self {
+        self.update_totals();
+        self
+    }
+
+    /// Every departure time counted, in order, as --detailed-departures writes them
+    pub fn sorted_departure_times(&self, time_format: TimeFormat) -> DepartureTimes {
+        let mut departure_times = self.departure_times.clone();
+        departure_times.sort();
+        match time_format {
+            TimeFormat::Clock => DepartureTimes::Clock(departure_times),
+            TimeFormat::Seconds => {
+                DepartureTimes::Seconds(departure_times.iter().map(|time| time.0).collect())
+            }
+        }
+    }
+
+    /// Add the departures of `other`, for the same station, to these
+    pub fn merge(&mut self, other: HourlyDepartures) {
+        for hour in 0..24 {
+            self.hour_counts[hour] += other.hour_counts[hour];
+            self.hour_counts_journey_starts[hour] += other.hour_counts_journey_starts[hour];
+            self.hour_counts_arrivals[hour] += other.hour_counts_arrivals[hour];
+            self.hour_counts_journey_ends[hour] += other.hour_counts_journey_ends[hour];
+            self.weighted_hour_counts[hour] += other.weighted_hour_counts[hour];
+        }
+        for (next_stops, other_next_stops) in self
+            .next_stop_three_alpha_code
+            .iter_mut()
+            .zip(other.next_stop_three_alpha_code)
+        {
+            next_stops.extend(other_next_stops);
+        }
+        self.departure_times.extend(other.departure_times);
+        self.update_totals();
+    }
+}
+
+/// Serde for 24 hourly counts, for `#[serde(with = "hour_array")]`. They are written as an
+/// array indexed by hour, or as an object keyed "00" to "23" through [`HoursAsObject`], and
+/// read in either form.
+pub mod hour_array {
+    use serde::de::{Deserializer, Error};
+    use serde::ser::Serializer;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        counts: &[T; 24],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        counts.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<[T; 24], D::Error>
+    where
+        T: Deserialize<'de> + Copy + Default,
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Form<T> {
+            Array([T; 24]),
+            Object(BTreeMap<String, T>),
+        }
+        match Form::deserialize(deserializer)? {
+            Form::Array(counts) => Ok(counts),
+            Form::Object(object) => {
+                let mut counts = [T::default(); 24];
+                for (key, count) in &object {
+                    let hour = key
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|hour| *hour < 24 && key.len() == 2)
+                        .ok_or_else(|| D::Error::custom(format!("invalid hour {key:?}")))?;
+                    counts[hour] = *count;
+                }
+                if object.len() != 24 {
+                    return Err(D::Error::custom(format!(
+                        "expected 24 hours, found {}",
+                        object.len()
+                    )));
+                }
+                Ok(counts)
+            }
+        }
+    }
+}
+
+/// The fields written with [`hour_array`]
+const HOUR_ARRAY_FIELDS: [&str; 7] = [
+    "hour_counts",
+    "hour_counts_journey_starts",
+    "hour_counts_arrivals",
+    "hour_counts_journey_ends",
+    "weighted_hour_counts",
+    "journeys",
+    "stop_departures",
+];
+
+/// `T` serialized with its hourly counts as objects keyed "00" to "23" rather than arrays
+/// indexed by hour, as --hours-as-object writes them
+pub struct HoursAsObject<T>(pub T);
+
+impl<T: Serialize> Serialize for HoursAsObject<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = serde_json::to_value(&self.0).map_err(serde::ser::Error::custom)?;
+        hours_as_objects(value).serialize(serializer)
+    }
+}
+
+/// Rewrite the [`hour_array`] fields of `value` as objects keyed by hour, under their
+/// snake_case or camelCase keys
+fn hours_as_objects(value: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    let is_hour_array_field = |key: &str| {
+        HOUR_ARRAY_FIELDS
+            .iter()
+            .any(|field| key == *field || key == utils::camel_case(field))
+    };
+    match value {
+        Value::Object(object) => object
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::Array(counts) if counts.len() == 24 && is_hour_array_field(&key) => {
+                        counts
+                            .into_iter()
+                            .enumerate()
+                            .map(|(hour, count)| (format!("{hour:02}"), count))
+                            .collect()
+                    }
+                    value => hours_as_objects(value),
+                };
+                (key, value)
+            })
+            .collect(),
+        Value::Array(values) => values.into_iter().map(hours_as_objects).collect(),
+        value => value,
+    }
+}
+
+/// Sum the departures of several runs station by station
+pub fn merge_departures(
+    all_departures: Vec<HashMap<ThreeAlphaCode, HourlyDepartures>>,
+) -> HashMap<ThreeAlphaCode, HourlyDepartures> {
+    let mut merged: HashMap<ThreeAlphaCode, HourlyDepartures> = HashMap::new();
+    for departures in all_departures {
+        for (three_alpha_code, hourly_departures) in departures {
+            match merged.get_mut(&three_alpha_code) {
+                Some(existing) => existing.merge(hourly_departures),
+                None => {
+                    merged.insert(three_alpha_code, hourly_departures);
+                }
+            }
+        }
+    }
+    merged
+}
+
+/// The fewest GB stations a journey must call at to be counted: one to leave from and one to
+/// go to
+pub const DEFAULT_MIN_TRIP_STOPS: usize = 2;
+
+/// The fewest different GB stations a journey must call at to be counted, so a journey calling
+/// twice at one station, such as at two TIPLOCs of it with an operational stop between, adds
+/// no departures
+pub const DEFAULT_MIN_DISTINCT_STATIONS: usize = 2;
+
+/// The dates the departures are counted for
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupingContext {
+    /// Journeys are only counted if they run over this date
+    pub operating_date: Date,
+    /// The exact calendar date, when one was given instead of a week
+    pub calendar_date: Option<CalendarDate>,
+    /// Count a stop with a blank departure time at its arrival time instead of skipping it
+    pub arrival_time_fallback: bool,
+    /// Journeys calling at fewer GB stations than this are left out
+    pub min_trip_stops: usize,
+    /// Journeys calling at fewer different GB stations than this are left out. It is never
+    /// more than [`GroupingContext::min_trip_stops`], so a threshold of one stop still counts
+    /// journeys calling at one station.
+    pub min_distinct_stations: usize,
+    /// Count the arrivals as well as the departures, for the arrival criteria
+    pub count_arrivals: bool,
+    /// Count a departure from a journey's origin even when its LO record says passengers can
+    /// only alight there
+    pub count_setdown_origins: bool,
+    /// Leave out the departure from a journey's last call even when passengers may board
+    /// there, as at a terminus coded for both before the train runs empty to a depot. It is
+    /// still counted as an arrival.
+    pub exclude_final_pickup: bool,
+    /// The modes of train statuses, from --mode-aliases, looked up before the built in list
+    pub mode_aliases: ModeAliases,
+    /// The weight of each journey's departures, from --weights, summed into
+    /// [`HourlyDepartures::weighted_hour_counts`]. Without them those are left at 0.
+    pub departure_weights: Option<DepartureWeights>,
+    /// Journeys with a TN note containing one of these, ignoring case, are left out, such as
+    /// "schooldays" for the journeys that only run in term time
+    pub exclude_note_patterns: Vec<String>,
+    /// Only the departures and arrivals in these hours are counted, from --from-hour and
+    /// --to-hour, leaving the other hours at 0
+    pub hour_window: Option<HourWindow>,
+    /// Keep every departure time in [`HourlyDepartures::departure_times`], for
+    /// --detailed-departures and --regularity-stats. Off by default, as at national scale the
+    /// times take far more memory than the hour counts.
+    pub collect_departure_times: bool,
+    /// Draw progress bars while building the lookup and counting, unless --no-progress
+    pub show_progress: bool,
+}
+
+/// The hours from the start of `from_hour` up to the start of `to_hour`, for counting only part
+/// of the day. A journey starting before the window still has its calls within it counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HourWindow {
+    pub from_hour: usize,
+    pub to_hour: usize,
+}
+
+impl HourWindow {
+    pub fn contains(&self, hour: usize) -> bool {
+        (self.from_hour..self.to_hour).contains(&hour)
+    }
+
+    /// Whether every one of `hours` is in the window, so was counted in full
+    pub fn covers(&self, hours: impl IntoIterator<Item = usize>) -> bool {
+        hours.into_iter().all(|hour| self.contains(hour))
+    }
+}
+
+impl fmt::Display for HourWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:00 to {:02}:00", self.from_hour, self.to_hour)
+    }
+}
+
+impl GroupingContext {
+    pub fn for_week(operating_week: Date) -> Self {
+        GroupingContext {
+            operating_date: operating_week,
+            calendar_date: None,
+            arrival_time_fallback: true,
+            min_trip_stops: DEFAULT_MIN_TRIP_STOPS,
+            min_distinct_stations: DEFAULT_MIN_DISTINCT_STATIONS,
+            count_arrivals: true,
+            count_setdown_origins: false,
+            exclude_final_pickup: false,
+            mode_aliases: ModeAliases::new(),
+            departure_weights: None,
+            exclude_note_patterns: Vec::new(),
+            hour_window: None,
+            collect_departure_times: false,
+            show_progress: true,
+        }
+    }
+
+    pub fn for_date(calendar_date: CalendarDate) -> Self {
+        GroupingContext {
+            operating_date: calendar_date.to_cif_date(),
+            calendar_date: Some(calendar_date),
+            ..Self::for_week(calendar_date.to_cif_date())
+        }
+    }
+}
+
+/// Count departures per station and hour for passenger journeys running on `day` in the week
+/// (or on the date) of `context`
+///
+/// ```
+/// use rail_hourly_departures::hour_grouping::GroupingContext;
+/// use rail_hourly_departures::{
+///     Date, Day, ThreeAlphaCode, assemble_journeys, create_lookup, group, parse,
+/// };
+///
+/// let cif = [
+///     "TIKNGX   00121000 LONDON KINGS CROSS        87701    KGXLONDON KINGS CRS",
+///     "TICAMBDGE00331000 CAMBRIDGE                 51003    CBGCAMBRIDGE",
+///     "BSNC123452601012612311111100 POO",
+///     "LOKNGX    0800 0800",
+///     "LTCAMBDGE 0900 0900",
+/// ]
+/// .map(|line| format!("{line:<80}"))
+/// .join("\n");
+/// let records = parse(cif);
+/// let codes = [ThreeAlphaCode("KGX".to_string()), ThreeAlphaCode("CBG".to_string())];
+/// let lookup = create_lookup(&records, &codes);
+///
+/// let journeys = assemble_journeys(records);
+///
+/// let context = GroupingContext::for_week(Date(260113));
+/// let departures = group(&journeys, &lookup, &Day::Tuesday, &context);
+/// assert_eq!(departures[&codes[0]].hour_counts[8], 1);
+/// assert_eq!(departures[&codes[1]].hour_counts[9], 0);
+/// assert_eq!(departures[&codes[1]].hour_counts_arrivals[9], 1);
+/// ```
 pub fn group(
-    records: Vec<Record>,
+    journeys: &[Journey],
     lookup: &HashMap<Tiploc, ThreeAlphaCode>,
     day: &Day,
-    date: &Date,
+    context: &GroupingContext,
 ) -> HashMap<ThreeAlphaCode, HourlyDepartures> {
-    let mut hourly_departures: HashMap<ThreeAlphaCode, HourlyDepartures> = HashMap::new();
-
-    let mut current_trip_header: Option<JourneyHeader> = None;
-    let mut current_trip_stops: Vec<TripStop> = Vec::new();
-
-    let progress = progress_bar_for_count(records.len());
-    for record in records.iter().progress_with(progress) {
-        match record {
-            Record::JourneyHeader(header) => {
-                push_previous_trip_if_acceptable(
-                    &mut hourly_departures,
-                    &current_trip_header,
-                    &current_trip_stops,
-                    day,
-                    date,
-                );
-                current_trip_header = Some(header.clone());
-                current_trip_stops.clear();
+    let selector = DaySelector::Day(day.clone());
+    group_days(journeys, lookup, std::slice::from_ref(&selector), context)
+        .remove(&selector)
+        .unwrap_or_default()
+}
+
+/// [`group`], counting only the journeys `filter` keeps, as well as those the day, date and
+/// status checks do. The filter sees the whole [`Journey`], so it can pick out journeys by
+/// their operator from the BX record as well as by their header.
+///
+/// ```
+/// use rail_hourly_departures::hour_grouping::{GroupingContext, group_filtered};
+/// use rail_hourly_departures::{
+///     Date, Day, ThreeAlphaCode, assemble_journeys, create_lookup, parse,
+/// };
+///
+/// let cif = [
+///     "TIKNGX   00121000 LONDON KINGS CROSS        87701    KGXLONDON KINGS CRS",
+///     "TICAMBDGE00331000 CAMBRIDGE                 51003    CBGCAMBRIDGE",
+///     "BSNC123452601012612311111100 POO",
+///     "BX         GNY",
+///     "LOKNGX    0800 0800",
+///     "LTCAMBDGE 0900 0900",
+///     "BSNC123462601012612311111100 POO",
+///     "BX         GRY",
+///     "LOKNGX    0815 0815",
+///     "LTCAMBDGE 0915 0915",
+/// ]
+/// .map(|line| format!("{line:<80}"))
+/// .join("\n");
+/// let records = parse(cif);
+/// let codes = [ThreeAlphaCode("KGX".to_string()), ThreeAlphaCode("CBG".to_string())];
+/// let lookup = create_lookup(&records, &codes);
+/// let journeys = assemble_journeys(records);
+///
+/// let context = GroupingContext::for_week(Date(260113));
+/// let great_northern = |journey: &rail_hourly_departures::Journey| {
+///     journey.atoc_code.as_deref() == Some("GN")
+/// };
+/// let departures = group_filtered(&journeys, &lookup, &Day::Tuesday, &context, great_northern);
+/// assert_eq!(departures[&codes[0]].hour_counts[8], 1);
+/// ```
+pub fn group_filtered(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    day: &Day,
+    context: &GroupingContext,
+    filter: impl Fn(&Journey) -> bool + Sync,
+) -> HashMap<ThreeAlphaCode, HourlyDepartures> {
+    let selector = DaySelector::Day(day.clone());
+    group_days_filtered(
+        journeys,
+        lookup,
+        std::slice::from_ref(&selector),
+        context,
+        filter,
+    )
+    .0
+    .remove(&selector)
+    .unwrap_or_default()
+}
+
+type DeparturesByDay = HashMap<DaySelector, HashMap<ThreeAlphaCode, HourlyDepartures>>;
+
+/// A mode's journeys and departures across the whole network, bucketed by hour
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ModeProfile {
+    /// Each journey counted once, in the hour it leaves the first station counted
+    #[serde(with = "hour_array")]
+    pub journeys: [u32; 24],
+    /// The departures from every station, as added to the station counts
+    #[serde(with = "hour_array")]
+    pub stop_departures: [u32; 24],
+}
+
+/// The [`ModeProfile`] of each mode counted, keyed by [`JourneyHeader::mode`]
+pub type NetworkProfile = BTreeMap<&'static str, ModeProfile>;
+
+/// Why a journey's departures were left out of the counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TripRejection {
+    /// Calling at fewer GB stations than [`GroupingContext::min_trip_stops`]
+    TooFewStops,
+    /// Calling at enough GB stations, but fewer different ones than
+    /// [`GroupingContext::min_distinct_stations`]
+    TooFewDistinctStations,
+    /// A schedule with the delete transaction type
+    Deleted,
+    /// A freight, empty stock or other non passenger train
+    NotPassenger,
+    /// Not running over the operating date
+    OutsideDateRange,
+    /// Not running on any of the selected days
+    NotOnSelectedDays,
+    /// With a note matching one of [`GroupingContext::exclude_note_patterns`]
+    ExcludedByNote,
+    /// Left out by the filter given to [`group_days_filtered`]
+    Filtered,
+}
+
+/// How many journeys grouping counted, and why it left the others out
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct GroupingStats {
+    pub accepted: usize,
+    pub too_few_stops: usize,
+    pub too_few_distinct_stations: usize,
+    pub deleted: usize,
+    pub not_passenger: usize,
+    pub outside_date_range: usize,
+    pub not_on_selected_days: usize,
+    pub excluded_by_note: usize,
+    /// Left out by the filter given to [`group_days_filtered`], so none for the command line
+    pub filtered: usize,
+    /// Departures added over all the selected days
+    pub departures: usize,
+    /// Stops of counted journeys merged into the stop before at the same station, see
+    /// [`trip_stops`]
+    pub merged_stops: usize,
+    /// The train statuses of counted journeys whose mode is unknown, which --mode-aliases can
+    /// give a mode
+    pub unknown_train_statuses: BTreeSet<char>,
+}
+
+impl GroupingStats {
+    fn reject(&mut self, rejection: TripRejection) {
+        let count = match rejection {
+            TripRejection::TooFewStops => &mut self.too_few_stops,
+            TripRejection::TooFewDistinctStations => &mut self.too_few_distinct_stations,
+            TripRejection::Deleted => &mut self.deleted,
+            TripRejection::NotPassenger => &mut self.not_passenger,
+            TripRejection::OutsideDateRange => &mut self.outside_date_range,
+            TripRejection::NotOnSelectedDays => &mut self.not_on_selected_days,
+            TripRejection::ExcludedByNote => &mut self.excluded_by_note,
+            TripRejection::Filtered => &mut self.filtered,
+        };
+        *count += 1;
+    }
+
+    fn add(&mut self, other: &GroupingStats) {
+        self.accepted += other.accepted;
+        self.too_few_stops += other.too_few_stops;
+        self.too_few_distinct_stations += other.too_few_distinct_stations;
+        self.deleted += other.deleted;
+        self.not_passenger += other.not_passenger;
+        self.outside_date_range += other.outside_date_range;
+        self.not_on_selected_days += other.not_on_selected_days;
+        self.excluded_by_note += other.excluded_by_note;
+        self.filtered += other.filtered;
+        self.departures += other.departures;
+        self.merged_stops += other.merged_stops;
+        self.unknown_train_statuses
+            .extend(&other.unknown_train_statuses);
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.too_few_stops
+            + self.too_few_distinct_stations
+            + self.deleted
+            + self.not_passenger
+            + self.outside_date_range
+            + self.not_on_selected_days
+            + self.excluded_by_note
+            + self.filtered
+    }
+
+    /// A one line summary for printing
+    pub fn summary(&self) -> String {
+        format!(
+            "Journeys counted: {}, rejected: {} ({} too few stops, {} too few different \
+             stations, {} deleted, {} not passenger, {} outside the date range, {} not on the \
+             selected days, {} excluded by a note, {} filtered out); departures added: {}; \
+             repeated stops merged: {}",
+            self.accepted,
+            self.rejected(),
+            self.too_few_stops,
+            self.too_few_distinct_stations,
+            self.deleted,
+            self.not_passenger,
+            self.outside_date_range,
+            self.not_on_selected_days,
+            self.excluded_by_note,
+            self.filtered,
+            self.departures,
+            self.merged_stops
+        )
+    }
+}
+
+/// Count departures for each of `selectors` in a single pass over the journeys, as [`group`]
+/// does for one day
+pub fn group_days(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    selectors: &[DaySelector],
+    context: &GroupingContext,
+) -> DeparturesByDay {
+    group_days_with_profiles(journeys, lookup, selectors, context).0
+}
+
+/// [`group_days`], also counting the journeys accepted and rejected
+pub fn group_days_with_stats(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    selectors: &[DaySelector],
+    context: &GroupingContext,
+) -> (DeparturesByDay, GroupingStats) {
+    let (departures, _, stats) = group_days_with_profiles(journeys, lookup, selectors, context);
+    (departures, stats)
+}
+
+/// [`group_days_with_stats`], also giving each selected day's [`NetworkProfile`]
+pub fn group_days_with_profiles(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    selectors: &[DaySelector],
+    context: &GroupingContext,
+) -> (
+    DeparturesByDay,
+    HashMap<DaySelector, NetworkProfile>,
+    GroupingStats,
+) {
+    group_days_filtered(journeys, lookup, selectors, context, |_| true)
+}
+
+/// [`group_days_with_profiles`], counting only the journeys `filter` keeps, see
+/// [`group_filtered`]. The journeys it leaves out are counted as [`GroupingStats::filtered`].
+pub fn group_days_filtered(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    selectors: &[DaySelector],
+    context: &GroupingContext,
+    filter: impl Fn(&Journey) -> bool + Sync,
+) -> (
+    DeparturesByDay,
+    HashMap<DaySelector, NetworkProfile>,
+    GroupingStats,
+) {
+    let stations = StationIds::new(lookup);
+    let empty = || -> (Vec<DayCounts>, GroupingStats) {
+        (
+            vec![DayCounts::default(); selectors.len()],
+            GroupingStats::default(),
+        )
+    };
+
+    // Each rayon job counts its journeys into its own counts, which are then merged back
+    // together in journey order, so the result is the same as counting them one by one
+    let progress = progress_bar_for_count(journeys.len(), context.show_progress);
+    let (compact, stats) = journeys
+        .par_iter()
+        .progress_with(progress)
+        .fold(empty, |(mut departures, mut stats), journey| {
+            if !filter(journey) {
+                stats.reject(TripRejection::Filtered);
+                return (departures, stats);
             }
-            Record::JourneyRecordStop(stop) => {
-                if stop.activity_flag == ActivityFlag::Neither {
-                    continue;
+            add_journey(
+                &mut departures,
+                &mut stats,
+                journey,
+                &stations,
+                lookup,
+                selectors,
+                context,
+            );
+            (departures, stats)
+        })
+        .reduce(
+            empty,
+            |(mut departures, mut stats), (other, other_stats)| {
+                stats.add(&other_stats);
+                for (day_counts, other_counts) in departures.iter_mut().zip(other) {
+                    day_counts.merge(other_counts);
                 }
-                if let Some(three_alpha_code) = lookup.get(&stop.tiploc) {
-                    current_trip_stops.push(TripStop {
-                        three_alpha_code: three_alpha_code.clone(),
-                        activity_flag: stop.activity_flag.clone(),
-                        departure_time: stop.departure_time,
-                        is_first_stop: stop.is_first_stop,
-                    });
-                } else {
-                    continue;
+                (departures, stats)
+            },
+        );
+
+    let mut departures = HashMap::new();
+    let mut profiles = HashMap::new();
+    for (selector, day_counts) in selectors.iter().zip(compact) {
+        departures.insert(selector.clone(), stations.expand(day_counts.stations));
+        profiles.insert(selector.clone(), day_counts.profile);
+    }
+    (departures, profiles, stats)
+}
+
+/// Dense ids for the three alpha codes of a lookup, so that grouping counts into vectors
+/// indexed by station rather than into maps keyed by code
+struct StationIds {
+    ids: HashMap<ThreeAlphaCode, u32>,
+    codes: Vec<ThreeAlphaCode>,
+}
+
+impl StationIds {
+    fn new(lookup: &HashMap<Tiploc, ThreeAlphaCode>) -> Self {
+        let mut codes: Vec<ThreeAlphaCode> = lookup.values().cloned().collect();
+        codes.sort();
+        codes.dedup();
+        let ids = codes
+            .iter()
+            .enumerate()
+            .map(|(id, three_alpha_code)| (three_alpha_code.clone(), id as u32))
+            .collect();
+        StationIds { ids, codes }
+    }
+
+    fn id(&self, three_alpha_code: &ThreeAlphaCode) -> u32 {
+        self.ids[three_alpha_code]
+    }
+
+    /// The keyed departures of one day's compact counts
+    fn expand(&self, compact: CompactDepartures) -> HashMap<ThreeAlphaCode, HourlyDepartures> {
+        compact
+            .into_iter()
+            .enumerate()
+            .filter_map(|(id, counts)| {
+                let counts = counts?;
+                let mut departures = HourlyDepartures {
+                    three_alpha_code: self.codes[id].clone(),
+                    hour_counts: counts.hour_counts,
+                    hour_counts_journey_starts: counts.hour_counts_journey_starts,
+                    hour_counts_arrivals: counts.hour_counts_arrivals,
+                    hour_counts_journey_ends: counts.hour_counts_journey_ends,
+                    weighted_hour_counts: counts.weighted_hour_counts,
+                    departure_times: counts.departure_times,
+                    ..HourlyDepartures::default()
+                };
+                for (hour, next_stop) in counts.next_stops {
+                    departures.next_stop_three_alpha_code[hour as usize]
+                        .push(self.codes[next_stop as usize].clone());
                 }
+                departures.update_totals();
+                Some((self.codes[id].clone(), departures))
+            })
+            .collect()
+    }
+}
+
+/// One station's counts while grouping, as [`HourlyDepartures`] has them but with the next
+/// stops as station ids, each with its hour, in the order they were counted
+#[derive(Clone, Default)]
+struct StationCounts {
+    hour_counts: [u32; 24],
+    hour_counts_journey_starts: [u32; 24],
+    hour_counts_arrivals: [u32; 24],
+    hour_counts_journey_ends: [u32; 24],
+    weighted_hour_counts: [f32; 24],
+    next_stops: Vec<(u8, u32)>,
+    departure_times: Vec<SecondsPastMidnight>,
+}
+
+/// A day's counts indexed by station id, grown as far as the highest id counted
+type CompactDepartures = Vec<Option<Box<StationCounts>>>;
+
+fn station_counts(departures: &mut CompactDepartures, id: u32) -> &mut StationCounts {
+    let id = id as usize;
+    if departures.len() <= id {
+        departures.resize(id + 1, None);
+    }
+    departures[id].get_or_insert_with(Box::default)
+}
+
+/// One selected day's counts while grouping
+#[derive(Clone, Default)]
+struct DayCounts {
+    stations: CompactDepartures,
+    profile: NetworkProfile,
+}
+
+impl DayCounts {
+    /// Add the counts of `other`, after those already here
+    fn merge(&mut self, other: DayCounts) {
+        merge_compact(&mut self.stations, other.stations);
+        for (mode, other_profile) in other.profile {
+            let profile = self.profile.entry(mode).or_default();
+            for hour in 0..24 {
+                profile.journeys[hour] += other_profile.journeys[hour];
+                profile.stop_departures[hour] += other_profile.stop_departures[hour];
             }
-            _ => {
-                // Ignore other records
-                continue;
+        }
+    }
+}
+
+/// Add the counts of `other` to `departures`, after those already there
+fn merge_compact(departures: &mut CompactDepartures, other: CompactDepartures) {
+    for (id, other_counts) in other.into_iter().enumerate() {
+        let Some(other_counts) = other_counts else {
+            continue;
+        };
+        let counts = station_counts(departures, id as u32);
+        for hour in 0..24 {
+            counts.hour_counts[hour] += other_counts.hour_counts[hour];
+            counts.hour_counts_journey_starts[hour] +=
+                other_counts.hour_counts_journey_starts[hour];
+            counts.hour_counts_arrivals[hour] += other_counts.hour_counts_arrivals[hour];
+            counts.hour_counts_journey_ends[hour] += other_counts.hour_counts_journey_ends[hour];
+            counts.weighted_hour_counts[hour] += other_counts.weighted_hour_counts[hour];
+        }
+        counts.next_stops.extend(other_counts.next_stops);
+        counts.departure_times.extend(other_counts.departure_times);
+    }
+}
+
+fn add_journey(
+    departures: &mut [DayCounts],
+    stats: &mut GroupingStats,
+    journey: &Journey,
+    stations: &StationIds,
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    selectors: &[DaySelector],
+    context: &GroupingContext,
+) {
+    let (trip_stops, merged_stops) = trip_stops_with_merges(journey, lookup, context);
+    match trip_rejection(journey, &trip_stops, selectors, context) {
+        Some(rejection) => stats.reject(rejection),
+        None => {
+            stats.accepted += 1;
+            stats.merged_stops += merged_stops;
+            let mode = journey.header.mode_with(&context.mode_aliases);
+            if mode == UNKNOWN_MODE {
+                stats
+                    .unknown_train_statuses
+                    .insert(journey.header.train_status);
             }
+            stats.departures += push_trip(
+                departures,
+                selectors,
+                stations,
+                journey,
+                mode,
+                &trip_stops,
+                context,
+            );
         }
     }
+}
+
+/// The journey's stops at GB stations, including those where passengers can neither board nor
+/// alight, so the whole calling pattern is there; only the stops that [`TripStop::calls`] at
+/// are counted. Consecutive stops at TIPLOCs of the same station are one call there, so are
+/// merged into one.
+pub fn trip_stops(
+    journey: &Journey,
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    context: &GroupingContext,
+) -> Vec<TripStop> {
+    trip_stops_with_merges(journey, lookup, context).0
+}
 
-    // Push the last trip if applicable
-    push_previous_trip_if_acceptable(
-        &mut hourly_departures,
-        &current_trip_header,
-        &current_trip_stops,
-        day,
-        date,
-    );
-    hourly_departures
+/// As [`trip_stops`], with how many stops were merged into the one before
+fn trip_stops_with_merges(
+    journey: &Journey,
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    context: &GroupingContext,
+) -> (Vec<TripStop>, usize) {
+    let last_index = journey.stops.len().saturating_sub(1);
+    let mut trip_stops: Vec<TripStop> = journey
+        .stops
+        .iter()
+        .enumerate()
+        .filter_map(|(index, stop)| {
+            Some(TripStop {
+                three_alpha_code: lookup.get(&stop.tiploc)?.clone(),
+                activity_flag: stop.activity_flag.clone(),
+                departure_time: stop
+                    .departure_time
+                    .or(stop.arrival_time.filter(|_| context.arrival_time_fallback)),
+                arrival_time: stop.arrival_time.or(stop
+                    .departure_time
+                    .filter(|_| context.arrival_time_fallback)),
+                is_first_stop: stop.is_first_stop,
+                is_last_stop: index == last_index,
+            })
+        })
+        .collect();
+    let stop_count = trip_stops.len();
+    trip_stops.dedup_by(|later, earlier| {
+        if later.three_alpha_code != earlier.three_alpha_code {
+            return false;
+        }
+        earlier.is_last_stop |= later.is_last_stop;
+        match (earlier.calls(), later.calls()) {
+            // Passing through the station just called at, which adds nothing to the call
+            (true, false) => return true,
+            (false, true) => {
+                earlier.activity_flag = later.activity_flag.clone();
+                earlier.arrival_time = later.arrival_time;
+                earlier.departure_time = later.departure_time;
+                return true;
+            }
+            _ => {}
+        }
+        // Arriving at the first TIPLOC and leaving from the last one with a departure time
+        let picks_up =
+            |flag: &ActivityFlag| matches!(flag, ActivityFlag::PickUpOnly | ActivityFlag::Both);
+        let sets_down =
+            |flag: &ActivityFlag| matches!(flag, ActivityFlag::SetDownOnly | ActivityFlag::Both);
+        earlier.activity_flag = match (
+            picks_up(&earlier.activity_flag) || picks_up(&later.activity_flag),
+            sets_down(&earlier.activity_flag) || sets_down(&later.activity_flag),
+        ) {
+            (true, true) => ActivityFlag::Both,
+            (true, false) => ActivityFlag::PickUpOnly,
+            (false, true) => ActivityFlag::SetDownOnly,
+            (false, false) => ActivityFlag::Neither,
+        };
+        earlier.arrival_time = earlier.arrival_time.or(later.arrival_time);
+        earlier.departure_time = later.departure_time.or(earlier.departure_time);
+        true
+    });
+    let merged_stops = stop_count - trip_stops.len();
+    (trip_stops, merged_stops)
+}
+
+/// A journey left out of the counts, as written by --dump-rejected
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RejectedTrip {
+    pub atoc_code: Option<String>,
+    pub uid: String,
+    /// The days it operates on, Monday first, as the CIF file gives them
+    pub operating_days: String,
+    pub status: Status,
+    /// The GB stations it calls at, as compared with [`GroupingContext::min_trip_stops`]
+    pub stop_count: usize,
+    pub reason: TripRejection,
+}
+
+/// Every journey [`group_days`] leaves out for `selectors`, and why, in journey order
+pub fn rejected_trips(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    selectors: &[DaySelector],
+    context: &GroupingContext,
+) -> Vec<RejectedTrip> {
+    journeys
+        .iter()
+        .filter_map(|journey| {
+            let trip_stops = trip_stops(journey, lookup, context);
+            let reason = trip_rejection(journey, &trip_stops, selectors, context)?;
+            Some(RejectedTrip {
+                atoc_code: journey.atoc_code.clone(),
+                uid: journey.header.uid.clone(),
+                operating_days: journey.header.operating_days.to_cif_str(),
+                status: journey.header.status.clone(),
+                stop_count: trip_stops.iter().filter(|stop| stop.calls()).count(),
+                reason,
+            })
+        })
+        .collect()
 }
 
 fn date_in_scope(operating_date: &Date, start_date: &Date, end_date: &Date) -> bool {
     operating_date.0 >= start_date.0 && operating_date.0 <= end_date.0
 }
 
-fn push_previous_trip_if_acceptable(
-    hourly_departures: &mut HashMap<ThreeAlphaCode, HourlyDepartures>,
-    current_trip_header: &Option<JourneyHeader>,
-    current_trip_stops: &[TripStop],
-    operating_day: &Day,
-    operating_date: &Date,
-) {
-    if current_trip_stops.len() > 1
-        && current_trip_header
-            .as_ref()
-            .unwrap()
-            .operating_days
-            .contains(operating_day)
-        && current_trip_header.as_ref().unwrap().status.is_operating()
-        && current_trip_header.as_ref().unwrap().category == TrainCategory::Passenger
-        && date_in_scope(
-            operating_date,
-            &current_trip_header.as_ref().unwrap().date_runs_from,
-            &current_trip_header.as_ref().unwrap().date_runs_to,
-        )
+/// Why the trip's departures shouldn't be counted for any of `selectors`, or None to count them
+pub fn trip_rejection(
+    journey: &Journey,
+    trip_stops: &[TripStop],
+    selectors: &[DaySelector],
+    context: &GroupingContext,
+) -> Option<TripRejection> {
+    let header = &journey.header;
+    if !header.status.is_operating() {
+        Some(TripRejection::Deleted)
+    } else if header.category != TrainCategory::Passenger {
+        Some(TripRejection::NotPassenger)
+    } else if journey
+        .note_matching(&context.exclude_note_patterns)
+        .is_some()
+    {
+        Some(TripRejection::ExcludedByNote)
+    } else if trip_stops.iter().filter(|stop| stop.calls()).count() < context.min_trip_stops {
+        Some(TripRejection::TooFewStops)
+    } else if distinct_stations(trip_stops)
+        < context.min_distinct_stations.min(context.min_trip_stops)
+    {
+        Some(TripRejection::TooFewDistinctStations)
+    } else if !date_in_scope(
+        &context.operating_date,
+        &header.date_runs_from,
+        &header.date_runs_to,
+    ) {
+        Some(TripRejection::OutsideDateRange)
+    } else if !selectors
+        .iter()
+        .any(|selector| selector.selects(&header.operating_days))
     {
+        Some(TripRejection::NotOnSelectedDays)
+    } else {
+        None
+    }
+}
+
+/// How many different GB stations the trip calls at
+fn distinct_stations(trip_stops: &[TripStop]) -> usize {
+    trip_stops
+        .iter()
+        .filter(|stop| stop.calls())
+        .map(|stop| &stop.three_alpha_code)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Add the trip's departures, and its arrivals if the context counts them, to the counts of
+/// every selector that selects the journey's operating days, and to the network profile of
+/// `mode`, returning how many departures were added
+fn push_trip(
+    departures: &mut [DayCounts],
+    selectors: &[DaySelector],
+    stations: &StationIds,
+    journey: &Journey,
+    mode: &'static str,
+    current_trip_stops: &[TripStop],
+    context: &GroupingContext,
+) -> usize {
+    let mut added = 0;
+    let weight = context.departure_weights.as_ref().map_or(0.0, |weights| {
+        weights.weight(mode, journey.atoc_code.as_deref())
+    });
+    let ids: Vec<u32> = current_trip_stops
+        .iter()
+        .map(|stop| stations.id(&stop.three_alpha_code))
+        .collect();
+    // The next station called at from each stop, passing over those only passed through
+    let mut next_stops: Vec<Option<u32>> = vec![None; ids.len()];
+    for index in (0..ids.len().saturating_sub(1)).rev() {
+        next_stops[index] = match current_trip_stops[index + 1].calls() {
+            true => Some(ids[index + 1]),
+            false => next_stops[index + 1],
+        };
+    }
+    let origin_hour = current_trip_stops
+        .iter()
+        .find(|stop| stop.calls())
+        .and_then(|stop| stop.departure_time)
+        .map(hour_of)
+        .filter(|&hour| {
+            context
+                .hour_window
+                .is_none_or(|window| window.contains(hour))
+        });
+    // Nobody can travel anywhere from the last call on this train, so with
+    // --exclude-final-pickup it is no departure, unless it is also the first
+    let final_call = current_trip_stops
+        .iter()
+        .rposition(|stop| stop.calls())
+        .filter(|_| context.exclude_final_pickup)
+        .filter(|&index| !current_trip_stops[index].is_first_stop);
+    for (selector, day_counts) in selectors.iter().zip(departures.iter_mut()) {
+        if !selector.selects(&journey.header.operating_days) {
+            continue;
+        }
+        let profile = day_counts.profile.entry(mode).or_default();
+        if let Some(hour) = origin_hour {
+            profile.journeys[hour] += 1;
+        }
+        let day_departures = &mut day_counts.stations;
         for (index, stop) in current_trip_stops.iter().enumerate() {
-            match stop.activity_flag {
-                ActivityFlag::PickUpOnly | ActivityFlag::Both => {
-                    let next_stop_three_alpha_code: Option<ThreeAlphaCode> =
-                        if index < current_trip_stops.len() - 1 {
-                            Some(current_trip_stops[index + 1].three_alpha_code.clone())
-                        } else {
-                            None
-                        };
-                    add_departure_hour_count(hourly_departures, stop, next_stop_three_alpha_code);
-                }
-                _ => {}
+            let picks_up = match stop.activity_flag {
+                ActivityFlag::PickUpOnly | ActivityFlag::Both => true,
+                ActivityFlag::SetDownOnly => stop.is_first_stop && context.count_setdown_origins,
+                ActivityFlag::Neither => false,
+            };
+            if picks_up
+                && final_call != Some(index)
+                && let Some(hour) = add_departure_hour_count(
+                    day_departures,
+                    ids[index],
+                    stop,
+                    next_stops[index],
+                    context,
+                )
+            {
+                added += 1;
+                profile.stop_departures[hour] += 1;
+                station_counts(day_departures, ids[index]).weighted_hour_counts[hour] += weight;
+            }
+            if context.count_arrivals
+                && !stop.is_first_stop
+                && matches!(
+                    stop.activity_flag,
+                    ActivityFlag::SetDownOnly | ActivityFlag::Both
+                )
+            {
+                add_arrival_hour_count(
+                    day_departures,
+                    ids[index],
+                    stop,
+                    context.hour_window.as_ref(),
+                );
             }
         }
     }
+    added
+}
+
+/// The hour a time falls in
+fn hour_of(time: SecondsPastMidnight) -> usize {
+    (time.0 as f64 / 3600.0).floor() as usize
 }
 
+/// Stops with no departure time can't be placed in an hour, so are left out, as are those
+/// outside the context's hour window. The departure time itself is only kept when the context
+/// collects them. Returns the hour the departure was counted in.
 fn add_departure_hour_count(
-    hourly_departures: &mut HashMap<ThreeAlphaCode, HourlyDepartures>,
+    departures: &mut CompactDepartures,
+    id: u32,
     trip_stop: &TripStop,
-    next_stop_three_alpha_code: Option<ThreeAlphaCode>,
+    next_stop: Option<u32>,
+    context: &GroupingContext,
+) -> Option<usize> {
+    let departure_time = trip_stop.departure_time?;
+    let hour = hour_of(departure_time);
+    if context
+        .hour_window
+        .is_some_and(|window| !window.contains(hour))
+    {
+        return None;
+    }
+    let counts = station_counts(departures, id);
+    counts.hour_counts[hour] += 1;
+    if context.collect_departure_times {
+        counts.departure_times.push(departure_time);
+    }
+    if let Some(next_stop) = next_stop {
+        counts.next_stops.push((hour as u8, next_stop));
+    }
+
+    // If this is the first stop of the journey, also increment journey starts
+    if trip_stop.is_first_stop {
+        counts.hour_counts_journey_starts[hour] += 1;
+    }
+    Some(hour)
+}
+
+/// As [`add_departure_hour_count`], for an arrival. The last stop of the journey is also counted
+/// as a journey end.
+fn add_arrival_hour_count(
+    departures: &mut CompactDepartures,
+    id: u32,
+    trip_stop: &TripStop,
+    hour_window: Option<&HourWindow>,
 ) {
-    if let Some(departure_time) = trip_stop.departure_time {
-        let hour = (departure_time.0 as f64 / 3600.0).floor() as usize;
-        let departures = hourly_departures
-            .entry(trip_stop.three_alpha_code.clone())
-            .or_insert_with(empty_hour_counts);
-        departures.hour_counts[hour] += 1;
-        if let Some(next_stop_three_alpha_code) = next_stop_three_alpha_code {
-            departures.next_stop_three_alpha_code[hour].push(next_stop_three_alpha_code);
+    let Some(arrival_time) = trip_stop.arrival_time else {
+        return;
+    };
+    let hour = hour_of(arrival_time);
+    if hour_window.is_some_and(|window| !window.contains(hour)) {
+        return;
+    }
+    let counts = station_counts(departures, id);
+    counts.hour_counts_arrivals[hour] += 1;
+    if trip_stop.is_last_stop {
+        counts.hour_counts_journey_ends[hour] += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{DayChoice, DayMatch, assemble_journeys};
+    use crate::test_cif;
+
+    fn departures(code: &str, hour: usize, count: u32, starts: u32) -> HourlyDepartures {
+        let mut hourly_departures = HourlyDepartures {
+            three_alpha_code: ThreeAlphaCode(code.to_string()),
+            ..HourlyDepartures::default()
+        };
+        hourly_departures.hour_counts[hour] = count;
+        hourly_departures.hour_counts_journey_starts[hour] = starts;
+        hourly_departures.next_stop_three_alpha_code[hour] =
+            vec![ThreeAlphaCode("NXT".to_string()); count as usize];
+        hourly_departures.with_totals()
+    }
+
+    #[test]
+    fn test_hour_object_round_trips_to_array() {
+        let mut hourly_departures = departures("AAA", 8, 3, 1);
+        hourly_departures.hour_counts[23] = 2;
+        hourly_departures.update_totals();
+        let array = serde_json::to_value(&hourly_departures).unwrap();
+        let object = serde_json::to_value(HoursAsObject(&hourly_departures)).unwrap();
+        assert!(array["hour_counts"].is_array());
+        assert_eq!(object["hour_counts"]["08"], 3);
+        assert_eq!(object["hour_counts"]["23"], 2);
+        assert_eq!(object["hour_counts_journey_starts"]["00"], 0);
+        let camel_case = HoursAsObject(serde_json::json!({ "hourCounts": vec![1; 24] }));
+        assert_eq!(
+            serde_json::to_value(camel_case).unwrap()["hourCounts"]["23"],
+            1
+        );
+
+        for value in [array, object.clone()] {
+            let read_back: HourlyDepartures = serde_json::from_value(value).unwrap();
+            assert_eq!(read_back.hour_counts, hourly_departures.hour_counts);
+            assert_eq!(
+                read_back.hour_counts_journey_starts,
+                hourly_departures.hour_counts_journey_starts
+            );
+        }
+
+        let mut missing_hour = object.clone();
+        missing_hour["hour_counts"]
+            .as_object_mut()
+            .unwrap()
+            .remove("05");
+        assert!(serde_json::from_value::<HourlyDepartures>(missing_hour).is_err());
+        let mut bad_hour = object;
+        bad_hour["hour_counts"]["24"] = 1.into();
+        assert!(serde_json::from_value::<HourlyDepartures>(bad_hour).is_err());
+    }
+
+    #[test]
+    fn test_sorted_departure_times() {
+        let hourly_departures = HourlyDepartures {
+            departure_times: [30_630, 6 * 3600, 86_399].map(SecondsPastMidnight).to_vec(),
+            ..HourlyDepartures::default()
+        };
+        assert_eq!(
+            hourly_departures.sorted_departure_times(TimeFormat::Clock),
+            DepartureTimes::Clock([6 * 3600, 30_630, 86_399].map(SecondsPastMidnight).to_vec())
+        );
+        assert_eq!(
+            serde_json::to_string(&hourly_departures.sorted_departure_times(TimeFormat::Clock))
+                .unwrap(),
+            r#"["06:00","08:30:30","23:59:59"]"#
+        );
+        assert_eq!(
+            hourly_departures.sorted_departure_times(TimeFormat::Seconds),
+            DepartureTimes::Seconds(vec![21_600, 30_630, 86_399])
+        );
+    }
+
+    #[test]
+    fn test_merge_departures_sums_counts() {
+        let code = ThreeAlphaCode("AAA".to_string());
+        let other = ThreeAlphaCode("BBB".to_string());
+        let merged = merge_departures(vec![
+            HashMap::from([(code.clone(), departures("AAA", 8, 2, 1))]),
+            HashMap::from([
+                (code.clone(), departures("AAA", 8, 3, 0)),
+                (other.clone(), departures("BBB", 9, 1, 1)),
+            ]),
+        ]);
+
+        assert_eq!(merged[&code].hour_counts[8], 5);
+        assert_eq!(merged[&code].hour_counts_journey_starts[8], 1);
+        assert_eq!(merged[&code].next_stop_three_alpha_code[8].len(), 5);
+        assert_eq!(merged[&other].hour_counts[9], 1);
+    }
+
+    fn assert_totals_are_sums(hourly_departures: &HourlyDepartures) {
+        let hour_counts = &hourly_departures.hour_counts;
+        assert_eq!(hourly_departures.total, hour_counts.iter().sum::<u32>());
+        assert_eq!(
+            hourly_departures.total_7_19,
+            hour_counts[7..19].iter().sum::<u32>()
+        );
+        assert_eq!(
+            hourly_departures.total_6_22,
+            hour_counts[6..22].iter().sum::<u32>()
+        );
+    }
+
+    #[test]
+    fn test_totals_match_the_hour_counts() {
+        let records = crate::records::parse(
+            include_str!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.CIF").to_string(),
+        );
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let context = GroupingContext::for_week(Date(260113));
+        let grouped = group(&journeys, &lookup, &Day::Tuesday, &context);
+        assert!(grouped[&codes[0]].total_6_22 > grouped[&codes[0]].total_7_19);
+        grouped.values().for_each(assert_totals_are_sums);
+
+        let merged = merge_departures(vec![
+            grouped,
+            HashMap::from([(codes[0].clone(), departures("KGX", 8, 3, 0))]),
+        ]);
+        merged.values().for_each(assert_totals_are_sums);
+
+        // Files without the totals, or with ones that disagree, are summed again
+        let mut value = serde_json::to_value(&merged[&codes[0]]).unwrap();
+        value.as_object_mut().unwrap().remove("total");
+        value["total_7_19"] = 0.into();
+        let read_back: HourlyDepartures = serde_json::from_value(value).unwrap();
+        assert_eq!(read_back.total, merged[&codes[0]].total);
+        assert_totals_are_sums(&read_back);
+        assert_eq!(read_back.total_within(&(8..9)), read_back.hour_counts[8]);
+    }
+
+    #[test]
+    fn test_group_days_matches_single_day_group() {
+        let cif = test_cif::with_stations([
+            "BSNC123452601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+            "BSNC123462601012612310000011 POO",
+            "LOKNGX    1000 1000",
+            "LTCAMBDGE 1100 1100",
+        ]);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let date = GroupingContext::for_week(Date(260112));
+
+        let selectors = DayChoice::All.selectors(DayMatch::Any);
+        let all_days = group_days(&journeys, &lookup, &selectors, &date);
+        for day in Day::all() {
+            let single_day = group(&journeys, &lookup, &day, &date);
+            assert_eq!(
+                serde_json::to_value(&all_days[&DaySelector::Day(day)]).unwrap(),
+                serde_json::to_value(&single_day).unwrap()
+            );
         }
+        let monday = &all_days[&DaySelector::Day(Day::Monday)];
+        let sunday = &all_days[&DaySelector::Day(Day::Sunday)];
+        assert_eq!(monday[&codes[0]].hour_counts[8], 1);
+        assert_eq!(sunday[&codes[0]].hour_counts[10], 1);
+        assert_eq!(sunday[&codes[0]].hour_counts[8], 0);
+    }
+
+    #[test]
+    fn test_parallel_grouping_matches_sequential() {
+        let records = crate::records::parse(
+            include_str!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.CIF").to_string(),
+        );
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let mut context = GroupingContext::for_week(Date(260112));
+        context.collect_departure_times = true;
+        let selectors = DayChoice::All.selectors(DayMatch::Any);
 
-        // If this is the first stop of the journey, also increment journey starts
-        if trip_stop.is_first_stop {
-            departures.hour_counts_journey_starts[hour] += 1;
+        let parallel = group_days(&journeys, &lookup, &selectors, &context);
+        let stations = StationIds::new(&lookup);
+        let mut compact = vec![DayCounts::default(); selectors.len()];
+        let mut stats = GroupingStats::default();
+        for journey in &journeys {
+            add_journey(
+                &mut compact,
+                &mut stats,
+                journey,
+                &stations,
+                &lookup,
+                &selectors,
+                &context,
+            );
         }
+        let sequential: DeparturesByDay = selectors
+            .iter()
+            .cloned()
+            .zip(compact)
+            .map(|(selector, day_counts)| (selector, stations.expand(day_counts.stations)))
+            .collect();
 
-        // Ensure three_alpha_code is set
-        departures.three_alpha_code = trip_stop.three_alpha_code.clone();
-    } else {
-        panic!("Trip stop without departure time at stop {:?}", trip_stop);
+        assert!(!parallel[&selectors[1]].is_empty());
+        for selector in &selectors {
+            assert_eq!(
+                serde_json::to_value(&parallel[selector]).unwrap(),
+                serde_json::to_value(&sequential[selector]).unwrap()
+            );
+            for (three_alpha_code, departures) in &sequential[selector] {
+                assert_eq!(
+                    parallel[selector][three_alpha_code].departure_times,
+                    departures.departure_times
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_grouping_stats_and_rejected_trips() {
+        let journey = |bs: &str, last_tiploc: &str| {
+            [
+                bs.to_string(),
+                "LOKNGX    0800 0800".to_string(),
+                format!("LT{last_tiploc:<7} 0900 0900"),
+            ]
+        };
+        let cif = test_cif::with_stations(
+            [
+                journey("BSNC100012601012612311111100 POO", "CAMBDGE"),
+                journey("BSNC100022601012612311111100 POO", "CAMBDGE"),
+                journey("BSDC100032601012612311111100 POO", "CAMBDGE"),
+                journey("BSNC100042601012612311111100 FEE", "CAMBDGE"),
+                journey("BSNC100052601012612310000010 POO", "CAMBDGE"),
+                journey("BSNC100062602012612311111100 POO", "CAMBDGE"),
+                journey("BSNC100072601012612311111100 POO", "ELYYY"),
+            ]
+            .concat(),
+        );
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let selectors = [DaySelector::Day(Day::Tuesday)];
+        let mut context = GroupingContext::for_week(Date(260113));
+
+        let (_, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!(
+            stats,
+            GroupingStats {
+                accepted: 2,
+                too_few_stops: 1,
+                too_few_distinct_stations: 0,
+                deleted: 1,
+                not_passenger: 1,
+                outside_date_range: 1,
+                not_on_selected_days: 1,
+                excluded_by_note: 0,
+                filtered: 0,
+                departures: 2,
+                merged_stops: 0,
+                unknown_train_statuses: BTreeSet::new(),
+            }
+        );
+        assert_eq!(stats.rejected(), 5);
+
+        let rejected = rejected_trips(&journeys, &lookup, &selectors, &context);
+        assert_eq!(
+            rejected
+                .iter()
+                .map(|trip| (trip.uid.as_str(), trip.reason))
+                .collect::<Vec<_>>(),
+            [
+                ("C10003", TripRejection::Deleted),
+                ("C10004", TripRejection::NotPassenger),
+                ("C10005", TripRejection::NotOnSelectedDays),
+                ("C10006", TripRejection::OutsideDateRange),
+                ("C10007", TripRejection::TooFewStops),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&rejected[2]).unwrap(),
+            serde_json::json!({
+                "atoc_code": null,
+                "uid": "C10005",
+                "operating_days": "0000010",
+                "status": "New",
+                "stop_count": 2,
+                "reason": "NotOnSelectedDays",
+            })
+        );
+
+        // A single GB stop is counted once the threshold allows it
+        context.min_trip_stops = 1;
+        let (departures, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!((stats.accepted, stats.too_few_stops), (3, 0));
+        assert_eq!(departures[&selectors[0]][&codes[0]].hour_counts[8], 3);
+        // Two GB stops are too few for a threshold of three, which is checked before the dates
+        context.min_trip_stops = 3;
+        let (_, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!((stats.accepted, stats.too_few_stops), (0, 5));
+    }
+
+    #[test]
+    fn test_departure_times_are_only_collected_when_asked_for() {
+        let cif = test_cif::with_stations([
+            "BSNC100012601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LISTEVNGE 0820 0821      08200821         T",
+            "LTCAMBDGE 0900 0900",
+        ]);
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let mut context = GroupingContext::for_week(Date(260113));
+
+        let departures = group(&journeys, &lookup, &Day::Tuesday, &context);
+        assert_eq!(departures[&codes[0]].hour_counts[8], 1);
+        assert!(
+            departures
+                .values()
+                .all(|station| station.departure_times.is_empty())
+        );
+        context.collect_departure_times = true;
+        let departures = group(&journeys, &lookup, &Day::Tuesday, &context);
+        assert_eq!(
+            departures[&codes[1]].departure_times,
+            [SecondsPastMidnight(8 * 3600 + 21 * 60)]
+        );
     }
-}
 
-fn empty_hour_counts() -> HourlyDepartures {
-    // A little hacky way to create a Vec of Vecs
-    let mut next_stop_three_alpha_code: Vec<Vec<ThreeAlphaCode>> = Vec::new();
-    for _ in 0..24 {
-        next_stop_three_alpha_code.push(Vec::new());
+    #[test]
+    fn test_hour_window_counts_the_calls_within_it() {
+        // Leaves King's Cross before the window and reaches Cambridge after it, calling at
+        // Stevenage within it
+        let cif = test_cif::with_stations([
+            "BSNC100012601012612311111100 POO",
+            "LOKNGX    0650 0650",
+            "LISTEVNGE 0715 0716      07150716         T",
+            "LTCAMBDGE 1005 1005",
+        ]);
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let mut context = GroupingContext::for_week(Date(260113));
+        context.hour_window = Some(HourWindow {
+            from_hour: 7,
+            to_hour: 10,
+        });
+
+        let departures = group(&journeys, &lookup, &Day::Tuesday, &context);
+        let svg = &departures[&codes[1]];
+        assert_eq!(svg.hour_counts[7], 1);
+        assert_eq!(svg.hour_counts_arrivals[7], 1);
+        // Neither the departure from King's Cross nor the arrival at Cambridge is counted
+        assert!(!departures.contains_key(&codes[0]));
+        assert!(!departures.contains_key(&codes[2]));
+
+        let window = context.hour_window.unwrap();
+        assert_eq!(
+            crate::criteria::criteria_outside_window(&window, &Default::default()),
+            [
+                "all_7_7",
+                "all_6_10",
+                "avg_7_7",
+                "avg_6_10",
+                "night_service",
+                "early_start"
+            ]
+        );
+        let whole_day = HourWindow {
+            from_hour: 0,
+            to_hour: 24,
+        };
+        assert!(
+            crate::criteria::criteria_outside_window(&whole_day, &Default::default()).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_filtered_grouping_counts_one_operator() {
+        let cif = test_cif::with_stations([
+            "BSNC100012601012612311111100 POO",
+            "BX         GNY",
+            "LOKNGX    0800 0800",
+            "LISTEVNGE 0820 0821      08200821         T",
+            "LTCAMBDGE 0900 0900",
+            "BSNC100022601012612311111100 POO",
+            "BX         GRY",
+            "LOKNGX    0830 0830",
+            "LTCAMBDGE 0930 0930",
+            "BSNC100032601012612311111100 POO",
+            "BX         GNY",
+            "LOKNGX    0845 0845",
+            "LTSTEVNGE 0905 0905",
+            "BSNC100042601012612311111100 POO",
+            "LOKNGX    0850 0850",
+            "LTSTEVNGE 0910 0910",
+        ]);
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let context = GroupingContext::for_week(Date(260113));
+        let great_northern = |journey: &Journey| journey.atoc_code.as_deref() == Some("GN");
+
+        // Worked out by hand: the two Great Northern journeys leave King's Cross at 08:00 and
+        // 08:45, and the first leaves Stevenage at 08:21
+        let departures =
+            group_filtered(&journeys, &lookup, &Day::Tuesday, &context, great_northern);
+        let mut kgx = [0; 24];
+        kgx[8] = 2;
+        let mut svg = [0; 24];
+        svg[8] = 1;
+        assert_eq!(departures[&codes[0]].hour_counts, kgx);
+        assert_eq!(departures[&codes[1]].hour_counts, svg);
+        assert_eq!(departures[&codes[0]].total, 2);
+        assert_eq!(departures[&codes[2]].hour_counts_arrivals[9], 1);
+
+        let selectors = [DaySelector::Day(Day::Tuesday)];
+        let (_, _, stats) =
+            group_days_filtered(&journeys, &lookup, &selectors, &context, great_northern);
+        assert_eq!(
+            (stats.accepted, stats.filtered, stats.rejected()),
+            (2, 2, 2)
+        );
+        // Keeping every journey is the same as not filtering
+        let unfiltered = group(&journeys, &lookup, &Day::Tuesday, &context);
+        let kept_all = group_filtered(&journeys, &lookup, &Day::Tuesday, &context, |_| true);
+        assert_eq!(
+            kept_all[&codes[0]].hour_counts,
+            unfiltered[&codes[0]].hour_counts
+        );
+        assert_eq!(unfiltered[&codes[0]].total, 4);
+    }
+
+    #[test]
+    fn test_network_profile_by_mode() {
+        let cif = test_cif::with_stations([
+            "BSNC100012601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LISTEVNGE 0850 0905      08500905         T",
+            "LTCAMBDGE 0930 0930",
+            "BSNC100022601012612311111100 BOO",
+            "LOKNGX    0830 0830",
+            "LTCAMBDGE 1000 1000",
+            "BSNC100032601012612310000010 POO",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+        ]);
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let selectors = [
+            DaySelector::Day(Day::Tuesday),
+            DaySelector::Day(Day::Saturday),
+        ];
+        let context = GroupingContext::for_week(Date(260113));
+
+        let (_, profiles, _) = group_days_with_profiles(&journeys, &lookup, &selectors, &context);
+        let tuesday = &profiles[&selectors[0]];
+        assert_eq!(tuesday.keys().collect::<Vec<_>>(), [&"bus", &"train"]);
+        assert_eq!(tuesday["train"].journeys[8], 1);
+        // Leaving King's Cross at 08:00 and Stevenage at 09:05
+        assert_eq!(tuesday["train"].stop_departures[8..10], [1, 1]);
+        assert_eq!(tuesday["bus"].journeys[8], 1);
+        assert_eq!(tuesday["bus"].stop_departures.iter().sum::<u32>(), 1);
+        let saturday = &profiles[&selectors[1]];
+        assert_eq!(saturday.len(), 1);
+        assert_eq!(saturday["train"].journeys.iter().sum::<u32>(), 1);
+    }
+
+    #[test]
+    fn test_station_coded_twice_in_a_journey_is_one_call() {
+        // King's Cross has a second TIPLOC for the suburban platforms
+        let cif = test_cif::with_stations([
+            "TIKNGXSUB00121000 LONDON KINGS CROSS SUB    87702    KGXLONDON KINGS CRS",
+            "BSNC100012601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LIKNGXSUB 0802 0803      08020803         T",
+            "LTCAMBDGE 0900 0900",
+        ]);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let selectors = [DaySelector::Day(Day::Tuesday)];
+        let mut context = GroupingContext::for_week(Date(260113));
+        context.collect_departure_times = true;
+
+        let (departures, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        let kgx = &departures[&selectors[0]][&codes[0]];
+        assert_eq!(kgx.hour_counts[8], 1);
+        assert_eq!(kgx.hour_counts_journey_starts[8], 1);
+        // Leaving from the second TIPLOC, with its departure time
+        assert_eq!(kgx.departure_times, [SecondsPastMidnight(8 * 3600 + 180)]);
+        assert_eq!(kgx.next_stop_three_alpha_code[8], [codes[1].clone()]);
+        assert_eq!((stats.departures, stats.merged_stops), (1, 1));
+    }
+
+    #[test]
+    fn test_setdown_origins_are_only_counted_when_asked() {
+        let cif = test_cif::with_stations([
+            "BSNC100012601012612311111100 POO",
+            "LOKNGX    0800 0800          TBD",
+            "LTCAMBDGE 0900 0900      TF",
+        ]);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let selectors = [DaySelector::Day(Day::Tuesday)];
+        let mut context = GroupingContext::for_week(Date(260113));
+
+        let (departures, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!((stats.accepted, stats.departures), (1, 0));
+        assert!(!departures[&selectors[0]].contains_key(&codes[0]));
+
+        context.count_setdown_origins = true;
+        let (departures, _) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        let kgx = &departures[&selectors[0]][&codes[0]];
+        assert_eq!(kgx.hour_counts[8], 1);
+        assert_eq!(kgx.hour_counts_journey_starts[8], 1);
+        // An origin's set down isn't an arrival
+        assert_eq!(kgx.hour_counts_arrivals, [0; 24]);
+    }
+
+    #[test]
+    fn test_final_pickups_are_left_out_when_asked() {
+        // Four trains an hour call at Cambridge, coded for both, before running empty to a
+        // depot with no three alpha code
+        let mut lines = Vec::new();
+        for index in 0..48 {
+            let departure = 6 * 60 + index * 15;
+            let time = |minutes: usize| format!("{:02}{:02}", minutes / 60, minutes % 60);
+            lines.push(format!("BSNC1{index:04}2601012612311111100 POO"));
+            lines.push(format!("LOKNGX    {0} {0}", time(departure)));
+            lines.push(format!(
+                "LICAMBDGE {0} {1}      {0}{1}         T",
+                time(departure + 60),
+                time(departure + 62)
+            ));
+            lines.push(format!("LTCAMBDPT {0} {0}", time(departure + 70)));
+        }
+        let cif = test_cif::with_stations(lines);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let mut context = GroupingContext::for_week(Date(260113));
+        let thresholds = crate::criteria::CriteriaThresholds::default();
+
+        let departures = group(&journeys, &lookup, &Day::Tuesday, &context);
+        assert_eq!(departures[&codes[1]].hour_counts[7..19], [4; 12]);
+        let criteria = crate::criteria::evaluate_criteria(&departures, &thresholds);
+        assert!(criteria[&codes[1]].all_7_7);
+
+        context.exclude_final_pickup = true;
+        let departures = group(&journeys, &lookup, &Day::Tuesday, &context);
+        let cbg = &departures[&codes[1]];
+        assert_eq!(cbg.hour_counts, [0; 24]);
+        assert_eq!(cbg.hour_counts_arrivals[7..19], [4; 12]);
+        // The origins are still counted
+        assert_eq!(departures[&codes[0]].hour_counts[6..18], [4; 12]);
+        let criteria = crate::criteria::evaluate_criteria(&departures, &thresholds);
+        assert!(!criteria[&codes[1]].all_7_7);
     }
-    HourlyDepartures {
-        three_alpha_code: ThreeAlphaCode("".to_string()), // A bit hacky also
-        hour_counts: [0; 24],
-        hour_counts_journey_starts: [0; 24],
-        next_stop_three_alpha_code,
+
+    #[test]
+    fn test_operational_stops_stay_in_the_trip_uncounted() {
+        // The train stops at Stevenage for a crew change, with no activity for passengers
+        let cif = test_cif::with_stations([
+            "BSNC100012601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LISTEVNGE 0820 0825      00000000         OP",
+            "LTCAMBDGE 0900 0900",
+        ]);
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let selectors = [DaySelector::Day(Day::Tuesday)];
+        let mut context = GroupingContext::for_week(Date(260113));
+
+        let trip_stops = trip_stops(&journeys[0], &lookup, &context);
+        assert_eq!(
+            trip_stops
+                .iter()
+                .map(|stop| (stop.three_alpha_code.0.as_str(), stop.calls()))
+                .collect::<Vec<_>>(),
+            [("KGX", true), ("SVG", false), ("CBG", true)]
+        );
+        assert_eq!(
+            trip_stops[1].departure_time,
+            Some(SecondsPastMidnight(8 * 3600 + 1500))
+        );
+
+        let (departures, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        let departures = &departures[&selectors[0]];
+        assert_eq!(stats.departures, 1);
+        assert!(!departures.contains_key(&codes[1]));
+        // The next station called at is Cambridge, the train only stopping at Stevenage
+        assert_eq!(
+            departures[&codes[0]].next_stop_three_alpha_code[8],
+            [codes[2].clone()]
+        );
+        // Stevenage isn't one of the stops the threshold counts
+        context.min_trip_stops = 3;
+        let (_, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!((stats.accepted, stats.too_few_stops), (0, 1));
+    }
+
+    #[test]
+    fn test_journeys_calling_at_one_station_are_left_out() {
+        // Two journeys between the two TIPLOCs of King's Cross, one with a crew change at
+        // Stevenage between them, and one on to Cambridge
+        let cif = test_cif::with_stations([
+            "TIKNGXSUB00121000 LONDON KINGS CROSS SUB    87702    KGXLONDON KINGS CRS",
+            "BSNC100012601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LTKNGXSUB 0805 0805",
+            "BSNC100022601012612311111100 POO",
+            "LOKNGX    0900 0900",
+            "LISTEVNGE 0920 0925      00000000         OP",
+            "LTKNGXSUB 0950 0950",
+            "BSNC100032601012612311111100 POO",
+            "LOKNGX    1000 1000",
+            "LTCAMBDGE 1100 1100",
+        ]);
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let selectors = [DaySelector::Day(Day::Tuesday)];
+        let mut context = GroupingContext::for_week(Date(260113));
+
+        let (departures, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!(departures[&selectors[0]][&codes[0]].hour_counts[10], 1);
+        assert_eq!(stats.departures, 1);
+        // The stops of the first are merged into one call, too few stops, while the second
+        // calls twice at King's Cross
+        assert_eq!(
+            (
+                stats.accepted,
+                stats.too_few_stops,
+                stats.too_few_distinct_stations
+            ),
+            (1, 1, 1)
+        );
+        let rejected = rejected_trips(&journeys, &lookup, &selectors, &context);
+        assert_eq!(
+            (rejected[1].uid.as_str(), rejected[1].reason),
+            ("C10002", TripRejection::TooFewDistinctStations)
+        );
+
+        context.min_distinct_stations = 1;
+        let (departures, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!(departures[&selectors[0]][&codes[0]].hour_counts[9], 1);
+        assert_eq!((stats.accepted, stats.too_few_distinct_stations), (2, 0));
+        // A threshold above --min-trip-stops is capped by it
+        context.min_distinct_stations = 3;
+        let (_, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!((stats.accepted, stats.too_few_distinct_stations), (1, 1));
+    }
+
+    #[test]
+    fn test_blank_departure_time_falls_back_to_arrival() {
+        let cif = test_cif::with_stations([
+            "BSNC123452601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LISTEVNGE 0820            08200000         T",
+            "LTCAMBDGE 0900 0900",
+        ]);
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let mut context = GroupingContext::for_week(Date(260113));
+
+        let with_fallback = group(&journeys, &lookup, &Day::Tuesday, &context);
+        context.arrival_time_fallback = false;
+        let without_fallback = group(&journeys, &lookup, &Day::Tuesday, &context);
+
+        assert_eq!(with_fallback[&codes[1]].hour_counts[8], 1);
+        assert_eq!(without_fallback[&codes[1]].hour_counts[8], 0);
+        // Its arrival time is given, so is counted either way
+        assert_eq!(without_fallback[&codes[1]].hour_counts_arrivals[8], 1);
+        assert_eq!(without_fallback[&codes[0]].hour_counts[8], 1);
+    }
+
+    #[test]
+    fn test_half_minute_departures_stay_in_their_hour() {
+        let cif = test_cif::with_stations([
+            "BSNC123452601012612311111100 POO",
+            "LOKNGX    0859H0859",
+            "LTCAMBDGE 0959H0959",
+        ]);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let records = crate::records::parse(cif);
+        let lookup = crate::stops::create_lookup(&records, &codes);
+        let departures = group(
+            &assemble_journeys(records),
+            &lookup,
+            &Day::Tuesday,
+            &GroupingContext::for_week(Date(260113)),
+        );
+        assert_eq!(departures[&codes[0]].hour_counts[8], 1);
     }
 }
@@ -0,0 +1,1257 @@
+//! Get the number of departures for each national rail station in Great Britain at each hour.
+//!
+//! The pipeline reads a Network Rail CIF timetable, builds a TIPLOC to three alpha code (CRS)
+//! lookup, groups departures by hour and evaluates the frequency criteria for every station.
+//! [`run`] chains the stages together; each stage is also exposed on its own.
+//!
+//! Reading and writing files needs the default `fs` feature. Without it [`run_from`] runs on a
+//! [`CifSource`] and [`RunInputs`] held in memory, such as [`InMemoryCif`], and
+//! [`Outputs::write_to`] writes to an [`OutputSink`] such as [`InMemoryOutputs`].
+
+pub mod areas;
+#[cfg(feature = "fs")]
+pub mod cache;
+pub mod criteria;
+pub mod decompress;
+#[cfg(feature = "fs")]
+pub mod demo;
+pub mod diff;
+pub mod filter;
+pub mod gtfs_export;
+pub mod gtfs_import;
+pub mod hour_grouping;
+pub mod log;
+pub mod modes;
+pub mod operators;
+mod output_files;
+pub mod records;
+pub mod report;
+pub mod routes;
+pub mod sqlite_export;
+pub mod stats;
+pub mod stops;
+pub mod storage;
+#[cfg(test)]
+mod test_cif;
+pub mod utils;
+pub mod validation;
+pub mod weights;
+
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+pub use criteria::{CriteriaResults, CriteriaThresholds, evaluate_criteria};
+pub use decompress::Encoding;
+pub use filter::{BoundingBox, StationFilter, StationListCounts};
+pub use hour_grouping::{
+    GroupingContext, GroupingStats, HourWindow, HourlyDepartures, NetworkProfile, RejectedTrip,
+    group, group_filtered,
+};
+pub use records::{
+    CalendarDate, Date, Day, DayChoice, DayMatch, DaySelector, ErrorBudget, Journey, LineRange,
+    NumberedRecord, ParseOptions, ParseSummary, Record, RecordFilter, RejectedJourney,
+    ThreeAlphaCode, Tiploc, assemble_journeys, parse,
+};
+pub use stops::{
+    LocationConflict, NameCleanup, NameConflict, NamePolicy, NaptanStop, StationName,
+    create_lookup, create_lookup_with_progress,
+};
+#[cfg(feature = "fs")]
+pub use storage::{CifFile, OutputDirectory};
+pub use storage::{CifSource, InMemoryCif, InMemoryOutputs, OutputSink};
+pub use utils::{ManifestInput, RunManifest};
+pub use validation::{JourneyChecks, JourneyWarning};
+
+#[derive(Parser)]
+pub struct Args {
+    /// The directory of the CIF file, or of the GTFS feed with --input-format gtfs
+    #[clap(
+        long,
+        required_unless_present = "demo",
+        default_value = ".",
+        hide_default_value = true
+    )]
+    pub input_file_dir: PathBuf,
+    /// Another directory with a CIF file whose validity overlaps, such as the next quarter's.
+    /// Journeys in more than one file are counted from the one extracted last.
+    #[clap(long)]
+    pub extra_input_file_dir: Vec<PathBuf>,
+    /// Run over a miniature CIF file and station list built into the binary, to try the tool
+    /// without a timetable. Counts Tuesday 13 January 2026 unless a day is given.
+    #[clap(long, conflicts_with_all = ["input_file_dir", "extra_input_file_dir", "input_format"])]
+    pub demo: bool,
+    /// A day of the week, "weekday", "weekend", or "all" to count every day in one pass
+    #[clap(long, default_value = "tuesday", conflicts_with = "date")]
+    pub operating_day: DayChoice,
+    /// Whether weekday and weekend select journeys operating on any or on all of their days
+    #[clap(long, value_enum, default_value_t = DayMatch::Any)]
+    pub day_match: DayMatch,
+    /// Directory to write the outputs to, or "-" to print the --only output to stdout
+    #[clap(long)]
+    pub output_directory: PathBuf,
+    /// How to write the departures
+    #[clap(long, value_enum, default_value_t = OutputFormat::Json)]
+    pub format: OutputFormat,
+    /// The output to print when --output-directory is "-"
+    #[clap(long, value_enum, default_value_t = OutputSelection::Criteria)]
+    pub only: OutputSelection,
+    /// The outputs to build, skipping the stages the others need. With only the lookup the
+    /// journey records aren't even parsed.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [OutputSelection::Lookup, OutputSelection::Departures, OutputSelection::Criteria]
+    )]
+    pub outputs: Vec<OutputSelection>,
+    /// YYMMDD date that journeys must run over
+    #[clap(
+        long,
+        value_parser = records::parse_date,
+        required_unless_present_any = ["date", "demo"]
+    )]
+    pub operating_week: Option<Date>,
+    /// YYYY-MM-DD date to count, in place of --operating-day and --operating-week
+    #[clap(long, conflicts_with = "operating_week")]
+    pub date: Option<CalendarDate>,
+    /// Whether --input-file-dir holds a CIF file or a GTFS feed
+    #[clap(long, value_enum, default_value_t = InputFormat::Cif)]
+    pub input_format: InputFormat,
+    /// JSON object mapping GTFS stop_ids to three alpha codes, for stop_ids that aren't one
+    #[clap(long)]
+    pub gtfs_stop_mapping: Option<PathBuf>,
+    /// The character encoding of the CIF file
+    #[clap(long, value_enum, default_value_t = Encoding::Auto)]
+    pub encoding: Encoding,
+    /// Directory to cache parsed records in, so later runs over the same input skip parsing
+    #[clap(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// How many threads to parse and group with, instead of one per CPU
+    #[clap(long)]
+    pub threads: Option<usize>,
+    /// Parse the CIF file a chunk of lines at a time, using less memory than indexing every
+    /// line at once, at some cost in speed
+    #[clap(long)]
+    pub low_memory: bool,
+    /// Always parse the CIF file, ignoring --cache-dir
+    #[clap(long)]
+    pub no_cache: bool,
+    /// NaPTAN Stops.csv to take station names and locations from in preference to the CIF file
+    #[clap(long)]
+    pub naptan_csv: Option<PathBuf>,
+    /// How far apart in metres a station's NaPTAN stops may be before they are listed in
+    /// location_conflicts.json
+    #[clap(long, default_value_t = stops::DEFAULT_LOCATION_CONFLICT_METRES)]
+    pub location_conflict_metres: u32,
+    /// JSON or TOML files listing the GB station three alpha codes, merged in the order given
+    #[clap(long = "station-codes-file", default_value = stops::DEFAULT_STATION_CODES_FILE)]
+    pub station_codes_files: Vec<PathBuf>,
+    /// TOML file naming the STANOX areas that criteria_by_area.json counts stations by, as
+    /// `[areas]` then `87 = "London North Western"`
+    #[clap(long)]
+    pub area_names: Option<PathBuf>,
+    /// TOML file naming the operators of the BX records, as `[operators]` then `GR = "LNER"`,
+    /// for the GTFS agencies and operators_seen.json. Defaults to config/operators.toml when
+    /// that exists.
+    #[clap(long)]
+    pub operator_names: Option<PathBuf>,
+    /// TOML file giving the modes of train statuses the built in list doesn't know, as
+    /// `[train_status_aliases]` then `6 = "bus"`. Statuses known to neither are counted under
+    /// "unknown" and listed in run_report.json.
+    #[clap(long, value_name = "PATH")]
+    pub mode_aliases: Option<PathBuf>,
+    /// TOML file of weights for the departures, as `[[weights]]` rules giving the `weight` of
+    /// the journeys of a `mode`, an `operator` or both, the first to match applying and 1
+    /// otherwise. The sums are written as weighted_hour_counts beside the departures.
+    #[clap(long, value_name = "PATH")]
+    pub weights: Option<PathBuf>,
+    /// Evaluate the criteria over the weighted counts of --weights, rounded down to whole
+    /// departures each hour, instead of over the departures
+    #[clap(long, requires = "weights")]
+    pub weighted_criteria: bool,
+    /// Write the departures, criteria and station name lookup files as one file per STANOX
+    /// area, with the area after the file name, and list the areas in index.json
+    #[clap(long)]
+    pub split_by_area: bool,
+    /// Also write report.md, a Markdown summary of the run and its criteria results
+    #[clap(long)]
+    pub report: bool,
+    /// How to choose between the names of a station given different names by the CIF file or
+    /// NaPTAN
+    #[clap(long, value_enum, default_value_t = NamePolicy::PreferNaptan)]
+    pub name_policy: NamePolicy,
+    /// Clean up the station names, with every cleanup when none are listed. Names are
+    /// otherwise written as the CIF file or NaPTAN give them.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        num_args = 0..,
+        default_missing_values = ["collapse-spaces", "strip-rail-station", "title-case"]
+    )]
+    pub normalize_names: Vec<NameCleanup>,
+    /// Fail the run if any station with departures has no name
+    #[clap(long)]
+    pub strict_names: bool,
+    /// Leave the stations without a name out of the departures and criteria, counting their
+    /// departures as unattributed in run_report.json
+    #[clap(long)]
+    pub drop_anonymous_stops: bool,
+    /// A departures file of an earlier run, such as the last quarter's, to check this run's
+    /// counts against. Stations whose daily total moved by more than --max-change-percent, or
+    /// that only one of the runs has, are listed in anomalies.json.
+    #[clap(long, value_name = "PATH")]
+    pub baseline: Option<PathBuf>,
+    /// How far, in percent, a station's daily total may move from --baseline's
+    #[clap(long, default_value_t = diff::DEFAULT_MAX_CHANGE_PERCENT, requires = "baseline")]
+    pub max_change_percent: f64,
+    /// Fail the run if anomalies.json lists any station, as a QA gate
+    #[clap(long, requires = "baseline")]
+    pub fail_on_anomalies: bool,
+    /// Write the hourly counts as objects keyed "00" to "23" instead of arrays indexed by hour
+    #[clap(long)]
+    pub hours_as_object: bool,
+    /// Replace existing output files, set by the binary from --force
+    #[clap(skip)]
+    pub overwrite: bool,
+    /// Draw progress bars, turned off by the binary under --no-progress
+    #[clap(skip = true)]
+    pub show_progress: bool,
+    /// Write the keys of the departures files in camelCase, for JavaScript readers
+    #[clap(long)]
+    pub camel_case: bool,
+    /// Write the departures keyed by three alpha code with each station's name alongside its
+    /// counts, as the JSON Lines format does, instead of the counts alone
+    #[clap(long)]
+    pub embed_names: bool,
+    /// With --operating-day all, write every day into one file keyed by day instead of one
+    /// file per day
+    #[clap(long)]
+    pub nested_days: bool,
+    /// Skip stops with a blank departure time instead of counting them at their arrival time
+    #[clap(long)]
+    pub no_arrival_fallback: bool,
+    /// Count departures from origins whose LO record says passengers can only alight there,
+    /// as on some parliamentary services, instead of leaving them out
+    #[clap(long)]
+    pub count_setdown_origins: bool,
+    /// Don't count a departure from a journey's last station call, even when its record lets
+    /// passengers board there, as at a terminus before the train runs empty to a depot
+    #[clap(long)]
+    pub exclude_final_pickup: bool,
+    /// Departures an hour needs to pass the criteria, at least
+    #[clap(long, default_value_t = criteria::DEFAULT_MIN_DEPARTURES_PER_HOUR)]
+    pub min_departures_per_hour: u32,
+    /// Journey starts an hour needs to pass the criteria, at least, when there are fewer
+    /// departures than --min-departures-per-hour
+    #[clap(long, default_value_t = criteria::DEFAULT_MIN_JOURNEY_STARTS_PER_HOUR)]
+    pub min_journey_starts_per_hour: u32,
+    /// Arrivals an hour needs to pass the arrival criteria, at least, when fewer journeys end
+    /// there than --min-journey-starts-per-hour
+    #[clap(long, default_value_t = criteria::DEFAULT_MIN_ARRIVALS_PER_HOUR)]
+    pub min_arrivals_per_hour: u32,
+    /// Neither count the arrivals nor evaluate the all_7_7_arrivals and avg_7_7_arrivals
+    /// criteria, for only the departure side
+    #[clap(long)]
+    pub no_arrival_criteria: bool,
+    /// Departures each hour from 22:00 to 00:59 needs to pass the night_service criterion
+    #[clap(long, default_value_t = criteria::DEFAULT_NIGHT_SERVICE_MIN_DEPARTURES_PER_HOUR)]
+    pub night_service_min_departures_per_hour: u32,
+    /// The early_start criterion needs a departure from 01:00 up to this hour
+    #[clap(
+        long,
+        default_value_t = criteria::DEFAULT_EARLY_START_BEFORE_HOUR,
+        value_parser = clap::value_parser!(u32).range(1..=24)
+    )]
+    pub early_start_before_hour: u32,
+    /// Leave out journeys calling at fewer GB stations than this
+    #[clap(long, default_value_t = hour_grouping::DEFAULT_MIN_TRIP_STOPS)]
+    pub min_trip_stops: usize,
+    /// Leave out journeys calling at fewer different GB stations than this, such as one
+    /// calling twice at the same station with only an operational stop between. Never more
+    /// than --min-trip-stops.
+    #[clap(long, default_value_t = hour_grouping::DEFAULT_MIN_DISTINCT_STATIONS)]
+    pub min_distinct_stations: usize,
+    /// Leave out journeys with a TN note containing this text, ignoring case, such as
+    /// "schooldays". Can be given more than once.
+    #[clap(long)]
+    pub exclude_note_pattern: Vec<String>,
+    /// Only count the departures and arrivals from the start of this hour, leaving the earlier
+    /// hours at 0. A journey starting earlier still has its later calls counted.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(0..=23))]
+    pub from_hour: Option<u32>,
+    /// Only count the departures and arrivals before the start of this hour, leaving the later
+    /// hours at 0
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=24))]
+    pub to_hour: Option<u32>,
+    /// Also write the assembled journeys, and the records rejected from them, for debugging
+    #[clap(long)]
+    pub dump_journeys: bool,
+    /// Also write the records parsed from each line of the CIF file, with the line itself, to
+    /// numbered_records.jsonl, for debugging the parser
+    #[clap(long)]
+    pub dump_records: bool,
+    /// The lines --dump-records writes, as FIRST:LAST counted from 1, in place of every line
+    #[clap(long, requires = "dump_records")]
+    pub dump_range: Option<LineRange>,
+    /// Also write rail_departure_times.json, every station's departure times in order, for
+    /// headway analysis and journey planners
+    #[clap(long)]
+    pub detailed_departures: bool,
+    /// Add each station's headway spread and clockface flag to station_stats.json. Like
+    /// --detailed-departures this keeps every departure time in memory.
+    #[clap(long)]
+    pub regularity_stats: bool,
+    /// Also write departures_by_name.json, each station's total departures and criteria
+    /// passes keyed by its name, for reading by eye
+    #[clap(long)]
+    pub by_name: bool,
+    /// How --detailed-departures writes the times
+    #[clap(long, value_enum, default_value = "clock")]
+    pub departure_time_format: TimeFormat,
+    /// Write each journey left out of the counts, and why, to this JSON Lines file
+    #[clap(long, value_name = "PATH")]
+    pub dump_rejected: Option<PathBuf>,
+    /// Stop after parsing when more than this share of the lines are malformed
+    #[clap(long, default_value_t = 0.01)]
+    pub max_error_rate: f64,
+    /// Stop after parsing when the newest CIF file was produced more than this many days ago,
+    /// going by the date of extract in its header, so an old file isn't published by mistake
+    #[clap(long, value_name = "DAYS")]
+    pub max_age_days: Option<u32>,
+    /// Warn about journeys that dwell at a stop for longer than this
+    #[clap(long, default_value_t = 60)]
+    pub max_dwell_minutes: u32,
+    /// Leave journeys with implausible times, as listed in journey_warnings.json, out of the
+    /// counts
+    #[clap(long)]
+    pub drop_invalid_journeys: bool,
+    /// How many of the busiest stations of each day to list in top_stations.json
+    #[clap(long, default_value_t = 20)]
+    pub top_n: usize,
+    /// Only write the stations whose three alpha code starts with one of these, comma
+    /// separated. Every journey is still counted.
+    #[clap(long, value_delimiter = ',')]
+    pub code_prefix: Vec<String>,
+    /// Only write the stations inside minlon,minlat,maxlon,maxlat, as located by --naptan-csv
+    #[clap(long, allow_hyphen_values = true)]
+    pub bbox: Option<BoundingBox>,
+    /// Only write the stations listed in this file, one three alpha code per line
+    #[clap(long)]
+    pub include_stops: Option<PathBuf>,
+    /// Leave out the stations listed in this file, one three alpha code per line
+    #[clap(long)]
+    pub exclude_stops: Option<PathBuf>,
+    /// Start of the departures file names, followed by the day and week
+    #[clap(long, default_value = "rail_hourly_departures")]
+    pub departures_filename: String,
+    /// Name of the station name lookup file, without the .json extension
+    #[clap(long, default_value = "station_name_lookup")]
+    pub lookup_filename: String,
+}
+
+/// One of the outputs of a run, for --outputs and for printing to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputSelection {
+    /// The raw hourly departures of each station
+    Departures,
+    /// The hourly departures with the criteria results, as written to the departures files
+    Criteria,
+    /// The station name lookup
+    Lookup,
+}
+
+/// The timetable format read from --input-file-dir
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputFormat {
+    /// The Network Rail CIF file, plain or compressed
+    Cif,
+    /// The stops, calendar, trips and stop_times files of a GTFS feed, or a gtfs.zip of them
+    Gtfs,
+}
+
+/// How a departure time is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimeFormat {
+    /// As HH:MM
+    Clock,
+    /// As seconds past midnight
+    Seconds,
+}
+
+/// The form the departures are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// A departures and criteria JSON file per day
+    Json,
+    /// As JSON, but written as JSON Lines with a line per station, for very large outputs
+    Jsonl,
+    /// The counted journeys as a GTFS feed in gtfs.zip, in place of the departures files
+    Gtfs,
+    /// The departures, criteria results and stations as tables of output.sqlite, in place of
+    /// the departures files. Needs the sqlite3 tool.
+    Sqlite,
+}
+
+impl Args {
+    pub fn wants(&self, output: OutputSelection) -> bool {
+        self.outputs.contains(&output)
+    }
+
+    /// Whether the journeys are counted, which both the departures and the criteria need
+    pub fn counts_departures(&self) -> bool {
+        self.wants(OutputSelection::Departures) || self.wants(OutputSelection::Criteria)
+    }
+
+    /// How the CIF file is parsed: every record type when the journeys are counted, otherwise
+    /// only the stops
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            filter: if self.counts_departures() {
+                RecordFilter::All
+            } else {
+                RecordFilter::StopsOnly
+            },
+            low_memory: self.low_memory,
+            show_progress: self.show_progress,
+        }
+    }
+
+    /// The operator names file given, or the default one if it exists
+    pub fn operator_names_file(&self) -> Option<PathBuf> {
+        self.operator_names.clone().or_else(|| {
+            Some(PathBuf::from(operators::DEFAULT_OPERATOR_NAMES_FILE)).filter(|path| path.exists())
+        })
+    }
+
+    /// The operating day, taken from the weekday of --date when one is given
+    pub fn day_choice(&self) -> DayChoice {
+        match &self.date {
+            Some(date) => DayChoice::Day(date.weekday()),
+            None => self.operating_day.clone(),
+        }
+    }
+
+    pub fn criteria_thresholds(&self) -> CriteriaThresholds {
+        CriteriaThresholds {
+            min_departures_per_hour: self.min_departures_per_hour,
+            min_journey_starts_per_hour: self.min_journey_starts_per_hour,
+            min_arrivals_per_hour: (!self.no_arrival_criteria)
+                .then_some(self.min_arrivals_per_hour),
+            night_service_min_departures_per_hour: self.night_service_min_departures_per_hour,
+            early_start_before_hour: self.early_start_before_hour,
+            weighted_counts: self.weighted_criteria,
+        }
+    }
+
+    pub fn grouping_context(&self) -> GroupingContext {
+        let mut context = match (&self.date, &self.operating_week) {
+            (Some(date), _) => GroupingContext::for_date(*date),
+            (None, Some(operating_week)) => GroupingContext::for_week(operating_week.clone()),
+            (None, None) => unreachable!("clap requires --date or --operating-week"),
+        };
+        context.arrival_time_fallback = !self.no_arrival_fallback;
+        context.min_trip_stops = self.min_trip_stops;
+        context.min_distinct_stations = self.min_distinct_stations;
+        context.exclude_note_patterns = self.exclude_note_pattern.clone();
+        context.count_arrivals = !self.no_arrival_criteria;
+        context.count_setdown_origins = self.count_setdown_origins;
+        context.exclude_final_pickup = self.exclude_final_pickup;
+        context.hour_window = self.hour_window();
+        context.collect_departure_times = self.detailed_departures || self.regularity_stats;
+        context.show_progress = self.show_progress;
+        context
+    }
+
+    /// The window of --from-hour and --to-hour, when either is given
+    pub fn hour_window(&self) -> Option<HourWindow> {
+        (self.from_hour.is_some() || self.to_hour.is_some()).then(|| HourWindow {
+            from_hour: self.from_hour.unwrap_or(0) as usize,
+            to_hour: self.to_hour.unwrap_or(24) as usize,
+        })
+    }
+
+    /// The station filter of --code-prefix and --bbox, with the station lists of
+    /// --include-stops and --exclude-stops already read
+    pub fn station_filter(
+        &self,
+        include: Option<HashSet<ThreeAlphaCode>>,
+        exclude: Option<HashSet<ThreeAlphaCode>>,
+    ) -> StationFilter {
+        StationFilter {
+            code_prefixes: self.code_prefix.clone(),
+            bounding_box: self.bbox,
+            include,
+            exclude,
+        }
+    }
+
+    /// Check the outputs asked for can be written to `sink`
+    pub fn check_output_sink(&self, sink: &impl OutputSink) -> Result<()> {
+        let is_stdout = sink.is_stdout();
+        if is_stdout && !self.wants(self.only) {
+            bail!("--outputs must include the --only output");
+        }
+        if self.split_by_area
+            && (is_stdout || matches!(self.format, OutputFormat::Gtfs | OutputFormat::Sqlite))
+        {
+            bail!(
+                "--split-by-area writes a JSON or JSON Lines file per area to an output directory"
+            );
+        }
+        match self.format {
+            OutputFormat::Gtfs if is_stdout => {
+                bail!("--format gtfs writes a zip file, so needs an output directory")
+            }
+            OutputFormat::Sqlite if is_stdout => {
+                bail!("--format sqlite writes a database file, so needs an output directory")
+            }
+            _ if is_stdout && self.dump_records => {
+                bail!("--dump-records writes its own file, so needs an output directory")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Everything produced by a [`run`], left in memory so callers can serialize it as they like,
+/// or write the binary's files with [`Outputs::write_to`].
+pub struct Outputs {
+    pub parse_summary: ParseSummary,
+    pub journeys: Vec<Journey>,
+    /// Stops before any journey header, and journeys with fewer than two stops
+    pub rejected_journeys: Vec<RejectedJourney>,
+    /// Journeys with implausible times, which are left out of `journeys` with
+    /// --drop-invalid-journeys
+    pub journey_warnings: Vec<JourneyWarning>,
+    /// How many journeys were counted, and why the others were left out
+    pub grouping_stats: GroupingStats,
+    /// The modes of train statuses read from --mode-aliases
+    pub mode_aliases: modes::ModeAliases,
+    /// The journeys and departures of each mode across all stations, by hour, for each
+    /// selected day
+    pub network_profiles: HashMap<DaySelector, NetworkProfile>,
+    /// The journeys left out of the counts, only listed with --dump-rejected
+    pub rejected_trips: Vec<RejectedTrip>,
+    pub lookup: HashMap<Tiploc, ThreeAlphaCode>,
+    /// The inputs, stage timings and counts so far; the caller adds the write stage
+    pub manifest: RunManifest,
+    pub station_names: HashMap<ThreeAlphaCode, StationName>,
+    /// Stations given more than one name by the CIF file or by NaPTAN
+    pub name_conflicts: Vec<NameConflict>,
+    /// Stations whose NaPTAN stops are further apart than --location-conflict-metres
+    pub location_conflicts: Vec<LocationConflict>,
+    /// The STANOX area of each station
+    pub station_areas: HashMap<ThreeAlphaCode, String>,
+    /// One entry per selected operating day, Monday first, or none when --outputs leaves out
+    /// both the departures and the criteria
+    pub days: Vec<DayOutputs>,
+    /// How many stations the --include-stops and --exclude-stops lists dropped
+    pub station_list_counts: StationListCounts,
+    /// The stations of each day that changed too much from the --baseline, only with one
+    pub anomalies: Option<BTreeMap<DaySelector, Vec<diff::Anomaly>>>,
+    /// Stops of the counted journeys with no TI record, whose departures are lost
+    pub stops_without_records: Vec<stops::StopWithoutRecord>,
+    /// The unnamed stations taken out of `days`, only with --drop-anonymous-stops
+    pub unattributed: Option<stops::Unattributed>,
+    /// What the parser made of each line of the CIF file, only with --dump-records
+    pub numbered_records: Option<Vec<NumberedRecord>>,
+    /// The names of the STANOX areas, for criteria_by_area.json and the --split-by-area index
+    pub area_names: HashMap<String, String>,
+    /// The names of the operators, for the GTFS agencies and operators_seen.json
+    pub operator_names: HashMap<String, String>,
+}
+
+impl Outputs {
+    /// How many anomalies against the --baseline there are over all the days
+    pub fn anomaly_count(&self) -> usize {
+        self.anomalies
+            .iter()
+            .flatten()
+            .map(|(_, day)| day.len())
+            .sum()
+    }
+}
+
+pub struct DayOutputs {
+    pub selector: DaySelector,
+    pub departures: HashMap<ThreeAlphaCode, HourlyDepartures>,
+    /// Empty when --outputs leaves out the criteria
+    pub criteria_results: HashMap<ThreeAlphaCode, CriteriaResults>,
+}
+
+/// The error of a [`run`] stopped by --max-error-rate, carrying the parse summary and the
+/// manifest so far for the caller to write out
+#[derive(Debug)]
+pub struct ErrorRateExceeded {
+    pub parse_summary: ParseSummary,
+    pub manifest: RunManifest,
+}
+
+impl std::fmt::Display for ErrorRateExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} lines ({:.2}%) are malformed, more than --max-error-rate allows ({:.2}%)",
+            self.parse_summary.malformed,
+            self.parse_summary.total_lines(),
+            self.parse_summary.error_rate() * 100.0,
+            self.manifest
+                .error_budget
+                .map_or(0.0, |error_budget| error_budget.max_error_rate * 100.0)
+        )
+    }
+}
+
+impl std::error::Error for ErrorRateExceeded {}
+
+/// Everything a run reads besides its CIF timetable, already loaded so that [`run_from`] needs
+/// no filesystem. [`RunInputs::read`] reads the files named in [`Args`], as [`run`] does; a
+/// caller without one fills in what it has and leaves the rest at their defaults.
+#[derive(Default)]
+pub struct RunInputs {
+    /// The GB station three alpha codes of --station-codes-file
+    pub station_codes: Vec<ThreeAlphaCode>,
+    /// The timetables of --extra-input-file-dir, each superseding the ones before
+    pub extra_sources: Vec<Box<dyn CifSource + Send + Sync>>,
+    /// The files of the GTFS feed read with --input-format gtfs, keyed by file name
+    pub gtfs_feed: HashMap<String, String>,
+    /// The three alpha codes of --gtfs-stop-mapping, keyed by stop_id
+    pub gtfs_stop_mapping: HashMap<String, ThreeAlphaCode>,
+    /// The stops of --naptan-csv
+    pub naptan_stops: Option<Vec<NaptanStop>>,
+    /// The stations of --include-stops
+    pub include_stops: Option<HashSet<ThreeAlphaCode>>,
+    /// The stations of --exclude-stops
+    pub exclude_stops: Option<HashSet<ThreeAlphaCode>>,
+    /// The names of --area-names, keyed by STANOX area
+    pub area_names: HashMap<String, String>,
+    /// The names of the operator names file, keyed by ATOC code
+    pub operator_names: HashMap<String, String>,
+    /// The modes of --mode-aliases, keyed by train status
+    pub mode_aliases: modes::ModeAliases,
+    /// The weights of --weights
+    pub departure_weights: Option<weights::DepartureWeights>,
+    /// The departures of --baseline, to find the anomalies against
+    pub baseline: Option<HashMap<ThreeAlphaCode, diff::DepartureCounts>>,
+    /// The size and hash of each of these files, added to the manifest after the timetables
+    pub manifest_inputs: Vec<ManifestInput>,
+}
+
+#[cfg(feature = "fs")]
+impl RunInputs {
+    /// Read the files named in `args`, other than the CIF file of `args.input_file_dir`
+    pub fn read(args: &Args) -> Result<Self> {
+        let baseline = args
+            .baseline
+            .as_deref()
+            .map(utils::read_versioned_json_file)
+            .transpose()
+            .context("Failed to read the --baseline departures file")?;
+        let mut manifest_inputs = Vec::new();
+        let mut gtfs_feed = HashMap::new();
+        if args.input_format == InputFormat::Gtfs {
+            for path in gtfs_import::gtfs_input_paths(&args.input_file_dir) {
+                if path.exists() {
+                    manifest_inputs.push(ManifestInput::read(&path)?);
+                }
+            }
+            gtfs_feed = gtfs_import::read_feed_files(&args.input_file_dir)?;
+        }
+        for path in args.station_codes_files.iter() {
+            manifest_inputs.push(ManifestInput::read(path)?);
+        }
+        for path in [
+            &args.naptan_csv,
+            &args.include_stops,
+            &args.exclude_stops,
+            &args.area_names,
+            &args.operator_names_file(),
+            &args.mode_aliases,
+            &args.weights,
+            &args.baseline,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            manifest_inputs.push(ManifestInput::read(path)?);
+        }
+        let read_list =
+            |path: &Option<PathBuf>| path.as_deref().map(filter::read_station_list).transpose();
+        Ok(RunInputs {
+            station_codes: stops::read_station_codes(&args.station_codes_files)?,
+            extra_sources: args
+                .extra_input_file_dir
+                .iter()
+                .map(|input_file_dir| {
+                    Box::new(CifFile(cif_file_path(input_file_dir)))
+                        as Box<dyn CifSource + Send + Sync>
+                })
+                .collect(),
+            gtfs_feed,
+            gtfs_stop_mapping: gtfs_import::read_stop_mapping(args.gtfs_stop_mapping.as_deref())?,
+            naptan_stops: args
+                .naptan_csv
+                .as_deref()
+                .map(stops::read_naptan_csv)
+                .transpose()?,
+            include_stops: read_list(&args.include_stops)?,
+            exclude_stops: read_list(&args.exclude_stops)?,
+            area_names: args
+                .area_names
+                .as_deref()
+                .map(areas::read_area_names)
+                .transpose()?
+                .unwrap_or_default(),
+            operator_names: args
+                .operator_names_file()
+                .as_deref()
+                .map(operators::read_operator_names)
+                .transpose()?
+                .unwrap_or_default(),
+            mode_aliases: args
+                .mode_aliases
+                .as_deref()
+                .map(modes::read_mode_aliases)
+                .transpose()?
+                .unwrap_or_default(),
+            departure_weights: args
+                .weights
+                .as_deref()
+                .map(weights::read_departure_weights)
+                .transpose()?,
+            baseline,
+            manifest_inputs,
+        })
+    }
+}
+
+/// Run the full pipeline for the CIF file in `args.input_file_dir`, reading the other files
+/// named in `args` with [`RunInputs::read`].
+///
+/// No files are written; `args.output_directory` is left for the caller to use, such as with
+/// [`Outputs::write_to`].
+///
+/// ```no_run
+/// use clap::Parser;
+/// use rail_hourly_departures::{Args, OutputDirectory, run};
+///
+/// let args = Args::parse_from([
+///     "rail-hourly-departures",
+///     "--input-file-dir=input",
+///     "--operating-day=tuesday",
+///     "--operating-week=260112",
+///     "--output-directory=data",
+/// ]);
+/// let mut outputs = run(&args)?;
+/// println!("{} stations", outputs.days[0].criteria_results.len());
+/// let mut output_directory = OutputDirectory::new(args.output_directory.clone(), args.overwrite);
+/// outputs.write_to(&args, &mut output_directory)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[cfg(feature = "fs")]
+pub fn run(args: &Args) -> Result<Outputs> {
+    // Read before the run, so a missing or malformed input stops it straight away
+    let inputs = RunInputs::read(args)?;
+    run_from(args, &CifFile(cif_file_path(&args.input_file_dir)), inputs)
+}
+
+/// [`run`] over the CIF timetable `source`, such as an [`InMemoryCif`], and the `inputs`
+/// already read, in place of the files named in `args`. Only a --cache-dir is still used from
+/// disk, and only with the `fs` feature.
+pub fn run_from(
+    args: &Args,
+    source: &(impl CifSource + Sync),
+    mut inputs: RunInputs,
+) -> Result<Outputs> {
+    let baseline = inputs.baseline.take();
+    let mut outputs = match args.threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .context("Failed to start the thread pool for --threads")?
+            .install(|| run_stages(args, source, inputs)),
+        None => run_stages(args, source, inputs),
+    }?;
+    if let Some(baseline) = baseline {
+        // Every day is checked against the one baseline
+        outputs.anomalies = Some(
+            outputs
+                .days
+                .iter()
+                .map(|day_outputs| {
+                    let anomalies = diff::anomalies(
+                        &baseline,
+                        &day_outputs.departures,
+                        args.max_change_percent,
+                    );
+                    (day_outputs.selector.clone(), anomalies)
+                })
+                .collect(),
+        );
+        let anomaly_count = outputs.anomaly_count();
+        if anomaly_count > 0 {
+            warn!(
+                "{anomaly_count} stations changed by more than {}% from the baseline, or only \
+                 one of the runs has them",
+                args.max_change_percent
+            );
+        }
+    }
+    Ok(outputs)
+}
+
+fn run_stages(args: &Args, source: &impl CifSource, inputs: RunInputs) -> Result<Outputs> {
+    let cache_dir = args.cache_dir.as_deref().filter(|_| !args.no_cache);
+    let mut context = args.grouping_context();
+    context.mode_aliases = inputs.mode_aliases;
+    context.departure_weights = inputs.departure_weights;
+    if let Some(window) = &context.hour_window {
+        if window.from_hour >= window.to_hour {
+            bail!("--from-hour must be before --to-hour, not {window}");
+        }
+        if args.wants(OutputSelection::Criteria) {
+            check_criteria_window(window, &args.criteria_thresholds())
+                .context("Leave out the criteria with --outputs departures")?;
+        }
+    }
+    let mut manifest = RunManifest::new(
+        args.day_choice().to_string(),
+        context.operating_date.to_string(),
+    )
+    .with_calendar_date(context.calendar_date.map(|date| date.to_string()));
+    manifest.threads = args.threads;
+    manifest.hour_window = context.hour_window;
+    manifest.low_memory = args.low_memory;
+    manifest.criteria_thresholds = Some(args.criteria_thresholds());
+
+    if args.input_format == InputFormat::Cif {
+        manifest.add_source(source)?;
+        for extra_source in inputs.extra_sources.iter() {
+            manifest.add_source(extra_source.as_ref())?;
+        }
+    }
+    manifest.inputs.extend(inputs.manifest_inputs);
+
+    if args.dump_records && args.input_format != InputFormat::Cif {
+        bail!("--dump-records needs --input-format cif");
+    }
+    if !inputs.extra_sources.is_empty() && args.input_format != InputFormat::Cif {
+        bail!("--extra-input-file-dir needs --input-format cif");
+    }
+
+    let gb_station_three_alpha_codes = inputs.station_codes;
+    let station_filter = args.station_filter(inputs.include_stops, inputs.exclude_stops);
+    let parse_options = args.parse_options();
+    let mut superseded_journeys = None;
+    let mut numbered_records = None;
+    let (records, parse_summary) = manifest.time("parse", || match args.input_format {
+        InputFormat::Cif => {
+            // Decoded once, for both the numbered records and the parse
+            let raw_cif_text = source.read_text(args.encoding)?;
+            if args.dump_records {
+                numbered_records = Some(records::parse_numbered(&raw_cif_text, args.dump_range));
+            }
+            if inputs.extra_sources.is_empty() {
+                return parse_cached_text(raw_cif_text, cache_dir, parse_options);
+            }
+            let texts = std::iter::once(Ok(raw_cif_text)).chain(
+                inputs
+                    .extra_sources
+                    .iter()
+                    .map(|extra_source| extra_source.read_text(args.encoding)),
+            );
+            let (records, parse_summary, superseded) =
+                read_overlapping_records(texts, cache_dir, parse_options, &context.operating_date)?;
+            superseded_journeys = Some(superseded);
+            Ok((records, parse_summary))
+        }
+        InputFormat::Gtfs => gtfs_import::parse_gtfs(&inputs.gtfs_feed, &inputs.gtfs_stop_mapping),
+    })?;
+    if let Some(superseded) = superseded_journeys {
+        info!("Journeys superseded by a later file: {superseded}");
+        manifest.count("superseded_journeys", superseded);
+    }
+    let error_budget = ErrorBudget::check(&parse_summary, args.max_error_rate);
+    manifest.error_budget = Some(error_budget);
+    if error_budget.exceeded {
+        return Err(ErrorRateExceeded {
+            parse_summary,
+            manifest,
+        }
+        .into());
+    }
+    if let Some(max_age_days) = args.max_age_days {
+        let Some(age_days) =
+            records::newest_extract_age_days(&parse_summary.headers, CalendarDate::today())
+        else {
+            bail!("--max-age-days needs a date of extract in a CIF file header, and none has one");
+        };
+        manifest.newest_source_age_days = Some(age_days);
+        if age_days > max_age_days as i64 {
+            bail!(
+                "The newest CIF file was produced {age_days} days ago, more than the \
+                 --max-age-days of {max_age_days}"
+            );
+        }
+        info!("The newest CIF file was produced {age_days} days ago");
+    }
+    let naptan_stops = inputs.naptan_stops;
+
+    if let Some(date) = &context.calendar_date {
+        let day = date.weekday();
+        info!("{date} is a {day}; selecting journeys operating on {day}");
+        warn_if_outside_validity_period(&records, &context.operating_date, date);
+    }
+
+    let (lookup, station_names, name_conflicts, station_areas) = manifest.time("lookup", || {
+        let lookup = create_lookup_with_progress(
+            &records,
+            &gb_station_three_alpha_codes,
+            context.show_progress,
+        );
+        let (mut station_names, name_conflicts) = stops::create_station_name_lookup(
+            &records,
+            &lookup,
+            naptan_stops.as_deref(),
+            args.name_policy,
+        );
+        stops::normalize_names(&mut station_names, &args.normalize_names);
+        let station_areas = areas::create_station_areas(&records, &lookup);
+        (lookup, station_names, name_conflicts, station_areas)
+    });
+    let location_conflicts = naptan_stops.as_deref().map_or(Vec::new(), |naptan_stops| {
+        stops::location_conflicts(naptan_stops, &lookup, args.location_conflict_metres)
+    });
+    manifest.file_headers = parse_summary.headers.clone();
+    manifest.count("records", records.len());
+    manifest.count("stops", parse_summary.ti);
+    manifest.count("stations", station_names.len());
+    manifest.count("name_conflicts", name_conflicts.len());
+    if naptan_stops.is_some() {
+        manifest.count("location_conflicts", location_conflicts.len());
+    }
+
+    if !args.counts_departures() {
+        info!("Only the lookup was asked for, so no journeys are counted");
+        return Ok(filter_outputs(
+            args,
+            station_filter,
+            Outputs {
+                parse_summary,
+                journeys: Vec::new(),
+                rejected_journeys: Vec::new(),
+                journey_warnings: Vec::new(),
+                grouping_stats: GroupingStats::default(),
+                mode_aliases: context.mode_aliases,
+                network_profiles: HashMap::new(),
+                rejected_trips: Vec::new(),
+                lookup,
+                manifest,
+                station_names,
+                name_conflicts,
+                location_conflicts,
+                station_areas,
+                days: Vec::new(),
+                station_list_counts: StationListCounts::default(),
+                anomalies: None,
+                stops_without_records: Vec::new(),
+                unattributed: None,
+                numbered_records,
+                area_names: inputs.area_names,
+                operator_names: inputs.operator_names,
+            },
+        ));
+    }
+
+    let recorded_tiplocs = stops::recorded_tiplocs(&records);
+    let (journeys, rejected_journeys) = manifest.time("assemble", || {
+        records::assemble_journeys_with_rejects(records)
+    });
+    manifest.count("rejected_journeys", rejected_journeys.len());
+    let checks = JourneyChecks::new(args.max_dwell_minutes, &lookup, &station_names);
+    let (journeys, journey_warnings) = manifest.time("validate", || {
+        validation::validate_journeys(journeys, &checks, args.drop_invalid_journeys)
+    });
+    info!("Journey time warnings: {}", journey_warnings.len());
+    manifest.count("journeys", journeys.len());
+    manifest.count("journey_warnings", journey_warnings.len());
+
+    let selectors = args.day_choice().selectors(args.day_match);
+    let (mut departures_by_day, network_profiles, grouping_stats) = manifest.time("group", || {
+        hour_grouping::group_days_with_profiles(&journeys, &lookup, &selectors, &context)
+    });
+    info!("{}", grouping_stats.summary());
+    if !grouping_stats.unknown_train_statuses.is_empty() {
+        warn!(
+            "Counted journeys with train statuses of unknown mode: {:?}, give them a mode with \
+             --mode-aliases",
+            grouping_stats.unknown_train_statuses
+        );
+    }
+    let stops_without_records =
+        stops::stops_without_records(&journeys, &recorded_tiplocs, &lookup, &selectors, &context);
+    if !stops_without_records.is_empty() {
+        warn!(
+            "{} stops of the counted journeys have no TI record, so their departures are not \
+             counted, see stops_without_records.json",
+            stops_without_records.len()
+        );
+    }
+    let rejected_trips = match args.dump_rejected {
+        Some(_) => hour_grouping::rejected_trips(&journeys, &lookup, &selectors, &context),
+        None => Vec::new(),
+    };
+    let days: Vec<DayOutputs> = manifest.time("criteria", || {
+        selectors
+            .into_iter()
+            .map(|selector| {
+                let departures = departures_by_day.remove(&selector).unwrap_or_default();
+                let criteria_results = if args.wants(OutputSelection::Criteria) {
+                    evaluate_criteria(&departures, &args.criteria_thresholds())
+                } else {
+                    HashMap::new()
+                };
+                DayOutputs {
+                    selector,
+                    departures,
+                    criteria_results,
+                }
+            })
+            .collect()
+    });
+    Ok(filter_outputs(
+        args,
+        station_filter,
+        Outputs {
+            parse_summary,
+            journeys,
+            rejected_journeys,
+            journey_warnings,
+            grouping_stats,
+            mode_aliases: context.mode_aliases,
+            network_profiles,
+            rejected_trips,
+            lookup,
+            manifest,
+            station_names,
+            name_conflicts,
+            location_conflicts,
+            station_areas,
+            days,
+            station_list_counts: StationListCounts::default(),
+            anomalies: None,
+            stops_without_records,
+            unattributed: None,
+            numbered_records,
+            area_names: inputs.area_names,
+            operator_names: inputs.operator_names,
+        },
+    ))
+}
+
+/// Refuse to evaluate the criteria over departures counted only in `window` when it leaves out
+/// hours they need, as those hours would fail for want of counts rather than of trains
+pub fn check_criteria_window(window: &HourWindow, thresholds: &CriteriaThresholds) -> Result<()> {
+    let outside = criteria::criteria_outside_window(window, thresholds);
+    if !outside.is_empty() {
+        bail!(
+            "The departures were only counted from {window}, leaving out hours of the {} \
+             criteria",
+            outside.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Apply `station_filter` to the days and names of `outputs`, then drop the unnamed stations
+/// under --drop-anonymous-stops
+fn filter_outputs(args: &Args, station_filter: StationFilter, mut outputs: Outputs) -> Outputs {
+    outputs.station_list_counts =
+        station_filter.apply(&mut outputs.days, &mut outputs.station_names);
+    if !station_filter.is_empty() {
+        info!(
+            "Stations kept by the {station_filter} filter: {}",
+            outputs.station_names.len()
+        );
+        outputs.manifest.station_filter = Some(station_filter.to_string());
+        outputs
+            .manifest
+            .count("filtered_stations", outputs.station_names.len());
+    }
+    if args.drop_anonymous_stops {
+        let unattributed = stops::drop_unnamed_stations(&mut outputs.days, &outputs.station_names);
+        info!(
+            "Dropped {} stations without a name, with {} departures",
+            unattributed.stations, unattributed.departures
+        );
+        outputs.unattributed = Some(unattributed);
+    }
+    outputs
+}
+
+fn warn_if_outside_validity_period(records: &[Record], operating_date: &Date, date: &CalendarDate) {
+    if let Some((from, to)) = records::validity_period(records)
+        && (operating_date.0 < from.0 || operating_date.0 > to.0)
+    {
+        warn!(
+            "{date} is outside the timetable's validity period ({from} to {to}), \
+             expect no departures"
+        );
+    }
+}
+
+/// Read and parse the CIF file in `input_file_dir`, going through the record cache in
+/// `cache_dir` when one is given. The record type counts are printed as a table. The text is
+/// parsed as `options` say.
+#[cfg(feature = "fs")]
+pub fn read_records(
+    input_file_dir: &Path,
+    cache_dir: Option<&Path>,
+    encoding: Encoding,
+    options: ParseOptions,
+) -> Result<(Vec<Record>, ParseSummary)> {
+    read_cached_records(
+        &CifFile(cif_file_path(input_file_dir)),
+        cache_dir,
+        encoding,
+        options,
+    )
+}
+
+/// [`read_records_from`], going through the record cache in `cache_dir` when one is given
+#[cfg(feature = "fs")]
+fn read_cached_records(
+    source: &(impl CifSource + ?Sized),
+    cache_dir: Option<&Path>,
+    encoding: Encoding,
+    options: ParseOptions,
+) -> Result<(Vec<Record>, ParseSummary)> {
+    parse_cached_text(source.read_text(encoding)?, cache_dir, options)
+}
+
+/// Parse the decoded `raw_cif_text` as `options` say, going through the record cache in
+/// `cache_dir` when one is given, which needs the `fs` feature. The record type counts are
+/// printed as a table.
+fn parse_cached_text(
+    raw_cif_text: String,
+    cache_dir: Option<&Path>,
+    options: ParseOptions,
+) -> Result<(Vec<Record>, ParseSummary)> {
+    // The cache only holds full parses
+    let parsed = match cache_dir.filter(|_| options.filter == RecordFilter::All) {
+        #[cfg(feature = "fs")]
+        Some(cache_dir) => cache::parse_with_cache(raw_cif_text, cache_dir, options)?,
+        #[cfg(not(feature = "fs"))]
+        Some(_) => bail!("--cache-dir needs the fs feature"),
+        None => records::parse_with_options(raw_cif_text, options),
+    };
+    Ok(log_parse_summary(parsed))
+}
+
+/// Read and parse the CIF timetable of `source`, with no record cache. The record type counts
+/// are printed as a table. The text is parsed as `options` say.
+pub fn read_records_from(
+    source: &(impl CifSource + ?Sized),
+    encoding: Encoding,
+    options: ParseOptions,
+) -> Result<(Vec<Record>, ParseSummary)> {
+    let raw_cif_text = source.read_text(encoding)?;
+    Ok(log_parse_summary(records::parse_with_options(
+        raw_cif_text,
+        options,
+    )))
+}
+
+fn log_parse_summary(
+    (records, summary): (Vec<Record>, ParseSummary),
+) -> (Vec<Record>, ParseSummary) {
+    info!("Records len: {:?}", records.len());
+    info!("{}", summary.format_table().trim_end());
+    if let Some(first_malformed) = &summary.first_malformed {
+        warn!(
+            "Skipped {} malformed lines, the first: {first_malformed}",
+            summary.malformed
+        );
+    }
+    if summary.no_operating_days > 0 {
+        warn!(
+            "{} journeys have no regular operating days and were excluded, such as {}",
+            summary.no_operating_days,
+            summary.no_operating_days_sample.join(", ")
+        );
+    }
+    for header in summary.headers.iter() {
+        if !header.is_supported_version() {
+            warn!(
+                "{} is CIF version {:?}, only {:?} are supported",
+                header.current_file_reference,
+                header.version,
+                records::SUPPORTED_CIF_VERSIONS
+            );
+        }
+    }
+    (records, summary)
+}
+
+/// Parse each of the decoded CIF `texts`, as [`read_records`] does, joining their records with
+/// [`records::merge_overlapping_files`]. Also returns how many journeys were left out as
+/// superseded by a later file.
+pub fn read_overlapping_records(
+    texts: impl IntoIterator<Item = Result<String>>,
+    cache_dir: Option<&Path>,
+    options: ParseOptions,
+    operating_date: &Date,
+) -> Result<(Vec<Record>, ParseSummary, usize)> {
+    let mut files = Vec::new();
+    let mut parse_summary = ParseSummary::default();
+    for raw_cif_text in texts {
+        let (records, summary) = parse_cached_text(raw_cif_text?, cache_dir, options)?;
+        let date_of_extract = summary
+            .headers
+            .first()
+            .and_then(|header| header.date_of_extract.clone());
+        parse_summary.add(&summary);
+        files.push(records::TimetableFile {
+            records,
+            date_of_extract,
+        });
+    }
+    let (records, superseded) = records::merge_overlapping_files(files, operating_date);
+    Ok((records, parse_summary, superseded))
+}
+
+/// The path of the CIF file read from `input_file_dir`: the plain .CIF file, or failing that a
+/// gzipped .CIF.gz or a .zip of CIF files
+pub fn cif_file_path(input_file_dir: &Path) -> PathBuf {
+    let plain = input_file_dir.join("CIF_ALL_FULL_DAILY_toc-full.CIF");
+    [
+        "CIF_ALL_FULL_DAILY_toc-full.CIF.gz",
+        "CIF_ALL_FULL_DAILY_toc-full.zip",
+    ]
+    .map(|file_name| input_file_dir.join(file_name))
+    .into_iter()
+    .find(|path| !plain.exists() && path.exists())
+    .unwrap_or(plain)
+}
+
+/// Build the TIPLOC lookup from `records` and count the hourly departures at each GB station.
+pub fn departures(
+    records: Vec<Record>,
+    gb_station_three_alpha_codes: &[ThreeAlphaCode],
+    day: &Day,
+    context: &GroupingContext,
+) -> (
+    HashMap<Tiploc, ThreeAlphaCode>,
+    HashMap<ThreeAlphaCode, HourlyDepartures>,
+) {
+    let lookup = create_lookup_with_progress(
+        &records,
+        gb_station_three_alpha_codes,
+        context.show_progress,
+    );
+    let departures = group(&records::assemble_journeys(records), &lookup, day, context);
+    (lookup, departures)
+}
@@ -1,47 +1,376 @@
-mod criteria;
-mod hour_grouping;
-mod records;
-mod stops;
-mod utils;
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
 
-use anyhow::Result;
-use clap::Parser;
+use rail_hourly_departures::{
+    Args, CriteriaThresholds, Date, Day, Encoding, ErrorBudget, ErrorRateExceeded, GroupingContext,
+    HourlyDepartures, OutputDirectory, OutputSink, ParseOptions, Record, ThreeAlphaCode, criteria,
+    demo, diff, evaluate_criteria, hour_grouping, log, read_records, records, run, stops, utils,
+};
 
+/// Runs every stage in one go when no subcommand is given
 #[derive(Parser)]
-struct Args {
-    #[clap(long)]
-    input_file_dir: String,
-    #[clap(long, default_value = "tuesday")]
-    operating_day: records::Day,
-    #[clap(long)]
-    output_directory: String,
-    #[clap(long, value_parser = records::parse_date)]
-    operating_week: records::Date,
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    run_all: Option<Args>,
+    /// Only print warnings and errors, without progress bars
+    #[clap(short, long, global = true)]
+    quiet: bool,
+    /// Print extra detail
+    #[clap(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Overwrite existing output files instead of aborting
+    #[clap(long, global = true)]
+    force: bool,
+    /// Never draw progress bars, as under cron. They are also left out when stderr is not a
+    /// terminal.
+    #[clap(long, global = true)]
+    no_progress: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Parse the CIF file and write the records for the later stages
+    Parse {
+        #[clap(long)]
+        input_file_dir: PathBuf,
+        #[clap(long, value_enum, default_value_t = Encoding::Auto)]
+        encoding: Encoding,
+        #[clap(long)]
+        output_directory: PathBuf,
+    },
+    /// Count hourly departures from a records file written by `parse`, or straight from the CIF
+    Departures {
+        #[clap(long, required_unless_present = "input_file_dir")]
+        records_file: Option<PathBuf>,
+        #[clap(long, conflicts_with = "records_file")]
+        input_file_dir: Option<PathBuf>,
+        #[clap(long, default_value = "tuesday")]
+        operating_day: Day,
+        #[clap(long, value_parser = records::parse_date)]
+        operating_week: Date,
+        #[clap(long)]
+        output_directory: PathBuf,
+        #[clap(long = "station-codes-file", default_value = stops::DEFAULT_STATION_CODES_FILE)]
+        station_codes_files: Vec<PathBuf>,
+    },
+    /// Evaluate the criteria over a departures file written by `departures`
+    Criteria {
+        #[clap(long)]
+        departures_file: PathBuf,
+        #[clap(long)]
+        output_directory: PathBuf,
+        /// Departures an hour needs to pass the criteria, at least
+        #[clap(long, default_value_t = criteria::DEFAULT_MIN_DEPARTURES_PER_HOUR)]
+        min_departures_per_hour: u32,
+        /// Journey starts an hour needs to pass the criteria, at least, when there are fewer
+        /// departures than --min-departures-per-hour
+        #[clap(long, default_value_t = criteria::DEFAULT_MIN_JOURNEY_STARTS_PER_HOUR)]
+        min_journey_starts_per_hour: u32,
+        /// Arrivals an hour needs to pass the arrival criteria, at least, when fewer journeys
+        /// end there than --min-journey-starts-per-hour
+        #[clap(long, default_value_t = criteria::DEFAULT_MIN_ARRIVALS_PER_HOUR)]
+        min_arrivals_per_hour: u32,
+        /// Leave out the all_7_7_arrivals and avg_7_7_arrivals criteria
+        #[clap(long)]
+        no_arrival_criteria: bool,
+        /// Departures each hour from 22:00 to 00:59 needs to pass the night_service criterion
+        #[clap(long, default_value_t = criteria::DEFAULT_NIGHT_SERVICE_MIN_DEPARTURES_PER_HOUR)]
+        night_service_min_departures_per_hour: u32,
+        /// The early_start criterion needs a departure from 01:00 up to this hour
+        #[clap(
+            long,
+            default_value_t = criteria::DEFAULT_EARLY_START_BEFORE_HOUR,
+            value_parser = clap::value_parser!(u32).range(1..=24)
+        )]
+        early_start_before_hour: u32,
+        /// Evaluate the criteria over the weighted counts of a departures file written with
+        /// --weights
+        #[clap(long)]
+        weighted_criteria: bool,
+    },
+    /// Check the GB station three alpha code config against a records file or the CIF
+    ValidateConfig {
+        #[clap(long, required_unless_present = "input_file_dir")]
+        records_file: Option<PathBuf>,
+        #[clap(long, conflicts_with = "records_file")]
+        input_file_dir: Option<PathBuf>,
+        #[clap(long = "station-codes-file", default_value = stops::DEFAULT_STATION_CODES_FILE)]
+        station_codes_files: Vec<PathBuf>,
+    },
+    /// Compare two departures (or criteria results) files and report the stations that changed
+    Diff {
+        old_file: PathBuf,
+        new_file: PathBuf,
+        #[clap(long)]
+        output_directory: PathBuf,
+        /// Hide changed stations where no hour, nor the daily total, moved by this many
+        /// departures, or with --operator-origins operators whose journeys moved by fewer
+        #[clap(long, default_value_t = 1)]
+        threshold: u32,
+        /// Compare two operator_origins.json files and report the operators that changed
+        #[clap(long)]
+        operator_origins: bool,
+    },
+    /// Sum the departures of several departures files into one
+    Merge {
+        #[clap(required = true, num_args = 2..)]
+        departures_files: Vec<PathBuf>,
+        #[clap(long)]
+        output_directory: PathBuf,
+    },
+    /// Run every stage, the same as giving no subcommand
+    RunAll(Box<Args>),
+}
+
+const RECORDS_FILE_NAME: &str = "cif_records";
+
+/// Written to parse_errors.json when a run stops at --max-error-rate
+#[derive(Serialize)]
+struct ParseErrors<'a> {
+    #[serde(flatten)]
+    error_budget: Option<ErrorBudget>,
+    total_lines: usize,
+    malformed: usize,
+    first_malformed: &'a Option<String>,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    
-    let raw_cif_text = records::read_file(&format!(
-        "{}/{}.CIF",
-        &args.input_file_dir, "CIF_ALL_FULL_DAILY_toc-full"
-    ));
-
-    let record_lines = records::parse(raw_cif_text);
-    println!("Records len: {:?}", record_lines.len());
-
-    let gb_station_three_alpha_codes: Vec<records::ThreeAlphaCode> = utils::read_json_file(
-        "config/gb_station_three_alpha_codes.json".to_string(),
-    )?;
-    let lookup = stops::create_lookup(&record_lines, &gb_station_three_alpha_codes);
-
-    let hourly_departures =
-        hour_grouping::group(record_lines, &lookup, &args.operating_day, &args.operating_week);
-    let criteria_results = criteria::evaluate_criteria(&hourly_departures);
-    utils::write_json_file(
-        format!("rail_hourly_departures_{}_{}", args.operating_day.to_string(), args.operating_week.to_string()),
-        &args.output_directory,
-        &criteria_results,
-    )?;
+    let cli = Cli::parse();
+    log::set_level(log::level_from_flags(cli.quiet, cli.verbose));
+    let overwrite = cli.force;
+    let show_progress = !cli.no_progress;
+    let parse_options = ParseOptions {
+        show_progress,
+        ..ParseOptions::default()
+    };
+
+    match cli.command {
+        Some(Command::Parse {
+            input_file_dir,
+            encoding,
+            output_directory,
+        }) => {
+            let (records, _) = read_records(&input_file_dir, None, encoding, parse_options)?;
+            OutputDirectory::new(output_directory, overwrite)
+                .write_json(RECORDS_FILE_NAME, &records)?;
+        }
+        Some(Command::Departures {
+            records_file,
+            input_file_dir,
+            operating_day,
+            operating_week,
+            output_directory,
+            station_codes_files,
+        }) => {
+            let records = load_records(records_file, input_file_dir, parse_options)?;
+            let mut context = GroupingContext::for_week(operating_week.clone());
+            context.show_progress = show_progress;
+            let (_, departures) = rail_hourly_departures::departures(
+                records,
+                &stops::read_station_codes(&station_codes_files)?,
+                &operating_day,
+                &context,
+            );
+            utils::write_versioned_json_file(
+                &format!("hourly_departures_{operating_day}_{operating_week}"),
+                &mut OutputDirectory::new(output_directory, overwrite),
+                &departures,
+                false,
+            )?;
+        }
+        Some(Command::Criteria {
+            departures_file,
+            output_directory,
+            min_departures_per_hour,
+            min_journey_starts_per_hour,
+            min_arrivals_per_hour,
+            no_arrival_criteria,
+            night_service_min_departures_per_hour,
+            early_start_before_hour,
+            weighted_criteria,
+        }) => {
+            let criteria_thresholds = CriteriaThresholds {
+                min_departures_per_hour,
+                min_journey_starts_per_hour,
+                min_arrivals_per_hour: (!no_arrival_criteria).then_some(min_arrivals_per_hour),
+                night_service_min_departures_per_hour,
+                early_start_before_hour,
+                weighted_counts: weighted_criteria,
+            };
+            let (departures, hour_window): (HashMap<ThreeAlphaCode, HourlyDepartures>, _) =
+                utils::read_versioned_json_file_with_window(&departures_file)?;
+            if let Some(window) = &hour_window {
+                rail_hourly_departures::check_criteria_window(window, &criteria_thresholds)?;
+            }
+            utils::write_criteria_json_file(
+                "criteria_results",
+                &mut OutputDirectory::new(output_directory, overwrite),
+                evaluate_criteria(&departures, &criteria_thresholds),
+                false,
+                Some(criteria_thresholds),
+                &[],
+                hour_window,
+            )?;
+        }
+        Some(Command::ValidateConfig {
+            records_file,
+            input_file_dir,
+            station_codes_files,
+        }) => {
+            let records = load_records(records_file, input_file_dir, parse_options)?;
+            let mut problem_count = 0;
+            for path in station_codes_files.iter() {
+                let codes = stops::read_station_codes_file(path)?;
+                for problem in stops::validate_station_codes(&codes, &records) {
+                    println!("{} {problem}", path.display());
+                    problem_count += 1;
+                }
+            }
+            if problem_count > 0 {
+                bail!("{problem_count} problems found in the station config");
+            }
+            println!("No problems found in the station config");
+        }
+        Some(Command::Diff {
+            old_file,
+            new_file,
+            output_directory,
+            threshold,
+            operator_origins: true,
+        }) => {
+            let diffs = diff::diff_operator_origins(
+                &utils::read_json_file(&old_file)?,
+                &utils::read_json_file(&new_file)?,
+                threshold,
+            );
+            print!("{}", diff::format_operator_table(&diffs));
+            OutputDirectory::new(output_directory, overwrite)
+                .write_json("operator_origins_diff", &diffs)?;
+        }
+        Some(Command::Diff {
+            old_file,
+            new_file,
+            output_directory,
+            threshold,
+            operator_origins: false,
+        }) => {
+            let diffs = diff::diff_departures(
+                &utils::read_versioned_json_file(&old_file)?,
+                &utils::read_versioned_json_file(&new_file)?,
+                threshold,
+            );
+            print!("{}", diff::format_table(&diffs));
+            OutputDirectory::new(output_directory, overwrite)
+                .write_json("departures_diff", &diffs)?;
+        }
+        Some(Command::Merge {
+            departures_files,
+            output_directory,
+        }) => {
+            let mut all_departures: Vec<HashMap<ThreeAlphaCode, HourlyDepartures>> = Vec::new();
+            for path in departures_files {
+                // The arrays only deserialize with exactly one bin per hour, so files with a
+                // different bin size are rejected here
+                let departures = utils::read_versioned_json_file(&path).with_context(|| {
+                    format!(
+                        "{} is not a departures file with 24 hourly bins",
+                        path.display()
+                    )
+                })?;
+                all_departures.push(departures);
+            }
+            utils::write_versioned_json_file(
+                "merged_hourly_departures",
+                &mut OutputDirectory::new(output_directory, overwrite),
+                hour_grouping::merge_departures(all_departures),
+                false,
+            )?;
+        }
+        Some(Command::RunAll(args)) => run_all_or_demo(*args, overwrite, show_progress)?,
+        None => run_all_or_demo(
+            cli.run_all.expect("clap requires the run arguments"),
+            overwrite,
+            show_progress,
+        )?,
+    }
     Ok(())
 }
+
+/// [`run_all`], first writing out the built in input for --demo. `overwrite` and
+/// `show_progress` come from the global --force and --no-progress.
+fn run_all_or_demo(mut args: Args, overwrite: bool, show_progress: bool) -> Result<()> {
+    args.overwrite = overwrite;
+    args.show_progress = show_progress;
+    if !args.demo {
+        return run_all(&args);
+    }
+    let input_file_dir = demo::use_demo_input(&mut args)?;
+    let result = run_all(&args);
+    fs_err::remove_dir_all(input_file_dir)?;
+    result
+}
+
+fn load_records(
+    records_file: Option<PathBuf>,
+    input_file_dir: Option<PathBuf>,
+    parse_options: ParseOptions,
+) -> Result<Vec<Record>> {
+    match (records_file, input_file_dir) {
+        (Some(records_file), _) => utils::read_json_file(&records_file),
+        (None, Some(input_file_dir)) => {
+            Ok(read_records(&input_file_dir, None, Encoding::Auto, parse_options)?.0)
+        }
+        (None, None) => unreachable!("clap requires one of the inputs"),
+    }
+}
+
+fn run_all(args: &Args) -> Result<()> {
+    let mut output_directory = OutputDirectory::new(args.output_directory.clone(), args.overwrite);
+    // Also checked by write_to, but before the run a bad combination stops it straight away
+    args.check_output_sink(&output_directory)?;
+    let mut outputs =
+        run(args).map_err(|error| write_parse_errors(args, &mut output_directory, error))?;
+    outputs.write_to(args, &mut output_directory)
+}
+
+/// When `error` is from a parse over --max-error-rate, write parse_errors.json and the manifest
+/// before stopping, and point the error at them
+fn write_parse_errors(
+    args: &Args,
+    output_directory: &mut OutputDirectory,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    let Some(exceeded) = error.downcast_ref::<ErrorRateExceeded>() else {
+        return error;
+    };
+    if output_directory.is_stdout() {
+        return error;
+    }
+    let summary = &exceeded.parse_summary;
+    let written = output_directory
+        .write_json(
+            "parse_errors",
+            &ParseErrors {
+                error_budget: exceeded.manifest.error_budget,
+                total_lines: summary.total_lines(),
+                malformed: summary.malformed,
+                first_malformed: &summary.first_malformed,
+            },
+        )
+        .and_then(|()| output_directory.write_json("manifest", &exceeded.manifest));
+    match written {
+        Ok(()) => error.context(format!(
+            "Too many malformed lines, see {}",
+            args.output_directory.join("parse_errors.json").display()
+        )),
+        Err(write_error) => {
+            error.context(format!("Failed to write parse_errors.json: {write_error}"))
+        }
+    }
+}
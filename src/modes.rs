@@ -0,0 +1,122 @@
+//! The modes of transport of the journeys. A journey's mode comes from the train status of its
+//! BS record, see [`JourneyHeader::mode`], and a TOML file of aliases can give the mode of
+//! statuses the built in list doesn't know, so new ones don't need a release.
+
+use anyhow::{Result, bail};
+#[cfg(feature = "fs")]
+use serde::Deserialize;
+use std::collections::HashMap;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+#[cfg(doc)]
+use super::records::JourneyHeader;
+#[cfg(feature = "fs")]
+use super::utils;
+
+/// The modes a train status can be an alias of
+pub const MODES: [&str; 4] = ["train", "bus", "ship", "freight"];
+
+/// The mode of a train status neither the aliases nor the built in list know
+pub const UNKNOWN_MODE: &str = "unknown";
+
+/// The mode of each aliased train status
+pub type ModeAliases = HashMap<char, &'static str>;
+
+/// A TOML mode aliases file, such as `[train_status_aliases]` then `6 = "bus"`
+#[cfg(feature = "fs")]
+#[derive(Deserialize)]
+struct ModeAliasesToml {
+    train_status_aliases: HashMap<String, String>,
+}
+
+/// Read the train status aliases from a TOML `[train_status_aliases]` table, checking each
+/// status is one character and each mode is one of [`MODES`]
+#[cfg(feature = "fs")]
+pub fn read_mode_aliases(path: &Path) -> Result<ModeAliases> {
+    let aliases: ModeAliasesToml = utils::read_toml_file(path)?;
+    parse_mode_aliases(aliases.train_status_aliases)
+        .map_err(|e| e.context(format!("Invalid train status alias in {}", path.display())))
+}
+
+/// The modes of the train statuses `aliases`, keyed by status, as read from an aliases file
+pub fn parse_mode_aliases(aliases: HashMap<String, String>) -> Result<ModeAliases> {
+    aliases
+        .into_iter()
+        .map(|(status, mode)| {
+            let mut chars = status.chars();
+            let (Some(train_status), None) = (chars.next(), chars.next()) else {
+                bail!("{status:?} is not a one character train status");
+            };
+            let Some(mode) = MODES.into_iter().find(|known| *known == mode) else {
+                bail!(
+                    "{mode:?} for train status {status:?} is not one of {}",
+                    MODES.join(", ")
+                );
+            };
+            Ok((train_status, mode))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hour_grouping::{GroupingContext, group_days_with_stats};
+    use crate::records::{Date, Day, DaySelector, ThreeAlphaCode, assemble_journeys, parse};
+    use crate::stops::create_lookup;
+    use crate::test_cif;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_mode_aliases() {
+        let aliases = parse_mode_aliases(HashMap::from([
+            ("6".to_string(), "bus".to_string()),
+            ("P".to_string(), "ship".to_string()),
+        ]))
+        .unwrap();
+        let cif = test_cif::with_stations([
+            "BSNC123452601012612311111100 6OO",
+            "LOKNGX    2345 2345",
+            "LTCAMBDGE 0030H0030",
+            "BSNC123462601012612311111100 POO",
+            "LOKNGX    2345 2345",
+            "LTCAMBDGE 0030H0030",
+            "BSNC123472601012612311111100 7OO",
+            "LOKNGX    2345 2345",
+            "LTCAMBDGE 0030H0030",
+        ]);
+        let records = parse(cif);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let modes: Vec<(&str, &str)> = journeys
+            .iter()
+            .map(|journey| (journey.header.mode(), journey.header.mode_with(&aliases)))
+            .collect();
+        // The aliases come before the built in list
+        assert_eq!(
+            modes,
+            [
+                (UNKNOWN_MODE, "bus"),
+                ("train", "ship"),
+                (UNKNOWN_MODE, UNKNOWN_MODE)
+            ]
+        );
+        let mut context = GroupingContext::for_week(Date(260113));
+        context.mode_aliases = aliases;
+        let selectors = [DaySelector::Day(Day::Tuesday)];
+        let (_, stats) = group_days_with_stats(&journeys, &lookup, &selectors, &context);
+        assert_eq!(stats.unknown_train_statuses, BTreeSet::from(['7']));
+
+        let error =
+            parse_mode_aliases(HashMap::from([("6".to_string(), "tram".to_string())])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "\"tram\" for train status \"6\" is not one of train, bus, ship, freight"
+        );
+        assert!(
+            parse_mode_aliases(HashMap::from([("66".to_string(), "bus".to_string())])).is_err()
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Names for the two letter operator codes of the BX records, which mean little to readers of
+//! the outputs, the list of the operators seen in a timetable, and the stations each operator's
+//! journeys start from, to compare one quarter's timetable with the last.
+
+#[cfg(feature = "fs")]
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+use super::hour_grouping::{GroupingContext, trip_rejection, trip_stops};
+use super::modes::ModeAliases;
+use super::records::{DaySelector, Journey, ThreeAlphaCode, Tiploc};
+#[cfg(feature = "fs")]
+use super::utils;
+
+/// The operator names file read when --operator-names isn't given, if it exists
+pub const DEFAULT_OPERATOR_NAMES_FILE: &str = "config/operators.toml";
+
+/// The key of the journeys without a BX record in operator_origins.json, which no two letter
+/// operator code can clash with
+pub const NO_OPERATOR: &str = "none";
+
+/// The journeys an operator ran on a day, as listed in operator_origins.json
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OperatorOrigins {
+    pub journeys: usize,
+    /// How many of the journeys start from each GB station
+    pub origins: BTreeMap<ThreeAlphaCode, usize>,
+}
+
+/// A TOML operator names file, such as `[operators]` then `GR = "LNER"`
+#[cfg(feature = "fs")]
+#[derive(Deserialize)]
+struct OperatorNamesToml {
+    operators: HashMap<String, String>,
+}
+
+/// Read the names of the operators from a TOML `[operators]` table
+#[cfg(feature = "fs")]
+pub fn read_operator_names(path: &Path) -> Result<HashMap<String, String>> {
+    let operator_names: OperatorNamesToml = utils::read_toml_file(path)?;
+    Ok(operator_names.operators)
+}
+
+/// An operator as listed in operators_seen.json
+#[derive(Debug, PartialEq, Serialize)]
+pub struct OperatorSeen {
+    /// None for the journeys without a BX record
+    pub code: Option<String>,
+    pub name: Option<String>,
+    pub journeys: usize,
+    /// Such as "train" and "bus", see [`JourneyHeader::mode`](super::records::JourneyHeader::mode)
+    pub modes: BTreeSet<&'static str>,
+}
+
+/// Every operator of `journeys` with its name, if `operator_names` has one, how many journeys
+/// it runs and their modes, with `mode_aliases`, in code order with the journeys without an
+/// operator last
+pub fn operators_seen(
+    journeys: &[Journey],
+    operator_names: &HashMap<String, String>,
+    mode_aliases: &ModeAliases,
+) -> Vec<OperatorSeen> {
+    let mut by_code: BTreeMap<Option<&str>, (usize, BTreeSet<&'static str>)> = BTreeMap::new();
+    for journey in journeys {
+        let (count, modes) = by_code.entry(journey.atoc_code.as_deref()).or_default();
+        *count += 1;
+        modes.insert(journey.header.mode_with(mode_aliases));
+    }
+    let mut operators: Vec<OperatorSeen> = by_code
+        .into_iter()
+        .map(|(code, (journeys, modes))| OperatorSeen {
+            code: code.map(str::to_string),
+            name: code.and_then(|code| operator_names.get(code).cloned()),
+            journeys,
+            modes,
+        })
+        .collect();
+    // None sorts first in the map
+    operators.sort_by_key(|operator| operator.code.is_none());
+    operators
+}
+
+/// How many of the journeys counted on `selector` each operator runs, and how many start from
+/// each station, keyed by operator code with [`NO_OPERATOR`] for the journeys without one. The
+/// origin is the first GB station a journey calls at. Only the counts are kept, not the
+/// journeys.
+pub fn operator_origins(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    selector: &DaySelector,
+    context: &GroupingContext,
+) -> BTreeMap<String, OperatorOrigins> {
+    let selectors = std::slice::from_ref(selector);
+    let mut by_operator: BTreeMap<String, OperatorOrigins> = BTreeMap::new();
+    for journey in journeys {
+        let trip_stops = trip_stops(journey, lookup, context);
+        if trip_rejection(journey, &trip_stops, selectors, context).is_some() {
+            continue;
+        }
+        let Some(origin) = trip_stops.into_iter().find(|stop| stop.calls()) else {
+            continue;
+        };
+        let operator = by_operator
+            .entry(
+                journey
+                    .atoc_code
+                    .as_deref()
+                    .unwrap_or(NO_OPERATOR)
+                    .to_string(),
+            )
+            .or_default();
+        operator.journeys += 1;
+        *operator.origins.entry(origin.three_alpha_code).or_default() += 1;
+    }
+    by_operator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{Date, Day, assemble_journeys, parse};
+    use crate::stops::create_lookup;
+    use crate::test_cif;
+
+    #[test]
+    fn test_operators_seen() {
+        let cif = test_cif::cif([
+            "BSNC123452601012612311111100 POO",
+            "BX         GRY",
+            "LOKNGX    2345 2345",
+            "LTCAMBDGE 0030H0030",
+            "BSNC123462601012612311111100 BOO",
+            "BX         GRY",
+            "LOKNGX    2345 2345",
+            "LTCAMBDGE 0030H0030",
+            "BSNC123472601012612311111100 POO",
+            "BX         XXY",
+            "LOKNGX    2345 2345",
+            "LTCAMBDGE 0030H0030",
+            "BSNC123482601012612311111100 POO",
+            "LOKNGX    2345 2345",
+            "LTCAMBDGE 0030H0030",
+        ]);
+        let journeys = assemble_journeys(parse(cif));
+        let operator_names = HashMap::from([("GR".to_string(), "LNER".to_string())]);
+
+        assert_eq!(
+            operators_seen(&journeys, &operator_names, &ModeAliases::new()),
+            [
+                OperatorSeen {
+                    code: Some("GR".to_string()),
+                    name: Some("LNER".to_string()),
+                    journeys: 2,
+                    modes: BTreeSet::from(["bus", "train"]),
+                },
+                OperatorSeen {
+                    code: Some("XX".to_string()),
+                    name: None,
+                    journeys: 1,
+                    modes: BTreeSet::from(["train"]),
+                },
+                OperatorSeen {
+                    code: None,
+                    name: None,
+                    journeys: 1,
+                    modes: BTreeSet::from(["train"]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_operator_origins() {
+        let cif = test_cif::with_stations([
+            "BSNC123452601012612311111100 POO",
+            "BX         GRY",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+            "BSNC123462601012612311111100 POO",
+            "BX         GRY",
+            "LOCAMBDGE 1000 1000",
+            "LTKNGX    1100 1100",
+            "BSNC123472601012612311111100 POO",
+            "BX         GRY",
+            "LOKNGX    1200 1200",
+            "LTCAMBDGE 1300 1300",
+            "BSNC123482601012612311111100 POO",
+            "LOKNGX    1200 1200",
+            "LTCAMBDGE 1300 1300",
+            // Weekends only, so not counted on a Tuesday
+            "BSNC123492601012612310000011 POO",
+            "BX         XXY",
+            "LOKNGX    1200 1200",
+            "LTCAMBDGE 1300 1300",
+        ]);
+        let records = parse(cif);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let context = GroupingContext::for_week(Date(260113));
+
+        let origins = operator_origins(
+            &journeys,
+            &lookup,
+            &DaySelector::Day(Day::Tuesday),
+            &context,
+        );
+        assert_eq!(
+            origins,
+            BTreeMap::from([
+                (
+                    "GR".to_string(),
+                    OperatorOrigins {
+                        journeys: 3,
+                        origins: BTreeMap::from([(codes[0].clone(), 2), (codes[1].clone(), 1)]),
+                    }
+                ),
+                (
+                    NO_OPERATOR.to_string(),
+                    OperatorOrigins {
+                        journeys: 1,
+                        origins: BTreeMap::from([(codes[0].clone(), 1)]),
+                    }
+                ),
+            ])
+        );
+    }
+}
@@ -0,0 +1,669 @@
+//! Writing the [`Outputs`] of a [`run`](super::run) to an [`OutputSink`], as the binary does to
+//! its output directory, so a program embedding the pipeline writes the same files without
+//! running the binary.
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::time::Instant;
+
+use super::hour_grouping::HoursAsObject;
+use super::storage::OutputSink;
+use super::utils::{self, Source};
+use super::{
+    Args, CriteriaThresholds, DayChoice, DayOutputs, DaySelector, GroupingStats, HourlyDepartures,
+    NetworkProfile, OutputFormat, OutputSelection, Outputs, ParseSummary, StationListCounts,
+    StationName, ThreeAlphaCode, areas, gtfs_export, info, operators, records, report, routes,
+    sqlite_export, stats, stops, warn,
+};
+
+/// A line of a JSON Lines departures or criteria file: the station's output with its name, and
+/// its day when the days share a file
+#[derive(Serialize)]
+struct StationLine<'a, T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    day: Option<&'a DaySelector>,
+    name: Option<&'a str>,
+    #[serde(flatten)]
+    output: &'a T,
+}
+
+/// A day's output as written to a JSON file, keyed by three alpha code in code order so the
+/// files of the same input are identical: as counted, or with each station's name embedded
+/// under --embed-names
+#[derive(Serialize)]
+#[serde(untagged)]
+enum DayFile<'a, T> {
+    Plain(BTreeMap<&'a ThreeAlphaCode, &'a T>),
+    Named(BTreeMap<&'a ThreeAlphaCode, StationLine<'a, T>>),
+}
+
+impl<'a, T> DayFile<'a, T> {
+    fn new(
+        day_output: &'a HashMap<ThreeAlphaCode, T>,
+        station_names: &'a HashMap<ThreeAlphaCode, StationName>,
+        embed_names: bool,
+        keep: &dyn Fn(&ThreeAlphaCode) -> bool,
+    ) -> Self {
+        let kept = day_output
+            .iter()
+            .filter(|(three_alpha_code, _)| keep(three_alpha_code));
+        if !embed_names {
+            return DayFile::Plain(kept.collect());
+        }
+        DayFile::Named(
+            kept.map(|(three_alpha_code, output)| {
+                let line = StationLine {
+                    day: None,
+                    name: station_names
+                        .get(three_alpha_code)
+                        .map(|station_name| station_name.name.as_str()),
+                    output,
+                };
+                (three_alpha_code, line)
+            })
+            .collect(),
+        )
+    }
+}
+
+/// A line of a JSON Lines station name lookup
+#[derive(Serialize)]
+struct LookupLine<'a> {
+    three_alpha_code: &'a ThreeAlphaCode,
+    #[serde(flatten)]
+    station_name: &'a StationName,
+}
+
+/// Written to run_report.json so a run can be sanity checked without rerunning it
+#[derive(Serialize)]
+struct RunReport<'a> {
+    parse: &'a ParseSummary,
+    grouping: &'a GroupingStats,
+    station_lists: &'a StationListCounts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unattributed: Option<&'a stops::Unattributed>,
+}
+
+/// A sink writing the JSON outputs of another with their hourly counts as objects, for
+/// --hours-as-object
+struct HoursAsObjectSink<'a, S>(&'a mut S);
+
+impl<S: OutputSink> OutputSink for HoursAsObjectSink<'_, S> {
+    fn write_output(&mut self, file_name: &str, bytes: &[u8]) -> Result<()> {
+        self.0.write_output(file_name, bytes)
+    }
+
+    fn write_with<F>(&mut self, file_name: &str, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        self.0.write_with(file_name, write)
+    }
+
+    fn write_json<T: Serialize>(&mut self, file_name: &str, data: &T) -> Result<()> {
+        self.0.write_json(file_name, &HoursAsObject(data))
+    }
+
+    fn write_jsonl<T: Serialize>(
+        &mut self,
+        file_name: &str,
+        rows: impl IntoIterator<Item = T>,
+    ) -> Result<()> {
+        self.0
+            .write_jsonl(file_name, rows.into_iter().map(HoursAsObject))
+    }
+
+    fn is_stdout(&self) -> bool {
+        self.0.is_stdout()
+    }
+
+    fn existing_outputs(&self) -> Result<Vec<String>> {
+        self.0.existing_outputs()
+    }
+}
+
+impl Outputs {
+    /// Write the outputs to `sink` as the binary does, or only the --only output when the sink
+    /// is stdout. With --strict-names or --fail-on-anomalies this fails after writing when
+    /// there are stations without a name or anomalies against the baseline.
+    pub fn write_to(&mut self, args: &Args, sink: &mut impl OutputSink) -> Result<()> {
+        if args.hours_as_object {
+            return self.write_outputs(args, &mut HoursAsObjectSink(sink));
+        }
+        self.write_outputs(args, sink)
+    }
+
+    fn write_outputs(&mut self, args: &Args, sink: &mut impl OutputSink) -> Result<()> {
+        args.check_output_sink(sink)?;
+        if !sink.is_stdout() {
+            warn_of_stale_outputs(args, sink)?;
+        }
+        if let Some(path) = &args.dump_rejected {
+            #[cfg(feature = "fs")]
+            utils::write_jsonl_path(path, &self.rejected_trips, args.overwrite)?;
+            #[cfg(not(feature = "fs"))]
+            bail!(
+                "--dump-rejected writes {}, which needs the fs feature",
+                path.display()
+            );
+        }
+        let all_departures: Vec<&HashMap<ThreeAlphaCode, HourlyDepartures>> = self
+            .days
+            .iter()
+            .map(|day_outputs| &day_outputs.departures)
+            .collect();
+        let unnamed_stations = stops::unnamed_stations(&all_departures, &self.station_names);
+        info!(
+            "Stations with departures but no name: {}",
+            unnamed_stations.len()
+        );
+        let top_stations: BTreeMap<&DaySelector, Vec<stops::TopStation>> = self
+            .days
+            .iter()
+            .map(|day_outputs| {
+                (
+                    &day_outputs.selector,
+                    stops::top_stations(&day_outputs.departures, &self.station_names, args.top_n),
+                )
+            })
+            .collect();
+        for (day, stations) in &top_stations {
+            info!("Busiest stations on {day}:");
+            for station in stations {
+                info!(
+                    "  {} {}: {} departures, peak {} at {:02}:00",
+                    station.three_alpha_code.0,
+                    station.name.as_deref().unwrap_or("(unnamed)"),
+                    station.total_departures,
+                    station.peak_hour_departures,
+                    station.peak_hour
+                );
+            }
+        }
+        if sink.is_stdout() {
+            // Only one JSON document goes to stdout, so several days are always nested
+            match args.only {
+                OutputSelection::Departures => {
+                    write_days(args, self, sink, None, true, None, |day_outputs| {
+                        &day_outputs.departures
+                    })?
+                }
+                OutputSelection::Criteria => write_days(
+                    args,
+                    self,
+                    sink,
+                    None,
+                    true,
+                    Some(args.criteria_thresholds()),
+                    |day_outputs| &day_outputs.criteria_results,
+                )?,
+                OutputSelection::Lookup if args.format == OutputFormat::Jsonl => {
+                    let mut lookup: Vec<(&ThreeAlphaCode, &StationName)> =
+                        self.station_names.iter().collect();
+                    lookup.sort_by_key(|(three_alpha_code, _)| *three_alpha_code);
+                    sink.write_jsonl(
+                        &args.lookup_filename,
+                        lookup
+                            .into_iter()
+                            .map(|(three_alpha_code, station_name)| LookupLine {
+                                three_alpha_code,
+                                station_name,
+                            }),
+                    )?
+                }
+                OutputSelection::Lookup => {
+                    sink.write_json(&args.lookup_filename, &sorted(&self.station_names))?
+                }
+            }
+        } else {
+            let write_start = Instant::now();
+            if let Some(numbered_records) = &self.numbered_records {
+                sink.write_jsonl(records::NUMBERED_RECORDS_FILE_NAME, numbered_records)?;
+            }
+            let nested = args.nested_days && args.day_choice() == DayChoice::All;
+            let station_areas = args.split_by_area.then_some(&self.station_areas);
+            match args.format {
+                _ if !args.counts_departures() => {}
+                OutputFormat::Json | OutputFormat::Jsonl
+                    if args.wants(OutputSelection::Criteria) =>
+                {
+                    write_days(
+                        args,
+                        self,
+                        sink,
+                        station_areas,
+                        nested,
+                        Some(args.criteria_thresholds()),
+                        |day_outputs| &day_outputs.criteria_results,
+                    )?
+                }
+                OutputFormat::Json | OutputFormat::Jsonl => write_days(
+                    args,
+                    self,
+                    sink,
+                    station_areas,
+                    nested,
+                    None,
+                    |day_outputs| &day_outputs.departures,
+                )?,
+                OutputFormat::Gtfs => gtfs_export::write_zip(
+                    &gtfs_export::build_feed(
+                        &self.journeys,
+                        &self.lookup,
+                        &self.station_names,
+                        &self.operator_names,
+                    ),
+                    sink,
+                )?,
+                OutputFormat::Sqlite => sqlite_export::write_database(
+                    &sqlite_export::build_sql(&self.days, &self.station_names),
+                    sink,
+                )?,
+            }
+            sink.write_json(
+                "run_report",
+                &RunReport {
+                    parse: &self.parse_summary,
+                    grouping: &self.grouping_stats,
+                    station_lists: &self.station_list_counts,
+                    unattributed: self.unattributed.as_ref(),
+                },
+            )?;
+            if args.wants(OutputSelection::Lookup) {
+                for area in file_areas(station_areas, self.station_names.keys()) {
+                    let (file_name, lookup) = match area {
+                        None => (args.lookup_filename.clone(), sorted(&self.station_names)),
+                        Some(area) => (
+                            format!("{}_{area}", args.lookup_filename),
+                            sorted(&self.station_names)
+                                .into_iter()
+                                .filter(|(three_alpha_code, _)| {
+                                    areas::area_of(three_alpha_code, &self.station_areas) == area
+                                })
+                                .collect(),
+                        ),
+                    };
+                    sink.write_json(&file_name, &lookup)?;
+                }
+                if args.naptan_csv.is_some() {
+                    sink.write_json(
+                        "stops_not_in_naptan",
+                        &stops::stations_not_in_naptan(&self.station_names),
+                    )?;
+                    sink.write_json("location_conflicts", &self.location_conflicts)?;
+                }
+                sink.write_json("name_conflicts", &self.name_conflicts)?;
+            }
+            if args.counts_departures() {
+                sink.write_json("unnamed_stops", &unnamed_stations)?;
+                sink.write_json("stops_without_records", &self.stops_without_records)?;
+                sink.write_json(
+                    "tiploc_lookup",
+                    &stops::station_tiplocs(&self.lookup, &all_departures),
+                )?;
+                sink.write_json("top_stations", &top_stations)?;
+                if args.wants(OutputSelection::Criteria) {
+                    let criteria_by_area: BTreeMap<&DaySelector, Vec<areas::AreaCriteria>> = self
+                        .days
+                        .iter()
+                        .map(|day_outputs| {
+                            (
+                                &day_outputs.selector,
+                                areas::criteria_by_area(
+                                    &day_outputs.criteria_results,
+                                    &self.station_areas,
+                                    &self.area_names,
+                                ),
+                            )
+                        })
+                        .collect();
+                    for (day, areas) in &criteria_by_area {
+                        info!(
+                            "Criteria by area on {day}:\n{}",
+                            areas::format_table(areas).trim_end()
+                        );
+                    }
+                    sink.write_json("criteria_by_area", &criteria_by_area)?;
+                }
+                let station_stats: BTreeMap<&DaySelector, _> = self
+                    .days
+                    .iter()
+                    .map(|day_outputs| {
+                        (
+                            &day_outputs.selector,
+                            stats::station_stats(&day_outputs.departures, args.regularity_stats),
+                        )
+                    })
+                    .collect();
+                sink.write_json("station_stats", &station_stats)?;
+                if let Some(anomalies) = &self.anomalies {
+                    sink.write_json("anomalies", anomalies)?;
+                }
+                let context = args.grouping_context();
+                let routes_summary: BTreeMap<&DaySelector, Vec<routes::RouteSummary>> = self
+                    .days
+                    .iter()
+                    .map(|day_outputs| {
+                        (
+                            &day_outputs.selector,
+                            routes::route_summaries(
+                                &self.journeys,
+                                &self.lookup,
+                                &self.station_names,
+                                &day_outputs.selector,
+                                &context,
+                            ),
+                        )
+                    })
+                    .collect();
+                sink.write_json("routes_summary", &routes_summary)?;
+                let network_profile: BTreeMap<&DaySelector, &NetworkProfile> =
+                    self.network_profiles.iter().collect();
+                sink.write_json("network_profile", &network_profile)?;
+                sink.write_json(
+                    "operators_seen",
+                    &operators::operators_seen(
+                        &self.journeys,
+                        &self.operator_names,
+                        &self.mode_aliases,
+                    ),
+                )?;
+                let operator_origins: BTreeMap<&DaySelector, _> = self
+                    .days
+                    .iter()
+                    .map(|day_outputs| {
+                        let origins = operators::operator_origins(
+                            &self.journeys,
+                            &self.lookup,
+                            &day_outputs.selector,
+                            &context,
+                        );
+                        (&day_outputs.selector, origins)
+                    })
+                    .collect();
+                sink.write_json("operator_origins", &operator_origins)?;
+                sink.write_json("journey_warnings", &self.journey_warnings)?;
+                if args.detailed_departures {
+                    let departure_times: BTreeMap<&DaySelector, BTreeMap<&ThreeAlphaCode, _>> =
+                        self.days
+                            .iter()
+                            .map(|day_outputs| {
+                                let stations = day_outputs
+                                    .departures
+                                    .iter()
+                                    .map(|(three_alpha_code, departures)| {
+                                        (
+                                            three_alpha_code,
+                                            departures
+                                                .sorted_departure_times(args.departure_time_format),
+                                        )
+                                    })
+                                    .collect();
+                                (&day_outputs.selector, stations)
+                            })
+                            .collect();
+                    sink.write_json("rail_departure_times", &departure_times)?;
+                }
+                if args.by_name {
+                    let by_name: BTreeMap<&DaySelector, _> = self
+                        .days
+                        .iter()
+                        .map(|day_outputs| {
+                            (
+                                &day_outputs.selector,
+                                stops::departures_by_name(
+                                    &day_outputs.departures,
+                                    &day_outputs.criteria_results,
+                                    &self.station_names,
+                                ),
+                            )
+                        })
+                        .collect();
+                    sink.write_json("departures_by_name", &by_name)?;
+                }
+                if args.dump_journeys {
+                    sink.write_json("journeys", &self.journeys)?;
+                    sink.write_json("rejected_journeys", &self.rejected_journeys)?;
+                }
+                if args.report && args.wants(OutputSelection::Criteria) {
+                    sink.write_output(
+                        report::REPORT_FILE_NAME,
+                        report::render(&self.manifest, &self.days, &self.station_names).as_bytes(),
+                    )?;
+                }
+            }
+            if args.split_by_area {
+                sink.write_json(
+                    "index",
+                    &areas::area_index(
+                        self.station_names.keys().chain(
+                            self.days
+                                .iter()
+                                .flat_map(|day_outputs| day_outputs.departures.keys()),
+                        ),
+                        &self.station_areas,
+                        &self.area_names,
+                    ),
+                )?;
+            }
+            self.manifest.record_stage("write", write_start);
+            sink.write_json("manifest", &self.manifest)?;
+        }
+
+        info!("Stage times: {}", self.manifest.format_stage_seconds());
+
+        if args.strict_names && !unnamed_stations.is_empty() {
+            bail!(
+                "{} stations have no name, see unnamed_stops.json",
+                unnamed_stations.len()
+            );
+        }
+        let anomaly_count = self.anomaly_count();
+        if args.fail_on_anomalies && anomaly_count > 0 {
+            bail!(
+                "{anomaly_count} stations are anomalies against the baseline, see anomalies.json"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Warn about files left in `sink` by an earlier run for outputs that --outputs now skips, as
+/// they won't match the files written alongside them
+fn warn_of_stale_outputs(args: &Args, sink: &impl OutputSink) -> Result<()> {
+    let mut stale_prefixes = Vec::new();
+    if !args.wants(OutputSelection::Lookup) {
+        stale_prefixes.extend([
+            format!("{}.", args.lookup_filename),
+            "stops_not_in_naptan.".to_string(),
+            "location_conflicts.".to_string(),
+            "name_conflicts.".to_string(),
+        ]);
+    }
+    if !args.counts_departures() {
+        stale_prefixes.extend(
+            [
+                "unnamed_stops.",
+                "stops_without_records.",
+                "tiploc_lookup.",
+                "top_stations.",
+                "station_stats.",
+                "anomalies.",
+                "routes_summary.",
+                "rail_departure_times.",
+                "network_profile.",
+                "departures_by_name.",
+                "operators_seen.",
+                "operator_origins.",
+                "journey_warnings.",
+                "journeys.",
+                "rejected_journeys.",
+                gtfs_export::GTFS_FILE_NAME,
+                sqlite_export::SQLITE_FILE_NAME,
+            ]
+            .map(String::from),
+        );
+        stale_prefixes.push(format!("{}_", args.departures_filename));
+    }
+    if !args.wants(OutputSelection::Criteria) {
+        stale_prefixes.extend(["criteria_by_area.", report::REPORT_FILE_NAME].map(String::from));
+    }
+    for file_name in sink.existing_outputs()? {
+        if stale_prefixes
+            .iter()
+            .any(|prefix| file_name.starts_with(prefix.as_str()))
+        {
+            warn!("{file_name} is left from an earlier run, as --outputs skips it");
+        }
+    }
+    Ok(())
+}
+
+/// Write one file per day, or all the days in one file keyed by day when `nested`. As JSON
+/// Lines each station is a line, named from `station_names` and carrying its day when nested.
+/// JSON files record the `sources` they were counted from and any --from-hour and --to-hour
+/// window, and those of criteria results the `criteria_thresholds` they were evaluated with.
+/// With `station_areas`, for --split-by-area, each file is written once per area, holding the
+/// stations of that area.
+fn write_days<T: Serialize>(
+    args: &Args,
+    outputs: &Outputs,
+    sink: &mut impl OutputSink,
+    station_areas: Option<&HashMap<ThreeAlphaCode, String>>,
+    nested: bool,
+    criteria_thresholds: Option<CriteriaThresholds>,
+    output: impl Fn(&DayOutputs) -> &HashMap<ThreeAlphaCode, T>,
+) -> Result<()> {
+    let (days, station_names) = (&outputs.days, &outputs.station_names);
+    let sources: Vec<Source> = outputs
+        .parse_summary
+        .headers
+        .iter()
+        .map(Source::from)
+        .collect();
+    let operating_date = args.grouping_context().operating_date;
+    let file_areas = file_areas(
+        station_areas,
+        days.iter()
+            .flat_map(|day_outputs| output(day_outputs).keys()),
+    );
+    for area in file_areas {
+        let keep = |three_alpha_code: &ThreeAlphaCode| {
+            area.is_none_or(|area| {
+                station_areas.is_some_and(|station_areas| {
+                    areas::area_of(three_alpha_code, station_areas) == area
+                })
+            })
+        };
+        let suffix = area.map_or(String::new(), |area| format!("_{area}"));
+        if nested && days.len() > 1 {
+            let file_name = format!("{}_all_{operating_date}{suffix}", args.departures_filename);
+            if args.format == OutputFormat::Jsonl {
+                sink.write_jsonl(
+                    &file_name,
+                    days.iter().flat_map(|day_outputs| {
+                        station_lines(day_outputs, output(day_outputs), station_names, true, &keep)
+                    }),
+                )?;
+            } else {
+                let nested: BTreeMap<&DaySelector, DayFile<T>> = days
+                    .iter()
+                    .map(|day_outputs| {
+                        let day_file = DayFile::new(
+                            output(day_outputs),
+                            station_names,
+                            args.embed_names,
+                            &keep,
+                        );
+                        (&day_outputs.selector, day_file)
+                    })
+                    .collect();
+                utils::write_criteria_json_file(
+                    &file_name,
+                    sink,
+                    nested,
+                    args.camel_case,
+                    criteria_thresholds,
+                    &sources,
+                    args.hour_window(),
+                )?;
+            }
+        } else {
+            for day_outputs in days.iter() {
+                let file_name = format!(
+                    "{}_{}_{operating_date}{suffix}",
+                    args.departures_filename, day_outputs.selector
+                );
+                if args.format == OutputFormat::Jsonl {
+                    sink.write_jsonl(
+                        &file_name,
+                        station_lines(
+                            day_outputs,
+                            output(day_outputs),
+                            station_names,
+                            false,
+                            &keep,
+                        ),
+                    )?;
+                } else {
+                    utils::write_criteria_json_file(
+                        &file_name,
+                        sink,
+                        DayFile::new(output(day_outputs), station_names, args.embed_names, &keep),
+                        args.camel_case,
+                        criteria_thresholds,
+                        &sources,
+                        args.hour_window(),
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The areas to write a file for, one for each area of the stations, in area order, or a
+/// single file of every station without `station_areas`
+fn file_areas<'a>(
+    station_areas: Option<&'a HashMap<ThreeAlphaCode, String>>,
+    three_alpha_codes: impl Iterator<Item = &'a ThreeAlphaCode>,
+) -> Vec<Option<&'a str>> {
+    let Some(station_areas) = station_areas else {
+        return vec![None];
+    };
+    let areas: BTreeSet<&str> = three_alpha_codes
+        .map(|three_alpha_code| areas::area_of(three_alpha_code, station_areas))
+        .collect();
+    areas.into_iter().map(Some).collect()
+}
+
+/// `by_station` in three alpha code order, for writing
+fn sorted<T>(by_station: &HashMap<ThreeAlphaCode, T>) -> BTreeMap<&ThreeAlphaCode, &T> {
+    by_station.iter().collect()
+}
+
+/// The lines of `day_output` that `keep` keeps, in three alpha code order
+fn station_lines<'a, T>(
+    day_outputs: &'a DayOutputs,
+    day_output: &'a HashMap<ThreeAlphaCode, T>,
+    station_names: &'a HashMap<ThreeAlphaCode, StationName>,
+    nested: bool,
+    keep: &dyn Fn(&ThreeAlphaCode) -> bool,
+) -> impl Iterator<Item = StationLine<'a, T>> {
+    let mut three_alpha_codes: Vec<&ThreeAlphaCode> = day_output
+        .keys()
+        .filter(|three_alpha_code| keep(three_alpha_code))
+        .collect();
+    three_alpha_codes.sort();
+    three_alpha_codes
+        .into_iter()
+        .map(move |three_alpha_code| StationLine {
+            day: nested.then_some(&day_outputs.selector),
+            name: station_names
+                .get(three_alpha_code)
+                .map(|station_name| station_name.name.as_str()),
+            output: &day_output[three_alpha_code],
+        })
+}
@@ -1,67 +1,596 @@
-use indicatif::ParallelProgressIterator;
+#[cfg(feature = "fs")]
+use anyhow::Context;
+use indicatif::{ParallelProgressIterator, ProgressBar};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{cmp::Eq, hash::Hash, str::FromStr};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::{cmp::Eq, fmt, hash::Hash, str::FromStr};
 
+#[cfg(feature = "fs")]
+use super::decompress::{self, Encoding};
+use super::info;
+use super::modes::{self, ModeAliases};
 use super::utils::progress_bar_for_count;
 
 /// Parse in the raw CIF rail timetable data
 /// See: https://wiki.openraildata.com/index.php/CIF_File_Format for details on the format
+///
+/// ```
+/// use rail_hourly_departures::records::{parse, Record};
+///
+/// let cif = [
+///     "TIKNGX   00121000 LONDON KINGS CROSS        87701    KGXLONDON KINGS CRS",
+///     "BSNC123452601012612311111100 POO",
+///     "LOKNGX    0800 0800",
+///     "LTCAMBDGE 0900 0900",
+/// ]
+/// .map(|line| format!("{line:<80}"))
+/// .join("\n");
+///
+/// let records = parse(cif);
+/// assert_eq!(records.len(), 4);
+/// assert!(matches!(records[1], Record::JourneyHeader(_)));
+/// ```
 pub fn parse(raw_cif_text: String) -> Vec<Record> {
-    println!("Parsing CIF file...");
-    // Remove carriage returns and split the string into lines
-    let cif = raw_cif_text.replace("\r", "");
-    let mut cif_lines = cif.split("\n").collect::<Vec<&str>>();
-    // Drop the last line if it is empty
-    if cif_lines.last().unwrap().is_empty() {
-        cif_lines.pop();
+    parse_with_summary(raw_cif_text).0
+}
+
+/// How many lines [`parse_with_options`] indexes at a time with [`ParseOptions::low_memory`]
+const LOW_MEMORY_CHUNK_LINES: usize = 100_000;
+
+/// Which record types a parse reads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordFilter {
+    #[default]
+    All,
+    /// Only the TI stops, skipping the journey records, for runs that only write the lookup
+    StopsOnly,
+}
+
+impl RecordFilter {
+    fn skips(self, record_identifier: &RecordIdentifier) -> bool {
+        self == RecordFilter::StopsOnly
+            && matches!(
+                record_identifier,
+                RecordIdentifier::BS
+                    | RecordIdentifier::BX
+                    | RecordIdentifier::TN
+                    | RecordIdentifier::LO
+                    | RecordIdentifier::LI
+                    | RecordIdentifier::LT
+            )
+    }
+}
+
+/// How a parse reads the CIF text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// The record types read. Skipped lines are counted as ignored.
+    pub filter: RecordFilter,
+    /// Parse the lines in chunks rather than indexing them all at once, for --low-memory
+    pub low_memory: bool,
+    /// Draw a progress bar while parsing, unless --no-progress
+    pub show_progress: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            filter: RecordFilter::All,
+            low_memory: false,
+            show_progress: true,
+        }
     }
+}
 
-    println!("Number of lines: {}", cif_lines.len());
-    let progress = progress_bar_for_count(cif_lines.len());
+/// [`parse`], also counting the lines of each record type as they are parsed
+pub fn parse_with_summary(raw_cif_text: String) -> (Vec<Record>, ParseSummary) {
+    parse_with_options(raw_cif_text, ParseOptions::default())
+}
+
+/// [`parse_with_summary`], reading the CIF text as `options` say
+pub fn parse_with_options(
+    raw_cif_text: String,
+    options: ParseOptions,
+) -> (Vec<Record>, ParseSummary) {
+    info!("Parsing CIF file...");
+    if options.low_memory {
+        return parse_in_chunks(&raw_cif_text, LOW_MEMORY_CHUNK_LINES, options);
+    }
+    // Handles both LF and CRLF endings, with or without a final newline
+    let cif_lines = raw_cif_text.lines().collect::<Vec<&str>>();
 
+    info!("Number of lines: {}", cif_lines.len());
+    let progress = progress_bar_for_count(cif_lines.len(), options.show_progress);
+    parse_lines(&cif_lines, progress, options.filter)
+}
+
+/// Parse `chunk_lines` lines at a time, so only a chunk of lines is indexed at once rather
+/// than the whole file
+fn parse_in_chunks(
+    raw_cif_text: &str,
+    chunk_lines: usize,
+    options: ParseOptions,
+) -> (Vec<Record>, ParseSummary) {
+    let line_count = raw_cif_text.lines().count();
+    info!("Number of lines: {line_count}");
+    let progress = progress_bar_for_count(line_count, options.show_progress);
+
+    let mut records = Vec::new();
+    let mut summary = ParseSummary::default();
+    let mut lines = raw_cif_text.lines();
+    let mut chunk: Vec<&str> = Vec::with_capacity(chunk_lines);
+    loop {
+        chunk.clear();
+        chunk.extend(lines.by_ref().take(chunk_lines));
+        if chunk.is_empty() {
+            break;
+        }
+        let (chunk_records, chunk_summary) = parse_lines(&chunk, progress.clone(), options.filter);
+        records.extend(chunk_records);
+        summary.add(&chunk_summary);
+    }
+    progress.finish();
+    (records, summary)
+}
+
+fn parse_lines(
+    cif_lines: &[&str],
+    progress: ProgressBar,
+    filter: RecordFilter,
+) -> (Vec<Record>, ParseSummary) {
+    // Each rayon job folds its lines into its own records and counts, which are then joined
+    // back together in line order
     cif_lines
         .par_iter()
         .progress_with(progress)
-        .filter_map(|line| {
-            let record_identifier = RecordIdentifier::from_str(&line[0..2]).unwrap();
-            match record_identifier {
-                RecordIdentifier::BS => {
-                    Some(Record::JourneyHeader(JourneyHeader::from_bs_str(line)))
+        .fold(
+            || (Vec::new(), ParseSummary::default()),
+            |(mut records, mut summary), line| {
+                if line.trim().is_empty() {
+                    summary.blank += 1;
+                    return (records, summary);
+                }
+                let record_identifier =
+                    RecordIdentifier::from_str(line.get(0..2).unwrap_or(line)).unwrap();
+                if let RecordIdentifier::HD = record_identifier {
+                    summary.headers.push(FileHeader::from_hd_str(line));
+                    return (records, summary);
+                }
+                if filter.skips(&record_identifier) {
+                    summary.ignored += 1;
+                    return (records, summary);
                 }
-                RecordIdentifier::TI => Some(Record::Stop(Stop::from_ti_str(line)?)),
-                RecordIdentifier::LO => Some(Record::JourneyRecordStop(
-                    JourneyRecordStop::from_lo_str(line)?,
-                )),
-                RecordIdentifier::LI => Some(Record::JourneyRecordStop(
-                    JourneyRecordStop::from_li_str(line)?,
-                )),
-                RecordIdentifier::LT => Some(Record::JourneyRecordStop(
-                    JourneyRecordStop::from_lt_str(line)?,
-                )),
-                _ => None,
+                match parse_line(&record_identifier, line) {
+                    Ok(record) => {
+                        summary.count(&record_identifier, record.is_some());
+                        if let Some(Record::JourneyHeader(header)) = &record {
+                            summary.count_no_operating_days(header);
+                        }
+                        records.extend(record);
+                    }
+                    Err(error) => {
+                        summary.malformed += 1;
+                        summary.first_malformed.get_or_insert(error.to_string());
+                    }
+                }
+                (records, summary)
+            },
+        )
+        .reduce(
+            || (Vec::new(), ParseSummary::default()),
+            |(mut records, mut summary), (other_records, other_summary)| {
+                records.extend(other_records);
+                summary.add(&other_summary);
+                (records, summary)
+            },
+        )
+}
+
+/// The lines of the CIF file to dump with --dump-range, counted from 1, both ends included,
+/// such as "100000:100200"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRange {
+    pub first: usize,
+    pub last: usize,
+}
+
+impl FromStr for LineRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid line range {s:?}, expected FIRST:LAST such as 100:200");
+        let (first, last) = s.split_once(':').ok_or_else(invalid)?;
+        let (first, last): (usize, usize) = (
+            first.trim().parse().map_err(|_| invalid())?,
+            last.trim().parse().map_err(|_| invalid())?,
+        );
+        if first == 0 || last < first {
+            return Err(invalid());
+        }
+        Ok(LineRange { first, last })
+    }
+}
+
+/// The file --dump-records writes, without its .jsonl extension
+pub const NUMBERED_RECORDS_FILE_NAME: &str = "numbered_records";
+
+/// A line of the CIF file with what the parser made of it, as written by --dump-records
+#[derive(Debug, Serialize)]
+pub struct NumberedRecord {
+    /// Counted from 1
+    pub line_number: usize,
+    pub raw: String,
+    /// None for record types that aren't parsed and for dropped or malformed lines
+    pub record: Option<Record>,
+    /// Why a malformed line could not be read
+    pub error: Option<String>,
+}
+
+/// Parse the lines of `range`, or every line without one, keeping each record with its line.
+/// Every record is read from a single line, so these are the records a full parse makes of
+/// those lines.
+pub fn parse_numbered(raw_cif_text: &str, range: Option<LineRange>) -> Vec<NumberedRecord> {
+    let (skip, take) = range.map_or((0, usize::MAX), |range| {
+        (range.first - 1, range.last - range.first + 1)
+    });
+    raw_cif_text
+        .lines()
+        .enumerate()
+        .skip(skip)
+        .take(take)
+        .map(|(index, line)| {
+            let parsed = match RecordIdentifier::from_str(line.get(0..2).unwrap_or(line)).unwrap() {
+                _ if line.trim().is_empty() => Ok(None),
+                record_identifier => parse_line(&record_identifier, line),
+            };
+            let (record, error) = match parsed {
+                Ok(record) => (record, None),
+                Err(error) => (None, Some(error.to_string())),
+            };
+            NumberedRecord {
+                line_number: index + 1,
+                raw: line.to_string(),
+                record,
+                error,
             }
         })
         .collect()
 }
 
-pub fn read_file(file_path: &str) -> String {
-    fs_err::read_to_string(file_path).expect("Something went wrong reading the file")
+/// The record of one line, or None for record types that aren't needed and dropped lines such
+/// as LI passing points
+fn parse_line(
+    record_identifier: &RecordIdentifier,
+    line: &str,
+) -> Result<Option<Record>, LineError> {
+    Ok(match record_identifier {
+        RecordIdentifier::BS => Some(Record::JourneyHeader(JourneyHeader::from_bs_str(line)?)),
+        RecordIdentifier::BX => Some(Record::JourneyExtra(JourneyExtra::from_bx_str(line))),
+        RecordIdentifier::TN => Some(Record::JourneyNote(JourneyNote::from_tn_str(line))),
+        RecordIdentifier::TI => Some(Record::Stop(Stop::from_ti_str(line)?)),
+        RecordIdentifier::LO => Some(Record::JourneyRecordStop(JourneyRecordStop::from_lo_str(
+            line,
+        )?)),
+        RecordIdentifier::LI => {
+            JourneyRecordStop::from_li_str(line)?.map(Record::JourneyRecordStop)
+        }
+        RecordIdentifier::LT => Some(Record::JourneyRecordStop(JourneyRecordStop::from_lt_str(
+            line,
+        )?)),
+        _ => None,
+    })
+}
+
+/// Why a line of one of the parsed record types could not be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineError {
+    /// The line ends before the last field its record type can't do without
+    TooShort {
+        record_type: &'static str,
+        width: usize,
+        required_width: usize,
+    },
+    /// A field that doesn't hold a value of its type, such as a day of "X"
+    InvalidField {
+        record_type: &'static str,
+        field: &'static str,
+        value: String,
+    },
+    /// A time field that isn't a working time, such as "08x0" or "2590"
+    InvalidTime {
+        record_type: &'static str,
+        field: &'static str,
+        value: String,
+        error: TimeParseError,
+    },
 }
 
-#[derive(Debug)]
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineError::TooShort {
+                record_type,
+                width,
+                required_width,
+            } => write!(
+                f,
+                "{record_type} line is {width} characters wide, but needs at least \
+                 {required_width}"
+            ),
+            LineError::InvalidField {
+                record_type,
+                field,
+                value,
+            } => write!(f, "{record_type} line has an invalid {field} {value:?}"),
+            LineError::InvalidTime {
+                record_type,
+                field,
+                value,
+                error,
+            } => write!(
+                f,
+                "{record_type} line has an invalid {field} {value:?}: {error}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LineError {}
+
+/// The fixed width fields of one line of `record_type`
+struct Fields<'a> {
+    record_type: &'static str,
+    line: &'a str,
+}
+
+impl<'a> Fields<'a> {
+    /// Fails when the line is narrower than `required_width`, the end of its last mandatory
+    /// field. Later fields missing from the line, as when trailing spaces have been trimmed,
+    /// read as blank.
+    fn new(
+        record_type: &'static str,
+        line: &'a str,
+        required_width: usize,
+    ) -> Result<Self, LineError> {
+        let width = line.chars().count();
+        if width < required_width {
+            return Err(LineError::TooShort {
+                record_type,
+                width,
+                required_width,
+            });
+        }
+        Ok(Fields { record_type, line })
+    }
+
+    fn get(&self, start: usize, end: usize) -> &'a str {
+        fixed_width_field(self.line, start, end)
+    }
+
+    fn invalid(&self, field: &'static str, value: &str) -> LineError {
+        LineError::InvalidField {
+            record_type: self.record_type,
+            field,
+            value: value.to_string(),
+        }
+    }
+
+    fn parse<T: FromStr>(
+        &self,
+        field: &'static str,
+        start: usize,
+        end: usize,
+    ) -> Result<T, LineError> {
+        let value = self.get(start, end);
+        value.parse().map_err(|_| self.invalid(field, value))
+    }
+
+    /// A working time field, or None when the field is left blank
+    fn working_time(
+        &self,
+        field: &'static str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<SecondsPastMidnight>, LineError> {
+        let value = self.get(start, end);
+        if value.trim().is_empty() {
+            return Ok(None);
+        }
+        SecondsPastMidnight::from_working_time_str(value)
+            .map(Some)
+            .map_err(|error| LineError::InvalidTime {
+                record_type: self.record_type,
+                field,
+                value: value.to_string(),
+                error,
+            })
+    }
+}
+
+/// How many lines of each record type a parse kept
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParseSummary {
+    pub bs: usize,
+    pub bx: usize,
+    #[serde(default)]
+    pub tn: usize,
+    pub ti: usize,
+    pub lo: usize,
+    pub li: usize,
+    pub lt: usize,
+    /// Lines of a parsed record type that were dropped, such as LI passing points, or skipped
+    /// by a [`RecordFilter`]
+    pub ignored: usize,
+    /// Lines of every other record type, such as TA and ZZ
+    pub other: usize,
+    /// Empty or whitespace only lines, which are skipped
+    pub blank: usize,
+    /// Lines of a parsed record type too short or garbled to read, which are skipped
+    #[serde(default)]
+    pub malformed: usize,
+    /// Why the first malformed line could not be read
+    #[serde(default)]
+    pub first_malformed: Option<String>,
+    /// The HD header of each file, more than one when files were concatenated
+    pub headers: Vec<FileHeader>,
+    /// Journeys that aren't deleted but run on none of the days of the week, which are never
+    /// counted, as they only run on dates given by exceptions this parser doesn't read
+    #[serde(default)]
+    pub no_operating_days: usize,
+    /// The UIDs of the first [`NO_OPERATING_DAYS_SAMPLE`] of them
+    #[serde(default)]
+    pub no_operating_days_sample: Vec<String>,
+}
+
+/// How many UIDs of journeys with no operating days [`ParseSummary`] lists
+pub const NO_OPERATING_DAYS_SAMPLE: usize = 10;
+
+impl ParseSummary {
+    fn count(&mut self, record_identifier: &RecordIdentifier, kept: bool) {
+        let count = match (record_identifier, kept) {
+            (RecordIdentifier::BS, true) => &mut self.bs,
+            (RecordIdentifier::BX, true) => &mut self.bx,
+            (RecordIdentifier::TN, true) => &mut self.tn,
+            (RecordIdentifier::TI, true) => &mut self.ti,
+            (RecordIdentifier::LO, true) => &mut self.lo,
+            (RecordIdentifier::LI, true) => &mut self.li,
+            (RecordIdentifier::LT, true) => &mut self.lt,
+            (RecordIdentifier::BS, false)
+            | (RecordIdentifier::TI, false)
+            | (RecordIdentifier::LO, false)
+            | (RecordIdentifier::LI, false)
+            | (RecordIdentifier::LT, false) => &mut self.ignored,
+            _ => &mut self.other,
+        };
+        *count += 1;
+    }
+
+    pub fn add(&mut self, other: &ParseSummary) {
+        self.bs += other.bs;
+        self.bx += other.bx;
+        self.tn += other.tn;
+        self.ti += other.ti;
+        self.lo += other.lo;
+        self.li += other.li;
+        self.lt += other.lt;
+        self.ignored += other.ignored;
+        self.other += other.other;
+        self.blank += other.blank;
+        self.malformed += other.malformed;
+        if self.first_malformed.is_none() {
+            self.first_malformed = other.first_malformed.clone();
+        }
+        self.headers.extend(other.headers.iter().cloned());
+        self.no_operating_days += other.no_operating_days;
+        self.no_operating_days_sample.extend(
+            other
+                .no_operating_days_sample
+                .iter()
+                .take(NO_OPERATING_DAYS_SAMPLE - self.no_operating_days_sample.len())
+                .cloned(),
+        );
+    }
+
+    fn count_no_operating_days(&mut self, header: &JourneyHeader) {
+        if header.status.is_operating() && header.operating_days.is_empty() {
+            self.no_operating_days += 1;
+            if self.no_operating_days_sample.len() < NO_OPERATING_DAYS_SAMPLE {
+                self.no_operating_days_sample.push(header.uid.clone());
+            }
+        }
+    }
+
+    fn rows(&self) -> [(&'static str, usize); 11] {
+        [
+            ("BS", self.bs),
+            ("BX", self.bx),
+            ("TN", self.tn),
+            ("TI", self.ti),
+            ("LO", self.lo),
+            ("LI", self.li),
+            ("LT", self.lt),
+            ("Ignored", self.ignored),
+            ("Other", self.other),
+            ("Blank", self.blank),
+            ("Malformed", self.malformed),
+        ]
+    }
+
+    /// Every line read, header and blank lines included
+    pub fn total_lines(&self) -> usize {
+        self.rows().iter().map(|(_, count)| count).sum::<usize>() + self.headers.len()
+    }
+
+    /// The share of the lines read that were malformed, 0 when there were none
+    pub fn error_rate(&self) -> f64 {
+        match self.total_lines() {
+            0 => 0.0,
+            total_lines => self.malformed as f64 / total_lines as f64,
+        }
+    }
+
+    /// A plain text table of the counts for printing
+    pub fn format_table(&self) -> String {
+        let mut table = format!("{:<9}{:>10}\n", "Record", "Lines");
+        for (name, count) in self.rows() {
+            table.push_str(&format!("{name:<9}{count:>10}\n"));
+        }
+        table
+    }
+}
+
+/// How a parse compared with --max-error-rate, recorded in the run manifest
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBudget {
+    pub max_error_rate: f64,
+    pub error_rate: f64,
+    /// Whether the error rate was over the maximum, so the run stopped after parsing
+    pub exceeded: bool,
+}
+
+impl ErrorBudget {
+    pub fn check(summary: &ParseSummary, max_error_rate: f64) -> Self {
+        let error_rate = summary.error_rate();
+        ErrorBudget {
+            max_error_rate,
+            error_rate,
+            exceeded: error_rate > max_error_rate,
+        }
+    }
+}
+
+/// Read the CIF file at `file_path`, decompressing it first when it is gzip or zip compressed
+#[cfg(feature = "fs")]
+pub fn read_file(file_path: &Path, encoding: Encoding) -> anyhow::Result<String> {
+    let bytes = fs_err::read(file_path)
+        .with_context(|| format!("Failed to read the CIF file {}", file_path.display()))?;
+    decompress::decode_cif_bytes(bytes, encoding)
+        .with_context(|| format!("Failed to read the CIF file {}", file_path.display()))
+}
+
+/// A single parsed CIF line that is relevant to counting departures
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Record {
     JourneyHeader(JourneyHeader),
+    JourneyExtra(JourneyExtra),
+    JourneyNote(JourneyNote),
     JourneyRecordStop(JourneyRecordStop),
     Stop(Stop),
 }
 
 #[derive(Debug)]
 pub enum RecordIdentifier {
+    HD, // Header Record
     TI, // TIPLOC Insert Record
     TA, // TIPLOC Amend Record
     TD, // TIPLOC Delete Record
     BS, // Basic Schedule Record
+    BX, // Basic Schedule Extra Details Record
+    TN, // Train Specific Note
     LO, // Location Origin
     LI, // Location Intermediate
     LT, // Location Terminate
@@ -73,10 +602,13 @@ impl FromStr for RecordIdentifier {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "HD" => Ok(RecordIdentifier::HD),
             "TI" => Ok(RecordIdentifier::TI),
             "TA" => Ok(RecordIdentifier::TA),
             "TD" => Ok(RecordIdentifier::TD),
             "BS" => Ok(RecordIdentifier::BS),
+            "BX" => Ok(RecordIdentifier::BX),
+            "TN" => Ok(RecordIdentifier::TN),
             "LO" => Ok(RecordIdentifier::LO),
             "LI" => Ok(RecordIdentifier::LI),
             "LT" => Ok(RecordIdentifier::LT),
@@ -87,9 +619,83 @@ impl FromStr for RecordIdentifier {
 
 /// A value for time past midnight in seconds.
 /// For example 8am is 28800 seconds past midnight.
+///
+/// Written as HH:MM, or HH:MM:SS for a time with seconds, with hours of 24 and over for times
+/// counted on into the next day:
+///
+/// ```
+/// use rail_hourly_departures::records::SecondsPastMidnight;
+///
+/// assert_eq!(SecondsPastMidnight(8 * 3600 + 1230).to_string(), "08:20:30");
+/// assert_eq!("24:15".parse(), Ok(SecondsPastMidnight(24 * 3600 + 900)));
+/// ```
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
 pub struct SecondsPastMidnight(pub usize);
 
+impl fmt::Display for SecondsPastMidnight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 3600, self.0 % 3600 / 60)?;
+        match self.0 % 60 {
+            0 => Ok(()),
+            seconds => write!(f, ":{seconds:02}"),
+        }
+    }
+}
+
+/// Reads HHMM, HH:MM or HH:MM:SS. HHMM is a working time, so its hours stop at 23, while the
+/// others allow the hours of 24 and over that [`Display`](fmt::Display) writes.
+impl FromStr for SecondsPastMidnight {
+    type Err = TimeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.contains(':') {
+            return Self::from_24hr_str(s);
+        }
+        let mut parts = s.split(':').map(|part| {
+            (part.len() == 2 && part.bytes().all(|byte| byte.is_ascii_digit()))
+                .then(|| part.parse::<usize>().unwrap())
+                .ok_or(TimeParseError::NotDigits)
+        });
+        let hours = parts.next().ok_or(TimeParseError::NotDigits)??;
+        let minutes = parts.next().ok_or(TimeParseError::NotDigits)??;
+        let seconds = parts.next().transpose()?.unwrap_or(0);
+        if parts.next().is_some() {
+            return Err(TimeParseError::NotDigits);
+        }
+        if minutes > 59 {
+            return Err(TimeParseError::MinuteOutOfRange(minutes));
+        }
+        if seconds > 59 {
+            return Err(TimeParseError::SecondOutOfRange(seconds));
+        }
+        Ok(SecondsPastMidnight(hours * 3600 + minutes * 60 + seconds))
+    }
+}
+
+impl Serialize for SecondsPastMidnight {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Reads the seconds past midnight of files written before times were written as HH:MM too
+impl<'de> Deserialize<'de> for SecondsPastMidnight {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Time {
+            Seconds(usize),
+            Clock(String),
+        }
+        match Time::deserialize(deserializer)? {
+            Time::Seconds(seconds) => Ok(SecondsPastMidnight(seconds)),
+            Time::Clock(time) => time.parse().map_err(|error| {
+                serde::de::Error::custom(format!("Invalid time {time:?}: {error}"))
+            }),
+        }
+    }
+}
+
 // One record per journey. A journey header may be immediately
 // followed by optional sets of date running records and journey note
 // records and should then be followed by a set of journey records
@@ -99,57 +705,324 @@ pub struct SecondsPastMidnight(pub usize);
 // may be immediately followed by one or more journey repetition
 // records.
 /// Denoted by "BS" in the CIF file
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JourneyHeader {
     pub status: Status,
-    pub _uid: String,
+    pub uid: String,
     pub date_runs_from: Date,
     pub date_runs_to: Date,
     pub operating_days: OperatingDays,
-    pub _train_status: char,
+    /// Such as 'P' for a passenger train, 'B' for a bus or 'S' for a ship, with the short term
+    /// planning statuses '1' to '5' for the same
+    #[serde(alias = "_train_status")]
+    pub train_status: char,
     pub category: TrainCategory,
 }
 
 impl JourneyHeader {
-    fn from_bs_str(bs_string: &str) -> Self {
+    fn from_bs_str(bs_string: &str) -> Result<Self, LineError> {
         // Parse the BS string and extract the relevant fields
-        JourneyHeader {
-            status: Status::from_str(&bs_string[2..3]).unwrap(),
-            _uid: bs_string[3..9].trim().to_string(),
-            date_runs_from: Date::from_str(&bs_string[9..15]).unwrap(),
-            date_runs_to: Date::from_str(&bs_string[15..21]).unwrap(),
-            operating_days: OperatingDays::from_cif_str(&bs_string[21..28]),
-            _train_status: bs_string.chars().nth(29).unwrap(),
-            category: TrainCategory::from_str(&bs_string[30..32]).unwrap(),
+        let fields = Fields::new("BS", bs_string, 28)?;
+        Ok(JourneyHeader {
+            status: fields.parse("transaction type", 2, 3)?,
+            uid: fields.get(3, 9).trim().to_string(),
+            date_runs_from: fields.parse("date runs from", 9, 15)?,
+            date_runs_to: fields.parse("date runs to", 15, 21)?,
+            operating_days: OperatingDays::from_cif_str(fields.get(21, 28)),
+            train_status: fields.get(29, 30).chars().next().unwrap_or(' '),
+            category: TrainCategory::from_str(fields.get(30, 32)).unwrap(),
+        })
+    }
+
+    /// The mode of transport of the train status, such as "train" or "bus"
+    pub fn mode(&self) -> &'static str {
+        match self.train_status {
+            'P' | '1' => "train",
+            'B' | '5' => "bus",
+            'S' | '4' => "ship",
+            'F' | 'T' | '2' | '3' => "freight",
+            _ => modes::UNKNOWN_MODE,
         }
     }
+
+    /// The mode of the train status in `aliases`, or failing that [`JourneyHeader::mode`]
+    pub fn mode_with(&self, aliases: &ModeAliases) -> &'static str {
+        aliases
+            .get(&self.train_status)
+            .copied()
+            .unwrap_or_else(|| self.mode())
+    }
+}
+
+/// Denoted by "BX" in the CIF file, straight after the BS record it adds to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JourneyExtra {
+    /// The two letter code of the train operating company, such as "GR" for LNER, trimmed
+    /// and upper case so the same operator always has the same code
+    pub atoc_code: String,
+}
+
+impl JourneyExtra {
+    fn from_bx_str(bx_string: &str) -> Self {
+        JourneyExtra {
+            atoc_code: fixed_width_field(bx_string, 11, 13)
+                .trim()
+                .to_ascii_uppercase(),
+        }
+    }
+}
+
+/// Denoted by "TN" in the CIF file, a free text note on the journey before it, such as "Runs
+/// on schooldays only". A journey can have several, one line each.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JourneyNote {
+    /// The text of the note, without the padding after it
+    pub note: String,
+}
+
+impl JourneyNote {
+    fn from_tn_str(tn_string: &str) -> Self {
+        // The note type is the character after the record identity
+        JourneyNote {
+            note: fixed_width_field(tn_string, 3, 80).trim().to_string(),
+        }
+    }
+}
+
+/// The CIF versions the parser was written against, as given in the HD record
+pub const SUPPORTED_CIF_VERSIONS: [&str; 1] = ["A"];
+
+/// Denoted by "HD" in the CIF file, the first line of each file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileHeader {
+    pub mainframe_identity: String,
+    pub date_of_extract: Option<Date>,
+    pub time_of_extract: String,
+    pub current_file_reference: String,
+    pub last_file_reference: String,
+    /// F for a full extract, U for an update
+    pub update_indicator: String,
+    pub version: String,
+    pub user_start_date: Option<Date>,
+    pub user_end_date: Option<Date>,
+}
+
+impl FileHeader {
+    fn from_hd_str(hd_string: &str) -> Self {
+        let field = |start: usize, end: usize| hd_string.get(start..end).unwrap_or("").trim();
+        FileHeader {
+            mainframe_identity: field(2, 22).to_string(),
+            date_of_extract: Date::from_ddmmyy(field(22, 28)),
+            time_of_extract: field(28, 32).to_string(),
+            current_file_reference: field(32, 39).to_string(),
+            last_file_reference: field(39, 46).to_string(),
+            update_indicator: field(46, 47).to_string(),
+            version: field(47, 48).to_string(),
+            user_start_date: Date::from_ddmmyy(field(48, 54)),
+            user_end_date: Date::from_ddmmyy(field(54, 60)),
+        }
+    }
+
+    pub fn is_supported_version(&self) -> bool {
+        SUPPORTED_CIF_VERSIONS.contains(&self.version.as_str())
+    }
+}
+
+/// Check that `raw_cif_text` starts with an HD header, catching input that isn't a CIF file
+pub fn check_starts_with_header(raw_cif_text: &str) -> anyhow::Result<()> {
+    if raw_cif_text.trim().is_empty() {
+        anyhow::bail!("The CIF file is empty");
+    }
+    let first_line = raw_cif_text.lines().next().unwrap_or("");
+    if !first_line.starts_with("HD") {
+        anyhow::bail!(
+            "The first line does not look like a CIF HD header: {:?}",
+            first_line.chars().take(40).collect::<String>()
+        );
+    }
+    Ok(())
 }
 
 /// YYMMDD format date
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Date(pub usize);
 
+impl Date {
+    /// The DDMMYY dates of the HD record, or None when blank or malformed
+    fn from_ddmmyy(s: &str) -> Option<Date> {
+        if s.len() != 6 || !s.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        Some(Date(
+            format!("{}{}{}", &s[4..6], &s[2..4], &s[0..2])
+                .parse()
+                .ok()?,
+        ))
+    }
+
+    /// The calendar date, taking the two digit year to be in this century, or None when the
+    /// month or day is out of range
+    pub fn to_calendar_date(&self) -> Option<CalendarDate> {
+        format!(
+            "20{:02}-{:02}-{:02}",
+            self.0 / 10000 % 100,
+            self.0 / 100 % 100,
+            self.0 % 100
+        )
+        .parse()
+        .ok()
+    }
+}
+
 impl FromStr for Date {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Date(s.parse::<usize>().unwrap()))
+        s.parse::<usize>().map(Date).map_err(|_| ())
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:06}", self.0)
+    }
+}
+
+/// A calendar date in YYYY-MM-DD form
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CalendarDate {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl CalendarDate {
+    /// Days since 1970-01-01, see https://howardhinnant.github.io/date_algorithms.html
+    pub fn days_since_epoch(&self) -> i64 {
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    /// The date `days` after 1970-01-01, the inverse of [`CalendarDate::days_since_epoch`]
+    pub fn from_days_since_epoch(days: i64) -> Self {
+        let days = days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        CalendarDate {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+        }
+    }
+
+    /// Today's date in UTC
+    pub fn today() -> Self {
+        let seconds = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        CalendarDate::from_days_since_epoch((seconds / 86400) as i64)
+    }
+
+    pub fn weekday(&self) -> Day {
+        // 1970-01-01 was a Thursday
+        Day::all()[(self.days_since_epoch() + 3).rem_euclid(7) as usize].clone()
+    }
+
+    /// The same date in the YYMMDD form of the CIF file
+    pub fn to_cif_date(&self) -> Date {
+        Date((self.year as usize % 100) * 10000 + self.month as usize * 100 + self.day as usize)
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        _ => 31,
+    }
+}
+
+impl FromStr for CalendarDate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid date {s:?}, expected YYYY-MM-DD");
+        let parts: Vec<&str> = s.split('-').collect();
+        let [year, month, day] = parts.as_slice() else {
+            return Err(invalid());
+        };
+        if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return Err(invalid());
+        }
+        let date = CalendarDate {
+            year: year.parse().map_err(|_| invalid())?,
+            month: month.parse().map_err(|_| invalid())?,
+            day: day.parse().map_err(|_| invalid())?,
+        };
+        if !(1..=12).contains(&date.month)
+            || !(1..=days_in_month(date.year, date.month)).contains(&date.day)
+        {
+            return Err(invalid());
+        }
+        Ok(date)
     }
 }
 
-impl ToString for Date {
-    fn to_string(&self) -> String {
-        format!("{:06}", self.0)
+impl fmt::Display for CalendarDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
     }
 }
 
+/// Days from the newest production date of the CIF files with `headers` to `today`, or None
+/// when none of them has a readable date
+pub fn newest_extract_age_days(headers: &[FileHeader], today: CalendarDate) -> Option<i64> {
+    headers
+        .iter()
+        .filter_map(|header| header.date_of_extract.as_ref()?.to_calendar_date())
+        .max()
+        .map(|newest| today.days_since_epoch() - newest.days_since_epoch())
+}
+
+/// The earliest and latest dates any journey in the records runs on
+pub fn validity_period(records: &[Record]) -> Option<(Date, Date)> {
+    records
+        .iter()
+        .filter_map(|record| match record {
+            Record::JourneyHeader(header) => Some((header.date_runs_from.0, header.date_runs_to.0)),
+            _ => None,
+        })
+        .reduce(|(from, to), (header_from, header_to)| (from.min(header_from), to.max(header_to)))
+        .map(|(from, to)| (Date(from), Date(to)))
+}
+
 pub fn parse_date(s: &str) -> Result<Date, String> {
     s.parse::<usize>()
         .map(Date)
         .map_err(|e| format!("Invalid date format: {}", e))
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TrainCategory {
     Passenger,
     Other,
@@ -174,7 +1047,7 @@ impl FromStr for TrainCategory {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Status {
     New,
     Delete,
@@ -203,133 +1076,601 @@ impl Status {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct OperatingDays(pub Vec<Day>);
+/// The days of the week a journey operates on, a bit per day with Monday the lowest. Written
+/// as the CIF file gives them, such as "1111100" for Monday to Friday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct OperatingDays(u8);
+
 impl OperatingDays {
     fn from_cif_str(s: &str) -> Self {
         // Example input ["1111100"]
-        let mut days = Vec::new();
-        for (i, c) in s.chars().enumerate() {
-            if c == '1' {
-                match i {
-                    0 => days.push(Day::Monday),
-                    1 => days.push(Day::Tuesday),
-                    2 => days.push(Day::Wednesday),
-                    3 => days.push(Day::Thursday),
-                    4 => days.push(Day::Friday),
-                    5 => days.push(Day::Saturday),
-                    6 => days.push(Day::Sunday),
-                    _ => {}
-                }
-            }
-        }
-        OperatingDays(days)
+        Day::all()
+            .into_iter()
+            .zip(s.chars())
+            .filter(|(_, c)| *c == '1')
+            .map(|(day, _)| day)
+            .collect()
+    }
+
+    fn bit(day: &Day) -> u8 {
+        1 << day.clone() as u8
     }
+
     pub fn contains(&self, day: &Day) -> bool {
-        self.0.contains(day)
+        self.0 & Self::bit(day) != 0
+    }
+
+    /// How many days of the week it operates on
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Whether it operates on no day of the week, only on dated exceptions
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The days as the CIF file gives them, such as "1111100" for Monday to Friday
+    pub fn to_cif_str(&self) -> String {
+        Day::all()
+            .iter()
+            .map(|day| if self.contains(day) { '1' } else { '0' })
+            .collect()
     }
 }
 
-#[derive(Debug, Clone, PartialEq, clap::ValueEnum)]
+impl FromIterator<Day> for OperatingDays {
+    fn from_iter<I: IntoIterator<Item = Day>>(days: I) -> Self {
+        OperatingDays(days.into_iter().fold(0, |mask, day| mask | Self::bit(&day)))
+    }
+}
+
+impl fmt::Display for OperatingDays {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_cif_str())
+    }
+}
+
+impl Serialize for OperatingDays {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_cif_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for OperatingDays {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let days = String::deserialize(deserializer)?;
+        if days.len() != 7 || !days.chars().all(|c| c == '0' || c == '1') {
+            return Err(serde::de::Error::custom(format!(
+                "Invalid operating days {days:?}, expected seven 0s and 1s such as \"1111100\""
+            )));
+        }
+        Ok(OperatingDays::from_cif_str(&days))
+    }
+}
+
+/// A day of the week a journey can operate on
+///
+/// ```
+/// use rail_hourly_departures::Day;
+///
+/// assert_eq!(Day::Tuesday.to_string(), "Tuesday");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, clap::ValueEnum)]
 pub enum Day {
+    #[value(alias = "mon")]
     Monday,
+    #[value(alias = "tue")]
     Tuesday,
+    #[value(alias = "wed")]
     Wednesday,
+    #[value(alias = "thu")]
     Thursday,
+    #[value(alias = "fri")]
     Friday,
+    #[value(alias = "sat")]
     Saturday,
+    #[value(alias = "sun")]
     Sunday,
 }
 
+/// Accepts full day names and three letter abbreviations in any case, the same values as the
+/// command line
 impl FromStr for Day {
-    type Err = ();
+    type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "Monday" => Ok(Day::Monday),
-            "Tuesday" => Ok(Day::Tuesday),
-            "Wednesday" => Ok(Day::Wednesday),
-            "Thursday" => Ok(Day::Thursday),
-            "Friday" => Ok(Day::Friday),
-            "Saturday" => Ok(Day::Saturday),
-            "Sunday" => Ok(Day::Sunday),
-            _ => Err(()),
+        match s.to_ascii_lowercase().as_str() {
+            "monday" | "mon" => Ok(Day::Monday),
+            "tuesday" | "tue" => Ok(Day::Tuesday),
+            "wednesday" | "wed" => Ok(Day::Wednesday),
+            "thursday" | "thu" => Ok(Day::Thursday),
+            "friday" | "fri" => Ok(Day::Friday),
+            "saturday" | "sat" => Ok(Day::Saturday),
+            "sunday" | "sun" => Ok(Day::Sunday),
+            _ => Err(format!(
+                "Invalid day {s:?}, expected a day name (Monday) or its first three letters (Mon) \
+                 in any case"
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Day {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Day {
+    /// Every day of the week, Monday first
+    pub fn all() -> [Day; 7] {
+        [
+            Day::Monday,
+            Day::Tuesday,
+            Day::Wednesday,
+            Day::Thursday,
+            Day::Friday,
+            Day::Saturday,
+            Day::Sunday,
+        ]
+    }
+}
+
+/// Which journeys to count, by the days they operate on
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DaySelector {
+    Day(Day),
+    /// Journeys operating on at least one day of the group
+    AnyOf(DayGroup),
+    /// Journeys operating on every day of the group
+    AllOf(DayGroup),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DayGroup {
+    Weekday,
+    Weekend,
+}
+
+impl DayGroup {
+    pub fn days(&self) -> &'static [Day] {
+        match self {
+            DayGroup::Weekday => &[
+                Day::Monday,
+                Day::Tuesday,
+                Day::Wednesday,
+                Day::Thursday,
+                Day::Friday,
+            ],
+            DayGroup::Weekend => &[Day::Saturday, Day::Sunday],
+        }
+    }
+}
+
+impl DaySelector {
+    pub fn selects(&self, operating_days: &OperatingDays) -> bool {
+        match self {
+            DaySelector::Day(day) => operating_days.contains(day),
+            DaySelector::AnyOf(group) => {
+                group.days().iter().any(|day| operating_days.contains(day))
+            }
+            DaySelector::AllOf(group) => {
+                group.days().iter().all(|day| operating_days.contains(day))
+            }
         }
     }
 }
 
-impl ToString for Day {
-    fn to_string(&self) -> String {
+impl fmt::Display for DaySelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Day::Monday => "Monday".to_string(),
-            Day::Tuesday => "Tuesday".to_string(),
-            Day::Wednesday => "Wednesday".to_string(),
-            Day::Thursday => "Thursday".to_string(),
-            Day::Friday => "Friday".to_string(),
-            Day::Saturday => "Saturday".to_string(),
-            Day::Sunday => "Sunday".to_string(),
+            DaySelector::Day(day) => write!(f, "{day}"),
+            DaySelector::AnyOf(DayGroup::Weekday) => write!(f, "weekday"),
+            DaySelector::AnyOf(DayGroup::Weekend) => write!(f, "weekend"),
+            DaySelector::AllOf(DayGroup::Weekday) => write!(f, "every_weekday"),
+            DaySelector::AllOf(DayGroup::Weekend) => write!(f, "every_weekend_day"),
         }
     }
 }
 
-pub trait TimeConversion {
-    fn from_24hr_str(s: &str) -> Self;
+impl Serialize for DaySelector {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Whether a weekday or weekend `--operating-day` selects journeys operating on any or on all
+/// of its days
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum DayMatch {
+    #[default]
+    Any,
+    All,
+}
+
+/// The `--operating-day` value: a single day, a weekday or weekend, or all seven days counted
+/// in one pass
+#[derive(Debug, Clone, PartialEq)]
+pub enum DayChoice {
+    Day(Day),
+    Weekday,
+    Weekend,
+    All,
+}
+
+impl DayChoice {
+    pub fn selectors(&self, day_match: DayMatch) -> Vec<DaySelector> {
+        let group_selector = |group| match day_match {
+            DayMatch::Any => DaySelector::AnyOf(group),
+            DayMatch::All => DaySelector::AllOf(group),
+        };
+        match self {
+            DayChoice::Day(day) => vec![DaySelector::Day(day.clone())],
+            DayChoice::Weekday => vec![group_selector(DayGroup::Weekday)],
+            DayChoice::Weekend => vec![group_selector(DayGroup::Weekend)],
+            DayChoice::All => Day::all().into_iter().map(DaySelector::Day).collect(),
+        }
+    }
+}
+
+impl FromStr for DayChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "all" => return Ok(DayChoice::All),
+            "weekday" => return Ok(DayChoice::Weekday),
+            "weekend" => return Ok(DayChoice::Weekend),
+            _ => {}
+        }
+        Day::from_str(s)
+            .map(DayChoice::Day)
+            .map_err(|e| format!("{e}, or weekday, weekend or all"))
+    }
+}
+
+impl fmt::Display for DayChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DayChoice::Day(day) => write!(f, "{day}"),
+            DayChoice::Weekday => write!(f, "weekday"),
+            DayChoice::Weekend => write!(f, "weekend"),
+            DayChoice::All => write!(f, "all"),
+        }
+    }
+}
+
+impl fmt::Display for Day {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Day::Monday => "Monday",
+            Day::Tuesday => "Tuesday",
+            Day::Wednesday => "Wednesday",
+            Day::Thursday => "Thursday",
+            Day::Friday => "Friday",
+            Day::Saturday => "Saturday",
+            Day::Sunday => "Sunday",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Why a time could not be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeParseError {
+    /// Not the four digits of HHMM
+    NotDigits,
+    /// An hour past 23. Working times start again at 0000 after midnight, so there are no
+    /// continuation hours such as 2430.
+    HourOutOfRange(usize),
+    MinuteOutOfRange(usize),
+    SecondOutOfRange(usize),
+    /// A working time ending in something other than a blank or "H"
+    InvalidSuffix,
+}
+
+impl fmt::Display for TimeParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeParseError::NotDigits => write!(f, "expected HHMM or HH:MM"),
+            TimeParseError::HourOutOfRange(hours) => write!(f, "hour {hours} is past 23"),
+            TimeParseError::MinuteOutOfRange(minutes) => {
+                write!(f, "minute {minutes} is past 59")
+            }
+            TimeParseError::SecondOutOfRange(seconds) => {
+                write!(f, "second {seconds} is past 59")
+            }
+            TimeParseError::InvalidSuffix => write!(f, "expected a blank or H after HHMM"),
+        }
+    }
+}
+
+impl std::error::Error for TimeParseError {}
+
+pub trait TimeConversion: Sized {
+    /// Four digits, HHMM
+    fn from_24hr_str(s: &str) -> Result<Self, TimeParseError>;
+    /// A five character working time field, HHMM followed by "H" for an extra half minute
+    fn from_working_time_str(s: &str) -> Result<Self, TimeParseError>;
 }
 
 impl TimeConversion for SecondsPastMidnight {
-    fn from_24hr_str(s: &str) -> Self {
-        let hours = s[0..2].parse::<usize>().unwrap();
-        let minutes = s[2..].parse::<usize>().unwrap();
-        SecondsPastMidnight((hours * 3600) + (minutes * 60))
+    fn from_24hr_str(s: &str) -> Result<Self, TimeParseError> {
+        if s.len() != 4 || !s.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(TimeParseError::NotDigits);
+        }
+        let (hours, minutes): (usize, usize) = (s[0..2].parse().unwrap(), s[2..].parse().unwrap());
+        if hours > 23 {
+            return Err(TimeParseError::HourOutOfRange(hours));
+        }
+        if minutes > 59 {
+            return Err(TimeParseError::MinuteOutOfRange(minutes));
+        }
+        Ok(SecondsPastMidnight((hours * 3600) + (minutes * 60)))
+    }
+
+    fn from_working_time_str(s: &str) -> Result<Self, TimeParseError> {
+        let (hhmm, suffix) = s.split_at_checked(4).ok_or(TimeParseError::NotDigits)?;
+        let SecondsPastMidnight(seconds) = Self::from_24hr_str(hhmm)?;
+        let half_minute = match suffix {
+            "" | " " => 0,
+            "H" => 30,
+            _ => return Err(TimeParseError::InvalidSuffix),
+        };
+        Ok(SecondsPastMidnight(seconds + half_minute))
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct JourneyRecordStop {
     pub tiploc: Tiploc,
     pub activity_flag: ActivityFlag,
-    pub _arrival_time: Option<SecondsPastMidnight>,
+    pub arrival_time: Option<SecondsPastMidnight>,
     pub departure_time: Option<SecondsPastMidnight>,
     pub is_first_stop: bool,
 }
 
 impl JourneyRecordStop {
     /// Denoted by "LO" in the CIF file
-    fn from_lo_str(s: &str) -> Option<Self> {
-        Some(JourneyRecordStop {
-            tiploc: Tiploc::from_str(&s[2..9]).unwrap(),
-            activity_flag: ActivityFlag::PickUpOnly, // As origin stop
-            _arrival_time: None,
-            departure_time: Some(SecondsPastMidnight::from_24hr_str(&s[10..14])),
+    fn from_lo_str(s: &str) -> Result<Self, LineError> {
+        let fields = Fields::new("LO", s, 14)?;
+        Ok(JourneyRecordStop {
+            tiploc: fields.parse("TIPLOC", 2, 9)?,
+            activity_flag: ActivityFlag::from_activity_codes(
+                fields.get(29, 41),
+                ActivityFlag::PickUpOnly,
+            ),
+            arrival_time: None,
+            departure_time: fields.working_time("departure time", 10, 15)?,
             is_first_stop: true,
         })
     }
-    /// Denoted by "LI" in the CIF file
-    fn from_li_str(s: &str) -> Option<Self> {
-        if !&s[20..24].trim().is_empty() {
-            return None;
+    /// Denoted by "LI" in the CIF file, or None for a passing point
+    fn from_li_str(s: &str) -> Result<Option<Self>, LineError> {
+        let fields = Fields::new("LI", s, 9)?;
+        if !fields.get(20, 24).trim().is_empty() {
+            return Ok(None);
         }
-        Some(JourneyRecordStop {
-            tiploc: Tiploc::from_str(&s[2..9]).unwrap(),
-            _arrival_time: Some(SecondsPastMidnight::from_24hr_str(&s[10..14])),
-            departure_time: Some(SecondsPastMidnight::from_24hr_str(&s[15..19])),
-            activity_flag: ActivityFlag::from_str(s[42..54].trim()).unwrap(),
+        Ok(Some(JourneyRecordStop {
+            tiploc: fields.parse("TIPLOC", 2, 9)?,
+            arrival_time: fields.working_time("arrival time", 10, 15)?,
+            departure_time: fields.working_time("departure time", 15, 20)?,
+            activity_flag: ActivityFlag::from_str(fields.get(42, 54).trim()).unwrap(),
             is_first_stop: false,
-        })
+        }))
     }
     /// Denoted by "LT" in the CIF file
-    fn from_lt_str(s: &str) -> Option<Self> {
-        Some(JourneyRecordStop {
-            tiploc: Tiploc::from_str(&s[2..9]).unwrap(),
-            activity_flag: ActivityFlag::SetDownOnly, // As final stop
-            _arrival_time: Some(SecondsPastMidnight::from_24hr_str(&s[10..14])),
+    fn from_lt_str(s: &str) -> Result<Self, LineError> {
+        let fields = Fields::new("LT", s, 14)?;
+        Ok(JourneyRecordStop {
+            tiploc: fields.parse("TIPLOC", 2, 9)?,
+            activity_flag: ActivityFlag::from_activity_codes(
+                fields.get(25, 37),
+                ActivityFlag::SetDownOnly,
+            ),
+            arrival_time: fields.working_time("arrival time", 10, 15)?,
             departure_time: None,
             is_first_stop: false,
         })
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A journey header with the stops that follow it in the CIF file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journey {
+    pub header: JourneyHeader,
+    /// From the BX record, when the journey has one
+    pub atoc_code: Option<String>,
+    /// The text of its TN records, in file order
+    #[serde(default)]
+    pub notes: Vec<String>,
+    pub stops: Vec<JourneyRecordStop>,
+}
+
+impl Journey {
+    /// The first of `patterns` found in one of the journey's notes, ignoring case
+    pub fn note_matching<'a>(&self, patterns: &'a [String]) -> Option<&'a str> {
+        patterns
+            .iter()
+            .find(|pattern| {
+                let pattern = pattern.to_lowercase();
+                self.notes
+                    .iter()
+                    .any(|note| note.to_lowercase().contains(&pattern))
+            })
+            .map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// Stops that came before any journey header
+    NoHeader,
+    /// A journey header followed by no stops, or only one, so there is no journey to count
+    TooFewStops,
+}
+
+/// Records that could not be assembled into a [`Journey`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedJourney {
+    pub reason: RejectReason,
+    pub header: Option<JourneyHeader>,
+    pub stops: Vec<JourneyRecordStop>,
+}
+
+/// The records of one timetable file, such as a quarter, with the date it was extracted from
+/// its HD header
+pub struct TimetableFile {
+    pub records: Vec<Record>,
+    pub date_of_extract: Option<Date>,
+}
+
+/// Join the records of timetable files whose validity periods overlap, such as consecutive
+/// quarters, so that a journey running over `operating_date` in more than one of them is only
+/// counted once. Journeys are the same when their operator and UID are, and are kept from the
+/// file extracted last, or the first given of those extracted the same day. Returns the
+/// records, in file order, and how many journeys were left out.
+pub fn merge_overlapping_files(
+    files: Vec<TimetableFile>,
+    operating_date: &Date,
+) -> (Vec<Record>, usize) {
+    // The header index, operator and UID of each journey running over the date
+    let running: Vec<Vec<(usize, Option<&str>, &str)>> = files
+        .iter()
+        .map(|file| {
+            let records = &file.records;
+            records
+                .iter()
+                .enumerate()
+                .filter_map(|(index, record)| match record {
+                    Record::JourneyHeader(header)
+                        if header.date_runs_from.0 <= operating_date.0
+                            && operating_date.0 <= header.date_runs_to.0 =>
+                    {
+                        let atoc_code = match records.get(index + 1) {
+                            Some(Record::JourneyExtra(extra)) if !extra.atoc_code.is_empty() => {
+                                Some(extra.atoc_code.as_str())
+                            }
+                            _ => None,
+                        };
+                        Some((index, atoc_code, header.uid.as_str()))
+                    }
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut preference: Vec<usize> = (0..files.len()).collect();
+    preference.sort_by_key(|&file| {
+        std::cmp::Reverse(files[file].date_of_extract.as_ref().map(|date| date.0))
+    });
+    let mut kept_in: HashMap<(Option<&str>, &str), usize> = HashMap::new();
+    let mut superseded: Vec<HashSet<usize>> = vec![HashSet::new(); files.len()];
+    for file in preference {
+        for &(index, atoc_code, uid) in &running[file] {
+            match kept_in.entry((atoc_code, uid)) {
+                Entry::Occupied(kept) if *kept.get() != file => {
+                    superseded[file].insert(index);
+                }
+                Entry::Occupied(_) => {}
+                Entry::Vacant(entry) => {
+                    entry.insert(file);
+                }
+            }
+        }
+    }
+
+    let superseded_count = superseded.iter().map(HashSet::len).sum();
+    let mut records = Vec::new();
+    for (file, superseded) in files.into_iter().zip(superseded) {
+        let mut skipping = false;
+        for (index, record) in file.records.into_iter().enumerate() {
+            match record {
+                Record::JourneyHeader(_) => skipping = superseded.contains(&index),
+                Record::Stop(_) => skipping = false,
+                Record::JourneyExtra(_) | Record::JourneyNote(_) | Record::JourneyRecordStop(_) => {
+                }
+            }
+            if !skipping {
+                records.push(record);
+            }
+        }
+    }
+    (records, superseded_count)
+}
+
+/// Group the journey stops in `records` under the journey header before them
+pub fn assemble_journeys(records: Vec<Record>) -> Vec<Journey> {
+    assemble_journeys_with_rejects(records).0
+}
+
+/// [`assemble_journeys`], also returning the records that don't form a journey
+pub fn assemble_journeys_with_rejects(
+    records: Vec<Record>,
+) -> (Vec<Journey>, Vec<RejectedJourney>) {
+    let mut journeys: Vec<Journey> = Vec::new();
+    let mut rejects: Vec<RejectedJourney> = Vec::new();
+    let mut current_header: Option<JourneyHeader> = None;
+    let mut current_atoc_code: Option<String> = None;
+    let mut current_notes: Vec<String> = Vec::new();
+    let mut current_stops: Vec<JourneyRecordStop> = Vec::new();
+
+    let mut finish_journey =
+        |header: Option<JourneyHeader>,
+         atoc_code: Option<String>,
+         notes: Vec<String>,
+         stops: Vec<JourneyRecordStop>| match header {
+            Some(header) if stops.len() > 1 => journeys.push(Journey {
+                header,
+                atoc_code,
+                notes,
+                stops,
+            }),
+            None if stops.is_empty() => {}
+            header => rejects.push(RejectedJourney {
+                reason: if header.is_some() {
+                    RejectReason::TooFewStops
+                } else {
+                    RejectReason::NoHeader
+                },
+                header,
+                stops,
+            }),
+        };
+
+    for record in records {
+        match record {
+            Record::JourneyHeader(header) => {
+                finish_journey(
+                    current_header.replace(header),
+                    current_atoc_code.take(),
+                    std::mem::take(&mut current_notes),
+                    std::mem::take(&mut current_stops),
+                );
+            }
+            // A blank code is no operator
+            Record::JourneyExtra(extra) => {
+                current_atoc_code = Some(extra.atoc_code).filter(|code| !code.is_empty())
+            }
+            Record::JourneyNote(note) => current_notes.push(note.note),
+            Record::JourneyRecordStop(stop) => current_stops.push(stop),
+            Record::Stop(_) => {}
+        }
+    }
+    finish_journey(
+        current_header,
+        current_atoc_code,
+        current_notes,
+        current_stops,
+    );
+    (journeys, rejects)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ActivityFlag {
     Both,
     PickUpOnly,
@@ -337,6 +1678,29 @@ pub enum ActivityFlag {
     Neither,
 }
 
+impl ActivityFlag {
+    /// The flag of an origin or terminus from the two character activity codes of its LO or
+    /// LT record, such as "TB" (train begins) then "D " (set down only), or `default` when
+    /// none of them say what passengers can do, as a lone "TB" or "TF" doesn't
+    fn from_activity_codes(field: &str, default: ActivityFlag) -> ActivityFlag {
+        let (mut picks_up, mut sets_down) = (false, false);
+        for code in field.as_bytes().chunks(2) {
+            match code {
+                b"T " | b"R " | b"T" | b"R" => (picks_up, sets_down) = (true, true),
+                b"U " | b"U" => picks_up = true,
+                b"D " | b"D" => sets_down = true,
+                _ => {}
+            }
+        }
+        match (picks_up, sets_down) {
+            (true, true) => ActivityFlag::Both,
+            (true, false) => ActivityFlag::PickUpOnly,
+            (false, true) => ActivityFlag::SetDownOnly,
+            (false, false) => default,
+        }
+    }
+}
+
 impl FromStr for ActivityFlag {
     type Err = ();
 
@@ -354,8 +1718,7 @@ impl FromStr for ActivityFlag {
                 _ => Ok(ActivityFlag::Neither),
             }
         } else {
-            let first_two_chars = &s[0..2];
-            match first_two_chars {
+            match s.get(0..2).unwrap_or("") {
                 "T " => Ok(ActivityFlag::Both),
                 "R " => Ok(ActivityFlag::Both), // "Request Stop" treated as Both
                 "D " => Ok(ActivityFlag::SetDownOnly),
@@ -366,7 +1729,7 @@ impl FromStr for ActivityFlag {
     }
 }
 
-#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
 pub struct ThreeAlphaCode(pub String);
 
 impl FromStr for ThreeAlphaCode {
@@ -380,7 +1743,7 @@ impl FromStr for ThreeAlphaCode {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Tiploc(pub String);
 
 impl FromStr for Tiploc {
@@ -394,33 +1757,968 @@ impl FromStr for Tiploc {
         }
     }
 }
-/// Denoted by "QL" in the CIF file
-#[derive(Debug)]
+/// Denoted by "TI" in the CIF file
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Stop {
     pub tiploc: Tiploc,
-    pub _nlc: String, // National Location Code
-    pub _tps_description: String,
+    /// The National Location Code
+    #[serde(alias = "_nlc")]
+    pub nlc: String,
+    pub tps_description: String,
     pub stanox: String,
     pub three_alpha_code: Option<ThreeAlphaCode>,
-    pub _nlc_description: String,
+    /// The Post Office MCP code, when the line has one
+    pub po_mcp_code: Option<String>,
+    /// The 16 character description of the NLC, such as "LONDON KINGS CRS", when the line
+    /// has one
+    pub nlc_description: Option<String>,
 }
 
 impl Stop {
-    fn from_ti_str(ti_string: &str) -> Option<Self> {
-        // Parse the QL string and extract the relevant fields
-        let three_alpha_code_str = ti_string[53..56].trim();
+    fn from_ti_str(ti_string: &str) -> Result<Self, LineError> {
+        // Parse the TI string and extract the relevant fields
+        let fields = Fields::new("TI", ti_string, 9)?;
+        let field = |start: usize, end: usize| fields.get(start, end);
+        // The trailing fields are often left off, leaving lines shorter than the full width
+        let optional_field = |start: usize, end: usize| {
+            Some(field(start, end).trim())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        let three_alpha_code_str = field(53, 56).trim();
         let three_alpha_code = if three_alpha_code_str.is_empty() {
             None
         } else {
-            Some(ThreeAlphaCode::from_str(three_alpha_code_str).unwrap())
+            Some(
+                ThreeAlphaCode::from_str(three_alpha_code_str)
+                    .map_err(|_| fields.invalid("three alpha code", three_alpha_code_str))?,
+            )
         };
-        Some(Stop {
-            tiploc: Tiploc::from_str(&ti_string[2..9]).unwrap(),
-            _nlc: ti_string[11..17].trim().to_string(),
-            _tps_description: ti_string[18..44].trim().to_string(),
-            stanox: ti_string[44..49].trim().to_string(),
+        Ok(Stop {
+            tiploc: fields.parse("TIPLOC", 2, 9)?,
+            nlc: field(11, 17).trim().to_string(),
+            tps_description: field(18, 44).trim().to_string(),
+            stanox: field(44, 49).trim().to_string(),
             three_alpha_code,
-            _nlc_description: ti_string[56..72].trim().to_string(),
+            po_mcp_code: optional_field(49, 53),
+            nlc_description: optional_field(56, 72),
         })
     }
 }
+
+/// The `start` to `end` columns of a fixed width line, counted in characters so that accented
+/// names, which take more than one byte, don't shift the fields after them. Columns past the
+/// end of the line are left out.
+fn fixed_width_field(line: &str, start: usize, end: usize) -> &str {
+    if line.is_ascii() {
+        return &line[start.min(line.len())..end.min(line.len())];
+    }
+    let byte_offset = |column: usize| {
+        line.char_indices()
+            .nth(column)
+            .map_or(line.len(), |(offset, _)| offset)
+    };
+    &line[byte_offset(start)..byte_offset(end)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hour_grouping::{GroupingContext, group_days_with_stats};
+    use crate::test_cif;
+
+    fn selected(operating_days: &str) -> Vec<String> {
+        let operating_days = OperatingDays::from_cif_str(operating_days);
+        [
+            DaySelector::Day(Day::Tuesday),
+            DaySelector::Day(Day::Saturday),
+            DaySelector::AnyOf(DayGroup::Weekday),
+            DaySelector::AllOf(DayGroup::Weekday),
+            DaySelector::AnyOf(DayGroup::Weekend),
+            DaySelector::AllOf(DayGroup::Weekend),
+        ]
+        .into_iter()
+        .filter(|selector| selector.selects(&operating_days))
+        .map(|selector| selector.to_string())
+        .collect()
+    }
+
+    const HEADER: &str = "HDTPS.UDFROC1.PD2601010101260600DFROC1A       FA010126311226";
+
+    #[test]
+    fn test_parse_summary_counts_each_record_type() {
+        let cif_header = &format!("{HEADER:<80}");
+        let cif = test_cif::cif([
+            HEADER,
+            test_cif::STATIONS[0],
+            "BSNC123452601012612311111100 POO",
+            "BX         GRY",
+            "LOKNGX    0800 0800",
+            "LIHITCHIN           0830",
+            "LISTEVNGE 0820 0821      08200821         T",
+            "LTCAMBDGE 0900 0900",
+            "ZZ",
+        ]);
+
+        let (records, summary) = parse_with_summary(cif);
+        assert_eq!(records.len(), 6);
+        assert!(matches!(records[1], Record::JourneyHeader(_)));
+        assert_eq!(
+            summary,
+            ParseSummary {
+                bs: 1,
+                bx: 1,
+                tn: 0,
+                ti: 1,
+                lo: 1,
+                li: 1,
+                lt: 1,
+                ignored: 1,
+                other: 1,
+                blank: 0,
+                malformed: 0,
+                first_malformed: None,
+                headers: vec![FileHeader::from_hd_str(cif_header)],
+                no_operating_days: 0,
+                no_operating_days_sample: Vec::new(),
+            }
+        );
+        assert_eq!(summary.headers[0].version, "A");
+        assert_eq!(summary.headers[0].user_start_date, Some(Date(260101)));
+        assert_eq!(summary.headers[0].user_end_date, Some(Date(261231)));
+    }
+
+    #[test]
+    fn test_journeys_with_no_operating_days_are_counted() {
+        let cif = test_cif::cif([
+            "BSNC100012601012612310000000 POO",
+            "BSNC100022601012612311111100 POO",
+            "BSDC100032601012612310000000 POO",
+            "BSNC100042601012612310000000 POO",
+        ]);
+
+        let (_, summary) = parse_with_summary(cif);
+        // The deleted schedule doesn't count
+        assert_eq!(summary.no_operating_days, 2);
+        assert_eq!(summary.no_operating_days_sample, ["C10001", "C10004"]);
+    }
+
+    #[test]
+    fn test_parse_numbered_keeps_the_lines_of_the_range() {
+        let cif = test_cif::cif([
+            HEADER,
+            "BSNC123452601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LIHITCHIN           0830",
+            "LTCAMBDGE 08x0 0900",
+            "ZZ",
+        ]);
+
+        let range: LineRange = "3:5".parse().unwrap();
+        let numbered = parse_numbered(&cif, Some(range));
+        assert_eq!(
+            numbered
+                .iter()
+                .map(|line| line.line_number)
+                .collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+        assert!(numbered[0].raw.starts_with("LOKNGX    0800"));
+        assert!(matches!(
+            numbered[0].record,
+            Some(Record::JourneyRecordStop(_))
+        ));
+        // A passing point is dropped
+        assert!(numbered[1].record.is_none() && numbered[1].error.is_none());
+        assert!(numbered[2].error.as_deref().unwrap().contains("08x0"));
+        assert_eq!(parse_numbered(&cif, None).len(), 6);
+
+        for invalid in ["0:5", "5:3", "5", "a:b"] {
+            assert!(invalid.parse::<LineRange>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_concatenated_file_headers_are_all_captured() {
+        let cif = test_cif::cif([
+            HEADER,
+            test_cif::STATIONS[0],
+            "ZZ",
+            "HDTPS.UDFROC1.PD2601020201260600DFROC1BDFROC1AUB020126311226",
+            test_cif::STATIONS[2],
+            "ZZ",
+        ]);
+
+        let (records, summary) = parse_with_summary(cif);
+        assert_eq!(records.len(), 2);
+        assert!(
+            records
+                .iter()
+                .all(|record| matches!(record, Record::Stop(_)))
+        );
+        let references: Vec<(&str, &str)> = summary
+            .headers
+            .iter()
+            .map(|header| {
+                (
+                    header.current_file_reference.as_str(),
+                    header.version.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(references, [("DFROC1A", "A"), ("DFROC1B", "B")]);
+        assert!(!summary.headers[1].is_supported_version());
+    }
+
+    #[test]
+    fn test_newest_extract_age() {
+        // Extracted on 1 and 2 January 2026
+        let cif = test_cif::cif([
+            HEADER,
+            "ZZ",
+            "HDTPS.UDFROC1.PD2601020201260600DFROC1BDFROC1AUA020126311226",
+            "ZZ",
+        ]);
+        let (_, summary) = parse_with_summary(cif);
+        let today: CalendarDate = "2026-01-30".parse().unwrap();
+        assert_eq!(newest_extract_age_days(&summary.headers, today), Some(28));
+        assert_eq!(
+            newest_extract_age_days(&summary.headers[..1], today),
+            Some(29)
+        );
+        assert_eq!(newest_extract_age_days(&[], today), None);
+
+        for date in ["1970-01-01", "2000-02-29", "2026-01-13", "2026-12-31"] {
+            let date: CalendarDate = date.parse().unwrap();
+            assert_eq!(
+                CalendarDate::from_days_since_epoch(date.days_since_epoch()),
+                date
+            );
+        }
+        assert_eq!(
+            Date(260113).to_calendar_date(),
+            "2026-01-13".parse::<CalendarDate>().ok()
+        );
+        assert_eq!(Date(261332).to_calendar_date(), None);
+    }
+
+    #[test]
+    fn test_working_times_keep_the_half_minute() {
+        assert_eq!(
+            SecondsPastMidnight::from_working_time_str("0820 "),
+            Ok(SecondsPastMidnight(8 * 3600 + 20 * 60))
+        );
+        assert_eq!(
+            SecondsPastMidnight::from_working_time_str("0820H"),
+            Ok(SecondsPastMidnight(8 * 3600 + 20 * 60 + 30))
+        );
+
+        let line = format!("{:<80}", "LISTEVNGE 0820H0821H     08200821         T");
+        let stop = JourneyRecordStop::from_li_str(&line).unwrap().unwrap();
+        assert_eq!(
+            stop.arrival_time,
+            Some(SecondsPastMidnight(8 * 3600 + 20 * 60 + 30))
+        );
+        assert_eq!(
+            stop.departure_time,
+            Some(SecondsPastMidnight(8 * 3600 + 21 * 60 + 30))
+        );
+    }
+
+    #[test]
+    fn test_blank_working_times_parse_as_none() {
+        let line = format!("{:<80}", "LISTEVNGE 0820            08200000         R");
+        let stop = JourneyRecordStop::from_li_str(&line).unwrap().unwrap();
+        assert_eq!(
+            stop.arrival_time,
+            Some(SecondsPastMidnight(8 * 3600 + 20 * 60))
+        );
+        assert_eq!(stop.departure_time, None);
+    }
+
+    #[test]
+    fn test_origin_and_terminus_activity_flags() {
+        let origin = |activity: &str| {
+            let line = format!("{:<80}", format!("LOKNGX    0800 0800          {activity}"));
+            JourneyRecordStop::from_lo_str(&line).unwrap().activity_flag
+        };
+        assert_eq!(origin("TB"), ActivityFlag::PickUpOnly);
+        assert_eq!(origin(""), ActivityFlag::PickUpOnly);
+        assert_eq!(origin("TBD "), ActivityFlag::SetDownOnly);
+        assert_eq!(origin("TBT "), ActivityFlag::Both);
+        // Lines cut short before the activity field
+        let stop = JourneyRecordStop::from_lo_str("LOKNGX    0800 0800").unwrap();
+        assert_eq!(stop.activity_flag, ActivityFlag::PickUpOnly);
+
+        let terminus = |activity: &str| {
+            let line = format!("{:<80}", format!("LTCAMBDGE 0900 0900      {activity}"));
+            JourneyRecordStop::from_lt_str(&line).unwrap().activity_flag
+        };
+        assert_eq!(terminus("TF"), ActivityFlag::SetDownOnly);
+        assert_eq!(terminus("TFU "), ActivityFlag::PickUpOnly);
+        assert_eq!(terminus("R TF"), ActivityFlag::Both);
+    }
+
+    #[test]
+    fn test_accented_stop_names_keep_later_fields_in_place() {
+        let line = format!(
+            "{:<80}",
+            "TIPONTYPR00121000 PONTYPRIDD GARé           87701    PPDPONTYPRIDD"
+        );
+        let stop = Stop::from_ti_str(&line).unwrap();
+        assert_eq!(stop.tps_description, "PONTYPRIDD GARé");
+        assert_eq!(stop.stanox, "87701");
+        assert_eq!(
+            stop.three_alpha_code,
+            Some(ThreeAlphaCode("PPD".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_trailing_ti_fields_are_optional() {
+        let full = format!(
+            "{:<80}",
+            "TIKNGX   00121000 LONDON KINGS CROSS        877010123KGXLONDON KINGS CRS"
+        );
+        let stop = Stop::from_ti_str(&full).unwrap();
+        assert_eq!(stop.po_mcp_code.as_deref(), Some("0123"));
+        assert_eq!(stop.nlc_description.as_deref(), Some("LONDON KINGS CRS"));
+
+        let stop = Stop::from_ti_str("TIKNGX   00121000 LONDON KINGS CROSS        87701").unwrap();
+        assert_eq!(stop.stanox, "87701");
+        assert_eq!(stop.po_mcp_code, None);
+        assert_eq!(stop.three_alpha_code, None);
+        assert_eq!(stop.nlc_description, None);
+    }
+
+    #[test]
+    fn test_operator_codes_are_upper_case_and_blank_is_none() {
+        let cif = test_cif::cif([
+            "BSNC100012601012612311111100 POO",
+            "BX         gr",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+            "BSNC100022601012612311111100 POO",
+            "BX",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+        ]);
+
+        let journeys = assemble_journeys(parse(cif));
+        assert_eq!(journeys[0].atoc_code.as_deref(), Some("GR"));
+        assert_eq!(journeys[1].atoc_code, None);
+    }
+
+    #[test]
+    fn test_journey_notes() {
+        let cif = test_cif::cif([
+            "BSNC100012601012612311111100 POO",
+            "BX         GRY",
+            "TNARuns on schooldays only",
+            "TNADoes not convey passengers beyond Stevenage",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+            "BSNC100022601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+        ]);
+
+        let (records, summary) = parse_with_summary(cif);
+        assert_eq!(summary.tn, 2);
+        let journeys = assemble_journeys(records);
+        assert_eq!(
+            journeys[0].notes,
+            [
+                "Runs on schooldays only",
+                "Does not convey passengers beyond Stevenage"
+            ]
+        );
+        assert!(journeys[1].notes.is_empty());
+
+        let patterns = ["bank holiday".to_string(), "SCHOOLDAYS".to_string()];
+        assert_eq!(journeys[0].note_matching(&patterns), Some("SCHOOLDAYS"));
+        assert_eq!(journeys[1].note_matching(&patterns), None);
+
+        let mut context = GroupingContext::for_week(Date(260113));
+        context.exclude_note_patterns = patterns.to_vec();
+        let selectors = [DaySelector::Day(Day::Tuesday)];
+        let (_, stats) = group_days_with_stats(&journeys, &HashMap::new(), &selectors, &context);
+        assert_eq!(stats.excluded_by_note, 1);
+    }
+
+    #[test]
+    fn test_assemble_journeys_rejects_orphan_stops_and_short_journeys() {
+        let cif = test_cif::cif([
+            "LOKNGX    0700 0700",
+            "BSNC123452601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+            "BSNC123462601012612311111100 POO",
+            "LOKNGX    1000 1000",
+            "BSNC123472601012612311111100 POO",
+            "BX         GRY",
+            "LOKNGX    1100 1100",
+            "LISTEVNGE 1120 1121      11201121         T",
+            "LTCAMBDGE 1200 1200",
+        ]);
+
+        let (journeys, rejects) = assemble_journeys_with_rejects(parse(cif));
+        let uids: Vec<&str> = journeys.iter().map(|j| j.header.uid.as_str()).collect();
+        assert_eq!(uids, ["C12345", "C12347"]);
+        assert_eq!(journeys[1].stops.len(), 3);
+        assert_eq!(journeys[0].atoc_code, None);
+        assert_eq!(journeys[1].atoc_code.as_deref(), Some("GR"));
+        let reasons: Vec<&RejectReason> = rejects.iter().map(|r| &r.reason).collect();
+        assert_eq!(
+            reasons,
+            [&RejectReason::NoHeader, &RejectReason::TooFewStops]
+        );
+        assert_eq!(rejects[1].header.as_ref().unwrap().uid, "C12346");
+    }
+
+    #[test]
+    fn test_check_starts_with_header() {
+        assert!(check_starts_with_header(HEADER).is_ok());
+        assert!(check_starts_with_header("ATCO-CIF0510").is_err());
+        let empty = check_starts_with_header("\n").unwrap_err();
+        assert_eq!(empty.to_string(), "The CIF file is empty");
+    }
+
+    fn journey_lines() -> Vec<String> {
+        [
+            HEADER,
+            test_cif::STATIONS[0],
+            "BSNC123452601012612311111100 POO",
+            "LOKNGX    0800 0800",
+            "LTCAMBDGE 0900 0900",
+        ]
+        .map(|line| format!("{line:<80}"))
+        .to_vec()
+    }
+
+    #[test]
+    fn test_stops_only_filter_skips_journey_records() {
+        let options = ParseOptions {
+            filter: RecordFilter::StopsOnly,
+            ..ParseOptions::default()
+        };
+        let (records, summary) = parse_with_options(journey_lines().join("\n"), options);
+        assert_eq!(records.len(), 1);
+        assert!(matches!(records[0], Record::Stop(_)));
+        assert_eq!((summary.ti, summary.bs, summary.ignored), (1, 0, 3));
+    }
+
+    #[test]
+    fn test_parse_empty_input() {
+        let (records, summary) = parse_with_summary(String::new());
+        assert!(records.is_empty());
+        assert_eq!(summary, ParseSummary::default());
+    }
+
+    #[test]
+    fn test_parse_header_only() {
+        let (records, summary) = parse_with_summary(format!("{HEADER:<80}\n"));
+        assert!(records.is_empty());
+        assert_eq!(summary.headers.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_line_endings_and_blank_lines() {
+        let expected = parse_with_summary(journey_lines().join("\n") + "\n");
+        let crlf = parse_with_summary(journey_lines().join("\r\n") + "\r\n");
+        let no_final_newline = parse_with_summary(journey_lines().join("\n"));
+        let blank_lines = parse_with_summary(journey_lines().join("\n\n  \n"));
+
+        for (records, summary) in [&crlf, &no_final_newline, &blank_lines] {
+            assert_eq!(records.len(), 4);
+            assert_eq!(summary.bs, expected.1.bs);
+            assert_eq!(summary.lt, expected.1.lt);
+            assert_eq!(summary.headers, expected.1.headers);
+        }
+        assert_eq!(expected.1.blank, 0);
+        assert_eq!(blank_lines.1.blank, 8);
+    }
+
+    #[test]
+    fn test_parse_in_chunks_matches_whole_file_parse() {
+        let cif = include_str!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.CIF");
+        let (records, summary) = parse_with_summary(cif.to_string());
+        for chunk_lines in [1, 7, cif.lines().count()] {
+            let (chunk_records, chunk_summary) =
+                parse_in_chunks(cif, chunk_lines, ParseOptions::default());
+            assert_eq!(
+                serde_json::to_value(&chunk_records).unwrap(),
+                serde_json::to_value(&records).unwrap()
+            );
+            assert_eq!(chunk_summary, summary);
+        }
+        let options = ParseOptions {
+            low_memory: true,
+            ..ParseOptions::default()
+        };
+        let (low_memory_records, low_memory_summary) = parse_with_options(cif.to_string(), options);
+        assert_eq!(low_memory_records.len(), records.len());
+        assert_eq!(low_memory_summary, summary);
+    }
+
+    /// A small repeatable random source for generating lines, in place of a property testing
+    /// crate
+    struct Lines(u64);
+
+    impl Lines {
+        fn below(&mut self, bound: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (self.0 >> 33) as usize % bound
+        }
+
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len())]
+        }
+
+        fn tiploc(&mut self) -> String {
+            let length = 1 + self.below(7);
+            (0..length)
+                .map(|_| self.pick(b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789") as char)
+                .collect()
+        }
+
+        /// A working time field and its value, often at the edges of the day
+        fn time(&mut self) -> (String, usize) {
+            let (hours, minutes) = match self.below(4) {
+                0 => (0, 0),
+                1 => (23, 59),
+                _ => (self.below(24), self.below(60)),
+            };
+            let half = self.pick(&[" ", "H"]);
+            let seconds = hours * 3600 + minutes * 60 + if half == "H" { 30 } else { 0 };
+            (format!("{hours:02}{minutes:02}{half}"), seconds)
+        }
+
+        /// A valid line of a random parsed record type, and what it should parse to
+        fn valid(&mut self) -> (String, String) {
+            let tiploc = self.tiploc();
+            let (line, expected) = match self.below(5) {
+                0 => {
+                    let (time, seconds) = self.time();
+                    (
+                        format!("LO{tiploc:<7} {time}{time}"),
+                        format!("{tiploc} None Some({seconds}) PickUpOnly"),
+                    )
+                }
+                1 => {
+                    let (arrival_time, arrival) = self.time();
+                    let (departure_time, departure) = self.time();
+                    let (activity, flag) = self.pick(&[
+                        ("T", "Both"),
+                        ("R", "Both"),
+                        ("D", "SetDownOnly"),
+                        ("U", "PickUpOnly"),
+                        (" ", "Neither"),
+                    ]);
+                    (
+                        format!(
+                            "LI{tiploc:<7} {arrival_time}{departure_time}     {:<17}{activity}",
+                            ""
+                        ),
+                        format!("{tiploc} Some({arrival}) Some({departure}) {flag}"),
+                    )
+                }
+                2 => {
+                    let (time, seconds) = self.time();
+                    (
+                        format!("LT{tiploc:<7} {time}{time}"),
+                        format!("{tiploc} Some({seconds}) None SetDownOnly"),
+                    )
+                }
+                3 => {
+                    let stanox = format!("{:05}", self.below(100_000));
+                    let code = self.pick(&["KGX", "SVG", "CBG", "   "]);
+                    (
+                        format!(
+                            "TI{tiploc:<7}00121000 {:<26}{stanox}    {code}",
+                            "SOMEWHERE"
+                        ),
+                        format!(
+                            "{tiploc} {stanox} {:?}",
+                            Some(code).filter(|code| code != &"   ")
+                        ),
+                    )
+                }
+                _ => {
+                    let status = self.pick(&["N", "D", "R"]);
+                    let days: String = (0..7).map(|_| self.pick(&['0', '1'])).collect();
+                    (
+                        format!(
+                            "BS{status}C{:05}260101261231{days} POO",
+                            self.below(100_000)
+                        ),
+                        format!("{status} 260101 261231 {days}"),
+                    )
+                }
+            };
+            // Trimmed and full width lines should read the same
+            match self.below(2) {
+                0 => (format!("{line:<80}"), expected),
+                _ => (line.trim_end().to_string(), expected),
+            }
+        }
+    }
+
+    /// The fields of a parsed line, in the form [`Lines::valid`] expects them
+    fn fields(record: &Record) -> String {
+        match record {
+            Record::JourneyRecordStop(stop) => format!(
+                "{} {:?} {:?} {:?}",
+                stop.tiploc.0,
+                stop.arrival_time.map(|time| time.0),
+                stop.departure_time.map(|time| time.0),
+                stop.activity_flag
+            ),
+            Record::Stop(stop) => format!(
+                "{} {} {:?}",
+                stop.tiploc.0,
+                stop.stanox,
+                stop.three_alpha_code.as_ref().map(|code| code.0.as_str())
+            ),
+            Record::JourneyHeader(header) => {
+                let status = match header.status {
+                    Status::New => "N",
+                    Status::Delete => "D",
+                    Status::Revise => "R",
+                };
+                let days = header.operating_days.to_cif_str();
+                format!(
+                    "{status} {} {} {days}",
+                    header.date_runs_from, header.date_runs_to
+                )
+            }
+            Record::JourneyExtra(_) | Record::JourneyNote(_) => {
+                unreachable!("no BX or TN lines are generated")
+            }
+        }
+    }
+
+    fn parse_one(line: &str) -> Result<Option<Record>, LineError> {
+        let record_identifier = RecordIdentifier::from_str(line.get(0..2).unwrap_or(line)).unwrap();
+        parse_line(&record_identifier, line)
+    }
+
+    #[test]
+    fn test_valid_lines_round_trip_their_fields() {
+        let mut lines = Lines(1);
+        for _ in 0..2_000 {
+            let (line, expected) = lines.valid();
+            let record = parse_one(&line).unwrap().unwrap();
+            assert_eq!(fields(&record), expected, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn test_short_lines_are_errors_not_panics() {
+        let required_widths = [("BS", 28), ("TI", 9), ("LO", 14), ("LI", 9), ("LT", 14)];
+        let mut lines = Lines(2);
+        for _ in 0..500 {
+            let (line, _) = lines.valid();
+            let required_width = required_widths
+                .iter()
+                .find(|(record_type, _)| line.starts_with(record_type))
+                .map(|(_, required_width)| *required_width)
+                .unwrap();
+            for width in 2..line.len() {
+                let result = parse_one(&line[..width]);
+                if width < required_width {
+                    assert!(
+                        matches!(result, Err(LineError::TooShort { .. })),
+                        "{:?}",
+                        &line[..width]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_garbled_lines_never_panic() {
+        let mut lines = Lines(3);
+        for _ in 0..2_000 {
+            let (line, _) = lines.valid();
+            let mut chars: Vec<char> = line.chars().collect();
+            for _ in 0..1 + lines.below(3) {
+                let column = 2 + lines.below(chars.len() - 2);
+                chars[column] = lines.pick(&['X', '9', ' ', 'H', '-', '\t', 'é', '€']);
+            }
+            let garbled: String = chars.into_iter().collect();
+            // Any result will do, as long as it is one
+            let _ = parse_one(&garbled);
+        }
+    }
+
+    #[test]
+    fn test_malformed_lines_are_counted_and_skipped() {
+        let cif = [
+            "BSNC123452601012612311111100 POO",
+            "LOKNGX    08",
+            "LISTEVNGE 08x0 0821      08200821         T",
+            "LTCAMBDGE 0900 0900",
+        ]
+        .join("\n");
+        let (records, summary) = parse_with_summary(cif);
+        assert_eq!(records.len(), 2);
+        assert_eq!(summary.malformed, 2);
+        assert_eq!(
+            summary.first_malformed.as_deref(),
+            Some("LO line is 12 characters wide, but needs at least 14")
+        );
+        assert_eq!(
+            parse_one("LISTEVNGE 08x0 0821").unwrap_err().to_string(),
+            "LI line has an invalid arrival time \"08x0 \": expected HHMM or HH:MM"
+        );
+    }
+
+    #[test]
+    fn test_times_display_and_parse_across_midnight() {
+        for (seconds, text) in [
+            (0, "00:00"),
+            (8 * 3600 + 20 * 60 + 30, "08:20:30"),
+            (86_340, "23:59"),
+            (86_399, "23:59:59"),
+            (86_400, "24:00"),
+            (87_300, "24:15"),
+        ] {
+            let time = SecondsPastMidnight(seconds);
+            assert_eq!(time.to_string(), text);
+            assert_eq!(text.parse(), Ok(time));
+            let json = serde_json::to_string(&time).unwrap();
+            assert_eq!(json, format!("{text:?}"));
+            assert_eq!(
+                serde_json::from_str::<SecondsPastMidnight>(&json).unwrap(),
+                time
+            );
+        }
+        assert_eq!("0830".parse(), Ok(SecondsPastMidnight(30_600)));
+        assert_eq!(
+            "2415".parse::<SecondsPastMidnight>(),
+            Err(TimeParseError::HourOutOfRange(24))
+        );
+        for invalid in [
+            "8:30",
+            "08:3",
+            "08:60",
+            "08:30:60",
+            "08:30:00:00",
+            "08:",
+            ":30",
+        ] {
+            assert!(invalid.parse::<SecondsPastMidnight>().is_err(), "{invalid}");
+        }
+        // Records cached before times were written as text
+        assert_eq!(
+            serde_json::from_str::<SecondsPastMidnight>("30600").unwrap(),
+            SecondsPastMidnight(30_600)
+        );
+    }
+
+    #[test]
+    fn test_24hr_times_are_validated() {
+        let time =
+            |hours: usize, minutes: usize| Ok(SecondsPastMidnight(hours * 3600 + minutes * 60));
+        for (input, expected) in [
+            ("0000", time(0, 0)),
+            ("0830", time(8, 30)),
+            ("2359", time(23, 59)),
+            ("2400", Err(TimeParseError::HourOutOfRange(24))),
+            ("2790", Err(TimeParseError::HourOutOfRange(27))),
+            ("9975", Err(TimeParseError::HourOutOfRange(99))),
+            ("0860", Err(TimeParseError::MinuteOutOfRange(60))),
+            ("ab12", Err(TimeParseError::NotDigits)),
+            ("08x0", Err(TimeParseError::NotDigits)),
+            (" 830", Err(TimeParseError::NotDigits)),
+            ("0830 ", Err(TimeParseError::NotDigits)),
+            ("830", Err(TimeParseError::NotDigits)),
+            ("", Err(TimeParseError::NotDigits)),
+            ("+830", Err(TimeParseError::NotDigits)),
+            ("０８３０", Err(TimeParseError::NotDigits)),
+        ] {
+            assert_eq!(
+                SecondsPastMidnight::from_24hr_str(input),
+                expected,
+                "{input:?}"
+            );
+        }
+
+        assert_eq!(
+            SecondsPastMidnight::from_working_time_str("2359H"),
+            Ok(SecondsPastMidnight(86_370))
+        );
+        assert_eq!(
+            SecondsPastMidnight::from_working_time_str("0830X"),
+            Err(TimeParseError::InvalidSuffix)
+        );
+        assert_eq!(
+            SecondsPastMidnight::from_working_time_str("08"),
+            Err(TimeParseError::NotDigits)
+        );
+        assert_eq!(
+            parse_one("LISTEVNGE 2430 0821").unwrap_err().to_string(),
+            "LI line has an invalid arrival time \"2430 \": hour 24 is past 23"
+        );
+    }
+
+    #[test]
+    fn test_error_budget() {
+        // One malformed line in 200 is half a percent
+        let summary = ParseSummary {
+            lo: 199,
+            malformed: 1,
+            ..ParseSummary::default()
+        };
+        assert_eq!(summary.error_rate(), 0.005);
+        assert!(!ErrorBudget::check(&summary, 0.0051).exceeded);
+        assert!(!ErrorBudget::check(&summary, 0.005).exceeded);
+        assert!(ErrorBudget::check(&summary, 0.0049).exceeded);
+        assert!(!ErrorBudget::check(&ParseSummary::default(), 0.0).exceeded);
+    }
+
+    #[test]
+    fn test_operating_days_single_day_masks() {
+        for (index, day) in Day::all().iter().enumerate() {
+            let cif_str: String = (0..7).map(|i| if i == index { '1' } else { '0' }).collect();
+            let operating_days = OperatingDays::from_cif_str(&cif_str);
+            assert_eq!(operating_days, [day.clone()].into_iter().collect());
+            assert_eq!(operating_days.len(), 1);
+            for other in Day::all() {
+                assert_eq!(
+                    operating_days.contains(&other),
+                    other == *day,
+                    "{cif_str} {other}"
+                );
+            }
+            assert_eq!(operating_days.to_string(), cif_str);
+        }
+        assert!(OperatingDays::from_cif_str("0000000").is_empty());
+        // A short or blank field runs on the days it gives
+        assert_eq!(OperatingDays::from_cif_str("11").to_string(), "1100000");
+    }
+
+    #[test]
+    fn test_operating_days_serde_round_trip() {
+        let operating_days = OperatingDays::from_cif_str("1111100");
+        let json = serde_json::to_string(&operating_days).unwrap();
+        assert_eq!(json, "\"1111100\"");
+        assert_eq!(
+            serde_json::from_str::<OperatingDays>(&json).unwrap(),
+            operating_days
+        );
+        assert!(serde_json::from_str::<OperatingDays>("\"11111\"").is_err());
+        assert!(serde_json::from_str::<OperatingDays>("\"111110x\"").is_err());
+    }
+
+    #[test]
+    fn test_day_selector_monday_to_friday_journey() {
+        assert_eq!(selected("1111100"), ["Tuesday", "weekday", "every_weekday"]);
+    }
+
+    #[test]
+    fn test_day_selector_saturday_only_journey() {
+        assert_eq!(selected("0000010"), ["Saturday", "weekend"]);
+    }
+
+    #[test]
+    fn test_day_selector_seven_day_journey() {
+        assert_eq!(
+            selected("1111111"),
+            [
+                "Tuesday",
+                "Saturday",
+                "weekday",
+                "every_weekday",
+                "weekend",
+                "every_weekend_day"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_day_selector_partial_week_journey_matches_any_but_not_all() {
+        assert_eq!(selected("0100001"), ["Tuesday", "weekday", "weekend"]);
+    }
+
+    #[test]
+    fn test_day_from_str_ignores_case_and_accepts_abbreviations() {
+        assert_eq!(Day::from_str("TUESDAY"), Ok(Day::Tuesday));
+        assert_eq!(Day::from_str("tue"), Ok(Day::Tuesday));
+        assert_eq!(Day::from_str("Sat"), Ok(Day::Saturday));
+        let error = Day::from_str("garbage").unwrap_err();
+        assert!(error.contains("\"garbage\""));
+        assert!(error.contains("Monday") && error.contains("Mon"));
+    }
+
+    #[test]
+    fn test_day_from_str_agrees_with_clap() {
+        for day in Day::all() {
+            let name = day.to_string();
+            for value in [name.clone(), name.to_uppercase(), name[..3].to_lowercase()] {
+                assert_eq!(Day::from_str(&value), Ok(day.clone()));
+                assert_eq!(
+                    <Day as clap::ValueEnum>::from_str(&value, true),
+                    Ok(day.clone())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_day_round_trips_through_serde() {
+        let json = serde_json::to_string(&Day::Wednesday).unwrap();
+        assert_eq!(json, "\"Wednesday\"");
+        assert_eq!(serde_json::from_str::<Day>(&json).unwrap(), Day::Wednesday);
+        assert_eq!(
+            serde_json::from_str::<Day>("\"wed\"").unwrap(),
+            Day::Wednesday
+        );
+    }
+
+    #[test]
+    fn test_calendar_date_weekday() {
+        let date: CalendarDate = "2025-12-02".parse().unwrap();
+        assert_eq!(date.weekday(), Day::Tuesday);
+        assert_eq!(date.to_cif_date(), Date(251202));
+        assert_eq!(date.to_string(), "2025-12-02");
+        assert_eq!(
+            "2024-02-29".parse::<CalendarDate>().unwrap().weekday(),
+            Day::Thursday
+        );
+        assert_eq!(
+            "2000-01-01".parse::<CalendarDate>().unwrap().weekday(),
+            Day::Saturday
+        );
+        // Dates are days, so the clocks changing on the last Sundays of March and October
+        // don't move them
+        for (date, weekday) in [
+            ("2026-03-28", Day::Saturday),
+            ("2026-03-29", Day::Sunday),
+            ("2026-03-30", Day::Monday),
+            ("2026-10-24", Day::Saturday),
+            ("2026-10-25", Day::Sunday),
+            ("2026-10-26", Day::Monday),
+        ] {
+            assert_eq!(date.parse::<CalendarDate>().unwrap().weekday(), weekday);
+        }
+    }
+
+    #[test]
+    fn test_calendar_date_rejects_invalid_dates() {
+        for value in [
+            "2023-02-29",
+            "2025-13-01",
+            "2025-04-31",
+            "25-12-02",
+            "2025/12/02",
+        ] {
+            assert!(value.parse::<CalendarDate>().is_err(), "{value}");
+        }
+    }
+}
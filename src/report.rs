@@ -0,0 +1,279 @@
+//! A Markdown summary of a run for readers who don't want the JSON: the run parameters, the
+//! criteria pass counts, the busiest stations, the stations closest to passing the all hours
+//! criteria and the stations that only narrowly fail.
+
+use std::collections::HashMap;
+
+use super::DayOutputs;
+use super::criteria::{CriteriaResults, CriteriaThresholds, closest_to_passing, evaluate_criteria};
+use super::hour_grouping::HourlyDepartures;
+use super::records::ThreeAlphaCode;
+use super::stops::{self, StationName};
+use super::utils::RunManifest;
+
+pub const REPORT_FILE_NAME: &str = "report.md";
+
+/// How many of the busiest stations the report lists
+const TOP_STATIONS: usize = 50;
+/// How many of the stations closest to passing each all hours criterion the report lists
+const CLOSEST_STATIONS: usize = 20;
+
+/// A criterion's name and whether a station's results pass it
+type Criterion = (&'static str, fn(&CriteriaResults) -> bool);
+
+const CRITERIA: [Criterion; 4] = [
+    ("all_7_7", |results| results.all_7_7),
+    ("all_6_10", |results| results.all_6_10),
+    ("avg_7_7", |results| results.avg_7_7),
+    ("avg_6_10", |results| results.avg_6_10),
+];
+
+/// An all hours criterion's name, and a station's shortfall and worst hour against it
+type Shortfall = (
+    &'static str,
+    fn(&CriteriaResults) -> u32,
+    fn(&CriteriaResults) -> Option<usize>,
+);
+
+const SHORTFALLS: [Shortfall; 2] = [
+    (
+        "all_7_7",
+        |results| results.all_7_7_shortfall,
+        |results| results.all_7_7_worst_hour,
+    ),
+    (
+        "all_6_10",
+        |results| results.all_6_10_shortfall,
+        |results| results.all_6_10_worst_hour,
+    ),
+];
+
+/// The report of a run, with a section per day
+pub fn render(
+    manifest: &RunManifest,
+    days: &[DayOutputs],
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+) -> String {
+    let mut report = String::from("# Rail hourly departures\n\n## Run parameters\n\n");
+    report.push_str(&format!("- Command: `{}`\n", manifest.arguments.join(" ")));
+    report.push_str(&format!("- Version: {}\n", manifest.crate_version));
+    report.push_str(&format!("- Operating day: {}\n", manifest.operating_day));
+    report.push_str(&format!("- Operating date: {}\n", manifest.operating_date));
+    if let Some(calendar_date) = &manifest.calendar_date {
+        report.push_str(&format!("- Calendar date: {calendar_date}\n"));
+    }
+    if let Some(station_filter) = &manifest.station_filter {
+        report.push_str(&format!("- Station filter: {station_filter}\n"));
+    }
+    for input in &manifest.inputs {
+        report.push_str(&format!("- Input: {}\n", input.path.display()));
+    }
+
+    let thresholds = manifest.criteria_thresholds.unwrap_or_default();
+    for day_outputs in days {
+        report.push_str(&render_day(day_outputs, station_names, &thresholds));
+    }
+    report
+}
+
+fn render_day(
+    day_outputs: &DayOutputs,
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+    thresholds: &CriteriaThresholds,
+) -> String {
+    let name = |three_alpha_code: &ThreeAlphaCode| {
+        station_names
+            .get(three_alpha_code)
+            .map_or("", |station_name| station_name.name.as_str())
+    };
+    let criteria_results = &day_outputs.criteria_results;
+    let mut section = format!("\n## {}\n\n", day_outputs.selector);
+    section.push_str(&format!(
+        "Stations evaluated: {}\n\n",
+        criteria_results.len()
+    ));
+
+    section.push_str("### Criteria passes\n\n| Criterion | Stations passing |\n|---|---:|\n");
+    for (criterion, passes) in CRITERIA {
+        let passing = criteria_results.values().filter(|r| passes(r)).count();
+        section.push_str(&format!("| {criterion} | {passing} |\n"));
+    }
+
+    section.push_str(&format!(
+        "\n### Busiest {TOP_STATIONS} stations\n\n\
+         | Code | Name | Departures | Peak hour | Peak hour departures |\n\
+         |---|---|---:|---:|---:|\n"
+    ));
+    for station in stops::top_stations(&day_outputs.departures, station_names, TOP_STATIONS) {
+        section.push_str(&format!(
+            "| {} | {} | {} | {:02}:00 | {} |\n",
+            station.three_alpha_code.0,
+            station.name.as_deref().unwrap_or(""),
+            station.total_departures,
+            station.peak_hour,
+            station.peak_hour_departures
+        ));
+    }
+
+    section.push_str(&format!(
+        "\n### Closest to passing\n\n\
+         The {CLOSEST_STATIONS} stations failing each all hours criterion by the fewest \
+         departures, summed over the hours short.\n"
+    ));
+    for (criterion, shortfall, worst_hour) in SHORTFALLS {
+        section.push_str(&format!("\n#### {criterion}\n\n"));
+        let closest = closest_to_passing(criteria_results, shortfall);
+        if closest.is_empty() {
+            section.push_str("None\n");
+            continue;
+        }
+        section.push_str("| Code | Name | Departures short | Worst hour |\n|---|---|---:|---:|\n");
+        for results in closest.into_iter().take(CLOSEST_STATIONS) {
+            section.push_str(&format!(
+                "| {} | {} | {} | {} |\n",
+                results.three_alpha_code.0,
+                name(&results.three_alpha_code),
+                shortfall(results),
+                worst_hour(results).map_or(String::new(), |hour| format!("{hour:02}:00"))
+            ));
+        }
+    }
+
+    let narrow_fails = narrow_fails(&day_outputs.departures, criteria_results, thresholds);
+    section.push_str(
+        "\n### Narrow fails\n\n\
+         Stations failing a criterion that one more departure every hour would pass.\n",
+    );
+    for (index, (criterion, _)) in CRITERIA.iter().enumerate() {
+        section.push_str(&format!("\n#### {criterion}\n\n"));
+        let mut failing: Vec<&ThreeAlphaCode> = narrow_fails
+            .iter()
+            .filter(|(_, fails)| fails[index])
+            .map(|(three_alpha_code, _)| *three_alpha_code)
+            .collect();
+        if failing.is_empty() {
+            section.push_str("None\n");
+            continue;
+        }
+        failing.sort();
+        section.push_str("| Code | Name |\n|---|---|\n");
+        for three_alpha_code in failing {
+            section.push_str(&format!(
+                "| {} | {} |\n",
+                three_alpha_code.0,
+                name(three_alpha_code)
+            ));
+        }
+    }
+    section
+}
+
+/// For each station failing any criterion, which of [`CRITERIA`] it fails but would pass with
+/// one more departure in every hour
+fn narrow_fails<'a>(
+    departures: &'a HashMap<ThreeAlphaCode, HourlyDepartures>,
+    criteria_results: &HashMap<ThreeAlphaCode, CriteriaResults>,
+    thresholds: &CriteriaThresholds,
+) -> Vec<(&'a ThreeAlphaCode, [bool; 4])> {
+    let boosted: HashMap<ThreeAlphaCode, HourlyDepartures> = departures
+        .iter()
+        .filter(|(three_alpha_code, _)| {
+            criteria_results
+                .get(three_alpha_code)
+                .is_some_and(|results| CRITERIA.iter().any(|(_, passes)| !passes(results)))
+        })
+        .map(|(three_alpha_code, hourly_departures)| {
+            let boosted = HourlyDepartures {
+                three_alpha_code: three_alpha_code.clone(),
+                hour_counts: hourly_departures.hour_counts.map(|count| count + 1),
+                hour_counts_journey_starts: hourly_departures.hour_counts_journey_starts,
+                hour_counts_arrivals: hourly_departures.hour_counts_arrivals,
+                hour_counts_journey_ends: hourly_departures.hour_counts_journey_ends,
+                next_stop_three_alpha_code: hourly_departures.next_stop_three_alpha_code.clone(),
+                ..HourlyDepartures::default()
+            }
+            .with_totals();
+            (three_alpha_code.clone(), boosted)
+        })
+        .collect();
+    let boosted_results = evaluate_criteria(&boosted, thresholds);
+
+    departures
+        .keys()
+        .filter_map(|three_alpha_code| {
+            let results = &criteria_results[three_alpha_code];
+            let boosted_results = boosted_results.get(three_alpha_code)?;
+            let fails = CRITERIA.map(|(_, passes)| !passes(results) && passes(boosted_results));
+            Some((three_alpha_code, fails))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{Day, DaySelector};
+    use crate::stops::NameSource;
+
+    fn departures(code: &str, count: u32) -> (ThreeAlphaCode, HourlyDepartures) {
+        let three_alpha_code = ThreeAlphaCode(code.to_string());
+        let hourly_departures = HourlyDepartures {
+            three_alpha_code: three_alpha_code.clone(),
+            hour_counts: [count; 24],
+            hour_counts_journey_starts: [0; 24],
+            hour_counts_arrivals: [0; 24],
+            hour_counts_journey_ends: [0; 24],
+            next_stop_three_alpha_code: vec![Vec::new(); 24],
+            ..HourlyDepartures::default()
+        }
+        .with_totals();
+        (three_alpha_code, hourly_departures)
+    }
+
+    #[test]
+    fn test_render_report() {
+        let departures = HashMap::from([
+            departures("KGX", 6),
+            departures("SVG", 3),
+            departures("CBG", 1),
+        ]);
+        let days = [DayOutputs {
+            selector: DaySelector::Day(Day::Tuesday),
+            criteria_results: evaluate_criteria(&departures, &CriteriaThresholds::default()),
+            departures,
+        }];
+        let station_names = HashMap::from([(
+            ThreeAlphaCode("SVG".to_string()),
+            StationName {
+                name: "Stevenage".to_string(),
+                easting: None,
+                northing: None,
+                source: NameSource::Cif,
+                locality: None,
+                nlc_description: None,
+            },
+        )]);
+        let manifest = RunManifest::new("Tuesday".to_string(), "260113".to_string());
+
+        let report = render(&manifest, &days, &station_names);
+        assert!(report.contains("- Operating date: 260113\n"));
+        assert!(report.contains("## Tuesday\n\nStations evaluated: 3\n"));
+        assert!(report.contains("| all_7_7 | 1 |\n"));
+        assert!(report.contains("| KGX |  | 144 | 00:00 | 6 |\n| SVG | Stevenage | 72 |"));
+        // Three departures an hour is one short of passing, one is not
+        assert!(
+            report.contains("#### all_7_7\n\n| Code | Name |\n|---|---|\n| SVG | Stevenage |\n\n")
+        );
+        assert!(!report.contains("| CBG |  |\n"));
+        // One departure short in each of the 12 hours, against two journey starts short
+        assert!(report.contains(
+            "### Closest to passing\n\n\
+             The 20 stations failing each all hours criterion by the fewest departures, summed \
+             over the hours short.\n\n\
+             #### all_7_7\n\n\
+             | Code | Name | Departures short | Worst hour |\n|---|---|---:|---:|\n\
+             | SVG | Stevenage | 12 | 07:00 |\n\
+             | CBG |  | 24 | 07:00 |\n"
+        ));
+    }
+}
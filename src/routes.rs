@@ -0,0 +1,198 @@
+//! A summary of the routes run on a day, to check the counts against without opening the CIF
+//! file. Rail has no route numbers, so as in the GTFS export a route is an operator, an origin
+//! and a destination, which also gives its direction.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use super::hour_grouping::{GroupingContext, trip_rejection, trip_stops};
+use super::records::{DaySelector, Journey, SecondsPastMidnight, ThreeAlphaCode, Tiploc};
+use super::stops::StationName;
+
+/// A route as listed in routes_summary.json
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RouteSummary {
+    /// None for the journeys without a BX record
+    pub operator: Option<String>,
+    pub origin: ThreeAlphaCode,
+    pub origin_name: Option<String>,
+    pub destination: ThreeAlphaCode,
+    pub destination_name: Option<String>,
+    pub journeys: usize,
+    /// The earliest and latest departures from the origin
+    pub first_departure: Option<SecondsPastMidnight>,
+    pub last_departure: Option<SecondsPastMidnight>,
+    /// The GB stations called at by any of the route's journeys
+    pub stops_served: usize,
+    /// From leaving the origin to arriving at the destination, over the journeys with both
+    /// times
+    pub mean_journey_duration_minutes: Option<f64>,
+}
+
+#[derive(Default)]
+struct RouteJourneys {
+    journeys: usize,
+    departures: BTreeSet<SecondsPastMidnight>,
+    stops: BTreeSet<ThreeAlphaCode>,
+    durations: Vec<usize>,
+}
+
+/// The seconds from `departure` to `arrival`, an arrival earlier in the day than the departure
+/// being on the next day
+pub fn journey_duration(departure: SecondsPastMidnight, arrival: SecondsPastMidnight) -> usize {
+    const DAY: usize = 24 * 3600;
+    (arrival.0 + DAY - departure.0) % DAY
+}
+
+/// The routes of the journeys counted on `selector`, in operator, origin and destination order.
+/// The origin and destination are the first and last GB stations a journey calls at.
+pub fn route_summaries(
+    journeys: &[Journey],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+    selector: &DaySelector,
+    context: &GroupingContext,
+) -> Vec<RouteSummary> {
+    let selectors = std::slice::from_ref(selector);
+    let mut routes: BTreeMap<(Option<&str>, ThreeAlphaCode, ThreeAlphaCode), RouteJourneys> =
+        BTreeMap::new();
+    for journey in journeys {
+        let trip_stops = trip_stops(journey, lookup, context);
+        if trip_rejection(journey, &trip_stops, selectors, context).is_some() {
+            continue;
+        }
+        let mut calls = trip_stops.iter().filter(|stop| stop.calls());
+        let Some(origin) = calls.next() else {
+            continue;
+        };
+        let destination = calls.next_back().unwrap_or(origin);
+        let route = routes
+            .entry((
+                journey.atoc_code.as_deref(),
+                origin.three_alpha_code.clone(),
+                destination.three_alpha_code.clone(),
+            ))
+            .or_default();
+        route.journeys += 1;
+        route.departures.extend(origin.departure_time);
+        if let (Some(departure), Some(arrival)) = (origin.departure_time, destination.arrival_time)
+            && !std::ptr::eq(origin, destination)
+        {
+            route.durations.push(journey_duration(departure, arrival));
+        }
+        route.stops.extend(
+            trip_stops
+                .into_iter()
+                .filter(|stop| stop.calls())
+                .map(|stop| stop.three_alpha_code),
+        );
+    }
+
+    let name = |three_alpha_code: &ThreeAlphaCode| {
+        station_names
+            .get(three_alpha_code)
+            .map(|station_name| station_name.name.clone())
+    };
+    routes
+        .into_iter()
+        .map(|((operator, origin, destination), route)| RouteSummary {
+            operator: operator.map(str::to_string),
+            origin_name: name(&origin),
+            destination_name: name(&destination),
+            origin,
+            destination,
+            journeys: route.journeys,
+            first_departure: route.departures.first().copied(),
+            last_departure: route.departures.last().copied(),
+            stops_served: route.stops.len(),
+            mean_journey_duration_minutes: (!route.durations.is_empty()).then(|| {
+                route.durations.iter().sum::<usize>() as f64 / route.durations.len() as f64 / 60.0
+            }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{Date, Day, assemble_journeys, parse};
+    use crate::stops::{NamePolicy, create_lookup, create_station_name_lookup};
+    use crate::test_cif;
+
+    #[test]
+    fn test_fixture_routes() {
+        let records =
+            parse(include_str!("../tests/fixtures/CIF_ALL_FULL_DAILY_toc-full.CIF").to_string());
+        let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let (station_names, _) =
+            create_station_name_lookup(&records, &lookup, None, NamePolicy::default());
+        let journeys = assemble_journeys(records);
+        let context = GroupingContext::for_week(Date(260113));
+
+        let routes = route_summaries(
+            &journeys,
+            &lookup,
+            &station_names,
+            &DaySelector::Day(Day::Tuesday),
+            &context,
+        );
+        assert_eq!(
+            routes,
+            [RouteSummary {
+                operator: None,
+                origin: codes[0].clone(),
+                origin_name: Some(station_names[&codes[0]].name.clone()),
+                destination: codes[2].clone(),
+                destination_name: Some(station_names[&codes[2]].name.clone()),
+                journeys: 40,
+                first_departure: Some(SecondsPastMidnight(6 * 3600)),
+                last_departure: Some(SecondsPastMidnight(21 * 3600)),
+                stops_served: 3,
+                mean_journey_duration_minutes: Some(60.0),
+            }]
+        );
+        assert_eq!(
+            serde_json::to_value(&routes[0]).unwrap()["last_departure"],
+            "21:00"
+        );
+        // The fixture's journeys run on weekdays only
+        let sunday = DaySelector::Day(Day::Sunday);
+        assert!(route_summaries(&journeys, &lookup, &station_names, &sunday, &context).is_empty());
+    }
+
+    #[test]
+    fn test_journey_durations_across_midnight() {
+        let cif = test_cif::with_stations([
+            "BSNC100012601012612311111100 POO",
+            "LOKNGX    2350 2350",
+            "LTCAMBDGE 0015 0015",
+            "BSNC100022601012612311111100 POO",
+            "LOKNGX    2300 2300",
+            "LTCAMBDGE 2345 2345",
+        ]);
+        let records = parse(cif);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let context = GroupingContext::for_week(Date(260113));
+
+        let routes = route_summaries(
+            &journeys,
+            &lookup,
+            &HashMap::new(),
+            &DaySelector::Day(Day::Tuesday),
+            &context,
+        );
+        assert_eq!(routes.len(), 1);
+        // 25 minutes over midnight and 45 before it
+        assert_eq!(routes[0].mean_journey_duration_minutes, Some(35.0));
+        assert_eq!(
+            journey_duration(
+                SecondsPastMidnight(23 * 3600 + 50 * 60),
+                SecondsPastMidnight(900)
+            ),
+            25 * 60
+        );
+    }
+}
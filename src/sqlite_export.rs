@@ -0,0 +1,286 @@
+//! Export of the departures and criteria results as an SQLite database.
+//!
+//! The database is not written in-process: the export shells out to the `sqlite3` command line
+//! tool, which has to be installed and on the PATH, feeding it the SQL written here one
+//! transaction per table. No SQLite library is linked in, and without the `fs` feature, for
+//! the temporary database file, only the SQL is built.
+
+#[cfg(feature = "fs")]
+use anyhow::Context;
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+#[cfg(feature = "fs")]
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::process::{Command, Stdio};
+
+use super::DayOutputs;
+use super::records::ThreeAlphaCode;
+use super::stops::StationName;
+use super::storage::OutputSink;
+
+pub const SQLITE_FILE_NAME: &str = "output.sqlite";
+
+/// The SQL that creates and fills the stops, hourly_departures and criteria tables
+pub fn build_sql(
+    days: &[DayOutputs],
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+) -> String {
+    let mut sql = String::from(
+        "CREATE TABLE stops (three_alpha_code TEXT PRIMARY KEY, name TEXT, lat REAL, lon REAL);\n\
+         CREATE TABLE hourly_departures (three_alpha_code TEXT, day TEXT, hour INTEGER, \
+         departures INTEGER, journey_starts INTEGER, arrivals INTEGER, \
+         journey_ends INTEGER);\n\
+         CREATE TABLE criteria (three_alpha_code TEXT, day TEXT, criterion TEXT, \
+         passed INTEGER);\n",
+    );
+
+    let mut stations: Vec<(&ThreeAlphaCode, &StationName)> = station_names.iter().collect();
+    stations.sort_by_key(|(three_alpha_code, _)| *three_alpha_code);
+    sql.push_str("BEGIN;\n");
+    for (three_alpha_code, station_name) in stations {
+        let (lat, lon) = station_name
+            .latitude_longitude()
+            .map_or(("NULL".to_string(), "NULL".to_string()), |(lat, lon)| {
+                (format!("{lat:.6}"), format!("{lon:.6}"))
+            });
+        sql.push_str(&format!(
+            "INSERT INTO stops VALUES ({}, {}, {lat}, {lon});\n",
+            quote(&three_alpha_code.0),
+            quote(&station_name.name)
+        ));
+    }
+    sql.push_str("COMMIT;\n");
+
+    sql.push_str("BEGIN;\n");
+    for day_outputs in days {
+        let day = quote(&day_outputs.selector.to_string());
+        for (three_alpha_code, departures) in sorted(&day_outputs.departures) {
+            for hour in 0..24 {
+                sql.push_str(&format!(
+                    "INSERT INTO hourly_departures VALUES ({}, {day}, {hour}, {}, {}, {}, {});\n",
+                    quote(&three_alpha_code.0),
+                    departures.hour_counts[hour],
+                    departures.hour_counts_journey_starts[hour],
+                    departures.hour_counts_arrivals[hour],
+                    departures.hour_counts_journey_ends[hour]
+                ));
+            }
+        }
+    }
+    sql.push_str("COMMIT;\n");
+
+    sql.push_str("BEGIN;\n");
+    for day_outputs in days {
+        let day = quote(&day_outputs.selector.to_string());
+        for (three_alpha_code, results) in sorted(&day_outputs.criteria_results) {
+            for (criterion, passed) in [
+                ("all_7_7", results.all_7_7),
+                ("all_6_10", results.all_6_10),
+                ("avg_7_7", results.avg_7_7),
+                ("avg_6_10", results.avg_6_10),
+                ("night_service", results.night_service),
+                ("early_start", results.early_start),
+            ]
+            .into_iter()
+            .chain(
+                [
+                    ("all_7_7_arrivals", results.all_7_7_arrivals),
+                    ("avg_7_7_arrivals", results.avg_7_7_arrivals),
+                ]
+                .into_iter()
+                .filter_map(|(criterion, passed)| Some((criterion, passed?))),
+            ) {
+                sql.push_str(&format!(
+                    "INSERT INTO criteria VALUES ({}, {day}, '{criterion}', {});\n",
+                    quote(&three_alpha_code.0),
+                    passed as u8
+                ));
+            }
+        }
+    }
+    sql.push_str("COMMIT;\n");
+
+    sql.push_str(
+        "CREATE INDEX hourly_departures_three_alpha_code ON hourly_departures \
+         (three_alpha_code);\n\
+         CREATE INDEX criteria_three_alpha_code ON criteria (three_alpha_code);\n",
+    );
+    sql
+}
+
+/// Build output.sqlite from `sql` with the sqlite3 tool and write it to `sink`. An existing
+/// database is only replaced under --force.
+#[cfg(feature = "fs")]
+pub fn write_database(sql: &str, sink: &mut impl OutputSink) -> Result<()> {
+    let tmp_path =
+        std::env::temp_dir().join(format!("rhd_{}_{SQLITE_FILE_NAME}", std::process::id()));
+    let _ = fs_err::remove_file(&tmp_path);
+    let run_sqlite = || -> Result<Vec<u8>> {
+        let mut sqlite = Command::new("sqlite3")
+            .arg(&tmp_path)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run sqlite3, which --format sqlite needs to be installed")?;
+        sqlite
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(sql.as_bytes())?;
+        let output = sqlite.wait_with_output()?;
+        if !output.status.success() {
+            bail!(
+                "sqlite3 failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(fs_err::read(&tmp_path)?)
+    };
+    let database = run_sqlite();
+    let _ = fs_err::remove_file(&tmp_path);
+    sink.write_output(SQLITE_FILE_NAME, &database?)
+}
+
+/// Without the `fs` feature sqlite3 has no file to build the database in
+#[cfg(not(feature = "fs"))]
+pub fn write_database(_sql: &str, _sink: &mut impl OutputSink) -> Result<()> {
+    bail!("--format sqlite builds the database in a temporary file, which needs the fs feature")
+}
+
+/// An SQL string literal
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn sorted<T>(by_station: &HashMap<ThreeAlphaCode, T>) -> Vec<(&ThreeAlphaCode, &T)> {
+    let mut sorted: Vec<(&ThreeAlphaCode, &T)> = by_station.iter().collect();
+    sorted.sort_by_key(|(three_alpha_code, _)| *three_alpha_code);
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hour_grouping::HourlyDepartures;
+    use crate::records::{Day, DaySelector};
+    use crate::stops::NameSource;
+    #[cfg(feature = "fs")]
+    use crate::storage::OutputDirectory;
+    use crate::{CriteriaThresholds, evaluate_criteria};
+
+    /// One station, King's Cross, with 6 departures and a journey start every hour on Tuesday
+    fn kgx_tuesday(
+        thresholds: &CriteriaThresholds,
+    ) -> ([DayOutputs; 1], HashMap<ThreeAlphaCode, StationName>) {
+        let kgx = ThreeAlphaCode("KGX".to_string());
+        let departures = HashMap::from([(
+            kgx.clone(),
+            HourlyDepartures {
+                three_alpha_code: kgx.clone(),
+                hour_counts: [6; 24],
+                hour_counts_journey_starts: [1; 24],
+                hour_counts_arrivals: [0; 24],
+                hour_counts_journey_ends: [0; 24],
+                next_stop_three_alpha_code: vec![Vec::new(); 24],
+                ..HourlyDepartures::default()
+            }
+            .with_totals(),
+        )]);
+        let days = [DayOutputs {
+            selector: DaySelector::Day(Day::Tuesday),
+            criteria_results: evaluate_criteria(&departures, thresholds),
+            departures,
+        }];
+        let station_names = HashMap::from([(
+            kgx,
+            StationName {
+                name: "King's Cross".to_string(),
+                easting: Some(530_400),
+                northing: Some(183_300),
+                source: NameSource::Naptan,
+                locality: None,
+                nlc_description: None,
+            },
+        )]);
+        (days, station_names)
+    }
+
+    #[test]
+    fn test_sql_fills_each_table() {
+        let (days, station_names) = kgx_tuesday(&CriteriaThresholds::default());
+        let sql = build_sql(&days, &station_names);
+        let rows = |table: &str| {
+            sql.lines()
+                .filter(|line| line.starts_with(&format!("INSERT INTO {table} ")))
+                .count()
+        };
+        assert_eq!(rows("stops"), 1);
+        assert!(sql.contains("'KGX', 'King''s Cross', 51."), "{sql}");
+        assert_eq!(rows("hourly_departures"), 24);
+        assert!(
+            sql.contains("INSERT INTO hourly_departures VALUES ('KGX', 'Tuesday', 8, 6, 1, 0, 0);")
+        );
+        // Six criteria, and the two arrival criteria
+        assert_eq!(rows("criteria"), 8);
+        assert!(sql.contains("INSERT INTO criteria VALUES ('KGX', 'Tuesday', 'all_7_7', 1);"));
+        assert!(
+            sql.contains("INSERT INTO criteria VALUES ('KGX', 'Tuesday', 'avg_7_7_arrivals', 0);")
+        );
+        assert_eq!(
+            sql.matches("BEGIN;").count(),
+            sql.matches("COMMIT;").count()
+        );
+    }
+
+    #[test]
+    fn test_sql_leaves_out_arrival_criteria_not_evaluated() {
+        let (days, station_names) = kgx_tuesday(&CriteriaThresholds {
+            min_arrivals_per_hour: None,
+            ..CriteriaThresholds::default()
+        });
+        let sql = build_sql(&days, &station_names);
+        assert_eq!(sql.matches("INSERT INTO criteria ").count(), 6);
+        assert!(!sql.contains("_arrivals'"));
+    }
+
+    // Run with `cargo test -- --ignored` where sqlite3 is installed
+    #[test]
+    #[ignore = "needs the sqlite3 CLI"]
+    #[cfg(feature = "fs")]
+    fn test_database_answers_count_queries() {
+        let (days, station_names) = kgx_tuesday(&CriteriaThresholds::default());
+
+        let output_directory =
+            std::env::temp_dir().join(format!("rhd_sqlite_{}", std::process::id()));
+        let _ = fs_err::remove_dir_all(&output_directory);
+        write_database(
+            &build_sql(&days, &station_names),
+            &mut OutputDirectory::new(output_directory.clone(), false),
+        )
+        .unwrap();
+        let query = |sql: &str| {
+            let output = Command::new("sqlite3")
+                .arg(output_directory.join(SQLITE_FILE_NAME))
+                .arg(sql)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        assert_eq!(query("SELECT COUNT(*) FROM stops"), "1");
+        assert_eq!(query("SELECT name FROM stops"), "King's Cross");
+        assert_eq!(
+            query("SELECT COUNT(*), SUM(departures) FROM hourly_departures"),
+            "24|144"
+        );
+        assert_eq!(
+            query("SELECT passed FROM criteria WHERE criterion = 'all_7_7'"),
+            "1"
+        );
+        assert_eq!(
+            query("SELECT passed FROM criteria WHERE criterion = 'avg_7_7_arrivals'"),
+            "0"
+        );
+        fs_err::remove_dir_all(&output_directory).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+use super::hour_grouping::HourlyDepartures;
+use super::records::{SecondsPastMidnight, ThreeAlphaCode};
+
+/// Departure hours of the morning peak, 07:00 to 10:00
+pub const AM_PEAK_HOURS: Range<usize> = 7..10;
+/// Departure hours between the peaks, 10:00 to 16:00
+pub const INTER_PEAK_HOURS: Range<usize> = 10..16;
+/// Departure hours of the evening peak, 16:00 to 19:00
+pub const PM_PEAK_HOURS: Range<usize> = 16..19;
+/// Departure hours the regularity of the service is judged over, 07:00 to 19:00
+pub const REGULARITY_HOURS: Range<usize> = 7..19;
+/// How far a departure may be from the minute it leaves at in other hours and still be taken
+/// as the same clockface departure
+const CLOCKFACE_TOLERANCE_MINUTES: f64 = 2.0;
+/// How many of the [`REGULARITY_HOURS`] must share departure minutes for a clockface service
+const CLOCKFACE_MIN_HOURS: usize = 8;
+
+/// How a station's departures are spread over the day
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StationStats {
+    /// The earliest of the hours with the most departures, or none without any departures
+    pub peak_hour: Option<usize>,
+    pub am_peak_departures: u32,
+    pub pm_peak_departures: u32,
+    /// Mean departures per hour between the peaks
+    pub inter_peak_hourly_average: f64,
+    /// Mean departures per peak hour over the inter peak mean, or none without inter peak
+    /// departures
+    pub peak_to_inter_peak_ratio: Option<f64>,
+    /// Only with the departure times, which grouping keeps for --regularity-stats
+    #[serde(flatten)]
+    pub regularity: Option<Regularity>,
+}
+
+/// How evenly the departures between 07:00 and 19:00 are spread
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Regularity {
+    /// Standard deviation of the minutes between consecutive departures, or none with fewer
+    /// than three departures
+    pub headway_std_dev_minutes: Option<f64>,
+    /// Whether at least 8 of the 12 hours have departures at the same minutes past the hour,
+    /// give or take 2 minutes
+    pub clockface: bool,
+}
+
+/// The peak and inter peak figures of one station's hourly departure counts, and the
+/// [`regularity`] of its departure times when given them
+///
+/// ```
+/// use rail_hourly_departures::stats::stats;
+///
+/// let mut hour_counts = [2; 24];
+/// hour_counts[8] = 6;
+/// let stats = stats(&hour_counts, None);
+/// assert_eq!(stats.peak_hour, Some(8));
+/// assert_eq!(stats.am_peak_departures, 10);
+/// ```
+pub fn stats(
+    hour_counts: &[u32; 24],
+    departure_times: Option<&[SecondsPastMidnight]>,
+) -> StationStats {
+    let total = |hours: Range<usize>| hour_counts[hours].iter().sum::<u32>();
+    let peak_count = hour_counts.iter().copied().max().unwrap_or(0);
+    let am_peak_departures = total(AM_PEAK_HOURS);
+    let pm_peak_departures = total(PM_PEAK_HOURS);
+    let inter_peak_hourly_average = total(INTER_PEAK_HOURS) as f64 / INTER_PEAK_HOURS.len() as f64;
+    let peak_hourly_average = (am_peak_departures + pm_peak_departures) as f64
+        / (AM_PEAK_HOURS.len() + PM_PEAK_HOURS.len()) as f64;
+
+    StationStats {
+        peak_hour: (peak_count > 0)
+            .then(|| hour_counts.iter().position(|&count| count == peak_count))
+            .flatten(),
+        am_peak_departures,
+        pm_peak_departures,
+        inter_peak_hourly_average,
+        peak_to_inter_peak_ratio: (inter_peak_hourly_average > 0.0)
+            .then(|| peak_hourly_average / inter_peak_hourly_average),
+        regularity: departure_times.map(regularity),
+    }
+}
+
+/// The spread of the headways between the departures within [`REGULARITY_HOURS`], and whether
+/// they follow a clockface pattern
+pub fn regularity(departure_times: &[SecondsPastMidnight]) -> Regularity {
+    let mut minutes: Vec<f64> = departure_times
+        .iter()
+        .filter(|time| REGULARITY_HOURS.contains(&(time.0 / 3600)))
+        .map(|time| time.0 as f64 / 60.0)
+        .collect();
+    minutes.sort_by(f64::total_cmp);
+
+    let headways: Vec<f64> = minutes.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let headway_std_dev_minutes = (headways.len() > 1).then(|| {
+        let mean = headways.iter().sum::<f64>() / headways.len() as f64;
+        let variance = headways
+            .iter()
+            .map(|headway| (headway - mean).powi(2))
+            .sum::<f64>()
+            / headways.len() as f64;
+        variance.sqrt()
+    });
+
+    // The minutes past the hour of each hour's departures, compared against each hour in turn
+    let mut hour_minutes: Vec<Vec<f64>> = vec![Vec::new(); REGULARITY_HOURS.len()];
+    for minute in &minutes {
+        let hour = (*minute / 60.0) as usize - REGULARITY_HOURS.start;
+        hour_minutes[hour].push(minute % 60.0);
+    }
+    let same_minutes = |a: &[f64], b: &[f64]| {
+        a.len() == b.len()
+            && a.iter()
+                .zip(b)
+                .all(|(a, b)| (a - b).abs() <= CLOCKFACE_TOLERANCE_MINUTES)
+    };
+    let clockface = hour_minutes.iter().any(|pattern| {
+        !pattern.is_empty()
+            && hour_minutes
+                .iter()
+                .filter(|other| same_minutes(pattern, other))
+                .count()
+                >= CLOCKFACE_MIN_HOURS
+    });
+
+    Regularity {
+        headway_std_dev_minutes,
+        clockface,
+    }
+}
+
+/// The [`stats`] of every station, in three alpha code order, `with_regularity` when grouping
+/// collected the departure times
+pub fn station_stats(
+    departures: &HashMap<ThreeAlphaCode, HourlyDepartures>,
+    with_regularity: bool,
+) -> BTreeMap<&ThreeAlphaCode, StationStats> {
+    departures
+        .iter()
+        .map(|(three_alpha_code, hourly_departures)| {
+            (
+                three_alpha_code,
+                stats(
+                    &hourly_departures.hour_counts,
+                    with_regularity.then_some(hourly_departures.departure_times.as_slice()),
+                ),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_of_known_counts() {
+        let hour_counts = [
+            0, 0, 0, 0, 0, 1, 2, 4, 6, 4, 2, 2, 2, 2, 2, 2, 4, 6, 6, 3, 2, 1, 1, 0,
+        ];
+        assert_eq!(
+            stats(&hour_counts, Some(&[])),
+            StationStats {
+                peak_hour: Some(8),
+                am_peak_departures: 14,
+                pm_peak_departures: 16,
+                inter_peak_hourly_average: 2.0,
+                peak_to_inter_peak_ratio: Some(2.5),
+                regularity: Some(Regularity {
+                    headway_std_dev_minutes: None,
+                    clockface: false,
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn test_stats_without_inter_peak_departures() {
+        let mut hour_counts = [0; 24];
+        hour_counts[17] = 3;
+        let stats = stats(&hour_counts, None);
+        assert_eq!(stats.peak_hour, Some(17));
+        assert_eq!(stats.pm_peak_departures, 3);
+        assert_eq!(stats.inter_peak_hourly_average, 0.0);
+        assert_eq!(stats.peak_to_inter_peak_ratio, None);
+    }
+
+    #[test]
+    fn test_stats_of_station_without_departures() {
+        assert_eq!(
+            stats(&[0; 24], None),
+            StationStats {
+                peak_hour: None,
+                am_peak_departures: 0,
+                pm_peak_departures: 0,
+                inter_peak_hourly_average: 0.0,
+                peak_to_inter_peak_ratio: None,
+                regularity: None,
+            }
+        );
+    }
+
+    fn times(minutes: impl IntoIterator<Item = usize>) -> Vec<SecondsPastMidnight> {
+        minutes
+            .into_iter()
+            .map(|minute| SecondsPastMidnight(minute * 60))
+            .collect()
+    }
+
+    #[test]
+    fn test_every_15_minutes_is_regular_clockface() {
+        // 07:00 to 18:45, with a late night train outside the hours judged
+        let departures = times((7 * 60..19 * 60).step_by(15).chain([23 * 60 + 10]));
+        let regularity = regularity(&departures);
+        assert_eq!(regularity.headway_std_dev_minutes, Some(0.0));
+        assert!(regularity.clockface);
+    }
+
+    #[test]
+    fn test_bunched_departures_are_irregular() {
+        // Four trains an hour, but at minutes that drift from hour to hour
+        let departures = times((7..19).flat_map(|hour| {
+            let start = hour * 60 + (hour * 7) % 20;
+            [start, start + 3, start + 6, start + 30]
+        }));
+        let regularity = regularity(&departures);
+        assert!(regularity.headway_std_dev_minutes.unwrap() > 5.0);
+        assert!(!regularity.clockface);
+    }
+
+    #[test]
+    fn test_clockface_allows_small_differences() {
+        // Within 2 minutes of :05 and :35 each hour, but only for 8 of the hours
+        let departures = times((7..15).flat_map(|hour| {
+            let slip = hour % 3;
+            [hour * 60 + 5 + slip, hour * 60 + 35]
+        }));
+        assert!(regularity(&departures).clockface);
+        assert!(!regularity(&departures[..14]).clockface);
+    }
+}
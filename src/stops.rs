@@ -1,19 +1,611 @@
+#[cfg(feature = "fs")]
+use anyhow::bail;
+use anyhow::{Context, Result};
 use indicatif::ProgressIterator;
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "fs")]
+use std::path::{Path, PathBuf};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+};
 
-use super::records::{Record, ThreeAlphaCode, Tiploc};
-use super::utils::progress_bar_for_count;
+use super::DayOutputs;
+use super::criteria::CriteriaResults;
+use super::hour_grouping::{self, GroupingContext, HourlyDepartures};
+use super::operators::NO_OPERATOR;
+use super::records::{ActivityFlag, DaySelector, Journey, Record, ThreeAlphaCode, Tiploc};
+#[cfg(feature = "fs")]
+use super::utils;
+use super::utils::{progress_bar_for_count, split_csv_line};
+use super::{info, warn};
 
+pub const DEFAULT_STATION_CODES_FILE: &str = "config/gb_station_three_alpha_codes.json";
+
+/// The TOML form of a station codes file, alongside the plain JSON list
+#[cfg(feature = "fs")]
+#[derive(Deserialize)]
+struct StationCodesToml {
+    three_alpha_codes: Vec<ThreeAlphaCode>,
+}
+
+/// Read a single station codes file, as a JSON list or a TOML `three_alpha_codes` array
+#[cfg(feature = "fs")]
+pub fn read_station_codes_file(path: &Path) -> Result<Vec<ThreeAlphaCode>> {
+    if !path.exists() {
+        bail!("Station codes file {} does not exist", path.display());
+    }
+    if utils::has_extension(path, &["toml"]) {
+        let codes: StationCodesToml = utils::read_toml_file(path)?;
+        Ok(codes.three_alpha_codes)
+    } else if utils::has_extension(path, &["json"]) {
+        utils::read_json_file(path)
+    } else {
+        bail!(
+            "Station codes file {} must end with .json or .toml",
+            path.display()
+        )
+    }
+}
+
+/// Read and merge the station codes files, logging codes already listed by an earlier file
+#[cfg(feature = "fs")]
+pub fn read_station_codes(paths: &[PathBuf]) -> Result<Vec<ThreeAlphaCode>> {
+    let mut merged: Vec<ThreeAlphaCode> = Vec::new();
+    let mut listed_by: HashMap<ThreeAlphaCode, &Path> = HashMap::new();
+    for path in paths {
+        for code in read_station_codes_file(path)? {
+            match listed_by.get(&code) {
+                Some(earlier_path) if *earlier_path != path.as_path() => {
+                    warn!(
+                        "{} from {} is already listed in {}",
+                        code.0,
+                        path.display(),
+                        earlier_path.display()
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    listed_by.insert(code.clone(), path);
+                    merged.push(code);
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// The display name and location of a GB station
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StationName {
+    pub name: String,
+    pub easting: Option<u32>,
+    pub northing: Option<u32>,
+    pub source: NameSource,
+    /// The NaPTAN locality of the station, such as "Manchester" for one of two Victorias
+    #[serde(default)]
+    pub locality: Option<String>,
+    /// The short description the CIF file gives the station's NLC
+    #[serde(default)]
+    pub nlc_description: Option<String>,
+}
+
+impl StationName {
+    /// WGS84 latitude and longitude in degrees, when the station has a location
+    pub fn latitude_longitude(&self) -> Option<(f64, f64)> {
+        Some(osgb_to_wgs84(self.easting? as f64, self.northing? as f64))
+    }
+}
+
+/// Convert an OS National Grid easting and northing to WGS84 latitude and longitude in
+/// degrees, following the Ordnance Survey's "A guide to coordinate systems in Great Britain".
+/// The Helmert transform between the datums is accurate to a few metres.
+pub fn osgb_to_wgs84(easting: f64, northing: f64) -> (f64, f64) {
+    let (latitude, longitude) = osgb_to_osgb36_lat_lon(easting, northing);
+
+    // OSGB36 latitude and longitude to cartesian coordinates on the Airy 1830 ellipsoid
+    let (a, b): (f64, f64) = (6_377_563.396, 6_356_256.909);
+    let e2 = 1.0 - (b * b) / (a * a);
+    let nu = a / (1.0 - e2 * latitude.sin().powi(2)).sqrt();
+    let x = nu * latitude.cos() * longitude.cos();
+    let y = nu * latitude.cos() * longitude.sin();
+    let z = nu * (1.0 - e2) * latitude.sin();
+
+    // Helmert transform from OSGB36 to WGS84
+    let arcseconds = |seconds: f64| (seconds / 3600.0).to_radians();
+    let (tx, ty, tz) = (446.448, -125.157, 542.060);
+    let scale = 1.0 - 20.4894e-6;
+    let (rx, ry, rz) = (arcseconds(0.1502), arcseconds(0.2470), arcseconds(0.8421));
+    let x_wgs = tx + scale * x - rz * y + ry * z;
+    let y_wgs = ty + rz * x + scale * y - rx * z;
+    let z_wgs = tz - ry * x + rx * y + scale * z;
+
+    // Cartesian coordinates to latitude and longitude on the WGS84 ellipsoid
+    let (a, b): (f64, f64) = (6_378_137.0, 6_356_752.314_245);
+    let e2 = 1.0 - (b * b) / (a * a);
+    let p = (x_wgs * x_wgs + y_wgs * y_wgs).sqrt();
+    let mut latitude = z_wgs.atan2(p * (1.0 - e2));
+    for _ in 0..10 {
+        let nu = a / (1.0 - e2 * latitude.sin().powi(2)).sqrt();
+        latitude = (z_wgs + e2 * nu * latitude.sin()).atan2(p);
+    }
+    (latitude.to_degrees(), y_wgs.atan2(x_wgs).to_degrees())
+}
+
+/// The inverse transverse Mercator projection of the National Grid, giving OSGB36 latitude
+/// and longitude in radians
+fn osgb_to_osgb36_lat_lon(easting: f64, northing: f64) -> (f64, f64) {
+    let (a, b): (f64, f64) = (6_377_563.396, 6_356_256.909);
+    let f0 = 0.999_601_271_7;
+    let (latitude0, longitude0) = (49f64.to_radians(), (-2f64).to_radians());
+    let (n0, e0) = (-100_000.0, 400_000.0);
+    let e2 = 1.0 - (b * b) / (a * a);
+    let n = (a - b) / (a + b);
+
+    let meridional_arc = |latitude: f64| {
+        let (d, s) = (latitude - latitude0, latitude + latitude0);
+        b * f0
+            * ((1.0 + n + 1.25 * n.powi(2) + 1.25 * n.powi(3)) * d
+                - (3.0 * n + 3.0 * n.powi(2) + 2.625 * n.powi(3)) * d.sin() * s.cos()
+                + (1.875 * n.powi(2) + 1.875 * n.powi(3)) * (2.0 * d).sin() * (2.0 * s).cos()
+                - (35.0 / 24.0) * n.powi(3) * (3.0 * d).sin() * (3.0 * s).cos())
+    };
+    let mut latitude = latitude0;
+    let mut m = 0.0;
+    while (northing - n0 - m).abs() >= 0.00001 {
+        latitude += (northing - n0 - m) / (a * f0);
+        m = meridional_arc(latitude);
+    }
+
+    let sin2 = latitude.sin().powi(2);
+    let nu = a * f0 / (1.0 - e2 * sin2).sqrt();
+    let rho = a * f0 * (1.0 - e2) / (1.0 - e2 * sin2).powf(1.5);
+    let eta2 = nu / rho - 1.0;
+    let tan = latitude.tan();
+    let sec = 1.0 / latitude.cos();
+
+    let vii = tan / (2.0 * rho * nu);
+    let viii = tan / (24.0 * rho * nu.powi(3))
+        * (5.0 + 3.0 * tan.powi(2) + eta2 - 9.0 * tan.powi(2) * eta2);
+    let ix = tan / (720.0 * rho * nu.powi(5)) * (61.0 + 90.0 * tan.powi(2) + 45.0 * tan.powi(4));
+    let x = sec / nu;
+    let xi = sec / (6.0 * nu.powi(3)) * (nu / rho + 2.0 * tan.powi(2));
+    let xii = sec / (120.0 * nu.powi(5)) * (5.0 + 28.0 * tan.powi(2) + 24.0 * tan.powi(4));
+    let xiia = sec / (5040.0 * nu.powi(7))
+        * (61.0 + 662.0 * tan.powi(2) + 1320.0 * tan.powi(4) + 720.0 * tan.powi(6));
+
+    let de = easting - e0;
+    (
+        latitude - vii * de.powi(2) + viii * de.powi(4) - ix * de.powi(6),
+        longitude0 + x * de - xi * de.powi(3) + xii * de.powi(5) - xiia * de.powi(7),
+    )
+}
+
+/// Where a station's name was taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NameSource {
+    /// The TPS description of a TIPLOC insert record
+    Cif,
+    Naptan,
+}
+
+/// A row of the NaPTAN Stops.csv
+#[derive(Debug, Clone, PartialEq)]
+pub struct NaptanStop {
+    pub atco_code: String,
+    pub common_name: String,
+    pub easting: Option<u32>,
+    pub northing: Option<u32>,
+    pub stop_type: String,
+    /// From the LocalityName column, when the file has one
+    pub locality: Option<String>,
+}
+
+impl NaptanStop {
+    /// Rail station access areas have ATCO codes of the form "9100" + TIPLOC
+    pub fn tiploc(&self) -> Option<Tiploc> {
+        if self.stop_type != "RLY" {
+            return None;
+        }
+        self.atco_code
+            .strip_prefix("9100")
+            .map(|tiploc| Tiploc(tiploc.to_string()))
+    }
+}
+
+#[cfg(feature = "fs")]
+pub fn read_naptan_csv(path: &Path) -> Result<Vec<NaptanStop>> {
+    info!("Reading NaPTAN stops from {}", path.display());
+    let text = fs_err::read_to_string(path)?;
+    parse_naptan_csv(&text)
+        .with_context(|| format!("Failed to parse NaPTAN stops in {}", path.display()))
+}
+
+/// The stops of the text of a NaPTAN Stops.csv
+pub fn parse_naptan_csv(text: &str) -> Result<Vec<NaptanStop>> {
+    let mut lines = text.lines();
+    let header = split_csv_line(lines.next().context("NaPTAN CSV is empty")?);
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| column == name)
+            .with_context(|| format!("NaPTAN CSV has no {name} column"))
+    };
+    let (atco_code, common_name, easting, northing, stop_type) = (
+        column("ATCOCode")?,
+        column("CommonName")?,
+        column("Easting")?,
+        column("Northing")?,
+        column("StopType")?,
+    );
+    let locality = header.iter().position(|column| column == "LocalityName");
+
+    let mut stops = Vec::new();
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+        let fields = split_csv_line(line);
+        let field = |index: usize| fields.get(index).map(String::as_str).unwrap_or_default();
+        stops.push(NaptanStop {
+            atco_code: field(atco_code).to_string(),
+            common_name: field(common_name).to_string(),
+            easting: field(easting).parse().ok(),
+            northing: field(northing).parse().ok(),
+            stop_type: field(stop_type).to_string(),
+            locality: locality
+                .map(field)
+                .filter(|locality| !locality.is_empty())
+                .map(str::to_string),
+        });
+    }
+    Ok(stops)
+}
+
+/// How [`create_station_name_lookup`] picks between the different names a station is given,
+/// by the TPS descriptions of concatenated CIF files or by several NaPTAN stops
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NamePolicy {
+    /// The first name in file order, CIF descriptions before NaPTAN names, so NaPTAN only
+    /// names stations the CIF file doesn't
+    First,
+    /// The longest name from either source, then the first alphabetically
+    Longest,
+    /// A NaPTAN name over any CIF description, then the longest, then the first alphabetically
+    #[default]
+    PreferNaptan,
+}
+
+impl NamePolicy {
+    /// The candidate to name the station by, from `candidates` in file order with the CIF
+    /// descriptions first
+    fn choose(self, candidates: &[(String, NameSource)]) -> &(String, NameSource) {
+        let longest_first =
+            |(name, _): &(String, NameSource)| (Reverse(name.chars().count()), name.clone());
+        match self {
+            NamePolicy::First => &candidates[0],
+            NamePolicy::Longest => candidates
+                .iter()
+                .min_by_key(|candidate| longest_first(candidate))
+                .expect("a station has at least one name"),
+            NamePolicy::PreferNaptan => candidates
+                .iter()
+                .min_by_key(|candidate| {
+                    (candidate.1 != NameSource::Naptan, longest_first(candidate))
+                })
+                .expect("a station has at least one name"),
+        }
+    }
+}
+
+/// A station given different names by the same source, written to name_conflicts.json
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NameConflict {
+    pub three_alpha_code: ThreeAlphaCode,
+    pub source: NameSource,
+    /// Every name the source gives the station, in file order
+    pub names: Vec<String>,
+    /// The name the [`NamePolicy`] chose, which may be from the other source
+    pub chosen: String,
+}
+
+/// Name every station in `lookup` from the TPS descriptions of the TIPLOCs carrying its three
+/// alpha code and the NaPTAN stops of any of its TIPLOCs, choosing between them by `policy`.
+/// The location and locality are always those of the first NaPTAN stop, and the NLC
+/// description that of the first TIPLOC with one. Stations given more than one name
+/// by a source are returned as conflicts, sorted by three alpha code.
+pub fn create_station_name_lookup(
+    records: &[Record],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    naptan_stops: Option<&[NaptanStop]>,
+    policy: NamePolicy,
+) -> (HashMap<ThreeAlphaCode, StationName>, Vec<NameConflict>) {
+    let mut candidates: HashMap<&ThreeAlphaCode, Vec<(String, NameSource)>> = HashMap::new();
+    let mut naptan_stops_by_code: HashMap<&ThreeAlphaCode, &NaptanStop> = HashMap::new();
+    let mut nlc_descriptions: HashMap<&ThreeAlphaCode, &str> = HashMap::new();
+    let mut add_candidate = |three_alpha_code, name: &str, source: NameSource| {
+        let names = candidates.entry(three_alpha_code).or_default();
+        if !names
+            .iter()
+            .any(|(other, other_source)| other == name && *other_source == source)
+        {
+            names.push((name.to_string(), source));
+        }
+    };
+
+    for record in records.iter() {
+        // The TIPLOC carrying the three alpha code is the station itself, so its description
+        // is the one to use
+        if let Record::Stop(stop) = record
+            && stop.three_alpha_code.is_some()
+            && let Some(three_alpha_code) = lookup.get(&stop.tiploc)
+        {
+            add_candidate(three_alpha_code, &stop.tps_description, NameSource::Cif);
+            if let Some(nlc_description) = &stop.nlc_description {
+                nlc_descriptions
+                    .entry(three_alpha_code)
+                    .or_insert(nlc_description);
+            }
+        }
+    }
+    for naptan_stop in naptan_stops.unwrap_or_default() {
+        if let Some(three_alpha_code) = naptan_stop.tiploc().and_then(|t| lookup.get(&t)) {
+            add_candidate(
+                three_alpha_code,
+                &naptan_stop.common_name,
+                NameSource::Naptan,
+            );
+            naptan_stops_by_code
+                .entry(three_alpha_code)
+                .or_insert(naptan_stop);
+        }
+    }
+
+    let mut station_names: HashMap<ThreeAlphaCode, StationName> = HashMap::new();
+    let mut conflicts: Vec<NameConflict> = Vec::new();
+    for (three_alpha_code, candidates) in candidates {
+        let (name, source) = policy.choose(&candidates).clone();
+        for conflict_source in [NameSource::Cif, NameSource::Naptan] {
+            let names: Vec<String> = candidates
+                .iter()
+                .filter(|(_, source)| *source == conflict_source)
+                .map(|(name, _)| name.clone())
+                .collect();
+            if names.len() > 1 {
+                conflicts.push(NameConflict {
+                    three_alpha_code: three_alpha_code.clone(),
+                    source: conflict_source,
+                    names,
+                    chosen: name.clone(),
+                });
+            }
+        }
+        let naptan_stop = naptan_stops_by_code.get(three_alpha_code);
+        station_names.insert(
+            three_alpha_code.clone(),
+            StationName {
+                name,
+                easting: naptan_stop.and_then(|naptan_stop| naptan_stop.easting),
+                northing: naptan_stop.and_then(|naptan_stop| naptan_stop.northing),
+                source,
+                locality: naptan_stop.and_then(|naptan_stop| naptan_stop.locality.clone()),
+                nlc_description: nlc_descriptions
+                    .get(three_alpha_code)
+                    .map(|nlc_description| nlc_description.to_string()),
+            },
+        );
+    }
+    conflicts.sort_by(|a, b| a.three_alpha_code.cmp(&b.three_alpha_code));
+
+    info!("Station name lookup len: {:?}", station_names.len());
+    if !conflicts.is_empty() {
+        info!("Stations given conflicting names: {}", conflicts.len());
+    }
+    (station_names, conflicts)
+}
+
+/// How far apart, in metres, the NaPTAN stops of a station may be before
+/// [`location_conflicts`] reports them
+pub const DEFAULT_LOCATION_CONFLICT_METRES: u32 = 1000;
+
+/// A NaPTAN stop's location, as listed in location_conflicts.json
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StopLocation {
+    pub atco_code: String,
+    pub easting: u32,
+    pub northing: u32,
+}
+
+/// A station whose NaPTAN stops are further apart than the distance allowed, written to
+/// location_conflicts.json. Usually a data error, or a stop placed at a platform or depot
+/// rather than at the station.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LocationConflict {
+    pub three_alpha_code: ThreeAlphaCode,
+    /// Every located NaPTAN stop of the station's TIPLOCs, in file order
+    pub locations: Vec<StopLocation>,
+    /// The distance between the two stops furthest apart
+    pub distance_metres: u32,
+    /// The ATCO code of the first stop, whose location [`create_station_name_lookup`] gives
+    /// the station
+    pub chosen: String,
+}
+
+/// The stations in `lookup` whose NaPTAN stops are more than `max_distance_metres` apart,
+/// sorted by three alpha code. Eastings and northings are in metres, so the distance is
+/// measured on the National Grid.
+pub fn location_conflicts(
+    naptan_stops: &[NaptanStop],
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    max_distance_metres: u32,
+) -> Vec<LocationConflict> {
+    let mut stops_by_code: HashMap<&ThreeAlphaCode, Vec<&NaptanStop>> = HashMap::new();
+    for naptan_stop in naptan_stops {
+        if let Some(three_alpha_code) = naptan_stop.tiploc().and_then(|t| lookup.get(&t)) {
+            stops_by_code
+                .entry(three_alpha_code)
+                .or_default()
+                .push(naptan_stop);
+        }
+    }
+
+    let mut conflicts: Vec<LocationConflict> = stops_by_code
+        .into_iter()
+        .filter_map(|(three_alpha_code, stops)| {
+            let locations: Vec<StopLocation> = stops
+                .iter()
+                .filter_map(|stop| {
+                    Some(StopLocation {
+                        atco_code: stop.atco_code.clone(),
+                        easting: stop.easting?,
+                        northing: stop.northing?,
+                    })
+                })
+                .collect();
+            let distance = |a: &StopLocation, b: &StopLocation| {
+                (a.easting as f64 - b.easting as f64).hypot(a.northing as f64 - b.northing as f64)
+            };
+            let distance_metres = locations
+                .iter()
+                .enumerate()
+                .flat_map(|(index, a)| locations[index + 1..].iter().map(|b| distance(a, b)))
+                .fold(0.0, f64::max)
+                .round() as u32;
+            (distance_metres > max_distance_metres).then(|| LocationConflict {
+                three_alpha_code: three_alpha_code.clone(),
+                locations,
+                distance_metres,
+                chosen: stops[0].atco_code.clone(),
+            })
+        })
+        .collect();
+    conflicts.sort_by(|a, b| a.three_alpha_code.cmp(&b.three_alpha_code));
+    if !conflicts.is_empty() {
+        warn!(
+            "Stations whose NaPTAN stops are over {max_distance_metres}m apart: {}",
+            conflicts.len()
+        );
+    }
+    conflicts
+}
+
+/// A cleanup of station names, applied only when asked for with --normalize-names. Names are
+/// otherwise kept exactly as the CIF file or NaPTAN give them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NameCleanup {
+    /// Collapse runs of spaces into one and trim the ends
+    CollapseSpaces,
+    /// Drop a " (Rail Station)" or " Rail Station" suffix, as NaPTAN names have
+    StripRailStation,
+    /// Title case names written all in capitals, as CIF descriptions are
+    TitleCase,
+}
+
+impl NameCleanup {
+    /// Every cleanup, in the order they are applied
+    pub const ALL: [NameCleanup; 3] = [
+        NameCleanup::CollapseSpaces,
+        NameCleanup::StripRailStation,
+        NameCleanup::TitleCase,
+    ];
+
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            NameCleanup::CollapseSpaces => name.split_whitespace().collect::<Vec<_>>().join(" "),
+            NameCleanup::StripRailStation => {
+                let lowercase = name.to_lowercase();
+                [" (rail station)", " rail station"]
+                    .iter()
+                    .find(|suffix| lowercase.ends_with(*suffix))
+                    .map_or(name, |suffix| {
+                        name[..name.len() - suffix.len()].trim_end_matches([' ', ','])
+                    })
+                    .to_string()
+            }
+            NameCleanup::TitleCase if name.chars().any(char::is_lowercase) => name.to_string(),
+            NameCleanup::TitleCase => {
+                // Capitalise the first letter of each word, but not after an apostrophe, so
+                // KING'S LYNN becomes King's Lynn
+                let mut previous = ' ';
+                name.chars()
+                    .map(|c| {
+                        let starts_word = !previous.is_alphanumeric() && previous != '\'';
+                        previous = c;
+                        if starts_word {
+                            c
+                        } else {
+                            c.to_lowercase().next().unwrap_or(c)
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Apply `cleanups` to every station name, in the order of [`NameCleanup::ALL`] whatever the
+/// order they were given in
+pub fn normalize_names(
+    station_names: &mut HashMap<ThreeAlphaCode, StationName>,
+    cleanups: &[NameCleanup],
+) {
+    for station_name in station_names.values_mut() {
+        for cleanup in NameCleanup::ALL {
+            if cleanups.contains(&cleanup) {
+                station_name.name = cleanup.apply(&station_name.name);
+            }
+        }
+    }
+}
+
+/// Stations whose name could not be found in NaPTAN, sorted by three alpha code
+pub fn stations_not_in_naptan(
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+) -> Vec<ThreeAlphaCode> {
+    let mut missing: Vec<ThreeAlphaCode> = station_names
+        .iter()
+        .filter(|(_, station_name)| station_name.source != NameSource::Naptan)
+        .map(|(three_alpha_code, _)| three_alpha_code.clone())
+        .collect();
+    missing.sort();
+    missing
+}
+
+/// Map each TIPLOC to the three alpha code of the GB station it belongs to, matching TIPLOCs
+/// to stations through their shared STANOX
+///
+/// ```
+/// use rail_hourly_departures::{ThreeAlphaCode, Tiploc, create_lookup, parse};
+///
+/// let cif = [
+///     "TIKNGX   00121000 LONDON KINGS CROSS        87701    KGXLONDON KINGS CRS",
+///     "TIKNGXBEL00121000 LONDON KINGS CROSS BELL   87701                        ",
+/// ]
+/// .map(|line| format!("{line:<80}"))
+/// .join("\n");
+///
+/// let lookup = create_lookup(&parse(cif), &[ThreeAlphaCode("KGX".to_string())]);
+/// let kgx = ThreeAlphaCode("KGX".to_string());
+/// assert_eq!(lookup.get(&Tiploc("KNGX".to_string())), Some(&kgx));
+/// assert_eq!(lookup.get(&Tiploc("KNGXBEL".to_string())), Some(&kgx));
+/// ```
 pub fn create_lookup(
     records: &[Record],
     gb_station_three_alpha_codes: &[ThreeAlphaCode],
 ) -> HashMap<Tiploc, ThreeAlphaCode> {
-    let stanox_lookup = create_stanox_lookup(records, gb_station_three_alpha_codes);
+    create_lookup_with_progress(records, gb_station_three_alpha_codes, true)
+}
+
+/// [`create_lookup`], drawing its progress bars only with `show_progress`
+pub fn create_lookup_with_progress(
+    records: &[Record],
+    gb_station_three_alpha_codes: &[ThreeAlphaCode],
+    show_progress: bool,
+) -> HashMap<Tiploc, ThreeAlphaCode> {
+    let stanox_lookup = create_stanox_lookup(records, gb_station_three_alpha_codes, show_progress);
 
     let mut rail_stop_lookup: HashMap<Tiploc, ThreeAlphaCode> = HashMap::new();
 
-    println!("Creating rail stop lookup");
-    let progress = progress_bar_for_count(records.len());
+    info!("Creating rail stop lookup");
+    let progress = progress_bar_for_count(records.len(), show_progress);
     for record in records.iter().progress_with(progress) {
         if let Record::Stop(stop) = record
             && let Some(three_alpha_code) = stanox_lookup.get(&stop.stanox)
@@ -24,21 +616,22 @@ pub fn create_lookup(
         }
     }
 
-    println!("Rail Stop Lookup len: {:?}", rail_stop_lookup.len());
+    info!("Rail Stop Lookup len: {:?}", rail_stop_lookup.len());
     rail_stop_lookup
 }
 
 fn create_stanox_lookup(
     records: &[Record],
     gb_station_three_alpha_codes: &[ThreeAlphaCode],
+    show_progress: bool,
 ) -> HashMap<String, ThreeAlphaCode> {
     let mut stanox_lookup: HashMap<String, ThreeAlphaCode> = HashMap::new();
 
     let three_alpha_code_set: HashSet<&ThreeAlphaCode> =
         gb_station_three_alpha_codes.iter().collect();
 
-    println!("Creating stanox lookup");
-    let progress = progress_bar_for_count(records.len());
+    info!("Creating stanox lookup");
+    let progress = progress_bar_for_count(records.len(), show_progress);
     for record in records.iter().progress_with(progress) {
         if let Record::Stop(stop) = record
             && let Some(three_alpha_code) = &stop.three_alpha_code
@@ -52,6 +645,1025 @@ fn create_stanox_lookup(
         }
     }
 
-    println!("Stanox Lookup len: {:?}", stanox_lookup.len());
+    info!("Stanox Lookup len: {:?}", stanox_lookup.len());
     stanox_lookup
 }
+
+/// A station with departures but no name in the station name lookup
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UnnamedStation {
+    pub three_alpha_code: ThreeAlphaCode,
+    pub total_departures: u32,
+}
+
+/// The TIPLOCs of each station with departures in any of `all_departures`, for joining the
+/// outputs to data keyed by TIPLOC. Stations and their TIPLOCs are in code order.
+pub fn station_tiplocs(
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    all_departures: &[&HashMap<ThreeAlphaCode, HourlyDepartures>],
+) -> BTreeMap<ThreeAlphaCode, Vec<Tiploc>> {
+    let mut station_tiplocs: BTreeMap<ThreeAlphaCode, Vec<Tiploc>> = BTreeMap::new();
+    for (tiploc, three_alpha_code) in lookup {
+        if all_departures
+            .iter()
+            .any(|departures| departures.contains_key(three_alpha_code))
+        {
+            station_tiplocs
+                .entry(three_alpha_code.clone())
+                .or_default()
+                .push(tiploc.clone());
+        }
+    }
+    for tiplocs in station_tiplocs.values_mut() {
+        tiplocs.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+    station_tiplocs
+}
+
+/// Stations in any of `all_departures` missing from `station_names`, busiest first so the most
+/// impactful gaps are fixed first
+pub fn unnamed_stations(
+    all_departures: &[&HashMap<ThreeAlphaCode, HourlyDepartures>],
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+) -> Vec<UnnamedStation> {
+    let mut totals: HashMap<&ThreeAlphaCode, u32> = HashMap::new();
+    for departures in all_departures {
+        for (three_alpha_code, hourly_departures) in departures.iter() {
+            if !station_names.contains_key(three_alpha_code) {
+                *totals.entry(three_alpha_code).or_insert(0) +=
+                    hourly_departures.hour_counts.iter().sum::<u32>();
+            }
+        }
+    }
+    let mut unnamed: Vec<UnnamedStation> = totals
+        .into_iter()
+        .map(|(three_alpha_code, total_departures)| UnnamedStation {
+            three_alpha_code: three_alpha_code.clone(),
+            total_departures,
+        })
+        .collect();
+    unnamed.sort_by(|a, b| {
+        b.total_departures
+            .cmp(&a.total_departures)
+            .then_with(|| a.three_alpha_code.cmp(&b.three_alpha_code))
+    });
+    unnamed
+}
+
+/// The TIPLOCs given a TI record, so a journey's stops can be checked against them
+pub fn recorded_tiplocs(records: &[Record]) -> HashSet<Tiploc> {
+    records
+        .iter()
+        .filter_map(|record| match record {
+            Record::Stop(stop) => Some(stop.tiploc.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A TIPLOC called at by counted journeys but with no TI record anywhere in the timetable, so
+/// with no station to count its departures at
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StopWithoutRecord {
+    pub tiploc: Tiploc,
+    /// The departures the journeys would have added there over all the selected days
+    pub total_departures: u32,
+    /// The operators of those journeys, with [`NO_OPERATOR`] for those without one, to help
+    /// tell what the stop is
+    pub operators: BTreeSet<String>,
+}
+
+/// The stops of the journeys counted for any of `selectors` whose TIPLOC is missing from
+/// `recorded_tiplocs`, busiest first. Their departures are lost from the counts without a word,
+/// as the lookup only reaches stations through TI records.
+pub fn stops_without_records(
+    journeys: &[Journey],
+    recorded_tiplocs: &HashSet<Tiploc>,
+    lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+    selectors: &[DaySelector],
+    context: &GroupingContext,
+) -> Vec<StopWithoutRecord> {
+    let mut stops: HashMap<&Tiploc, (u32, BTreeSet<String>)> = HashMap::new();
+    for journey in journeys {
+        if !journey
+            .stops
+            .iter()
+            .any(|stop| !recorded_tiplocs.contains(&stop.tiploc))
+        {
+            continue;
+        }
+        let trip_stops = hour_grouping::trip_stops(journey, lookup, context);
+        if hour_grouping::trip_rejection(journey, &trip_stops, selectors, context).is_some() {
+            continue;
+        }
+        let days = selectors
+            .iter()
+            .filter(|selector| selector.selects(&journey.header.operating_days))
+            .count() as u32;
+        let operator = journey.atoc_code.as_deref().unwrap_or(NO_OPERATOR);
+        let last_index = journey.stops.len().saturating_sub(1);
+        for (index, stop) in journey.stops.iter().enumerate() {
+            if recorded_tiplocs.contains(&stop.tiploc) {
+                continue;
+            }
+            let departs = index != last_index
+                && matches!(
+                    stop.activity_flag,
+                    ActivityFlag::PickUpOnly | ActivityFlag::Both
+                )
+                && stop
+                    .departure_time
+                    .or(stop.arrival_time.filter(|_| context.arrival_time_fallback))
+                    .is_some();
+            let (total_departures, operators) = stops.entry(&stop.tiploc).or_default();
+            if departs {
+                *total_departures += days;
+            }
+            operators.insert(operator.to_string());
+        }
+    }
+    let mut stops: Vec<StopWithoutRecord> = stops
+        .into_iter()
+        .map(
+            |(tiploc, (total_departures, operators))| StopWithoutRecord {
+                tiploc: tiploc.clone(),
+                total_departures,
+                operators,
+            },
+        )
+        .collect();
+    stops.sort_by(|a, b| {
+        b.total_departures
+            .cmp(&a.total_departures)
+            .then_with(|| a.tiploc.0.cmp(&b.tiploc.0))
+    });
+    stops
+}
+
+/// The departures --drop-anonymous-stops took out of the outputs, from stations without a name
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Unattributed {
+    pub stations: usize,
+    /// Summed over all the selected days
+    pub departures: u32,
+}
+
+/// Drop the stations missing from `station_names` from the departures and criteria of every
+/// day, returning how many there were and their departures
+pub fn drop_unnamed_stations(
+    days: &mut [DayOutputs],
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+) -> Unattributed {
+    let mut stations: HashSet<ThreeAlphaCode> = HashSet::new();
+    let mut departures = 0;
+    for day in days {
+        day.departures
+            .retain(|three_alpha_code, hourly_departures| {
+                if station_names.contains_key(three_alpha_code) {
+                    return true;
+                }
+                departures += hourly_departures.hour_counts.iter().sum::<u32>();
+                stations.insert(three_alpha_code.clone());
+                false
+            });
+        day.criteria_results
+            .retain(|three_alpha_code, _| station_names.contains_key(three_alpha_code));
+    }
+    Unattributed {
+        stations: stations.len(),
+        departures,
+    }
+}
+
+/// A station among the busiest of a day, for a quick look over a run
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TopStation {
+    pub three_alpha_code: ThreeAlphaCode,
+    pub name: Option<String>,
+    pub total_departures: u32,
+    /// The earliest of the hours with the most departures
+    pub peak_hour: usize,
+    pub peak_hour_departures: u32,
+}
+
+/// The `n` stations with the most departures in the day, ties going to the lower three alpha
+/// code so runs are repeatable
+/// A station as listed in departures_by_name.json
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StationByName {
+    pub three_alpha_code: ThreeAlphaCode,
+    pub total_departures: u32,
+    /// Left out when --outputs skips the criteria
+    #[serde(flatten)]
+    pub criteria: Option<CriteriaPasses>,
+}
+
+/// Which criteria a station passes, as in [`CriteriaResults`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CriteriaPasses {
+    pub all_7_7: bool,
+    pub all_6_10: bool,
+    pub avg_7_7: bool,
+    pub avg_6_10: bool,
+    pub night_service: bool,
+    pub early_start: bool,
+}
+
+/// The stations of `departures` keyed by name, for reading by eye. Stations without a name
+/// are keyed by their three alpha code, and stations sharing a name are each keyed by the
+/// name followed by their code, as in "Victoria (VIC)", rather than merged.
+pub fn departures_by_name<'a>(
+    departures: &'a HashMap<ThreeAlphaCode, HourlyDepartures>,
+    criteria_results: &HashMap<ThreeAlphaCode, CriteriaResults>,
+    station_names: &'a HashMap<ThreeAlphaCode, StationName>,
+) -> BTreeMap<String, StationByName> {
+    let name = |three_alpha_code: &'a ThreeAlphaCode| -> &'a str {
+        station_names
+            .get(three_alpha_code)
+            .map_or(&three_alpha_code.0, |station_name| &station_name.name)
+    };
+    let mut name_counts: HashMap<&str, usize> = HashMap::new();
+    for three_alpha_code in departures.keys() {
+        *name_counts.entry(name(three_alpha_code)).or_default() += 1;
+    }
+
+    departures
+        .iter()
+        .map(|(three_alpha_code, hourly_departures)| {
+            let name = name(three_alpha_code);
+            let key = match name_counts[name] {
+                1 => name.to_string(),
+                _ => format!("{name} ({})", three_alpha_code.0),
+            };
+            let station = StationByName {
+                three_alpha_code: three_alpha_code.clone(),
+                total_departures: hourly_departures.hour_counts.iter().sum(),
+                criteria: criteria_results
+                    .get(three_alpha_code)
+                    .map(|results| CriteriaPasses {
+                        all_7_7: results.all_7_7,
+                        all_6_10: results.all_6_10,
+                        avg_7_7: results.avg_7_7,
+                        avg_6_10: results.avg_6_10,
+                        night_service: results.night_service,
+                        early_start: results.early_start,
+                    }),
+            };
+            (key, station)
+        })
+        .collect()
+}
+
+pub fn top_stations(
+    departures: &HashMap<ThreeAlphaCode, HourlyDepartures>,
+    station_names: &HashMap<ThreeAlphaCode, StationName>,
+    n: usize,
+) -> Vec<TopStation> {
+    let mut stations: Vec<TopStation> = departures
+        .iter()
+        .map(|(three_alpha_code, hourly_departures)| {
+            let hour_counts = &hourly_departures.hour_counts;
+            let peak_hour_departures = hour_counts.iter().copied().max().unwrap_or(0);
+            TopStation {
+                three_alpha_code: three_alpha_code.clone(),
+                name: station_names
+                    .get(three_alpha_code)
+                    .map(|station_name| station_name.name.clone()),
+                total_departures: hour_counts.iter().sum(),
+                peak_hour: hour_counts
+                    .iter()
+                    .position(|&count| count == peak_hour_departures)
+                    .unwrap_or(0),
+                peak_hour_departures,
+            }
+        })
+        .collect();
+    stations.sort_by(|a, b| {
+        b.total_departures
+            .cmp(&a.total_departures)
+            .then_with(|| a.three_alpha_code.cmp(&b.three_alpha_code))
+    });
+    stations.truncate(n);
+    stations
+}
+
+/// Something wrong with an entry of the GB station three alpha code config
+#[derive(Debug, PartialEq)]
+pub enum StationCodeProblem {
+    Malformed {
+        index: usize,
+        code: ThreeAlphaCode,
+    },
+    Duplicate {
+        index: usize,
+        first_index: usize,
+        code: ThreeAlphaCode,
+    },
+    NotInTimetable {
+        index: usize,
+        code: ThreeAlphaCode,
+    },
+}
+
+impl fmt::Display for StationCodeProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StationCodeProblem::Malformed { index, code } => write!(
+                f,
+                "entry {index}: {:?} is not three upper case letters",
+                code.0
+            ),
+            StationCodeProblem::Duplicate {
+                index,
+                first_index,
+                code,
+            } => write!(
+                f,
+                "entry {index}: {} duplicates entry {first_index}, remove one of them",
+                code.0
+            ),
+            StationCodeProblem::NotInTimetable { index, code } => write!(
+                f,
+                "entry {index}: {} is not the three alpha code of any TIPLOC in the CIF file",
+                code.0
+            ),
+        }
+    }
+}
+
+/// Check the GB station three alpha codes for malformed and duplicate entries, and for codes
+/// that no TIPLOC insert record in `records` carries
+pub fn validate_station_codes(
+    gb_station_three_alpha_codes: &[ThreeAlphaCode],
+    records: &[Record],
+) -> Vec<StationCodeProblem> {
+    let codes_in_timetable: HashSet<&ThreeAlphaCode> = records
+        .iter()
+        .filter_map(|record| match record {
+            Record::Stop(stop) => stop.three_alpha_code.as_ref(),
+            _ => None,
+        })
+        .collect();
+
+    let mut problems = Vec::new();
+    let mut first_indices: HashMap<&ThreeAlphaCode, usize> = HashMap::new();
+    for (index, code) in gb_station_three_alpha_codes.iter().enumerate() {
+        if code.0.len() != 3 || !code.0.chars().all(|c| c.is_ascii_uppercase()) {
+            problems.push(StationCodeProblem::Malformed {
+                index,
+                code: code.clone(),
+            });
+        } else if let Some(first_index) = first_indices.get(code) {
+            problems.push(StationCodeProblem::Duplicate {
+                index,
+                first_index: *first_index,
+                code: code.clone(),
+            });
+        } else if !codes_in_timetable.contains(code) {
+            problems.push(StationCodeProblem::NotInTimetable {
+                index,
+                code: code.clone(),
+            });
+        }
+        first_indices.entry(code).or_insert(index);
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::parse;
+    use crate::test_cif;
+
+    fn records() -> Vec<Record> {
+        let cif = test_cif::with_stations([
+            "TIKNGXBEL00121000 LONDON KINGS CROSS BELL   87701                        ",
+        ]);
+        parse(cif)
+    }
+
+    #[test]
+    fn test_osgb_to_wgs84_matches_ordnance_survey_example() {
+        // The worked example of the OS guide, a point near Caister-on-Sea
+        let (latitude, longitude) = osgb_to_osgb36_lat_lon(651_409.903, 313_177.270);
+        assert!((latitude.to_degrees() - 52.657_570_3).abs() < 1e-6);
+        assert!((longitude.to_degrees() - 1.717_921_6).abs() < 1e-6);
+
+        let (latitude, longitude) = osgb_to_wgs84(651_409.903, 313_177.270);
+        assert!((latitude - 52.657_98).abs() < 1e-4, "{latitude}");
+        assert!((longitude - 1.716_05).abs() < 1e-4, "{longitude}");
+    }
+
+    #[test]
+    fn test_parse_naptan_csv() {
+        let csv = "ATCOCode,CommonName,Easting,Northing,StopType,LocalityName\n\
+            9100KNGX,\"London Kings Cross, Rail Station\",530400,183300,RLY,King's Cross\n\
+            490000000001,Some Bus Stop,,,BCT,\n";
+        let stops = parse_naptan_csv(csv).unwrap();
+        assert_eq!(stops.len(), 2);
+        assert_eq!(stops[0].common_name, "London Kings Cross, Rail Station");
+        assert_eq!(stops[0].easting, Some(530400));
+        assert_eq!(stops[0].tiploc(), Some(Tiploc("KNGX".to_string())));
+        assert_eq!(stops[0].locality.as_deref(), Some("King's Cross"));
+        assert_eq!(stops[1].easting, None);
+        assert_eq!(stops[1].tiploc(), None);
+        assert_eq!(stops[1].locality, None);
+    }
+
+    #[test]
+    fn test_station_names_prefer_naptan() {
+        let records = records();
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let naptan_stops = vec![NaptanStop {
+            atco_code: "9100KNGXBEL".to_string(),
+            common_name: "London Kings Cross Rail Station".to_string(),
+            easting: Some(530400),
+            northing: Some(183300),
+            stop_type: "RLY".to_string(),
+            locality: None,
+        }];
+
+        let (station_names, _) = create_station_name_lookup(
+            &records,
+            &lookup,
+            Some(&naptan_stops),
+            NamePolicy::PreferNaptan,
+        );
+        assert_eq!(
+            station_names[&codes[0]].name,
+            "London Kings Cross Rail Station"
+        );
+        assert_eq!(station_names[&codes[0]].source, NameSource::Naptan);
+        assert_eq!(station_names[&codes[1]].name, "CAMBRIDGE");
+        assert_eq!(station_names[&codes[1]].source, NameSource::Cif);
+        assert_eq!(
+            station_names[&codes[0]].nlc_description.as_deref(),
+            Some("LONDON KINGS CRS")
+        );
+        assert_eq!(
+            stations_not_in_naptan(&station_names),
+            vec![codes[1].clone()]
+        );
+    }
+
+    #[test]
+    fn test_location_conflicts() {
+        let records = records();
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let naptan_stop = |atco_code: &str, easting| NaptanStop {
+            atco_code: atco_code.to_string(),
+            common_name: "Station".to_string(),
+            easting,
+            northing: Some(183_300),
+            stop_type: "RLY".to_string(),
+            locality: None,
+        };
+        let naptan_stops = [
+            naptan_stop("9100KNGX", Some(530_400)),
+            naptan_stop("9100KNGXBEL", Some(532_400)),
+            naptan_stop("9100CAMBDGE", Some(546_200)),
+            naptan_stop("9100CAMBDGE", None),
+        ];
+
+        let conflicts = location_conflicts(&naptan_stops, &lookup, 1000);
+        assert_eq!(
+            conflicts,
+            [LocationConflict {
+                three_alpha_code: codes[0].clone(),
+                locations: vec![
+                    StopLocation {
+                        atco_code: "9100KNGX".to_string(),
+                        easting: 530_400,
+                        northing: 183_300,
+                    },
+                    StopLocation {
+                        atco_code: "9100KNGXBEL".to_string(),
+                        easting: 532_400,
+                        northing: 183_300,
+                    },
+                ],
+                distance_metres: 2000,
+                chosen: "9100KNGX".to_string(),
+            }]
+        );
+        // The chosen stop is the one the station is located at
+        let (station_names, _) = create_station_name_lookup(
+            &records,
+            &lookup,
+            Some(&naptan_stops),
+            NamePolicy::PreferNaptan,
+        );
+        assert_eq!(station_names[&codes[0]].easting, Some(530_400));
+        assert!(location_conflicts(&naptan_stops, &lookup, 2000).is_empty());
+    }
+
+    #[test]
+    fn test_station_tiplocs_of_stations_with_departures() {
+        let records = records();
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let departures = HashMap::from([(
+            codes[0].clone(),
+            HourlyDepartures {
+                three_alpha_code: codes[0].clone(),
+                hour_counts: [1; 24],
+                hour_counts_journey_starts: [0; 24],
+                hour_counts_arrivals: [0; 24],
+                hour_counts_journey_ends: [0; 24],
+                next_stop_three_alpha_code: vec![Vec::new(); 24],
+                ..HourlyDepartures::default()
+            }
+            .with_totals(),
+        )]);
+        let station_tiplocs = station_tiplocs(&lookup, &[&departures]);
+        assert_eq!(
+            serde_json::to_string(&station_tiplocs).unwrap(),
+            r#"{"KGX":["KNGX","KNGXBEL"]}"#
+        );
+    }
+
+    /// King's Cross as named by two concatenated CIF files and by two NaPTAN stops
+    fn conflicting_names(policy: NamePolicy) -> (StationName, Vec<NameConflict>) {
+        let cif = test_cif::cif([
+            "TIKNGX   00121000 LONDON KINGS X            87701    KGXLONDON KINGS CRS",
+            "TIKNGX   00121000 LONDON KINGS CROSS RAIL   87701    KGXLONDON KINGS CRS",
+            "TIKNGX   00121000 LONDON KINGS X            87701    KGXLONDON KINGS CRS",
+        ]);
+        let records = parse(cif);
+        let codes = [ThreeAlphaCode("KGX".to_string())];
+        let lookup = create_lookup(&records, &codes);
+        let naptan_stop = |common_name: &str, easting| NaptanStop {
+            atco_code: "9100KNGX".to_string(),
+            common_name: common_name.to_string(),
+            easting: Some(easting),
+            northing: Some(183_300),
+            stop_type: "RLY".to_string(),
+            locality: None,
+        };
+        let naptan_stops = [
+            naptan_stop("Kings Cross", 530_400),
+            naptan_stop("London Kings Cross", 530_500),
+        ];
+        let (mut station_names, conflicts) =
+            create_station_name_lookup(&records, &lookup, Some(&naptan_stops), policy);
+        (station_names.remove(&codes[0]).unwrap(), conflicts)
+    }
+
+    #[test]
+    fn test_name_policy_first() {
+        let (station_name, _) = conflicting_names(NamePolicy::First);
+        assert_eq!(station_name.name, "LONDON KINGS X");
+        assert_eq!(station_name.source, NameSource::Cif);
+        // The location still comes from NaPTAN
+        assert_eq!(station_name.easting, Some(530_400));
+    }
+
+    #[test]
+    fn test_name_policy_longest() {
+        let (station_name, _) = conflicting_names(NamePolicy::Longest);
+        assert_eq!(station_name.name, "LONDON KINGS CROSS RAIL");
+        assert_eq!(station_name.source, NameSource::Cif);
+
+        let candidates = [
+            ("LONDON KINGS XB".to_string(), NameSource::Cif),
+            ("LONDON KINGS XA".to_string(), NameSource::Cif),
+        ];
+        assert_eq!(NamePolicy::Longest.choose(&candidates).0, "LONDON KINGS XA");
+    }
+
+    #[test]
+    fn test_name_policy_prefer_naptan() {
+        let (station_name, conflicts) = conflicting_names(NamePolicy::PreferNaptan);
+        assert_eq!(station_name.name, "London Kings Cross");
+        assert_eq!(station_name.source, NameSource::Naptan);
+        assert_eq!(station_name.easting, Some(530_400));
+
+        let names: Vec<(NameSource, Vec<&str>, &str)> = conflicts
+            .iter()
+            .map(|conflict| {
+                (
+                    conflict.source,
+                    conflict.names.iter().map(String::as_str).collect(),
+                    conflict.chosen.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (
+                    NameSource::Cif,
+                    vec!["LONDON KINGS X", "LONDON KINGS CROSS RAIL"],
+                    "London Kings Cross"
+                ),
+                (
+                    NameSource::Naptan,
+                    vec!["Kings Cross", "London Kings Cross"],
+                    "London Kings Cross"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_station_names_keep_their_commas() {
+        let cif = format!(
+            "{:<80}",
+            "TICLFTNDN00121000 CLIFTON DOWN, BRISTOL     87701    CFNCLIFTON DOWN"
+        );
+        let records = parse(cif);
+        let codes = [ThreeAlphaCode("CFN".to_string())];
+        let lookup = create_lookup(&records, &codes);
+        let (station_names, _) =
+            create_station_name_lookup(&records, &lookup, None, NamePolicy::default());
+        assert_eq!(station_names[&codes[0]].name, "CLIFTON DOWN, BRISTOL");
+        assert_eq!(
+            crate::utils::join_csv_line(&["CFN", &station_names[&codes[0]].name]),
+            "CFN,\"CLIFTON DOWN, BRISTOL\""
+        );
+    }
+
+    #[test]
+    fn test_name_cleanups() {
+        let apply = |cleanup: NameCleanup, name: &str| cleanup.apply(name);
+        assert_eq!(
+            apply(NameCleanup::CollapseSpaces, " LONDON  KINGS   CROSS "),
+            "LONDON KINGS CROSS"
+        );
+
+        assert_eq!(
+            apply(NameCleanup::StripRailStation, "Cambridge Rail Station"),
+            "Cambridge"
+        );
+        assert_eq!(
+            apply(NameCleanup::StripRailStation, "Stevenage (Rail Station)"),
+            "Stevenage"
+        );
+        assert_eq!(
+            apply(
+                NameCleanup::StripRailStation,
+                "London Kings Cross, Rail Station"
+            ),
+            "London Kings Cross"
+        );
+        assert_eq!(
+            apply(NameCleanup::StripRailStation, "Rail Station Road"),
+            "Rail Station Road"
+        );
+
+        assert_eq!(apply(NameCleanup::TitleCase, "KING'S LYNN"), "King's Lynn");
+        assert_eq!(
+            apply(NameCleanup::TitleCase, "CLIFTON DOWN, BRISTOL"),
+            "Clifton Down, Bristol"
+        );
+        assert_eq!(
+            apply(NameCleanup::TitleCase, "BURTON-ON-TRENT (ST. PAUL)"),
+            "Burton-On-Trent (St. Paul)"
+        );
+        // Names already in mixed case are left alone
+        assert_eq!(
+            apply(NameCleanup::TitleCase, "McDonald Road"),
+            "McDonald Road"
+        );
+    }
+
+    #[test]
+    fn test_normalize_names_applies_cleanups_in_order() {
+        let mut station_names = HashMap::from([(
+            ThreeAlphaCode("CBG".to_string()),
+            StationName {
+                name: "CAMBRIDGE   RAIL STATION".to_string(),
+                easting: None,
+                northing: None,
+                source: NameSource::Cif,
+                locality: None,
+                nlc_description: None,
+            },
+        )]);
+        normalize_names(
+            &mut station_names,
+            &[NameCleanup::TitleCase, NameCleanup::StripRailStation],
+        );
+        // The suffix is stripped before title casing, whatever the order given
+        assert_eq!(
+            station_names[&ThreeAlphaCode("CBG".to_string())].name,
+            "Cambridge"
+        );
+        normalize_names(&mut station_names, &NameCleanup::ALL);
+        assert_eq!(
+            station_names[&ThreeAlphaCode("CBG".to_string())].name,
+            "Cambridge"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_latin1_station_names_reach_the_lookup() {
+        let dir = std::env::temp_dir().join(format!("rhd_latin1_{}", std::process::id()));
+        fs_err::create_dir_all(&dir).unwrap();
+        let path = dir.join("latin1.CIF");
+        let line = format!(
+            "{:<80}",
+            "TIPONTYPR00121000 PONTYPRIDD GARE           87701    PPDPONTYPRIDD"
+        );
+        // Swap the E for a Latin-1 é, a single byte so the line keeps its width
+        let mut bytes = line.into_bytes();
+        bytes[32] = 0xe9;
+        fs_err::write(&path, bytes).unwrap();
+
+        let text = crate::records::read_file(&path, crate::decompress::Encoding::Auto).unwrap();
+        fs_err::remove_dir_all(&dir).unwrap();
+        let records = parse(text);
+        let codes = [ThreeAlphaCode("PPD".to_string())];
+        let lookup = create_lookup(&records, &codes);
+        let (station_names, _) =
+            create_station_name_lookup(&records, &lookup, None, NamePolicy::default());
+
+        assert_eq!(station_names[&codes[0]].name, "PONTYPRIDD GARé");
+        assert!(
+            serde_json::to_string(&station_names)
+                .unwrap()
+                .contains("PONTYPRIDD GARé")
+        );
+    }
+
+    #[test]
+    fn test_stops_without_records_lists_tiplocs_with_no_ti_record() {
+        // MYSTRY has no TI record, so its departures go uncounted
+        let cif = test_cif::with_stations([
+            "BSNC123452601012612311111100 POO",
+            "BX         GNY",
+            "LOKNGX    0800 0800",
+            "LIMYSTRY  0820 0821      08200821         T",
+            "LTCAMBDGE 0900 0900",
+            "BSNC123462601012612311111100 POO",
+            "LOMYSTRY  1000 1000",
+            "LTCAMBDGE 1100 1100",
+        ]);
+        let records = parse(cif);
+        let recorded = recorded_tiplocs(&records);
+        let codes = ["KGX", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let journeys = crate::records::assemble_journeys(records);
+        let context = GroupingContext::for_week(crate::records::Date(260113));
+        let selectors = [
+            DaySelector::Day(crate::records::Day::Monday),
+            DaySelector::Day(crate::records::Day::Tuesday),
+        ];
+
+        // The second journey calls at only one station, so is not counted
+        let stops = stops_without_records(&journeys, &recorded, &lookup, &selectors, &context);
+        assert_eq!(
+            stops,
+            vec![StopWithoutRecord {
+                tiploc: Tiploc("MYSTRY".to_string()),
+                total_departures: 2,
+                operators: BTreeSet::from(["GN".to_string()]),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unnamed_stations_sorted_by_departures() {
+        let departures: HashMap<ThreeAlphaCode, HourlyDepartures> =
+            [("AAA", 1), ("BBB", 5), ("CCC", 3)]
+                .into_iter()
+                .map(|(code, count)| {
+                    let three_alpha_code = ThreeAlphaCode(code.to_string());
+                    let mut hour_counts = [0; 24];
+                    hour_counts[8] = count;
+                    let hourly_departures = HourlyDepartures {
+                        three_alpha_code: three_alpha_code.clone(),
+                        hour_counts,
+                        hour_counts_journey_starts: [0; 24],
+                        hour_counts_arrivals: [0; 24],
+                        hour_counts_journey_ends: [0; 24],
+                        next_stop_three_alpha_code: vec![Vec::new(); 24],
+                        ..HourlyDepartures::default()
+                    }
+                    .with_totals();
+                    (three_alpha_code, hourly_departures)
+                })
+                .collect();
+        let station_names = HashMap::from([(
+            ThreeAlphaCode("CCC".to_string()),
+            StationName {
+                name: "C".to_string(),
+                easting: None,
+                northing: None,
+                source: NameSource::Cif,
+                locality: None,
+                nlc_description: None,
+            },
+        )]);
+
+        let unnamed = unnamed_stations(&[&departures], &station_names);
+        assert_eq!(
+            unnamed,
+            vec![
+                UnnamedStation {
+                    three_alpha_code: ThreeAlphaCode("BBB".to_string()),
+                    total_departures: 5,
+                },
+                UnnamedStation {
+                    three_alpha_code: ThreeAlphaCode("AAA".to_string()),
+                    total_departures: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_departures_by_name_keeps_shared_names_apart() {
+        let departures: HashMap<ThreeAlphaCode, HourlyDepartures> =
+            [("VIC", 10), ("MCV", 1), ("VIR", 2), ("XXX", 3)]
+                .into_iter()
+                .map(|(code, count)| {
+                    let three_alpha_code = ThreeAlphaCode(code.to_string());
+                    let hourly_departures = HourlyDepartures {
+                        three_alpha_code: three_alpha_code.clone(),
+                        hour_counts: [count; 24],
+                        hour_counts_journey_starts: [0; 24],
+                        hour_counts_arrivals: [0; 24],
+                        hour_counts_journey_ends: [0; 24],
+                        next_stop_three_alpha_code: vec![Vec::new(); 24],
+                        ..HourlyDepartures::default()
+                    }
+                    .with_totals();
+                    (three_alpha_code, hourly_departures)
+                })
+                .collect();
+        let station_names: HashMap<ThreeAlphaCode, StationName> = [
+            ("VIC", "Victoria"),
+            ("VIR", "Victoria"),
+            ("MCV", "Manchester Victoria"),
+        ]
+        .into_iter()
+        .map(|(code, name)| {
+            let station_name = StationName {
+                name: name.to_string(),
+                easting: None,
+                northing: None,
+                source: NameSource::Cif,
+                locality: None,
+                nlc_description: None,
+            };
+            (ThreeAlphaCode(code.to_string()), station_name)
+        })
+        .collect();
+        let criteria_results = crate::evaluate_criteria(&departures, &Default::default());
+
+        let by_name = departures_by_name(&departures, &criteria_results, &station_names);
+        assert_eq!(
+            by_name.keys().collect::<Vec<_>>(),
+            [
+                "Manchester Victoria",
+                "Victoria (VIC)",
+                "Victoria (VIR)",
+                "XXX"
+            ]
+        );
+        assert_eq!(by_name["Victoria (VIC)"].total_departures, 240);
+        assert_eq!(
+            serde_json::to_value(&by_name["Victoria (VIR)"]).unwrap(),
+            serde_json::json!({
+                "three_alpha_code": "VIR",
+                "total_departures": 48,
+                "all_7_7": false,
+                "all_6_10": false,
+                "avg_7_7": false,
+                "avg_6_10": false,
+                "night_service": true,
+                "early_start": true,
+            })
+        );
+        assert!(by_name["Victoria (VIC)"].criteria.as_ref().unwrap().all_7_7);
+        // Without the criteria only the departures are given
+        let by_name = departures_by_name(&departures, &HashMap::new(), &station_names);
+        assert_eq!(
+            serde_json::to_value(&by_name["XXX"]).unwrap(),
+            serde_json::json!({"three_alpha_code": "XXX", "total_departures": 72})
+        );
+    }
+
+    #[test]
+    fn test_top_stations_ties_break_on_code() {
+        let departures: HashMap<ThreeAlphaCode, HourlyDepartures> = [
+            ("AAA", [(8, 2), (17, 2)]),
+            ("BBB", [(7, 1), (9, 5)]),
+            ("CCC", [(8, 4), (9, 0)]),
+            ("DDD", [(0, 1), (23, 0)]),
+        ]
+        .into_iter()
+        .map(|(code, counts)| {
+            let three_alpha_code = ThreeAlphaCode(code.to_string());
+            let mut hour_counts = [0; 24];
+            for (hour, count) in counts {
+                hour_counts[hour] = count;
+            }
+            let hourly_departures = HourlyDepartures {
+                three_alpha_code: three_alpha_code.clone(),
+                hour_counts,
+                hour_counts_journey_starts: [0; 24],
+                hour_counts_arrivals: [0; 24],
+                hour_counts_journey_ends: [0; 24],
+                next_stop_three_alpha_code: vec![Vec::new(); 24],
+                ..HourlyDepartures::default()
+            }
+            .with_totals();
+            (three_alpha_code, hourly_departures)
+        })
+        .collect();
+        let station_names = HashMap::from([(
+            ThreeAlphaCode("CCC".to_string()),
+            StationName {
+                name: "C".to_string(),
+                easting: None,
+                northing: None,
+                source: NameSource::Cif,
+                locality: None,
+                nlc_description: None,
+            },
+        )]);
+
+        let top = top_stations(&departures, &station_names, 3);
+        assert_eq!(
+            top,
+            vec![
+                TopStation {
+                    three_alpha_code: ThreeAlphaCode("BBB".to_string()),
+                    name: None,
+                    total_departures: 6,
+                    peak_hour: 9,
+                    peak_hour_departures: 5,
+                },
+                TopStation {
+                    three_alpha_code: ThreeAlphaCode("AAA".to_string()),
+                    name: None,
+                    total_departures: 4,
+                    peak_hour: 8,
+                    peak_hour_departures: 2,
+                },
+                TopStation {
+                    three_alpha_code: ThreeAlphaCode("CCC".to_string()),
+                    name: Some("C".to_string()),
+                    total_departures: 4,
+                    peak_hour: 8,
+                    peak_hour_departures: 4,
+                },
+            ]
+        );
+        assert!(top_stations(&departures, &station_names, 0).is_empty());
+        assert_eq!(top_stations(&departures, &station_names, 10).len(), 4);
+    }
+
+    #[test]
+    fn test_validate_station_codes() {
+        let codes =
+            ["KGX", "kgx", "CBG", "KGX", "ZZZ"].map(|code| ThreeAlphaCode(code.to_string()));
+        let code = |index: usize| codes[index].clone();
+
+        assert_eq!(
+            validate_station_codes(&codes, &records()),
+            vec![
+                StationCodeProblem::Malformed {
+                    index: 1,
+                    code: code(1)
+                },
+                StationCodeProblem::Duplicate {
+                    index: 3,
+                    first_index: 0,
+                    code: code(3)
+                },
+                StationCodeProblem::NotInTimetable {
+                    index: 4,
+                    code: code(4)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_read_station_codes_merges_json_and_toml() {
+        let dir = std::env::temp_dir().join(format!("rhd_station_codes_{}", std::process::id()));
+        fs_err::create_dir_all(&dir).unwrap();
+        let json_path = dir.join("base.json");
+        let toml_path = dir.join("extra.toml");
+        fs_err::write(&json_path, r#"["KGX", "CBG"]"#).unwrap();
+        fs_err::write(&toml_path, r#"three_alpha_codes = ["CBG", "SVG"]"#).unwrap();
+
+        let codes = read_station_codes(&[json_path, toml_path]).unwrap();
+        let missing = read_station_codes(&[dir.join("missing.json")]);
+        fs_err::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            codes,
+            ["KGX", "CBG", "SVG"].map(|code| ThreeAlphaCode(code.to_string()))
+        );
+        assert!(missing.unwrap_err().to_string().contains("missing.json"));
+    }
+}
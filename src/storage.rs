@@ -0,0 +1,216 @@
+//! Where the CIF timetable is read from and the outputs are written to. The file backed source
+//! and sink need the `fs` feature; the in-memory ones let the parsing and counting run in tests
+//! or a web page with no filesystem at all.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::path::PathBuf;
+
+use super::decompress::{self, Encoding};
+use super::records;
+use super::utils;
+
+/// A CIF timetable to parse
+pub trait CifSource {
+    /// The source as named in messages, such as the path of the file
+    fn name(&self) -> String;
+
+    /// The bytes of the timetable, which may be gzip or zip compressed
+    fn read_bytes(&self) -> Result<Vec<u8>>;
+
+    /// The decompressed and decoded text of the timetable, checked to start with a CIF header
+    fn read_text(&self, encoding: Encoding) -> Result<String> {
+        let raw_cif_text = decompress::decode_cif_bytes(self.read_bytes()?, encoding)
+            .with_context(|| format!("Failed to read the CIF file {}", self.name()))?;
+        records::check_starts_with_header(&raw_cif_text)
+            .with_context(|| format!("{} is not a CIF file", self.name()))?;
+        Ok(raw_cif_text)
+    }
+}
+
+/// A CIF file on disk
+#[cfg(feature = "fs")]
+pub struct CifFile(pub PathBuf);
+
+#[cfg(feature = "fs")]
+impl CifSource for CifFile {
+    fn name(&self) -> String {
+        self.0.display().to_string()
+    }
+
+    fn read_bytes(&self) -> Result<Vec<u8>> {
+        fs_err::read(&self.0)
+            .with_context(|| format!("Failed to read the CIF file {}", self.name()))
+    }
+}
+
+/// A CIF timetable already in memory, such as an upload or a test fixture
+pub struct InMemoryCif {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+impl InMemoryCif {
+    pub fn new(name: &str, text: &str) -> Self {
+        InMemoryCif {
+            name: name.to_string(),
+            bytes: text.as_bytes().to_vec(),
+        }
+    }
+}
+
+impl CifSource for InMemoryCif {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn read_bytes(&self) -> Result<Vec<u8>> {
+        Ok(self.bytes.clone())
+    }
+}
+
+/// Somewhere to write the output files
+pub trait OutputSink {
+    /// Write `bytes` as the output `file_name`, which includes its extension
+    fn write_output(&mut self, file_name: &str, bytes: &[u8]) -> Result<()>;
+
+    /// Write the output `file_name` with `write`. Sinks that can stream override this, the
+    /// default collects the bytes for [`write_output`](Self::write_output).
+    fn write_with<F>(&mut self, file_name: &str, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+        Self: Sized,
+    {
+        let mut bytes = Vec::new();
+        write(&mut bytes)?;
+        self.write_output(file_name, &bytes)
+    }
+
+    /// Write `data` as the JSON output `{file_name}.json`
+    fn write_json<T: Serialize>(&mut self, file_name: &str, data: &T) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.write_with(&format!("{file_name}.json"), |writer| {
+            serde_json::to_writer(writer, data)
+                .with_context(|| format!("Failed to serialize {file_name}"))
+        })
+    }
+
+    /// Write each of `rows` as a line of the JSON Lines output `{file_name}.jsonl`
+    fn write_jsonl<T: Serialize>(
+        &mut self,
+        file_name: &str,
+        rows: impl IntoIterator<Item = T>,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.write_with(&format!("{file_name}.jsonl"), |writer| {
+            utils::write_json_lines(writer, rows)
+        })
+    }
+
+    /// Whether the outputs go to stdout, which only takes one document
+    fn is_stdout(&self) -> bool {
+        false
+    }
+
+    /// The names of the files already there from an earlier run
+    fn existing_outputs(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// An output directory, or stdout when it is "-". Files go to a .tmp file first and are renamed
+/// into place, so a killed run never leaves a truncated file behind, and existing files are
+/// only replaced with `overwrite`, as under --force.
+#[cfg(feature = "fs")]
+pub struct OutputDirectory {
+    pub path: PathBuf,
+    pub overwrite: bool,
+}
+
+#[cfg(feature = "fs")]
+impl OutputDirectory {
+    pub fn new(path: PathBuf, overwrite: bool) -> Self {
+        OutputDirectory { path, overwrite }
+    }
+}
+
+#[cfg(feature = "fs")]
+impl OutputSink for OutputDirectory {
+    fn write_output(&mut self, file_name: &str, bytes: &[u8]) -> Result<()> {
+        self.write_with(file_name, |writer| Ok(writer.write_all(bytes)?))
+    }
+
+    fn write_with<F>(&mut self, file_name: &str, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        if self.is_stdout() {
+            return write(&mut std::io::stdout().lock())
+                .with_context(|| format!("Failed to write {file_name} to stdout"));
+        }
+        utils::write_file_with_overwrite(file_name, &self.path, self.overwrite, write)
+    }
+
+    fn write_json<T: Serialize>(&mut self, file_name: &str, data: &T) -> Result<()> {
+        let is_stdout = self.is_stdout();
+        self.write_with(&format!("{file_name}.json"), |writer| {
+            serde_json::to_writer(&mut *writer, data)
+                .with_context(|| format!("Failed to serialize {file_name}"))?;
+            // Printed JSON ends its line, as shells expect
+            if is_stdout {
+                writeln!(writer)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn is_stdout(&self) -> bool {
+        utils::is_stdout(&self.path)
+    }
+
+    fn existing_outputs(&self) -> Result<Vec<String>> {
+        if !self.path.is_dir() {
+            return Ok(Vec::new());
+        }
+        fs_err::read_dir(&self.path)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+}
+
+/// The outputs held in memory, keyed by file name
+#[derive(Debug, Default)]
+pub struct InMemoryOutputs(pub BTreeMap<String, Vec<u8>>);
+
+impl OutputSink for InMemoryOutputs {
+    fn write_output(&mut self, file_name: &str, bytes: &[u8]) -> Result<()> {
+        self.0.insert(file_name.to_string(), bytes.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_memory_source_and_sink() {
+        let source = InMemoryCif::new("upload", "HDTPS.UDFROC1.PD2601120901260000");
+        assert!(source.read_text(Encoding::Auto).is_ok());
+        let error = InMemoryCif::new("upload", "BSNC123452601012612311111100 POO")
+            .read_text(Encoding::Auto)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "upload is not a CIF file");
+
+        let mut outputs = InMemoryOutputs::default();
+        outputs.write_json("counts", &[1, 2]).unwrap();
+        assert_eq!(outputs.0["counts.json"], b"[1,2]");
+    }
+}
@@ -0,0 +1,27 @@
+//! Small CIF timetables for the unit tests, written out a few lines at a time
+
+/// The station records of King's Cross, Stevenage and Cambridge, which the test journeys run
+/// between
+pub const STATIONS: [&str; 3] = [
+    "TIKNGX   00121000 LONDON KINGS CROSS        87701    KGXLONDON KINGS CRS",
+    "TISTEVNGE00161000 STEVENAGE                 87902    SVGSTEVENAGE",
+    "TICAMBDGE00331000 CAMBRIDGE                 51003    CBGCAMBRIDGE",
+];
+
+/// `lines`, each padded to the 80 characters of a CIF record, joined into a timetable
+pub fn cif<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> String {
+    lines
+        .into_iter()
+        .map(|line| format!("{:<80}", line.as_ref()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// [`cif`] of the [`STATIONS`] followed by `journey_lines`
+pub fn with_stations<S: AsRef<str>>(journey_lines: impl IntoIterator<Item = S>) -> String {
+    cif(STATIONS.map(str::to_string).into_iter().chain(
+        journey_lines
+            .into_iter()
+            .map(|line| line.as_ref().to_string()),
+    ))
+}
@@ -1,34 +1,126 @@
 use anyhow::Result;
+#[cfg(feature = "fs")]
+use anyhow::{Context, bail};
+#[cfg(feature = "fs")]
 use fs_err::File;
 use indicatif::{ProgressBar, ProgressStyle};
-use serde::{Serialize, de::DeserializeOwned};
-use std::io::{BufReader, BufWriter, Write};
+#[cfg(feature = "fs")]
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+#[cfg(feature = "fs")]
+use std::io::{BufReader, BufWriter};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use super::criteria::CriteriaThresholds;
+use super::hour_grouping::HourWindow;
+#[cfg(feature = "fs")]
+use super::info;
+use super::log;
+use super::records::{Date, ErrorBudget, FileHeader};
+use super::storage::{CifSource, OutputSink};
 
 /// Creates a progress bar for monitoring function progress.
-pub fn progress_bar_for_count(count: usize) -> ProgressBar {
+/// The bar is hidden unless `show_progress`, which --no-progress turns off, under --quiet, and
+/// when stderr, which it draws on, is not a terminal, so logs under cron stay free of carriage
+/// returns.
+pub fn progress_bar_for_count(count: usize, show_progress: bool) -> ProgressBar {
+    if !show_progress || !log::enabled(log::Level::Info) || !std::io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
     ProgressBar::new(count as u64).with_style(ProgressStyle::with_template(
         "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, {eta})").unwrap())
 }
 
-pub fn write_json_file<T: Serialize>(
-    file_name: String,
-    output_directory: &str,
-    data: T,
+/// Whether `output_directory` is "-", meaning outputs are printed to stdout instead
+pub fn is_stdout(output_directory: &Path) -> bool {
+    output_directory == Path::new("-")
+}
+
+/// Write each of `rows` as a line of JSON to `path`, which includes its extension, in the same
+/// way as [`OutputSink::write_jsonl`]. An existing file is only replaced with `overwrite`.
+#[cfg(feature = "fs")]
+pub fn write_jsonl_path<T: Serialize>(
+    path: &Path,
+    rows: impl IntoIterator<Item = T>,
+    overwrite: bool,
 ) -> Result<()> {
-    let path = format!("{output_directory}/{file_name}.json");
-    println!("Writing to {path}");
-    let file = File::create(path)?;
-    let mut writer = BufWriter::new(file);
-    serde_json::to_writer(&mut writer, &data)?;
-    writer.flush()?;
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file path", path.display()))?
+        .to_string_lossy();
+    let output_directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    write_file_with_overwrite(&file_name, output_directory, overwrite, |writer| {
+        write_json_lines(writer, rows)
+    })
+}
+
+/// Write each of `rows` as a line of JSON. Rows are serialized one at a time as they are
+/// written, so the whole document is never held in memory.
+pub(crate) fn write_json_lines<T: Serialize>(
+    writer: &mut dyn Write,
+    rows: impl IntoIterator<Item = T>,
+) -> Result<()> {
+    for row in rows {
+        serde_json::to_writer(&mut *writer, &row)?;
+        writer.write_all(b"\n")?;
+    }
     Ok(())
 }
 
-pub fn read_json_file<T: DeserializeOwned>(path: String) -> Result<T> {
-    if !path.ends_with(".json") && !path.ends_with(".geojson") {
+/// Write `{output_directory}/{file_name}`, which includes its extension, with `write`, creating
+/// the directory if needed. The file goes through a .tmp file renamed into place, and an
+/// existing file is only replaced with `overwrite`.
+#[cfg(feature = "fs")]
+pub(crate) fn write_file_with_overwrite(
+    file_name: &str,
+    output_directory: &Path,
+    overwrite: bool,
+    write: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    let path = output_directory.join(file_name);
+    if !overwrite && path.exists() {
+        bail!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        );
+    }
+    info!("Writing to {}", path.display());
+    fs_err::create_dir_all(output_directory).with_context(|| {
+        format!(
+            "Failed to create the output directory {}",
+            output_directory.display()
+        )
+    })?;
+
+    let tmp_path = output_directory.join(format!("{file_name}.tmp"));
+    let write_tmp = || -> Result<()> {
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    };
+    if let Err(e) = write_tmp() {
+        let _ = fs_err::remove_file(&tmp_path);
+        return Err(e.context(format!("Failed to write {}", path.display())));
+    }
+    fs_err::rename(&tmp_path, &path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(feature = "fs")]
+pub fn read_json_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    if !has_extension(path, &["json", "geojson"]) {
         return Err(anyhow::anyhow!(
             "read_json_file needs {} to end with .json or .geojson",
-            path
+            path.display()
         ));
     }
     let file = File::open(path)?;
@@ -36,3 +128,671 @@ pub fn read_json_file<T: DeserializeOwned>(path: String) -> Result<T> {
     let data = serde_json::from_reader(reader)?;
     Ok(data)
 }
+
+/// The shape of the departures and criteria files, bumped whenever `HourlyDepartures`,
+/// `CriteriaResults` or the wrapper around them change shape. Files from before the version was
+/// written count as 1.
+pub const SCHEMA_VERSION: u32 = 10;
+
+/// A CIF file the outputs were counted from, as listed in the departures and criteria files so
+/// that a file re-processed by mistake shows its age
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Source {
+    pub file_reference: String,
+    /// When the file was produced, from its HD record
+    pub date_of_extract: Option<Date>,
+    pub time_of_extract: String,
+}
+
+impl From<&FileHeader> for Source {
+    fn from(header: &FileHeader) -> Self {
+        Source {
+            file_reference: header.current_file_reference.clone(),
+            date_of_extract: header.date_of_extract.clone(),
+            time_of_extract: header.time_of_extract.clone(),
+        }
+    }
+}
+
+/// A departures or criteria file: the data under the schema version it was written with, the
+/// CIF files it was counted from, and for a criteria file the thresholds it was evaluated with
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    criteria_thresholds: Option<CriteriaThresholds>,
+    #[serde(skip_serializing_if = "<[Source]>::is_empty")]
+    sources: &'a [Source],
+    /// The --from-hour and --to-hour window the counts were limited to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hour_window: Option<HourWindow>,
+    data: T,
+}
+
+/// Write `data` to `sink` as the JSON output `{file_name}.json`, wrapped in the
+/// [`SCHEMA_VERSION`]. With `camel_case` the keys of `data` are written in camelCase for
+/// JavaScript readers.
+pub fn write_versioned_json_file<T: Serialize>(
+    file_name: &str,
+    sink: &mut impl OutputSink,
+    data: T,
+    camel_case: bool,
+) -> Result<()> {
+    write_criteria_json_file(file_name, sink, data, camel_case, None, &[], None)
+}
+
+/// [`write_versioned_json_file`], also recording the `criteria_thresholds` the results in
+/// `data` were evaluated with, the `sources` they were counted from and the `hour_window` they
+/// were limited to, so the file describes itself
+pub fn write_criteria_json_file<T: Serialize>(
+    file_name: &str,
+    sink: &mut impl OutputSink,
+    data: T,
+    camel_case: bool,
+    criteria_thresholds: Option<CriteriaThresholds>,
+    sources: &[Source],
+    hour_window: Option<HourWindow>,
+) -> Result<()> {
+    if camel_case {
+        let data = camel_case_keys(serde_json::to_value(data)?);
+        return sink.write_json(
+            file_name,
+            &Versioned {
+                schema_version: SCHEMA_VERSION,
+                criteria_thresholds,
+                sources,
+                hour_window,
+                data,
+            },
+        );
+    }
+    sink.write_json(
+        file_name,
+        &Versioned {
+            schema_version: SCHEMA_VERSION,
+            criteria_thresholds,
+            sources,
+            hour_window,
+            data,
+        },
+    )
+}
+
+/// Read a file written by [`write_versioned_json_file`], or an unwrapped file from before the
+/// schema version was written
+#[cfg(feature = "fs")]
+pub fn read_versioned_json_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    Ok(read_versioned_json_file_with_window(path)?.0)
+}
+
+/// [`read_versioned_json_file`], with the hour window the counts were limited to, if any
+#[cfg(feature = "fs")]
+pub fn read_versioned_json_file_with_window<T: DeserializeOwned>(
+    path: &Path,
+) -> Result<(T, Option<HourWindow>)> {
+    let value: serde_json::Value = read_json_file(path)?;
+    let (data, hour_window) = match value {
+        serde_json::Value::Object(mut object)
+            if object.contains_key("data")
+                && object.keys().all(|key| {
+                    [
+                        "schema_version",
+                        "criteria_thresholds",
+                        "sources",
+                        "hour_window",
+                        "data",
+                    ]
+                    .contains(&key.as_str())
+                }) =>
+        {
+            let schema_version = object.get("schema_version").and_then(|v| v.as_u64());
+            match schema_version {
+                Some(version) if version <= SCHEMA_VERSION as u64 => {}
+                _ => bail!(
+                    "{} has schema version {}, this version reads up to {SCHEMA_VERSION}",
+                    path.display(),
+                    object["schema_version"]
+                ),
+            }
+            let hour_window = object
+                .remove("hour_window")
+                .map(serde_json::from_value)
+                .transpose()
+                .with_context(|| format!("Failed to read the hour window of {}", path.display()))?;
+            (object.remove("data").expect("checked above"), hour_window)
+        }
+        legacy => (legacy, None),
+    };
+    let data = serde_json::from_value(data)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok((data, hour_window))
+}
+
+/// Rename every snake_case object key in `value` to camelCase, as
+/// `#[serde(rename_all = "camelCase")]` would
+fn camel_case_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => object
+            .into_iter()
+            .map(|(key, value)| (camel_case(&key), camel_case_keys(value)))
+            .collect(),
+        serde_json::Value::Array(values) => values.into_iter().map(camel_case_keys).collect(),
+        value => value,
+    }
+}
+
+pub(crate) fn camel_case(key: &str) -> String {
+    let mut parts = key.split('_');
+    let mut camel = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+#[cfg(feature = "fs")]
+pub fn read_toml_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    if !has_extension(path, &["toml"]) {
+        return Err(anyhow::anyhow!(
+            "read_toml_file needs {} to end with .toml",
+            path.display()
+        ));
+    }
+    let text = fs_err::read_to_string(path)?;
+    let data =
+        toml::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(data)
+}
+
+/// Whether the extension of `path` is one of `extensions`
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extensions.contains(&extension))
+}
+
+/// 64-bit FNV-1a hash of `bytes`. Unlike `DefaultHasher` this is stable between runs and
+/// Rust versions, so it can be used to key files on disk.
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+/// A running [`fnv1a_hash`], for hashing several pieces as one without joining them first
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0 = bytes.iter().fold(self.0, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a::new()
+    }
+}
+
+/// Split a line of CSV into its fields, removing the quotes around quoted fields
+pub fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Join fields into a line of CSV, quoting the fields that contain a comma, quote or newline
+pub fn join_csv_line<S: AsRef<str>>(fields: &[S]) -> String {
+    fields
+        .iter()
+        .map(|field| {
+            let field = field.as_ref();
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Hex encoded SHA-256 digest of `bytes`, following FIPS 180-4
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    // Pad with a one bit, zeros, then the message length in bits, to a multiple of 64 bytes
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((bytes.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (word, added) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(added);
+        }
+    }
+    state.iter().map(|word| format!("{word:08x}")).collect()
+}
+
+/// An input file as recorded in the run manifest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestInput {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub sha256: String,
+}
+
+impl ManifestInput {
+    /// The size and SHA-256 hash of `bytes`, read from `path`
+    pub fn new(path: PathBuf, bytes: &[u8]) -> Self {
+        ManifestInput {
+            path,
+            size_bytes: bytes.len() as u64,
+            sha256: sha256_hex(bytes),
+        }
+    }
+
+    /// The size and SHA-256 hash of the input file at `path`
+    #[cfg(feature = "fs")]
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(ManifestInput::new(path.to_path_buf(), &fs_err::read(path)?))
+    }
+}
+
+/// How an output directory was produced, written to manifest.json. Each stage of a run
+/// adds its inputs, timings and counts as it goes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunManifest {
+    pub crate_version: String,
+    /// The full command line the run was started with
+    pub arguments: Vec<String>,
+    pub operating_day: String,
+    pub operating_date: String,
+    pub calendar_date: Option<String>,
+    pub inputs: Vec<ManifestInput>,
+    /// The HD header of each CIF file read
+    pub file_headers: Vec<FileHeader>,
+    /// Days from the newest CIF file's production date to the run, when --max-age-days
+    /// checked it
+    #[serde(default)]
+    pub newest_source_age_days: Option<i64>,
+    /// Wall-clock seconds per stage, in the order the stages ran
+    pub stage_seconds: Vec<(String, f64)>,
+    pub counts: BTreeMap<String, usize>,
+    /// The station filter, when one was set
+    #[serde(default)]
+    pub station_filter: Option<String>,
+    /// The --from-hour and --to-hour window the counts were limited to, when one was set
+    #[serde(default)]
+    pub hour_window: Option<HourWindow>,
+    /// The --threads setting, when the thread count wasn't left to rayon
+    #[serde(default)]
+    pub threads: Option<usize>,
+    #[serde(default)]
+    pub low_memory: bool,
+    /// The malformed line rate of the parse against --max-error-rate
+    #[serde(default)]
+    pub error_budget: Option<ErrorBudget>,
+    /// The thresholds the criteria were evaluated with
+    #[serde(default)]
+    pub criteria_thresholds: Option<CriteriaThresholds>,
+}
+
+impl RunManifest {
+    pub fn new(operating_day: String, operating_date: String) -> Self {
+        RunManifest {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            arguments: std::env::args().collect(),
+            operating_day,
+            operating_date,
+            calendar_date: None,
+            inputs: Vec::new(),
+            file_headers: Vec::new(),
+            newest_source_age_days: None,
+            stage_seconds: Vec::new(),
+            counts: BTreeMap::new(),
+            station_filter: None,
+            hour_window: None,
+            threads: None,
+            low_memory: false,
+            error_budget: None,
+            criteria_thresholds: None,
+        }
+    }
+
+    pub fn with_calendar_date(mut self, calendar_date: Option<String>) -> Self {
+        self.calendar_date = calendar_date;
+        self
+    }
+
+    /// Record the size and SHA-256 hash of the input file at `path`
+    #[cfg(feature = "fs")]
+    pub fn add_input(&mut self, path: &Path) -> Result<()> {
+        self.inputs.push(ManifestInput::read(path)?);
+        Ok(())
+    }
+
+    /// Record the size and SHA-256 hash of the CIF timetable `source`, under its name
+    pub fn add_source(&mut self, source: &(impl CifSource + ?Sized)) -> Result<()> {
+        let bytes = source.read_bytes()?;
+        self.inputs
+            .push(ManifestInput::new(PathBuf::from(source.name()), &bytes));
+        Ok(())
+    }
+
+    /// Run `stage`, recording how long it took under `name`
+    pub fn time<T>(&mut self, name: &str, stage: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = stage();
+        self.record_stage(name, start);
+        result
+    }
+
+    /// The stage timings on one line, as "parse: 84.2s, group: 12.1s"
+    pub fn format_stage_seconds(&self) -> String {
+        self.stage_seconds
+            .iter()
+            .map(|(name, seconds)| format!("{name}: {seconds:.1}s"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Record the time since `start` under `name`, for stages that can't run in a closure
+    pub fn record_stage(&mut self, name: &str, start: Instant) {
+        self.stage_seconds
+            .push((name.to_string(), start.elapsed().as_secs_f64()));
+    }
+
+    pub fn count(&mut self, name: &str, count: usize) {
+        self.counts.insert(name.to_string(), count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "fs")]
+    use crate::storage::OutputDirectory;
+
+    #[test]
+    fn test_running_fnv1a_matches_hashing_the_joined_bytes() {
+        let mut hasher = Fnv1a::new();
+        hasher.update(b"0.1.0");
+        hasher.update(b"HDTPS.UDFROC1");
+        assert_eq!(hasher.finish(), fnv1a_hash(b"0.1.0HDTPS.UDFROC1"));
+        assert_eq!(fnv1a_hash(b""), 0xcbf2_9ce4_8422_2325);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_write_file_creates_directory_and_respects_overwrite() {
+        let dir = std::env::temp_dir().join(format!("rhd_write_{}", std::process::id()));
+        let output_directory = dir.join("nested");
+        let path = output_directory.join("data.json");
+        let write = |count: u32, overwrite: bool| {
+            write_file_with_overwrite("data.json", &output_directory, overwrite, |writer| {
+                Ok(serde_json::to_writer(writer, &[count])?)
+            })
+        };
+
+        write(1, false).unwrap();
+        let refused = write(2, false);
+        let unchanged: Vec<u32> = read_json_file(&path).unwrap();
+        write(3, true).unwrap();
+        let overwritten: Vec<u32> = read_json_file(&path).unwrap();
+        let tmp_left = output_directory.join("data.json.tmp").exists();
+        fs_err::remove_dir_all(&dir).unwrap();
+
+        assert!(refused.is_err());
+        assert_eq!(unchanged, [1]);
+        assert_eq!(overwritten, [3]);
+        assert!(!tmp_left);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_versioned_json_files_read_back_wrapped_or_legacy() {
+        use crate::hour_grouping::HourlyDepartures;
+        use std::collections::HashMap;
+
+        let dir = std::env::temp_dir().join(format!("rhd_versioned_{}", std::process::id()));
+        let departures = BTreeMap::from([(
+            "KGX".to_string(),
+            HourlyDepartures {
+                three_alpha_code: crate::ThreeAlphaCode("KGX".to_string()),
+                hour_counts: [4; 24],
+                hour_counts_journey_starts: [1; 24],
+                hour_counts_arrivals: [0; 24],
+                hour_counts_journey_ends: [0; 24],
+                next_stop_three_alpha_code: vec![Vec::new(); 24],
+                ..HourlyDepartures::default()
+            }
+            .with_totals(),
+        )]);
+        let _ = fs_err::remove_dir_all(&dir);
+        let mut sink = OutputDirectory::new(dir.clone(), false);
+        sink.write_json("legacy", &departures).unwrap();
+        write_versioned_json_file("wrapped", &mut sink, &departures, false).unwrap();
+        write_versioned_json_file("camel", &mut sink, &departures, true).unwrap();
+        let thresholds = CriteriaThresholds {
+            min_departures_per_hour: 6,
+            min_journey_starts_per_hour: 3,
+            ..CriteriaThresholds::default()
+        };
+        write_criteria_json_file(
+            "criteria",
+            &mut sink,
+            &departures,
+            false,
+            Some(thresholds),
+            &[Source {
+                file_reference: "DFROC1A".to_string(),
+                date_of_extract: Some(Date(260101)),
+                time_of_extract: "0101".to_string(),
+            }],
+            Some(HourWindow {
+                from_hour: 7,
+                to_hour: 10,
+            }),
+        )
+        .unwrap();
+
+        let camel = fs_err::read_to_string(dir.join("camel.json")).unwrap();
+        assert!(camel.starts_with(r#"{"schema_version":10,"data":{"KGX":{"#));
+        assert!(camel.contains(r#""hourCountsJourneyStarts":[1,"#));
+        assert!(!camel.contains("hour_counts"));
+        let criteria = fs_err::read_to_string(dir.join("criteria.json")).unwrap();
+        assert!(criteria.starts_with(
+            r#"{"schema_version":10,"criteria_thresholds":{"min_departures_per_hour":6,"#
+        ));
+        assert!(criteria.contains(
+            r#""sources":[{"file_reference":"DFROC1A","date_of_extract":260101,"time_of_extract":"0101"}],"hour_window":{"from_hour":7,"to_hour":10},"data":"#
+        ));
+        let (_, hour_window): (HashMap<String, HourlyDepartures>, _) =
+            read_versioned_json_file_with_window(&dir.join("criteria.json")).unwrap();
+        assert_eq!(hour_window.map(|window| window.to_hour), Some(10));
+        // Version 3 files have no arrivals
+        let mut version_3 = serde_json::to_value(&departures).unwrap();
+        for key in ["hour_counts_arrivals", "hour_counts_journey_ends"] {
+            version_3["KGX"].as_object_mut().unwrap().remove(key);
+        }
+        fs_err::write(
+            dir.join("version_3.json"),
+            serde_json::json!({"schema_version": 3, "data": version_3}).to_string(),
+        )
+        .unwrap();
+        for file_name in ["legacy", "wrapped", "camel", "criteria", "version_3"] {
+            let read_back: HashMap<String, HourlyDepartures> =
+                read_versioned_json_file(&dir.join(format!("{file_name}.json"))).unwrap();
+            assert_eq!(read_back["KGX"].hour_counts, [4; 24], "{file_name}");
+            assert_eq!(read_back["KGX"].hour_counts_journey_starts, [1; 24]);
+        }
+
+        fs_err::write(
+            dir.join("future.json"),
+            r#"{"schema_version":11,"data":{}}"#,
+        )
+        .unwrap();
+        assert!(
+            read_versioned_json_file::<HashMap<String, HourlyDepartures>>(&dir.join("future.json"))
+                .is_err()
+        );
+        fs_err::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_camel_case() {
+        assert_eq!(
+            camel_case("hour_counts_journey_starts"),
+            "hourCountsJourneyStarts"
+        );
+        assert_eq!(camel_case("all_7_7"), "all77");
+        assert_eq!(camel_case("KGX"), "KGX");
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_write_jsonl_writes_a_line_per_row() {
+        let output_directory =
+            std::env::temp_dir().join(format!("rhd_write_jsonl_{}", std::process::id()));
+        let _ = fs_err::remove_dir_all(&output_directory);
+        let mut sink = OutputDirectory::new(output_directory.clone(), false);
+        sink.write_jsonl("rows", [("KGX", 1), ("CBG", 2)]).unwrap();
+        let text = fs_err::read_to_string(output_directory.join("rows.jsonl")).unwrap();
+        assert_eq!(text, "[\"KGX\",1]\n[\"CBG\",2]\n");
+        assert!(sink.write_jsonl("rows", [0]).is_err());
+        fs_err::remove_dir_all(&output_directory).unwrap();
+    }
+
+    #[test]
+    fn test_join_csv_line_round_trips_through_split() {
+        let fields = ["KGX", "London Kings Cross, Platform \"Y\"", ""];
+        let line = join_csv_line(&fields);
+        assert_eq!(line, "KGX,\"London Kings Cross, Platform \"\"Y\"\"\",");
+        assert_eq!(split_csv_line(&line), fields);
+    }
+
+    #[test]
+    fn test_sha256_hex_matches_known_digests() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn test_run_manifest_round_trips_with_input_hashes() {
+        let dir = std::env::temp_dir().join(format!("rhd_manifest_{}", std::process::id()));
+        fs_err::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.CIF");
+        fs_err::write(&input, "abc").unwrap();
+
+        let mut manifest = RunManifest::new("Tuesday".to_string(), "260113".to_string());
+        manifest.add_input(&input).unwrap();
+        let doubled = manifest.time("parse", || 2 * 2);
+        manifest.count("records", doubled);
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        fs_err::remove_dir_all(&dir).unwrap();
+        let read_back: RunManifest = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(read_back, manifest);
+        assert_eq!(read_back.inputs[0].size_bytes, 3);
+        assert_eq!(
+            read_back.inputs[0].sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(read_back.stage_seconds[0].0, "parse");
+        assert!(read_back.format_stage_seconds().starts_with("parse: 0.0s"));
+        assert_eq!(read_back.counts["records"], 4);
+    }
+}
@@ -0,0 +1,262 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::records::{Date, Journey, SecondsPastMidnight, ThreeAlphaCode, Tiploc};
+use super::stops::StationName;
+
+const SECONDS_PER_DAY: usize = 24 * 3600;
+
+/// A decrease in time of more than this between consecutive times is taken as the journey
+/// running past midnight, anything smaller as the times going backwards
+const MIDNIGHT_WRAP_SECONDS: usize = 12 * 3600;
+
+/// Hops taking no time between stations further apart than this are implausible
+const ZERO_DURATION_HOP_MAX_METRES: f64 = 3000.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum JourneyProblem {
+    /// A stop is timed earlier than the stop before it
+    TimeGoesBackwards,
+    /// A stop departs before it arrives
+    DepartsBeforeArriving,
+    /// A stop is dwelt at for longer than the threshold
+    LongDwell { minutes: f64 },
+    /// Consecutive stops are timed the same but are far apart
+    ZeroDurationHop { metres: f64 },
+}
+
+/// A problem with the times of one journey, between `from_stop` and `to_stop` (the same stop
+/// for a problem at a single stop)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JourneyWarning {
+    pub uid: String,
+    /// The UID is shared by schedules of the same train over different dates
+    pub date_runs_from: Date,
+    pub problem: JourneyProblem,
+    pub from_stop: Tiploc,
+    pub to_stop: Tiploc,
+}
+
+/// The thresholds and station locations journeys are checked against
+pub struct JourneyChecks {
+    pub max_dwell: SecondsPastMidnight,
+    /// Easting and northing of the stops with a known location
+    pub locations: HashMap<Tiploc, (u32, u32)>,
+}
+
+impl JourneyChecks {
+    /// Locations are taken from the station names, so only stops with a NaPTAN location take
+    /// part in the zero duration hop check
+    pub fn new(
+        max_dwell_minutes: u32,
+        lookup: &HashMap<Tiploc, ThreeAlphaCode>,
+        station_names: &HashMap<ThreeAlphaCode, StationName>,
+    ) -> Self {
+        let locations = lookup
+            .iter()
+            .filter_map(|(tiploc, code)| {
+                let station_name = station_names.get(code)?;
+                Some((
+                    tiploc.clone(),
+                    (station_name.easting?, station_name.northing?),
+                ))
+            })
+            .collect();
+        JourneyChecks {
+            max_dwell: SecondsPastMidnight(max_dwell_minutes as usize * 60),
+            locations,
+        }
+    }
+
+    /// Every problem found with the times of `journey`, in stop order
+    pub fn check(&self, journey: &Journey) -> Vec<JourneyWarning> {
+        let mut problems: Vec<(JourneyProblem, &Tiploc, &Tiploc)> = Vec::new();
+        // The stop and time the journey was last seen at, skipping stops with no times
+        let mut previous: Option<(&Tiploc, SecondsPastMidnight)> = None;
+
+        for stop in journey.stops.iter() {
+            let first_time = stop.arrival_time.or(stop.departure_time);
+            let last_time = stop.departure_time.or(stop.arrival_time);
+            let (Some(first_time), Some(last_time)) = (first_time, last_time) else {
+                continue;
+            };
+
+            if let Some((previous_tiploc, previous_time)) = previous {
+                match elapsed(previous_time, first_time) {
+                    None => problems.push((
+                        JourneyProblem::TimeGoesBackwards,
+                        previous_tiploc,
+                        &stop.tiploc,
+                    )),
+                    Some(0) => {
+                        if let Some(metres) = self.distance(previous_tiploc, &stop.tiploc)
+                            && metres > ZERO_DURATION_HOP_MAX_METRES
+                        {
+                            problems.push((
+                                JourneyProblem::ZeroDurationHop { metres },
+                                previous_tiploc,
+                                &stop.tiploc,
+                            ));
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            match elapsed(first_time, last_time) {
+                None => problems.push((
+                    JourneyProblem::DepartsBeforeArriving,
+                    &stop.tiploc,
+                    &stop.tiploc,
+                )),
+                Some(dwell) if dwell > self.max_dwell.0 => problems.push((
+                    JourneyProblem::LongDwell {
+                        minutes: dwell as f64 / 60.0,
+                    },
+                    &stop.tiploc,
+                    &stop.tiploc,
+                )),
+                Some(_) => {}
+            }
+            previous = Some((&stop.tiploc, last_time));
+        }
+
+        problems
+            .into_iter()
+            .map(|(problem, from_stop, to_stop)| JourneyWarning {
+                uid: journey.header.uid.clone(),
+                date_runs_from: journey.header.date_runs_from.clone(),
+                problem,
+                from_stop: from_stop.clone(),
+                to_stop: to_stop.clone(),
+            })
+            .collect()
+    }
+
+    fn distance(&self, from: &Tiploc, to: &Tiploc) -> Option<f64> {
+        let (from_easting, from_northing) = self.locations.get(from)?;
+        let (to_easting, to_northing) = self.locations.get(to)?;
+        let east = *to_easting as f64 - *from_easting as f64;
+        let north = *to_northing as f64 - *from_northing as f64;
+        Some((east * east + north * north).sqrt())
+    }
+}
+
+/// Check every journey, dropping the journeys with problems when `drop_invalid`
+pub fn validate_journeys(
+    journeys: Vec<Journey>,
+    checks: &JourneyChecks,
+    drop_invalid: bool,
+) -> (Vec<Journey>, Vec<JourneyWarning>) {
+    let mut warnings: Vec<JourneyWarning> = Vec::new();
+    let journeys = journeys
+        .into_iter()
+        .filter(|journey| {
+            let journey_warnings = checks.check(journey);
+            let is_valid = journey_warnings.is_empty();
+            warnings.extend(journey_warnings);
+            is_valid || !drop_invalid
+        })
+        .collect();
+    (journeys, warnings)
+}
+
+/// Seconds from `from` to `to`, allowing for running past midnight, or None when `to` is
+/// before `from`
+fn elapsed(from: SecondsPastMidnight, to: SecondsPastMidnight) -> Option<usize> {
+    if to.0 >= from.0 {
+        Some(to.0 - from.0)
+    } else if from.0 - to.0 > MIDNIGHT_WRAP_SECONDS {
+        Some(to.0 + SECONDS_PER_DAY - from.0)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::records::{assemble_journeys, parse};
+    use crate::test_cif;
+
+    fn journey(stops: &[&str]) -> Journey {
+        let cif = test_cif::cif(
+            std::iter::once("BSNC123452601012612311111100 POO").chain(stops.iter().copied()),
+        );
+        assemble_journeys(parse(cif)).remove(0)
+    }
+
+    fn checks() -> JourneyChecks {
+        JourneyChecks {
+            max_dwell: SecondsPastMidnight(30 * 60),
+            locations: HashMap::from([
+                (Tiploc("KNGX".to_string()), (530_000, 183_000)),
+                (Tiploc("STEVNGE".to_string()), (523_000, 226_000)),
+            ]),
+        }
+    }
+
+    fn problems(journey: &Journey) -> Vec<(JourneyProblem, String, String)> {
+        checks()
+            .check(journey)
+            .into_iter()
+            .map(|warning| (warning.problem, warning.from_stop.0, warning.to_stop.0))
+            .collect()
+    }
+
+    #[test]
+    fn test_plausible_journey_past_midnight_has_no_warnings() {
+        let journey = journey(&[
+            "LOKNGX    2350 2350",
+            "LISTEVNGE 2358 0002      23580002         T",
+            "LTCAMBDGE 0040 0040",
+        ]);
+        assert!(checks().check(&journey).is_empty());
+    }
+
+    #[test]
+    fn test_backwards_times_and_long_dwells_are_flagged() {
+        let journey = journey(&[
+            "LOKNGX    0800 0800",
+            "LIFNPK    0810 0805      08100805         T",
+            "LISTEVNGE 0800 0900      08000900         T",
+            "LTCAMBDGE 0940 0940",
+        ]);
+        assert_eq!(
+            problems(&journey),
+            [
+                (
+                    JourneyProblem::DepartsBeforeArriving,
+                    "FNPK".to_string(),
+                    "FNPK".to_string()
+                ),
+                (
+                    JourneyProblem::TimeGoesBackwards,
+                    "FNPK".to_string(),
+                    "STEVNGE".to_string()
+                ),
+                (
+                    JourneyProblem::LongDwell { minutes: 60.0 },
+                    "STEVNGE".to_string(),
+                    "STEVNGE".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zero_duration_hop_needs_both_locations() {
+        let journey = journey(&[
+            "LOKNGX    0800 0800",
+            "LISTEVNGE 0800 0801      08000801         T",
+            "LICAMBDGE 0801 0802      08010802         T",
+            "LTELYY    0820 0820",
+        ]);
+        let problems = problems(&journey);
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(
+            problems[0].0,
+            JourneyProblem::ZeroDurationHop { metres } if metres > 40_000.0
+        ));
+    }
+}
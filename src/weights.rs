@@ -0,0 +1,183 @@
+//! Weights for the departures, so a bus or a short train can count for less than a long
+//! intercity train. They come from a TOML table of rules matching a journey's mode and
+//! operator, and are summed into [`HourlyDepartures::weighted_hour_counts`] beside the
+//! unweighted counts.
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+#[cfg(doc)]
+use super::hour_grouping::HourlyDepartures;
+use super::modes::{MODES, UNKNOWN_MODE};
+#[cfg(feature = "fs")]
+use super::utils;
+
+/// A rule of a weights file. A rule without a mode or an operator matches any.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeightRule {
+    /// Such as "train" or "bus", see [`JourneyHeader::mode`](super::records::JourneyHeader::mode)
+    pub mode: Option<String>,
+    /// The two letter operator code of the BX record
+    pub operator: Option<String>,
+    pub weight: f32,
+}
+
+/// The rules of a weights file, in the order they are tried
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DepartureWeights(pub Vec<WeightRule>);
+
+/// A TOML weights file, such as `[[weights]]` then `operator = "GR"` and `weight = 2.0`
+#[cfg(feature = "fs")]
+#[derive(Deserialize)]
+struct DepartureWeightsToml {
+    weights: Vec<WeightRule>,
+}
+
+impl DepartureWeights {
+    /// The weight of the first rule matching a journey of `mode` run by `operator`, or 1 when
+    /// none do
+    pub fn weight(&self, mode: &str, operator: Option<&str>) -> f32 {
+        self.0
+            .iter()
+            .find(|rule| {
+                rule.mode
+                    .as_deref()
+                    .is_none_or(|rule_mode| rule_mode == mode)
+                    && rule
+                        .operator
+                        .as_deref()
+                        .is_none_or(|rule_operator| Some(rule_operator) == operator)
+            })
+            .map_or(1.0, |rule| rule.weight)
+    }
+}
+
+/// Read the weights from a TOML `[[weights]]` array, checking each mode is one of [`MODES`]
+/// or "unknown" and each weight is a number of at least 0
+#[cfg(feature = "fs")]
+pub fn read_departure_weights(path: &Path) -> Result<DepartureWeights> {
+    let weights: DepartureWeightsToml = utils::read_toml_file(path)?;
+    check_weights(weights.weights)
+        .map_err(|e| e.context(format!("Invalid weight in {}", path.display())))
+}
+
+/// The rules of a weights file, checked as [`read_departure_weights`] does
+pub fn check_weights(rules: Vec<WeightRule>) -> Result<DepartureWeights> {
+    for rule in &rules {
+        if let Some(mode) = &rule.mode
+            && !MODES.contains(&mode.as_str())
+            && mode != UNKNOWN_MODE
+        {
+            bail!(
+                "{mode:?} is not one of {}, {UNKNOWN_MODE}",
+                MODES.join(", ")
+            );
+        }
+        if !rule.weight.is_finite() || rule.weight < 0.0 {
+            bail!("{} is not a weight of at least 0", rule.weight);
+        }
+    }
+    Ok(DepartureWeights(rules))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::criteria::{CriteriaThresholds, evaluate_criteria};
+    use crate::hour_grouping::{GroupingContext, group};
+    use crate::records::{Date, Day, ThreeAlphaCode, assemble_journeys, parse};
+    use crate::stops::create_lookup;
+    use crate::test_cif;
+
+    fn rule(mode: Option<&str>, operator: Option<&str>, weight: f32) -> WeightRule {
+        WeightRule {
+            mode: mode.map(str::to_string),
+            operator: operator.map(str::to_string),
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_first_matching_rule_gives_the_weight() {
+        let weights = check_weights(vec![
+            rule(Some("train"), Some("GR"), 2.0),
+            rule(Some("bus"), None, 0.25),
+            rule(None, Some("GR"), 1.5),
+        ])
+        .unwrap();
+        assert_eq!(weights.weight("train", Some("GR")), 2.0);
+        assert_eq!(weights.weight("bus", Some("GR")), 0.25);
+        assert_eq!(weights.weight("ship", Some("GR")), 1.5);
+        assert_eq!(weights.weight("train", None), 1.0);
+
+        let error = check_weights(vec![rule(Some("tram"), None, 1.0)]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "\"tram\" is not one of train, bus, ship, freight, unknown"
+        );
+        assert!(check_weights(vec![rule(None, None, -1.0)]).is_err());
+    }
+
+    #[test]
+    fn test_half_weight_halves_the_totals() {
+        // Four departures an hour from King's Cross, each to a different station so none
+        // passes on two departures to the same next stop
+        let destinations = ["CAMBDGE", "STEVNGE", "PBRO", "ELYY"];
+        let cif = test_cif::with_stations(
+            [
+                "TIPBRO   00181000 PETERBOROUGH              56001    PBOPETERBOROUGH",
+                "TIELYY   00191000 ELY                       51004    ELYELY",
+            ]
+            .into_iter()
+            .map(str::to_string)
+            .chain((7..19).flat_map(|hour| {
+                destinations
+                    .into_iter()
+                    .enumerate()
+                    .flat_map(move |(quarter, destination)| {
+                        let minute = quarter * 15;
+                        [
+                            format!("BSNC{hour:02}{quarter:03}2601012612311111100 POO"),
+                            "BX         GNY".to_string(),
+                            format!("LOKNGX    {hour:02}{minute:02} {hour:02}{minute:02}"),
+                            format!("LT{destination:<7} {:02}00 {:02}00", hour + 1, hour + 1),
+                        ]
+                    })
+            })),
+        );
+        let records = parse(cif);
+        let codes =
+            ["KGX", "CBG", "SVG", "PBO", "ELY"].map(|code| ThreeAlphaCode(code.to_string()));
+        let lookup = create_lookup(&records, &codes);
+        let journeys = assemble_journeys(records);
+        let mut context = GroupingContext::for_week(Date(260113));
+
+        let unweighted = group(&journeys, &lookup, &Day::Tuesday, &context);
+        assert_eq!(unweighted[&codes[0]].weighted_hour_counts, [0.0; 24]);
+        context.departure_weights = Some(DepartureWeights(vec![rule(None, Some("GN"), 0.5)]));
+        let departures = group(&journeys, &lookup, &Day::Tuesday, &context);
+        let kgx = &departures[&codes[0]];
+        assert_eq!(kgx.hour_counts, unweighted[&codes[0]].hour_counts);
+        assert_eq!(kgx.total, 48);
+        assert_eq!(kgx.weighted_hour_counts.iter().sum::<f32>(), 24.0);
+        assert_eq!(kgx.weighted_hour_counts[8], 2.0);
+
+        // Four departures an hour pass, but not at half weight, with too few journey starts
+        // to pass on those
+        let thresholds = CriteriaThresholds {
+            min_journey_starts_per_hour: 5,
+            ..CriteriaThresholds::default()
+        };
+        let weighted = CriteriaThresholds {
+            weighted_counts: true,
+            ..thresholds
+        };
+        assert!(evaluate_criteria(&departures, &thresholds)[&codes[0]].all_7_7);
+        let results = &evaluate_criteria(&departures, &weighted)[&codes[0]];
+        assert!(!results.all_7_7);
+        assert_eq!(results.hour_counts[8], 2);
+    }
+}
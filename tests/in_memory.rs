@@ -0,0 +1,45 @@
+//! A run with no filesystem, as without the `fs` feature: the miniature CIF file and its
+//! station codes are passed in already read, and the outputs are written to memory.
+
+use clap::Parser;
+
+use rail_hourly_departures::{
+    Args, InMemoryCif, InMemoryOutputs, OutputSelection, RunInputs, ThreeAlphaCode, run_from,
+};
+
+#[test]
+fn test_run_from_memory_to_memory() {
+    let mut args = Args::parse_from([
+        "rail-hourly-departures",
+        "--input-file-dir=unused",
+        "--operating-week=260113",
+        "--output-directory=unused",
+    ]);
+    args.outputs = vec![OutputSelection::Departures, OutputSelection::Lookup];
+    let source = InMemoryCif::new(
+        "mini",
+        include_str!("fixtures/mini/CIF_ALL_FULL_DAILY_toc-full.CIF"),
+    );
+    let inputs = RunInputs {
+        station_codes: ["KGX", "SVG", "CBG"]
+            .map(|code| ThreeAlphaCode(code.to_string()))
+            .into(),
+        ..RunInputs::default()
+    };
+
+    let mut outputs = run_from(&args, &source, inputs).unwrap();
+    let mut sink = InMemoryOutputs::default();
+    outputs.write_to(&args, &mut sink).unwrap();
+
+    let departures: serde_json::Value =
+        serde_json::from_slice(&sink.0["rail_hourly_departures_Tuesday_260113.json"]).unwrap();
+    let totals: Vec<(&str, u64)> = ["CBG", "KGX", "SVG"]
+        .map(|code| (code, departures["data"][code]["total"].as_u64().unwrap()))
+        .into();
+    assert_eq!(totals, [("CBG", 3), ("KGX", 6), ("SVG", 7)]);
+    let lookup: serde_json::Value =
+        serde_json::from_slice(&sink.0["station_name_lookup.json"]).unwrap();
+    assert_eq!(lookup["KGX"]["name"], "LONDON KINGS CROSS");
+    let manifest: serde_json::Value = serde_json::from_slice(&sink.0["manifest.json"]).unwrap();
+    assert_eq!(manifest["inputs"][0]["path"], "mini");
+}
@@ -0,0 +1,375 @@
+//! The whole pipeline, parse to criteria, over the miniature CIF file in tests/fixtures/mini.
+//!
+//! Its journeys run London King's Cross (KGX), Stevenage (SVG) and Cambridge (CBG), Monday to
+//! Friday unless noted, and are counted on Tuesday 13 January 2026:
+//!
+//! - C10005 runs on Tuesdays only, and is counted
+//! - C10006 runs at weekends only, and C10007 is a deleted schedule, so neither is counted
+//! - C10008 starts from KNGXBEL, which has no three alpha code of its own but shares King's
+//!   Cross's STANOX, so it is counted at KGX
+//! - C10009 calls at Finsbury Park, whose FPK is left out of station_codes.toml, so it is
+//!   counted at King's Cross with Stevenage as the next station
+
+mod support;
+
+use clap::Parser;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use rail_hourly_departures::hour_grouping::GroupingContext;
+use rail_hourly_departures::{
+    Args, Date, Day, DaySelector, Encoding, ErrorRateExceeded, InMemoryCif, InMemoryOutputs,
+    OutputDirectory, OutputSelection, OutputSink, ParseOptions, RunInputs, ThreeAlphaCode, Tiploc,
+    assemble_journeys, cif_file_path, create_lookup, demo, group, parse, read_records_from, run,
+    run_from, utils,
+};
+
+#[test]
+fn test_miniature_cif_file() {
+    let outputs = run(&support::miniature_cif_args()).unwrap();
+
+    let lookup: BTreeMap<&str, &str> = outputs
+        .lookup
+        .iter()
+        .map(|(Tiploc(tiploc), ThreeAlphaCode(code))| (tiploc.as_str(), code.as_str()))
+        .collect();
+    assert_eq!(
+        lookup,
+        BTreeMap::from([
+            ("CAMBDGE", "CBG"),
+            ("KNGX", "KGX"),
+            ("KNGXBEL", "KGX"),
+            ("STEVNGE", "SVG"),
+        ])
+    );
+
+    let [day_outputs] = &outputs.days[..] else {
+        panic!("expected one day, got {}", outputs.days.len());
+    };
+    assert_eq!(day_outputs.selector, DaySelector::Day(Day::Tuesday));
+    let departures: BTreeMap<_, _> = day_outputs.departures.iter().collect();
+    assert_eq!(
+        serde_json::to_value(departures).unwrap(),
+        json!({
+            "CBG": {
+                "three_alpha_code": "CBG",
+                "hour_counts":                [0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                "hour_counts_journey_starts": [0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                "hour_counts_arrivals":       [0, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0],
+                "hour_counts_journey_ends":   [0, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0],
+                "total": 3,
+                "total_7_19": 3,
+                "total_6_22": 3,
+                "next_stop_three_alpha_code": [
+                    [], [], [], [], [], [], [], ["SVG"], ["SVG"], [], [], [], [], ["KGX"], [], [], [], [], [], [], [], [], [], []
+                ],
+            },
+            "KGX": {
+                "three_alpha_code": "KGX",
+                "hour_counts":                [0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+                "hour_counts_journey_starts": [0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+                "hour_counts_arrivals":       [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                "hour_counts_journey_ends":   [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                "total": 6,
+                "total_7_19": 5,
+                "total_6_22": 6,
+                "next_stop_three_alpha_code": [
+                    [], [], [], [], [], [], ["SVG"], ["SVG", "CBG"], [], [], ["SVG"], ["SVG"], [], [], [], [], [], [], ["SVG"], [], [], [], [], []
+                ],
+            },
+            "SVG": {
+                "three_alpha_code": "SVG",
+                "hour_counts":                [0, 0, 0, 0, 0, 0, 1, 2, 1, 0, 1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+                "hour_counts_journey_starts": [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                "hour_counts_arrivals":       [0, 0, 0, 0, 0, 0, 1, 2, 1, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+                "hour_counts_journey_ends":   [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                "total": 7,
+                "total_7_19": 6,
+                "total_6_22": 7,
+                "next_stop_three_alpha_code": [
+                    [], [], [], [], [], [], ["CBG"], ["CBG", "KGX"], ["KGX"], [], ["CBG"], [], ["KGX"], [], [], [], [], [], ["CBG"], [], [], [], [], []
+                ],
+            },
+        })
+    );
+
+    // No station has the all day service any criterion asks for
+    let criteria_results: BTreeMap<_, _> = day_outputs
+        .criteria_results
+        .iter()
+        .map(|(three_alpha_code, results)| {
+            (
+                three_alpha_code,
+                [
+                    results.all_7_7,
+                    results.all_6_10,
+                    results.avg_7_7,
+                    results.avg_6_10,
+                    results.flagged_for_review,
+                ],
+            )
+        })
+        .collect();
+    assert_eq!(
+        serde_json::to_value(criteria_results).unwrap(),
+        json!({
+            "CBG": [false, false, false, false, false],
+            "KGX": [false, false, false, false, false],
+            "SVG": [false, false, false, false, false],
+        })
+    );
+}
+
+#[test]
+fn test_lookup_only_run_skips_the_journeys() {
+    let full = run(&support::miniature_cif_args()).unwrap();
+    let mut args = support::miniature_cif_args();
+    args.outputs = vec![OutputSelection::Lookup];
+    let outputs = run(&args).unwrap();
+
+    assert_eq!(outputs.lookup, full.lookup);
+    assert_eq!(outputs.station_names, full.station_names);
+    assert!(outputs.days.is_empty());
+    assert!(outputs.journeys.is_empty());
+    assert_eq!(outputs.parse_summary.lo, 0);
+    assert_eq!(outputs.parse_summary.ti, full.parse_summary.ti);
+}
+
+#[test]
+fn test_departures_only_run_skips_the_criteria() {
+    let mut args = support::miniature_cif_args();
+    args.outputs = vec![OutputSelection::Departures];
+    let outputs = run(&args).unwrap();
+
+    assert_eq!(outputs.days[0].departures.len(), 3);
+    assert!(outputs.days[0].criteria_results.is_empty());
+}
+
+#[test]
+fn test_run_stops_over_the_error_budget() {
+    // The miniature file's 50 lines and one garbled line, 1.96% of the lines
+    let input_file_dir =
+        std::env::temp_dir().join(format!("rhd_error_budget_{}", std::process::id()));
+    fs_err::create_dir_all(&input_file_dir).unwrap();
+    let mut cif = fs_err::read_to_string(cif_file_path(Path::new("tests/fixtures/mini"))).unwrap();
+    cif.push_str("LTCAMBDGE 09\n");
+    fs_err::write(cif_file_path(&input_file_dir), cif).unwrap();
+    let mut args = support::miniature_cif_args();
+    args.input_file_dir = input_file_dir.clone();
+
+    args.max_error_rate = 0.0195;
+    let error = run(&args).err().unwrap();
+    let exceeded = error.downcast_ref::<ErrorRateExceeded>().unwrap();
+    assert_eq!(exceeded.parse_summary.malformed, 1);
+    let error_budget = exceeded.manifest.error_budget.unwrap();
+    assert!(error_budget.exceeded);
+    assert_eq!(error_budget.error_rate, 1.0 / 51.0);
+
+    args.max_error_rate = 0.0197;
+    let outputs = run(&args).unwrap();
+    assert!(!outputs.manifest.error_budget.unwrap().exceeded);
+    fs_err::remove_dir_all(&input_file_dir).unwrap();
+}
+
+#[test]
+fn test_synthetic_cif_file_counts_every_departure() {
+    let cif = support::synthetic_cif(1_000);
+    let records = parse(cif.text);
+    let lookup = create_lookup(&records, &cif.three_alpha_codes);
+    let journeys = assemble_journeys(records);
+    let departures = group(
+        &journeys,
+        &lookup,
+        &Day::Tuesday,
+        &GroupingContext::for_week(Date(260113)),
+    );
+    let counted: u32 = departures
+        .values()
+        .map(|hourly_departures| hourly_departures.hour_counts.iter().sum::<u32>())
+        .sum();
+    // Every stop but the terminus
+    assert_eq!(counted as usize, journeys.len() * 9);
+}
+
+#[test]
+fn test_overlapping_quarters_count_each_journey_once() {
+    // Both quarters run over 13 January 2026. C20001 is in both, and is counted from the later
+    // extract at 09:00; C20002 and C20003 are in one quarter each.
+    let mut args = support::miniature_cif_args();
+    args.input_file_dir = "tests/fixtures/quarters/q4".into();
+    args.extra_input_file_dir = vec!["tests/fixtures/quarters/q1".into()];
+    let outputs = run(&args).unwrap();
+
+    assert_eq!(outputs.manifest.counts["superseded_journeys"], 1);
+    assert!(
+        outputs
+            .manifest
+            .inputs
+            .iter()
+            .any(|input| input.path.starts_with("tests/fixtures/quarters/q1"))
+    );
+    assert_eq!(outputs.parse_summary.headers.len(), 2);
+    let mut uids: Vec<&str> = outputs
+        .journeys
+        .iter()
+        .map(|journey| journey.header.uid.as_str())
+        .collect();
+    uids.sort();
+    assert_eq!(uids, ["C20001", "C20002", "C20003"]);
+    let kgx = &outputs.days[0].departures[&ThreeAlphaCode("KGX".to_string())];
+    assert_eq!(kgx.hour_counts[8..12], [0, 1, 1, 1]);
+}
+
+#[test]
+fn test_demo_runs_the_miniature_cif_file() {
+    let mut args = Args::parse_from([
+        "rail-hourly-departures",
+        "--demo",
+        "--output-directory=unused",
+    ]);
+    let input_file_dir = demo::use_demo_input(&mut args).unwrap();
+    let outputs = run(&args).unwrap();
+    fs_err::remove_dir_all(input_file_dir).unwrap();
+
+    let miniature = run(&support::miniature_cif_args()).unwrap();
+    assert_eq!(outputs.lookup, miniature.lookup);
+    assert_eq!(
+        serde_json::to_value(&outputs.days[0].departures).unwrap(),
+        serde_json::to_value(&miniature.days[0].departures).unwrap()
+    );
+}
+
+#[test]
+fn test_in_memory_source_and_sink_match_the_file_run() {
+    let source = InMemoryCif::new(
+        "mini",
+        include_str!("fixtures/mini/CIF_ALL_FULL_DAILY_toc-full.CIF"),
+    );
+    let (records, _) = read_records_from(&source, Encoding::Auto, ParseOptions::default()).unwrap();
+    let codes = ["KGX", "SVG", "CBG"].map(|code| ThreeAlphaCode(code.to_string()));
+    let (_, departures) = rail_hourly_departures::departures(
+        records,
+        &codes,
+        &Day::Tuesday,
+        &GroupingContext::for_week(Date(260113)),
+    );
+    let mut sink = InMemoryOutputs::default();
+    sink.write_json("hourly_departures", &departures).unwrap();
+
+    let miniature = run(&support::miniature_cif_args()).unwrap();
+    let written: serde_json::Value =
+        serde_json::from_slice(&sink.0["hourly_departures.json"]).unwrap();
+    assert_eq!(
+        written,
+        serde_json::to_value(&miniature.days[0].departures).unwrap()
+    );
+}
+
+#[test]
+fn test_max_age_days_stops_runs_over_old_files() {
+    // The miniature CIF file was extracted on 1 January 2026
+    let mut args = support::miniature_cif_args();
+    args.max_age_days = Some(100_000);
+    let outputs = run(&args).unwrap();
+    let age_days = outputs.manifest.newest_source_age_days.unwrap();
+    assert!(age_days >= 0);
+
+    args.max_age_days = Some(age_days as u32 - 1);
+    let error = run(&args).err().unwrap();
+    assert!(
+        error
+            .to_string()
+            .contains(&format!("produced {age_days} days ago, more than"))
+    );
+}
+
+#[test]
+fn test_write_to_writes_the_binary_files() {
+    let dir = std::env::temp_dir().join(format!("rhd_write_to_{}", std::process::id()));
+    let args = support::miniature_cif_args();
+    let mut outputs = run(&args).unwrap();
+    outputs
+        .write_to(&args, &mut OutputDirectory::new(dir.clone(), false))
+        .unwrap();
+
+    // With the criteria asked for, the day's file holds the criteria results
+    let criteria_results: serde_json::Value =
+        utils::read_versioned_json_file(&dir.join("rail_hourly_departures_Tuesday_260113.json"))
+            .unwrap();
+    assert_eq!(
+        criteria_results,
+        serde_json::to_value(&outputs.days[0].criteria_results).unwrap()
+    );
+    for file_name in [
+        "station_name_lookup.json",
+        "run_report.json",
+        "manifest.json",
+    ] {
+        assert!(dir.join(file_name).exists(), "{file_name} was not written");
+    }
+    fs_err::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_run_from_an_in_memory_source_writes_to_an_in_memory_sink() {
+    let args = support::miniature_cif_args();
+    let source = InMemoryCif::new(
+        "mini",
+        include_str!("fixtures/mini/CIF_ALL_FULL_DAILY_toc-full.CIF"),
+    );
+    let mut outputs = run_from(&args, &source, RunInputs::read(&args).unwrap()).unwrap();
+    let mut sink = InMemoryOutputs::default();
+    outputs.write_to(&args, &mut sink).unwrap();
+
+    let written: serde_json::Value =
+        serde_json::from_slice(&sink.0["rail_hourly_departures_Tuesday_260113.json"]).unwrap();
+    assert_eq!(
+        written["data"],
+        serde_json::to_value(&run(&args).unwrap().days[0].criteria_results).unwrap()
+    );
+    assert!(sink.0.contains_key("manifest.json"));
+    assert_eq!(outputs.manifest.inputs[0].path, Path::new("mini"));
+}
+
+#[test]
+fn test_hours_as_object_only_changes_its_own_writes() {
+    let mut args = support::miniature_cif_args();
+    args.outputs = vec![OutputSelection::Departures];
+    let departures_file = |args: &Args| {
+        let mut sink = InMemoryOutputs::default();
+        run(args).unwrap().write_to(args, &mut sink).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(&sink.0["rail_hourly_departures_Tuesday_260113.json"]).unwrap();
+        written["data"]["KGX"]["hour_counts"].clone()
+    };
+    let as_array = departures_file(&args);
+    args.hours_as_object = true;
+    let as_object = departures_file(&args);
+    args.hours_as_object = false;
+
+    assert_eq!(as_object["08"], as_array[8]);
+    assert!(departures_file(&args).is_array());
+}
+
+#[test]
+fn test_dump_records_is_written_through_the_sink() {
+    let mut args = support::miniature_cif_args();
+    args.dump_records = true;
+    args.dump_range = Some("2:4".parse().unwrap());
+    let mut outputs = run(&args).unwrap();
+    assert_eq!(outputs.numbered_records.as_ref().unwrap().len(), 3);
+    let mut sink = InMemoryOutputs::default();
+    outputs.write_to(&args, &mut sink).unwrap();
+
+    let written = String::from_utf8(sink.0["numbered_records.jsonl"].clone()).unwrap();
+    let line_numbers: Vec<u64> = written
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<serde_json::Value>(line).unwrap()["line_number"]
+                .as_u64()
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(line_numbers, [2, 3, 4]);
+}
@@ -0,0 +1,46 @@
+//! The serialized shape of each output of a run over the miniature CIF file, checked against
+//! the JSON committed in tests/snapshots. A renamed, reordered or retyped field fails here;
+//! when the change is intended, rerun with UPDATE_SNAPSHOTS=1 and review the snapshot diff.
+
+mod support;
+
+use std::collections::BTreeMap;
+
+use rail_hourly_departures::{Outputs, run};
+
+fn outputs() -> Outputs {
+    run(&support::miniature_cif_args()).unwrap()
+}
+
+#[test]
+fn test_departures_snapshot() {
+    let outputs = outputs();
+    let departures: BTreeMap<_, _> = outputs.days[0].departures.iter().collect();
+    support::assert_json_snapshot("departures", &departures);
+}
+
+#[test]
+fn test_criteria_results_snapshot() {
+    let outputs = outputs();
+    let criteria_results: BTreeMap<_, _> = outputs.days[0].criteria_results.iter().collect();
+    support::assert_json_snapshot("criteria_results", &criteria_results);
+}
+
+#[test]
+fn test_station_name_lookup_snapshot() {
+    let outputs = outputs();
+    let station_names: BTreeMap<_, _> = outputs.station_names.iter().collect();
+    support::assert_json_snapshot("station_name_lookup", &station_names);
+}
+
+#[test]
+fn test_manifest_snapshot() {
+    let mut manifest = outputs().manifest;
+    // The parts that change from run to run
+    manifest.crate_version = "0.0.0".to_string();
+    manifest.arguments = vec!["rail-hourly-departures".to_string()];
+    for (_, seconds) in manifest.stage_seconds.iter_mut() {
+        *seconds = 0.0;
+    }
+    support::assert_json_snapshot("manifest", &manifest);
+}
@@ -0,0 +1,127 @@
+//! Shared by the integration tests and benchmarks: synthetic CIF files of any size.
+
+#![allow(dead_code)]
+
+use clap::Parser;
+use serde::Serialize;
+use std::path::Path;
+
+use rail_hourly_departures::{Args, ThreeAlphaCode};
+
+/// The arguments of a run over the miniature CIF file in tests/fixtures/mini, counting
+/// Tuesday 13 January 2026
+pub fn miniature_cif_args() -> Args {
+    Args::parse_from([
+        "rail-hourly-departures",
+        "--input-file-dir=tests/fixtures/mini",
+        "--station-codes-file=tests/fixtures/mini/station_codes.toml",
+        "--operating-week=260113",
+        "--output-directory=unused",
+    ])
+}
+
+/// Check `value`, serialized as pretty JSON, against tests/snapshots/{name}.json. With
+/// UPDATE_SNAPSHOTS=1 set the snapshot is written instead, to be reviewed in the diff.
+pub fn assert_json_snapshot<T: Serialize>(name: &str, value: &T) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{name}.json"));
+    let json = serde_json::to_string_pretty(value).unwrap() + "\n";
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs_err::create_dir_all(path.parent().unwrap()).unwrap();
+        fs_err::write(&path, json).unwrap();
+        return;
+    }
+    let snapshot = fs_err::read_to_string(&path).unwrap_or_else(|_| {
+        panic!(
+            "No snapshot at {}, run with UPDATE_SNAPSHOTS=1 to write it",
+            path.display()
+        )
+    });
+    assert!(
+        json == snapshot,
+        "{name} no longer matches {}; if the change is intended, run with UPDATE_SNAPSHOTS=1 \
+         and review the diff\n\nnow:\n{json}",
+        path.display()
+    );
+}
+
+/// How many stations a synthetic file's journeys call at
+const STATIONS: usize = 200;
+/// The stops of every synthetic journey: an origin, intermediates and a terminus
+const STOPS_PER_JOURNEY: usize = 10;
+
+/// A generated CIF file and the three alpha codes of its stations, to build the lookup with
+pub struct SyntheticCif {
+    pub text: String,
+    pub three_alpha_codes: Vec<ThreeAlphaCode>,
+}
+
+/// A valid fixed-width CIF file of about `lines` lines: a header, a TIPLOC insert per station,
+/// then passenger journeys running Monday to Friday. The same `lines` always gives the same
+/// file.
+pub fn synthetic_cif(lines: usize) -> SyntheticCif {
+    let mut text = String::new();
+    let mut push = |line: String| {
+        text.push_str(&format!("{line:<80}\n"));
+    };
+    push("HDTPS.UDFROC1.PD2601010101260600DFROC1A       FA010126311226".to_string());
+
+    let three_alpha_codes: Vec<ThreeAlphaCode> = (0..STATIONS).map(three_alpha_code).collect();
+    for (index, code) in three_alpha_codes.iter().enumerate() {
+        push(format!(
+            "TI{}00{:06} {:<26}{:05}    {}{:<16}",
+            tiploc(index),
+            100_000 + index,
+            format!("SYNTHETIC {index}"),
+            10_000 + index,
+            code.0,
+            format!("SYNTHETIC {index}")
+        ));
+    }
+
+    // A small linear congruential generator keeps the journeys varied but repeatable
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = |bound: usize| {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (state >> 33) as usize % bound
+    };
+    let journeys = lines.saturating_sub(1 + STATIONS) / (STOPS_PER_JOURNEY + 1);
+    for journey in 0..journeys {
+        push(format!(
+            "BSNS{:05}2601012612311111100 POO",
+            journey % 100_000
+        ));
+        let first_station = next(STATIONS);
+        let mut minutes = 5 * 60 + next(17 * 60);
+        for stop in 0..STOPS_PER_JOURNEY {
+            let station = tiploc((first_station + stop) % STATIONS);
+            let time = format!("{:02}{:02}", minutes / 60 % 24, minutes % 60);
+            match stop {
+                0 => push(format!("LO{station} {time} {time}")),
+                _ if stop == STOPS_PER_JOURNEY - 1 => push(format!("LT{station} {time} {time}")),
+                _ => push(format!(
+                    "LI{station} {time} {time}      {time}{time}         T"
+                )),
+            }
+            minutes += 2 + next(6);
+        }
+    }
+
+    SyntheticCif {
+        text,
+        three_alpha_codes,
+    }
+}
+
+fn tiploc(index: usize) -> String {
+    format!("SYN{index:04}   ")[..7].to_string()
+}
+
+/// AAA, AAB, ... for the nth station
+fn three_alpha_code(index: usize) -> ThreeAlphaCode {
+    let letter = |place: usize| (b'A' + (index / 26usize.pow(place as u32) % 26) as u8) as char;
+    ThreeAlphaCode([letter(2), letter(1), letter(0)].iter().collect())
+}